@@ -15,7 +15,18 @@ export type FormatNames =
   | "XPM"
   | "ICO"
   | "AVIF"
+  | "JPEG XL"
   | "PSD"
+  | "ORA"
+  | "KRA"
+  | "XCF"
+  | "DNG"
+  | "CR2"
+  | "NEF"
+  | "ARW"
+  | "SVG"
+  | "EXR"
+  | "HDR"
   | "MP4"
   | "MKV"
   | "WEBM"
@@ -114,10 +125,65 @@ export const FORMATS: { [name in FormatNames]: FileFormat } = {
     mimeType: "image/avif",
     fileExtension: [".avif"],
   },
+  "JPEG XL": {
+    name: "JPEG XL",
+    mimeType: "image/jxl",
+    fileExtension: [".jxl"],
+  },
   PSD: {
     name: "PSD",
     mimeType: "image/vnd.adobe.photoshop",
-    fileExtension: [".psd"],
+    fileExtension: [".psd", ".psb"],
+  },
+  ORA: {
+    name: "ORA",
+    mimeType: "image/openraster",
+    fileExtension: [".ora"],
+  },
+  KRA: {
+    name: "KRA",
+    mimeType: "application/x-krita",
+    fileExtension: [".kra"],
+  },
+  XCF: {
+    name: "XCF",
+    mimeType: "image/x-xcf",
+    fileExtension: [".xcf"],
+  },
+  DNG: {
+    name: "DNG",
+    mimeType: "image/x-adobe-dng",
+    fileExtension: [".dng"],
+  },
+  CR2: {
+    name: "CR2",
+    mimeType: "image/x-canon-cr2",
+    fileExtension: [".cr2"],
+  },
+  NEF: {
+    name: "NEF",
+    mimeType: "image/x-nikon-nef",
+    fileExtension: [".nef"],
+  },
+  ARW: {
+    name: "ARW",
+    mimeType: "image/x-sony-arw",
+    fileExtension: [".arw"],
+  },
+  SVG: {
+    name: "SVG",
+    mimeType: "image/svg+xml",
+    fileExtension: [".svg", ".svgz"],
+  },
+  EXR: {
+    name: "EXR",
+    mimeType: "image/x-exr",
+    fileExtension: [".exr"],
+  },
+  HDR: {
+    name: "HDR",
+    mimeType: "image/vnd.radiance",
+    fileExtension: [".hdr"],
   },
   MP4: {
     name: "MP4",
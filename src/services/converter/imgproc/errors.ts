@@ -0,0 +1,66 @@
+/** Mirrors `ErrorCode` in wasm/src/error.rs. */
+export type ImgprocErrorCode =
+  | "unsupported_format"
+  | "corrupt_data"
+  | "encode_failed"
+  | "invalid_argument"
+  | "limit_exceeded"
+  | "cancelled"
+  | "internal";
+
+export interface ImgprocErrorInfo {
+  code: ImgprocErrorCode;
+  message: string;
+  format?: string;
+  frameIndex?: number;
+  byteOffset?: number;
+  cause?: string;
+  /** The wasm instance trapped and the worker holding it must not be reused. */
+  fatal?: boolean;
+}
+
+export class ImgprocError extends Error {
+  public code: ImgprocErrorCode;
+  public format?: string;
+  public frameIndex?: number;
+  public byteOffset?: number;
+  public cause?: string;
+
+  constructor(info: ImgprocErrorInfo) {
+    super(info.message);
+    this.name = "ImgprocError";
+    this.code = info.code;
+    this.format = info.format;
+    this.frameIndex = info.frameIndex;
+    this.byteOffset = info.byteOffset;
+    this.cause = info.cause;
+  }
+}
+
+/** Extracts the fields of an error thrown by the wasm module, so it can cross `postMessage`. */
+export function ToImgprocErrorInfo(err: unknown): ImgprocErrorInfo {
+  if (err instanceof Error && "code" in err) {
+    const { code, format, frameIndex, byteOffset, cause } =
+      err as Error & Partial<ImgprocErrorInfo>;
+    return {
+      code: code ?? "internal",
+      message: err.message,
+      format,
+      frameIndex,
+      byteOffset,
+      cause,
+    };
+  }
+
+  if (err instanceof WebAssembly.RuntimeError) {
+    return { code: "internal", message: err.message, fatal: true };
+  }
+
+  const message =
+    err instanceof Error
+      ? err.message
+      : err instanceof Object
+        ? JSON.stringify(err)
+        : String(err);
+  return { code: "internal", message };
+}
@@ -1,183 +1,283 @@
 import {
   type FileFormat,
   FORMATS,
-  type FormatNames,
   type LogPrinter,
 } from "services/converter/file-formats";
+import { ImgprocError } from "services/converter/imgproc/errors";
 import type {
+  CancelToken,
+  CapabilitiesRequest,
+  CapabilitiesResponse,
   ConvertAnimatedImageRequest,
   ConvertAnimatedImageResponse,
   DecodeStaticImageRequest,
   DecodeStaticImageResponse,
   DetectAnimationRequest,
   DetectAnimationResponse,
+  ErrorResponse,
+  FormatCapabilityInfo,
   GetFirstFrameRequest,
   GetFirstFrameResponse,
   LogResponse,
+  SvgRasterOptions,
+  WorkerRequest,
 } from "services/converter/imgproc/worker";
+import {
+  FormatProgress,
+  type ProgressListener,
+} from "services/converter/imgproc/progress";
 import { ToBlobPart } from "structs/blob-part";
 
-const DECODE_STATIC_IMAGE_SUPPORTED_INPUT_FORMATS: FormatNames[] = ["PSD"];
-
-const DETECT_ANIMATION_SUPPORTED_INPUT_FORMAT: FormatNames[] = [
-  "PNG",
-  "GIF",
-  "WebP",
-  "APNG",
-];
-
-const CONVERT_ANIMATED_IMAGE_SUPPORTED_INPUT_FORMATS: FormatNames[] = [
-  "GIF",
-  "PNG",
-  "APNG",
-  "WebP",
-];
-
-const CONVERT_ANIMATED_IMAGE_SUPPORTED_OUTPUT_FORMATS: FormatNames[] = [
-  "GIF",
-  "APNG",
-];
-
-const GET_FIRST_FRAME_SUPPORTED_INPUT_FORMATS: FormatNames[] = [
-  "GIF",
-  "PNG",
-  "APNG",
-  "WebP",
-];
-
 export default class Imgproc {
-  public static IsSupportedDecodeStaticImage(
-    sourceFormat: FileFormat,
-  ): boolean {
-    return DECODE_STATIC_IMAGE_SUPPORTED_INPUT_FORMATS.includes(
-      sourceFormat.name,
+  /** Loaded once from the wasm module, which owns the list of supported formats. */
+  private static capabilities?: Promise<FormatCapabilityInfo[]>;
+
+  public static Capabilities(): Promise<FormatCapabilityInfo[]> {
+    Imgproc.capabilities ??= new Promise((resolve, reject) => {
+      const worker = Imgproc.AcquireWorker();
+      const fail = (reason: unknown) => {
+        worker.terminate();
+        Imgproc.capabilities = undefined;
+        reject(reason);
+      };
+
+      worker.onerror = (err) => fail(err.error);
+      worker.onmessage = (
+        e: MessageEvent<CapabilitiesResponse | ErrorResponse>,
+      ) => {
+        const data = e.data;
+        if (data.functionName === "Error") {
+          fail(new ImgprocError(data.error));
+          return;
+        }
+        Imgproc.ReleaseWorker(worker);
+        resolve(data.formats);
+      };
+
+      const req: CapabilitiesRequest = { functionName: "Capabilities" };
+      worker.postMessage(req);
+    });
+    return Imgproc.capabilities;
+  }
+
+  /** Matches on the format name first, then on its file extensions. */
+  private static async FindCapability(
+    format: FileFormat,
+  ): Promise<FormatCapabilityInfo | undefined> {
+    const names = [format.name, ...format.fileExtension].map((name) =>
+      name.replace(/^\./, "").toLowerCase(),
+    );
+    const formats = await Imgproc.Capabilities();
+    return (
+      formats.find((capability) => names.includes(capability.name)) ??
+      formats.find((capability) =>
+        capability.extensions.some((extension) => names.includes(extension)),
+      )
     );
   }
 
-  public static IsSupportedDetectAnimation(sourceFormat: FileFormat): boolean {
-    return DETECT_ANIMATION_SUPPORTED_INPUT_FORMAT.includes(sourceFormat.name);
+  public static async IsSupportedDecodeStaticImage(
+    sourceFormat: FileFormat,
+  ): Promise<boolean> {
+    return (await Imgproc.FindCapability(sourceFormat))?.decode ?? false;
+  }
+
+  public static async IsSupportedDetectAnimation(
+    sourceFormat: FileFormat,
+  ): Promise<boolean> {
+    const source = await Imgproc.FindCapability(sourceFormat);
+    return source?.detectAnimation ?? false;
   }
 
-  public static IsSupportedConvertAnimatedImage(
+  public static async IsSupportedConvertAnimatedImage(
     sourceFormat: FileFormat,
     targetFormat: FileFormat,
-  ): boolean {
-    const sourceSupported =
-      CONVERT_ANIMATED_IMAGE_SUPPORTED_INPUT_FORMATS.includes(
-        sourceFormat.name,
-      );
-    const targetSupported =
-      CONVERT_ANIMATED_IMAGE_SUPPORTED_OUTPUT_FORMATS.includes(
-        targetFormat.name,
-      );
-    return sourceSupported && targetSupported;
+  ): Promise<boolean> {
+    const [source, target] = await Promise.all([
+      Imgproc.FindCapability(sourceFormat),
+      Imgproc.FindCapability(targetFormat),
+    ]);
+    return (source?.animate ?? false) && (target?.encodeAnimation ?? false);
+  }
+
+  public static async IsSupportedGetFirstFrame(
+    sourceFormat: FileFormat,
+  ): Promise<boolean> {
+    return (await Imgproc.FindCapability(sourceFormat))?.decode ?? false;
+  }
+
+  /** Workers are kept alive between calls so the wasm module is only initialised once. */
+  private static idleWorkers: Worker[] = [];
+
+  private static AcquireWorker(): Worker {
+    return (
+      Imgproc.idleWorkers.pop() ??
+      new Worker(new URL("worker.ts", import.meta.url), {
+        type: "module",
+      })
+    );
+  }
+
+  private static ReleaseWorker(worker: Worker) {
+    worker.onmessage = null;
+    worker.onerror = null;
+    Imgproc.idleWorkers.push(worker);
   }
 
-  public static IsSupportedGetFirstFrame(sourceFormat: FileFormat): boolean {
-    return GET_FIRST_FRAME_SUPPORTED_INPUT_FORMATS.includes(sourceFormat.name);
+  /**
+   * Asks a conversion to stop between frames instead of the worker being terminated. A
+   * posted message would not arrive until the conversion returns, so the worker reads a
+   * shared flag when the page is cross-origin isolated. Otherwise it polls an object URL
+   * that cancelling revokes; isolation is not forced, as it would break the Google sign-in
+   * popup.
+   */
+  private static CreateCancelToken(): CancelToken {
+    if (self.crossOriginIsolated) {
+      return { flag: new Int32Array(new SharedArrayBuffer(4)) };
+    }
+    return { url: URL.createObjectURL(new Blob()) };
+  }
+
+  private static Cancel(token: CancelToken) {
+    if (token.flag) {
+      Atomics.store(token.flag, 0, 1);
+    }
+    if (token.url) {
+      URL.revokeObjectURL(token.url);
+    }
   }
 
   private abortController: AbortController;
-  private worker: Worker;
   private LogMessage: LogPrinter;
-  constructor(abortController: AbortController, LogMessage: LogPrinter) {
+  private onProgress?: ProgressListener;
+  constructor(
+    abortController: AbortController,
+    LogMessage: LogPrinter,
+    onProgress?: ProgressListener,
+  ) {
     this.abortController = abortController;
-    this.worker = new Worker(new URL("worker.ts", import.meta.url), {
-      type: "module",
-    });
     this.LogMessage = LogMessage;
+    this.onProgress = onProgress;
   }
 
-  private postMessage<T>(req: T) {
-    this.worker.postMessage(req);
-  }
-
-  public async DecodeStaticImage(
-    file: File,
-    sourceFormat: FileFormat,
-  ): Promise<{
-    decodedFile: File;
-    decodedFileFormat: FileFormat;
-  }> {
-    const { abortController, worker, LogMessage } = this;
-    const functionName = "DecodeStaticImage";
-    const fileUrl = URL.createObjectURL(file);
+  /**
+   * Runs one request on a pooled worker. Without a cancel token an abort terminates the
+   * worker; with one the worker stops at the next frame and goes back to the pool.
+   */
+  private request<Res extends { functionName: string }>(
+    req: WorkerRequest,
+    abortMessage: string,
+    cancelToken?: CancelToken,
+  ): Promise<Res> {
+    const { abortController, LogMessage, onProgress } = this;
+    const worker = Imgproc.AcquireWorker();
 
     return new Promise((resolve, reject) => {
-      abortController.signal.addEventListener("abort", () => {
-        URL.revokeObjectURL(fileUrl);
-        worker.terminate();
-        reject("Decode aborted by user.");
-      });
+      const settle = (reusable: boolean) => {
+        abortController.signal.removeEventListener("abort", onAbort);
+        URL.revokeObjectURL(req.fileUrl);
+        if (cancelToken) {
+          Imgproc.Cancel(cancelToken);
+        }
+        if (reusable) {
+          Imgproc.ReleaseWorker(worker);
+        } else {
+          worker.terminate();
+        }
+      };
+
+      const onAbort = () => {
+        if (cancelToken) {
+          Imgproc.Cancel(cancelToken);
+          return;
+        }
+        settle(false);
+        reject(abortMessage);
+      };
+
+      if (abortController.signal.aborted) {
+        settle(true);
+        reject(abortMessage);
+        return;
+      }
+      abortController.signal.addEventListener("abort", onAbort);
 
       worker.onerror = (err) => {
+        settle(false);
         reject(err.error);
       };
 
       worker.onmessage = (
-        e: MessageEvent<DecodeStaticImageResponse | LogResponse>,
+        e: MessageEvent<Res | LogResponse | ErrorResponse>,
       ) => {
-        if (e.data.functionName === "Log") {
-          LogMessage(e.data.message);
+        const data = e.data;
+        if (data.functionName === "Log") {
+          const { event } = data as LogResponse;
+          LogMessage(FormatProgress(event));
+          onProgress?.(event);
           return;
         }
 
-        const decodedFileFormat = FORMATS.PNG;
-
-        const decodedFile = new File(
-          [ToBlobPart(e.data.decodedFile)],
-          file.name.replace(/(\.[^.]+)$/, decodedFileFormat.fileExtension[0]),
-          { type: decodedFileFormat.mimeType },
-        );
-
-        resolve({ decodedFile, decodedFileFormat });
+        if (data.functionName === "Error") {
+          const { error } = data as ErrorResponse;
+          settle(!error.fatal);
+          reject(
+            error.code === "cancelled" ? abortMessage : new ImgprocError(error),
+          );
+          return;
+        }
+        settle(true);
+        resolve(data as Res);
       };
 
-      this.postMessage<DecodeStaticImageRequest>({
-        functionName,
-        fileUrl,
-        sourceFormat,
-      });
+      worker.postMessage(req);
     });
   }
 
-  public async DetectAnimation(
+  public async DecodeStaticImage(
     file: File,
     sourceFormat: FileFormat,
-  ): Promise<{ isAnimation: boolean }> {
-    const { abortController, worker, LogMessage } = this;
-    const functionName = "DetectAnimation";
-    const fileUrl = URL.createObjectURL(file);
-
-    return new Promise((resolve, reject) => {
-      abortController.signal.addEventListener("abort", () => {
-        URL.revokeObjectURL(fileUrl);
-        worker.terminate();
-        reject("Detection aborted by user.");
-      });
+    svgOptions?: SvgRasterOptions,
+  ): Promise<{
+    decodedFile: File;
+    decodedFileFormat: FileFormat;
+  }> {
+    const req: DecodeStaticImageRequest = {
+      functionName: "DecodeStaticImage",
+      fileUrl: URL.createObjectURL(file),
+      sourceFormat,
+      svgOptions,
+    };
+    const res = await this.request<DecodeStaticImageResponse>(
+      req,
+      "Decode aborted by user.",
+    );
 
-      worker.onerror = (err) => {
-        reject(err.error);
-      };
+    const decodedFileFormat = FORMATS.PNG;
 
-      worker.onmessage = (
-        e: MessageEvent<DetectAnimationResponse | LogResponse>,
-      ) => {
-        if (e.data.functionName === "Log") {
-          LogMessage(e.data.message);
-          return;
-        }
+    const decodedFile = new File(
+      [ToBlobPart(res.decodedFile)],
+      file.name.replace(/(\.[^.]+)$/, decodedFileFormat.fileExtension[0]),
+      { type: decodedFileFormat.mimeType },
+    );
 
-        const { isAnimation } = e.data;
-        resolve({ isAnimation });
-      };
+    return { decodedFile, decodedFileFormat };
+  }
 
-      this.postMessage<DetectAnimationRequest>({
-        functionName,
-        fileUrl,
-        sourceFormat,
-      });
-    });
+  public async DetectAnimation(
+    file: File,
+    sourceFormat: FileFormat,
+  ): Promise<{ isAnimation: boolean }> {
+    const req: DetectAnimationRequest = {
+      functionName: "DetectAnimation",
+      fileUrl: URL.createObjectURL(file),
+      sourceFormat,
+    };
+    const { isAnimation } = await this.request<DetectAnimationResponse>(
+      req,
+      "Detection aborted by user.",
+    );
+    return { isAnimation };
   }
 
   public async ConvertAnimatedImage(
@@ -194,62 +294,43 @@ export default class Imgproc {
       fileFormat: FileFormat;
     };
   }> {
-    const { abortController, worker, LogMessage } = this;
-    const functionName = "ConvertAnimatedImage";
-    const fileUrl = URL.createObjectURL(file);
-
-    return new Promise((resolve, reject) => {
-      abortController.signal.addEventListener("abort", () => {
-        URL.revokeObjectURL(fileUrl);
-        worker.terminate();
-        reject("Convertion aborted by user.");
-      });
-
-      worker.onerror = (err) => {
-        reject(err.error);
-      };
+    const cancelToken = Imgproc.CreateCancelToken();
+    const req: ConvertAnimatedImageRequest = {
+      functionName: "ConvertAnimatedImage",
+      fileUrl: URL.createObjectURL(file),
+      sourceFormat,
+      targetFormat,
+      cancelToken,
+    };
+    const res = await this.request<ConvertAnimatedImageResponse>(
+      req,
+      "Convertion aborted by user.",
+      cancelToken,
+    );
 
-      worker.onmessage = (
-        e: MessageEvent<ConvertAnimatedImageResponse | LogResponse>,
-      ) => {
-        if (e.data.functionName === "Log") {
-          LogMessage(e.data.message);
-          return;
-        }
+    const convertedFile = new File(
+      [ToBlobPart(res.convertedFile)],
+      file.name.replace(/(\.[^.]+)$/, targetFormat.fileExtension[0]),
+      { type: targetFormat.mimeType },
+    );
 
-        const convertedFile = new File(
-          [ToBlobPart(e.data.convertedFile)],
-          file.name.replace(/(\.[^.]+)$/, targetFormat.fileExtension[0]),
-          { type: targetFormat.mimeType },
-        );
-
-        const firstFrameFormat = FORMATS.PNG;
-        const firstFrame = new File(
-          [ToBlobPart(e.data.firstFrame)],
-          file.name.replace(/(\.[^.]+)$/, firstFrameFormat.fileExtension[0]),
-          { type: firstFrameFormat.mimeType },
-        );
-
-        const convertedFileFormat = targetFormat;
-        resolve({
-          converted: {
-            file: convertedFile,
-            fileFormat: convertedFileFormat,
-          },
-          firstFrame: {
-            file: firstFrame,
-            fileFormat: firstFrameFormat,
-          },
-        });
-      };
+    const firstFrameFormat = FORMATS.PNG;
+    const firstFrame = new File(
+      [ToBlobPart(res.firstFrame)],
+      file.name.replace(/(\.[^.]+)$/, firstFrameFormat.fileExtension[0]),
+      { type: firstFrameFormat.mimeType },
+    );
 
-      this.postMessage<ConvertAnimatedImageRequest>({
-        functionName,
-        fileUrl,
-        sourceFormat,
-        targetFormat,
-      });
-    });
+    return {
+      converted: {
+        file: convertedFile,
+        fileFormat: targetFormat,
+      },
+      firstFrame: {
+        file: firstFrame,
+        fileFormat: firstFrameFormat,
+      },
+    };
   }
 
   public async GetFirstFrame(
@@ -259,48 +340,24 @@ export default class Imgproc {
     firstFrameFile: File;
     firstFrameFileFormat: FileFormat;
   }> {
-    const { abortController, worker, LogMessage } = this;
-    const functionName = "GetFirstFrame";
-    const fileUrl = URL.createObjectURL(file);
-
-    return new Promise((resolve, reject) => {
-      abortController.signal.addEventListener("abort", () => {
-        URL.revokeObjectURL(fileUrl);
-        worker.terminate();
-        reject("Get first frame aborted by user.");
-      });
-
-      worker.onerror = (err) => {
-        reject(err.error);
-      };
-
-      worker.onmessage = (
-        e: MessageEvent<GetFirstFrameResponse | LogResponse>,
-      ) => {
-        if (e.data.functionName === "Log") {
-          LogMessage(e.data.message);
-          return;
-        }
-
-        const firstFrameFileFormat = FORMATS.PNG;
+    const req: GetFirstFrameRequest = {
+      functionName: "GetFirstFrame",
+      fileUrl: URL.createObjectURL(file),
+      sourceFormat,
+    };
+    const res = await this.request<GetFirstFrameResponse>(
+      req,
+      "Get first frame aborted by user.",
+    );
 
-        const firstFrameFile = new File(
-          [ToBlobPart(e.data.firstFrame)],
-          file.name.replace(
-            /(\.[^.]+)$/,
-            firstFrameFileFormat.fileExtension[0],
-          ),
-          { type: firstFrameFileFormat.mimeType },
-        );
+    const firstFrameFileFormat = FORMATS.PNG;
 
-        resolve({ firstFrameFile, firstFrameFileFormat });
-      };
+    const firstFrameFile = new File(
+      [ToBlobPart(res.firstFrame)],
+      file.name.replace(/(\.[^.]+)$/, firstFrameFileFormat.fileExtension[0]),
+      { type: firstFrameFileFormat.mimeType },
+    );
 
-      this.postMessage<GetFirstFrameRequest>({
-        functionName,
-        fileUrl,
-        sourceFormat,
-      });
-    });
+    return { firstFrameFile, firstFrameFileFormat };
   }
 }
@@ -0,0 +1,63 @@
+/** Mirrors `ProgressEvent` in wasm/src/callback_logs.rs. */
+export interface ProgressEvent {
+  stage: "decode" | "transform" | "quantize" | "encode" | "finalize";
+  code: string;
+  args: string[];
+  current?: number;
+  total?: number;
+  bytesWritten?: number;
+}
+
+export type ProgressListener = (event: ProgressEvent) => void;
+
+const MESSAGES: { [code: string]: (args: string[]) => string } = {
+  decode_start: ([format]) => `Decoding ${format} image...`,
+  decode_frame: () => "Decoding frames...",
+  decode_done: ([count, format]) => `Decoded ${count} ${format} frames.`,
+  first_frame_encoded: () => "Successfully encoded the first frame as PNG.",
+  encode_start: ([format]) => `Starting ${format} encoding...`,
+  image_dimensions: ([width, height]) =>
+    `Image dimensions: ${width}x${height}`,
+  frame_count: ([count]) => `Number of frames: ${count}`,
+  encode_frame: () => "Encoding frames...",
+  encode_done: ([format]) => `${format} encoding finished successfully.`,
+  hash_frames: ([count]) => `Hashing ${count} frames...`,
+  hash_pixels: ([count]) => `Hashing pixels of ${count} frames...`,
+  extract_colors: ([count]) => `Extracting ${count} dominant colors...`,
+  encode_placeholders: () => "Encoding BlurHash and ThumbHash placeholders...",
+  compare_frames: ([a, b]) => `Comparing ${a} frames against ${b} frames...`,
+  quality_search: ([format, minSsim]) =>
+    `Searching ${format} settings for SSIM of at least ${minSsim}...`,
+  quantize_palette: ([colors, frames]) =>
+    `Quantizing ${frames} frames to ${colors} colors...`,
+  quality_candidate: ([settings, bytes, ssim]) =>
+    `Tried ${settings}: ${bytes} bytes, SSIM ${ssim}`,
+  pipeline_step: ([op]) => `Running ${op}...`,
+  composite_layers: ([count]) => `Compositing ${count} layers...`,
+  export_layer: ([name]) => `Exporting layer ${name}...`,
+  psd_source: ([source]) =>
+    source === "merged_image"
+      ? "Using the PSD's merged image."
+      : "The PSD has no readable merged image; compositing its layers...",
+  raw_preview: ([format]) => `Extracting the embedded ${format} preview...`,
+  raw_develop: ([camera]) => `Developing the ${camera} raw image...`,
+  tone_map: ([operator]) => `Tone mapping the HDR image (${operator})...`,
+};
+
+/** Renders an event as an English log line. */
+export function FormatProgress(event: ProgressEvent): string {
+  const format = MESSAGES[event.code];
+  let message = format ? format(event.args) : event.code;
+
+  if (event.current !== undefined) {
+    const count =
+      event.total !== undefined
+        ? `${event.current}/${event.total}`
+        : `${event.current}`;
+    message = `${message} (${count})`;
+  }
+  if (event.bytesWritten !== undefined) {
+    message = `${message} [${event.bytesWritten} bytes]`;
+  }
+  return message;
+}
@@ -1,24 +1,69 @@
 use image::{
     codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
-    AnimationDecoder, Frame, ImageBuffer, Rgba,
+    AnimationDecoder, Frame, ImageBuffer, Rgba, RgbaImage,
 };
-use js_sys::Uint8Array;
 use std::{io::Cursor, vec::IntoIter};
-use wasm_bindgen::JsError;
 
-use crate::{callback_logs::CallbackLogs, first_frame::encode_png};
+use crate::{
+    callback_logs::{CallbackLogs, ProgressEvent, Stage},
+    cancellation::Cancellation,
+    error::{ErrorCode, ImgprocError, Result},
+    first_frame::encode_png,
+    formats::{sniff_handler, source_format, FormatHandler},
+    limits::{limit_decoder, DecodeBudget, DecodeLimits},
+};
 
 pub type ImgprocFrameDecoder = Box<dyn FrameDecoder>;
+/// A frame and its delay in milliseconds. Frames are always 8-bit RGBA: 16-bit and HDR
+/// samples are only kept for stills, through `FormatHandler::decode_deep`.
+pub type DecodedFrame = (ImageBuffer<Rgba<u8>, Vec<u8>>, u32);
+
+/// Returns the frame delay rounded to whole milliseconds.
+pub fn delay_ms(frame: &Frame) -> u32 {
+    let (numerator, denominator) = frame.delay().numer_denom_ms();
+    if denominator == 0 {
+        return 0;
+    }
+    ((numerator as u64 + denominator as u64 / 2) / denominator as u64) as u32
+}
+
+/// Checks the canvas size and the frame count from the `acTL` chunk before any frame is decoded.
+pub fn check_apng_header(image_data: &[u8], limits: &DecodeLimits) -> Result<()> {
+    let decoder = png::Decoder::new_with_limits(image_data, png::Limits { bytes: usize::MAX });
+    let reader = decoder.read_info().map_err(|e| {
+        ImgprocError::corrupt("Failed to read PNG header.")
+            .format("APNG")
+            .cause(e)
+    })?;
+
+    let info = reader.info();
+    limits.check_dimensions(info.width, info.height, "APNG")?;
+    if let Some(control) = info.animation_control() {
+        limits.check_frame_count(control.num_frames, "APNG")?;
+    }
+    Ok(())
+}
+
+/// Picks the frame decoder for `format` and decodes all frames up front. Still images decode
+/// to a single frame.
+pub fn animated_image_decode(
+    format: &str,
+    image_data: &[u8],
+    logs: &CallbackLogs,
+    cancel: &Cancellation,
+) -> Result<ImgprocFrameDecoder> {
+    source_format(format)?.frame_decoder(image_data, logs, cancel)
+}
 
 pub trait FrameDecoder {
-    fn new(image_data: &[u8], logs: &mut CallbackLogs) -> Result<Box<Self>, JsError>
+    fn new(image_data: &[u8], logs: &CallbackLogs, cancel: &Cancellation) -> Result<Box<Self>>
     where
         Self: Sized;
     fn width(&self) -> u32;
     fn height(&self) -> u32;
     fn num_frames(&self) -> u32;
-    fn next_frame(&mut self) -> Option<(ImageBuffer<Rgba<u8>, Vec<u8>>, u32)>;
-    fn first_frame(&mut self) -> Result<Uint8Array, JsError>;
+    fn next_frame(&mut self) -> Option<DecodedFrame>;
+    fn first_frame(&mut self) -> Result<Vec<u8>>;
 }
 
 pub struct ImgprocApngDecoder {
@@ -26,21 +71,29 @@ pub struct ImgprocApngDecoder {
     width: u32,
     height: u32,
     num_frames: u32,
-    first_frame: Option<Uint8Array>,
+    first_frame: Option<Vec<u8>>,
 }
 
 impl FrameDecoder for ImgprocApngDecoder {
-    fn new(image_data: &[u8], logs: &mut CallbackLogs) -> Result<Box<Self>, JsError> {
-        logs("Decoding APNG image...")?;
+    fn new(image_data: &[u8], logs: &CallbackLogs, cancel: &Cancellation) -> Result<Box<Self>> {
+        logs.send(ProgressEvent::new(Stage::Decode, "decode_start").arg("APNG"))?;
+        let mut budget = DecodeBudget::new("APNG");
+        check_apng_header(image_data, budget.limits())?;
+
         let cursor = Cursor::new(image_data);
-        let png_decoder = match PngDecoder::new(cursor) {
+        let png_decoder = match PngDecoder::with_limits(cursor, budget.limits().image_limits()) {
             Ok(decoder) => decoder,
-            Err(_) => return Err(JsError::new("Failed to create PNG decoder for APNG.")),
+            Err(e) => {
+                return Err(
+                    ImgprocError::decode("Failed to create PNG decoder for APNG.", e)
+                        .format("APNG"),
+                )
+            }
         };
-        
+
         let decoder = match png_decoder.apng() {
             Ok(decoder) => decoder,
-            Err(_) => return Err(JsError::new("Failed to decode APNG.")),
+            Err(e) => return Err(ImgprocError::decode("Failed to decode APNG.", e).format("APNG")),
         };
 
         let mut frames: Vec<Frame> = Vec::new();
@@ -49,25 +102,38 @@ impl FrameDecoder for ImgprocApngDecoder {
         let mut height = 0;
 
         let mut num_frames = 0;
-        let mut first_frame = Uint8Array::new_with_length(0);
+        let mut first_frame = Vec::new();
         for frame_result in decoder.into_frames() {
+            cancel()?;
             match frame_result {
                 Ok(frame) => {
+                    budget.add_frame(frame.buffer().width(), frame.buffer().height())?;
                     if num_frames == 0 {
                         let image = frame.buffer();
                         width = image.width();
                         height = image.height();
                         first_frame = encode_png(image.clone())?;
-                        logs("Successfully encoded the first frame as PNG.")?;
+                        logs.send(ProgressEvent::new(Stage::Decode, "first_frame_encoded"))?;
                     }
                     frames.push(frame);
                     num_frames += 1;
+                    logs.send(
+                        ProgressEvent::new(Stage::Decode, "decode_frame").step(num_frames, None),
+                    )?;
+                }
+                Err(e) => {
+                    return Err(ImgprocError::decode("Failed to decode a frame.", e)
+                        .format("APNG")
+                        .frame(num_frames))
                 }
-                Err(_) => return Err(JsError::new("Failed to decode a GIF frame.")),
             }
         }
 
-        logs(format!("Decoded {} APNG frames.", num_frames).as_str())?;
+        logs.send(
+            ProgressEvent::new(Stage::Decode, "decode_done")
+                .arg(num_frames)
+                .arg("APNG"),
+        )?;
 
         Ok(Box::new(ImgprocApngDecoder {
             frames: frames.into_iter(),
@@ -90,10 +156,10 @@ impl FrameDecoder for ImgprocApngDecoder {
         self.num_frames
     }
 
-    fn next_frame(&mut self) -> Option<(ImageBuffer<Rgba<u8>, Vec<u8>>, u32)> {
+    fn next_frame(&mut self) -> Option<DecodedFrame> {
         match self.frames.next() {
             Some(frame) => {
-                let delay = frame.delay().numer_denom_ms().0;
+                let delay = delay_ms(&frame);
                 let image_data = frame.into_buffer();
                 Some((image_data, delay))
             }
@@ -101,10 +167,12 @@ impl FrameDecoder for ImgprocApngDecoder {
         }
     }
 
-    fn first_frame(&mut self) -> Result<Uint8Array, JsError> {
+    fn first_frame(&mut self) -> Result<Vec<u8>> {
         match self.first_frame.take() {
             Some(first_frame) => Ok(first_frame),
-            None => Err(JsError::new("First frame of APNG not available.")),
+            None => {
+                Err(ImgprocError::internal("The first frame was already taken.").format("APNG"))
+            }
         }
     }
 }
@@ -114,43 +182,61 @@ pub struct ImgprocGifDecoder {
     width: u32,
     height: u32,
     num_frames: u32,
-    first_frame: Option<Uint8Array>,
+    first_frame: Option<Vec<u8>>,
 }
 
 impl FrameDecoder for ImgprocGifDecoder {
-    fn new(image_data: &[u8], logs: &mut CallbackLogs) -> Result<Box<Self>, JsError> {
-        logs("Decoding GIF image...")?;
+    fn new(image_data: &[u8], logs: &CallbackLogs, cancel: &Cancellation) -> Result<Box<Self>> {
+        logs.send(ProgressEvent::new(Stage::Decode, "decode_start").arg("GIF"))?;
         let cursor = Cursor::new(image_data);
-        let gif_decoder = match GifDecoder::new(cursor) {
+        let mut gif_decoder = match GifDecoder::new(cursor) {
             Ok(decoder) => decoder,
-            Err(_) => return Err(JsError::new("Failed to create GIF decoder.")),
+            Err(e) => {
+                return Err(ImgprocError::decode("Failed to create GIF decoder.", e).format("GIF"))
+            }
         };
 
+        limit_decoder(&mut gif_decoder, "GIF")?;
+        let mut budget = DecodeBudget::new("GIF");
+
         let mut frames: Vec<Frame> = Vec::new();
 
         let mut width = 0;
         let mut height = 0;
 
         let mut num_frames = 0;
-        let mut first_frame = Uint8Array::new_with_length(0);
+        let mut first_frame = Vec::new();
         for frame_result in gif_decoder.into_frames() {
+            cancel()?;
             match frame_result {
                 Ok(frame) => {
+                    budget.add_frame(frame.buffer().width(), frame.buffer().height())?;
                     if num_frames == 0 {
                         let image = frame.buffer();
                         width = image.width();
                         height = image.height();
                         first_frame = encode_png(image.clone())?;
-                        logs("Successfully encoded the first GIF frame as PNG.")?;
+                        logs.send(ProgressEvent::new(Stage::Decode, "first_frame_encoded"))?;
                     }
                     frames.push(frame);
                     num_frames += 1;
+                    logs.send(
+                        ProgressEvent::new(Stage::Decode, "decode_frame").step(num_frames, None),
+                    )?;
+                }
+                Err(e) => {
+                    return Err(ImgprocError::decode("Failed to decode a frame.", e)
+                        .format("GIF")
+                        .frame(num_frames))
                 }
-                Err(_) => return Err(JsError::new("Failed to decode a GIF frame.")),
             }
         }
 
-        logs(format!("Decoded {} GIF frames.", num_frames).as_str())?;
+        logs.send(
+            ProgressEvent::new(Stage::Decode, "decode_done")
+                .arg(num_frames)
+                .arg("GIF"),
+        )?;
 
         Ok(Box::new(ImgprocGifDecoder {
             frames: frames.into_iter(),
@@ -173,10 +259,10 @@ impl FrameDecoder for ImgprocGifDecoder {
         self.num_frames
     }
 
-    fn next_frame(&mut self) -> Option<(ImageBuffer<Rgba<u8>, Vec<u8>>, u32)> {
+    fn next_frame(&mut self) -> Option<DecodedFrame> {
         match self.frames.next() {
             Some(frame) => {
-                let delay = frame.delay().numer_denom_ms().0 * 10;
+                let delay = delay_ms(&frame);
                 let image_data = frame.into_buffer();
                 Some((image_data, delay))
             }
@@ -184,10 +270,10 @@ impl FrameDecoder for ImgprocGifDecoder {
         }
     }
 
-    fn first_frame(&mut self) -> Result<Uint8Array, JsError> {
+    fn first_frame(&mut self) -> Result<Vec<u8>> {
         match self.first_frame.take() {
             Some(first_frame) => Ok(first_frame),
-            None => Err(JsError::new("First frame of GIF not available.")),
+            None => Err(ImgprocError::internal("The first frame was already taken.").format("GIF")),
         }
     }
 }
@@ -197,43 +283,61 @@ pub struct ImgprocWebpDecoder {
     width: u32,
     height: u32,
     num_frames: u32,
-    first_frame: Option<Uint8Array>,
+    first_frame: Option<Vec<u8>>,
 }
 
 impl FrameDecoder for ImgprocWebpDecoder {
-    fn new(image_data: &[u8], logs: &mut CallbackLogs) -> Result<Box<Self>, JsError> {
-        logs("Decoding WebP image...")?;
+    fn new(image_data: &[u8], logs: &CallbackLogs, cancel: &Cancellation) -> Result<Box<Self>> {
+        logs.send(ProgressEvent::new(Stage::Decode, "decode_start").arg("WebP"))?;
         let cursor = Cursor::new(image_data);
-        let webp_decoder = match WebPDecoder::new(cursor) {
+        let mut webp_decoder = match WebPDecoder::new(cursor) {
             Ok(decoder) => decoder,
-            Err(_) => return Err(JsError::new("Failed to create WebP decoder.")),
+            Err(e) => {
+                return Err(ImgprocError::decode("Failed to create WebP decoder.", e).format("WebP"))
+            }
         };
 
+        limit_decoder(&mut webp_decoder, "WebP")?;
+        let mut budget = DecodeBudget::new("WebP");
+
         let mut frames: Vec<Frame> = Vec::new();
 
         let mut width = 0;
         let mut height = 0;
 
         let mut num_frames = 0;
-        let mut first_frame = Uint8Array::new_with_length(0);
+        let mut first_frame = Vec::new();
         for frame_result in webp_decoder.into_frames() {
+            cancel()?;
             match frame_result {
                 Ok(frame) => {
+                    budget.add_frame(frame.buffer().width(), frame.buffer().height())?;
                     if num_frames == 0 {
                         let image = frame.buffer();
                         width = image.width();
                         height = image.height();
                         first_frame = encode_png(image.clone())?;
-                        logs("Successfully encoded the first WebP frame as PNG.")?;
+                        logs.send(ProgressEvent::new(Stage::Decode, "first_frame_encoded"))?;
                     }
                     frames.push(frame);
                     num_frames += 1;
+                    logs.send(
+                        ProgressEvent::new(Stage::Decode, "decode_frame").step(num_frames, None),
+                    )?;
+                }
+                Err(e) => {
+                    return Err(ImgprocError::decode("Failed to decode a frame.", e)
+                        .format("WebP")
+                        .frame(num_frames))
                 }
-                Err(_) => return Err(JsError::new("Failed to decode a WebP frame.")),
             }
         }
 
-        logs(format!("Decoded {} WebP frames.", num_frames).as_str())?;
+        logs.send(
+            ProgressEvent::new(Stage::Decode, "decode_done")
+                .arg(num_frames)
+                .arg("WebP"),
+        )?;
 
         Ok(Box::new(ImgprocWebpDecoder {
             frames: frames.into_iter(),
@@ -256,10 +360,10 @@ impl FrameDecoder for ImgprocWebpDecoder {
         self.num_frames
     }
 
-    fn next_frame(&mut self) -> Option<(ImageBuffer<Rgba<u8>, Vec<u8>>, u32)> {
+    fn next_frame(&mut self) -> Option<DecodedFrame> {
         match self.frames.next() {
             Some(frame) => {
-                let delay = frame.delay().numer_denom_ms().0;
+                let delay = delay_ms(&frame);
                 let image_data = frame.into_buffer();
                 Some((image_data, delay))
             }
@@ -267,10 +371,78 @@ impl FrameDecoder for ImgprocWebpDecoder {
         }
     }
 
-    fn first_frame(&mut self) -> Result<Uint8Array, JsError> {
+    fn first_frame(&mut self) -> Result<Vec<u8>> {
         match self.first_frame.take() {
             Some(first_frame) => Ok(first_frame),
-            None => Err(JsError::new("First frame of WebP not available.")),
+            None => {
+                Err(ImgprocError::internal("The first frame was already taken.").format("WebP"))
+            }
+        }
+    }
+}
+
+/// Presents a still image as a one-frame animation, so still and animated inputs share
+/// the same path through conversion.
+pub struct ImgprocStillDecoder {
+    frame: Option<RgbaImage>,
+    width: u32,
+    height: u32,
+}
+
+impl ImgprocStillDecoder {
+    pub fn decode(
+        handler: &FormatHandler,
+        image_data: &[u8],
+        logs: &CallbackLogs,
+        cancel: &Cancellation,
+    ) -> Result<Box<Self>> {
+        let name = handler.name.to_uppercase();
+        logs.send(ProgressEvent::new(Stage::Decode, "decode_start").arg(&name))?;
+        cancel()?;
+        let image = handler.decode_still(image_data)?;
+        logs.send(
+            ProgressEvent::new(Stage::Decode, "decode_done")
+                .arg(1)
+                .arg(&name),
+        )?;
+
+        Ok(Box::new(ImgprocStillDecoder {
+            width: image.width(),
+            height: image.height(),
+            frame: Some(image),
+        }))
+    }
+}
+
+impl FrameDecoder for ImgprocStillDecoder {
+    /// Sniffs the format, since a still image can be any decodable format.
+    fn new(image_data: &[u8], logs: &CallbackLogs, cancel: &Cancellation) -> Result<Box<Self>> {
+        let handler = sniff_handler(image_data).ok_or_else(|| {
+            ImgprocError::new(ErrorCode::UnsupportedFormat, "Unrecognised image format.")
+        })?;
+        ImgprocStillDecoder::decode(handler, image_data, logs, cancel)
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn num_frames(&self) -> u32 {
+        1
+    }
+
+    fn next_frame(&mut self) -> Option<DecodedFrame> {
+        self.frame.take().map(|image| (image, 0))
+    }
+
+    fn first_frame(&mut self) -> Result<Vec<u8>> {
+        match &self.frame {
+            Some(image) => encode_png(image.clone()),
+            None => Err(ImgprocError::internal("The frame was already taken.")),
         }
     }
 }
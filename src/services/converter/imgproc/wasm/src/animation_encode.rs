@@ -1,94 +1,355 @@
 use image::{
-    codecs::gif::{GifEncoder, Repeat},
-    Frame,
+    codecs::{
+        gif::{GifEncoder, Repeat},
+        webp::WebPEncoder,
+    },
+    ExtendedColorType, Frame, ImageEncoder,
 };
-use js_sys::Uint8Array;
 use png::{self, Encoder};
-use wasm_bindgen::JsError;
+use std::cell::Cell;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
 
-use crate::{animation_decoder::ImgprocFrameDecoder, callback_logs::*};
+use crate::{
+    animation_decoder::{animated_image_decode, DecodedFrame},
+    callback_logs::*,
+    cancellation::Cancellation,
+    error::{ImgprocError, Result},
+    formats::target_format,
+};
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct ConvertedAnimatedImage {
+    converted_file: Vec<u8>,
+    first_frame: Vec<u8>,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl ConvertedAnimatedImage {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn first_frame(&self) -> Vec<u8> {
+        self.first_frame.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen)]
+    pub fn converted_file(self) -> Vec<u8> {
+        self.converted_file
+    }
+}
+
+/// Re-encodes any decodable image as an animated `target_type`, keeping the first frame as PNG.
+pub fn convert_animated(
+    image_data: &[u8],
+    source_type: &str,
+    target_type: &str,
+    logs: &CallbackLogs,
+    cancel: Cancellation,
+) -> Result<ConvertedAnimatedImage> {
+    let mut decoder = animated_image_decode(source_type, image_data, logs, &cancel)?;
+
+    let first_frame = decoder.first_frame()?;
+    let mut frames = Vec::with_capacity(decoder.num_frames() as usize);
+    while let Some(frame) = decoder.next_frame() {
+        frames.push(frame);
+    }
+
+    let converted_file = target_format(target_type)?.encode_frames(&frames, logs, cancel)?;
+
+    Ok(ConvertedAnimatedImage {
+        converted_file,
+        first_frame,
+    })
+}
 
 pub fn encode_apng(
-    mut decoder: ImgprocFrameDecoder,
-    logs: CallbackLogs,
-) -> Result<Uint8Array, JsError> {
-    logs("Starting APNG encoding...")?;
+    frames: &[DecodedFrame],
+    logs: &CallbackLogs,
+    cancel: Cancellation,
+) -> Result<Vec<u8>> {
+    logs.send(ProgressEvent::new(Stage::Encode, "encode_start").arg("APNG"))?;
 
-    let width = decoder.width();
-    let height = decoder.height();
-    let num_frames = decoder.num_frames();
+    let (width, height) = frames
+        .first()
+        .map(|(image, _)| image.dimensions())
+        .ok_or_else(|| ImgprocError::invalid_argument("Cannot encode an image without frames."))?;
+    let num_frames = frames.len() as u32;
 
-    logs(&format!("Image dimensions: {}x{}", width, height))?;
-    logs(&format!("Number of frames: {}", num_frames))?;
+    logs.send(
+        ProgressEvent::new(Stage::Encode, "image_dimensions")
+            .arg(width)
+            .arg(height),
+    )?;
+    logs.send(ProgressEvent::new(Stage::Encode, "frame_count").arg(num_frames))?;
 
     let mut output = Vec::new();
-    let mut encoder = Encoder::new(&mut output, width, height);
+    let written = Cell::new(0);
+    let mut encoder = Encoder::new(ByteCounter::new(&mut output, &written), width, height);
     encoder.set_color(png::ColorType::Rgba);
     encoder.set_depth(png::BitDepth::Eight);
     encoder.set_compression(png::Compression::Fast);
     encoder.set_filter(png::FilterType::Sub);
 
-    encoder
-        .set_animated(num_frames, 0)
-        .map_err(|e| JsError::new(&format!("Failed to set animation parameters: {}", e)))?;
+    encoder.set_animated(num_frames, 0).map_err(|e| {
+        ImgprocError::encode("Failed to set animation parameters.")
+            .format("APNG")
+            .cause(e)
+    })?;
 
-    let mut writer = encoder
-        .write_header()
-        .map_err(|e| JsError::new(&format!("Failed to write PNG header: {}", e)))?;
+    let mut writer = encoder.write_header().map_err(|e| {
+        ImgprocError::encode("Failed to write PNG header.")
+            .format("APNG")
+            .cause(e)
+    })?;
 
     let mut frame_count = 0;
-    while let Some((image, delay_ms)) = decoder.next_frame() {
+    for (image, delay_ms) in frames {
+        cancel()?;
         frame_count += 1;
-        logs(&format!("Encoding frame {}/{}...", frame_count, num_frames))?;
+        logs.send(
+            ProgressEvent::new(Stage::Encode, "encode_frame")
+                .step(frame_count, Some(num_frames))
+                .bytes_written(written.get()),
+        )?;
 
-        let delay_numerator = delay_ms as u16;
-        let delay_denominator = 1000;
         writer
-            .set_frame_delay(delay_numerator, delay_denominator)
-            .map_err(|e| JsError::new(&format!("Failed to set frame delay: {}", e)))?;
-        writer
-            .write_image_data(&image.into_raw())
-            .map_err(|e| JsError::new(&format!("Failed to write frame data: {}", e)))?;
+            .set_frame_delay((*delay_ms).min(u16::MAX as u32) as u16, 1000)
+            .map_err(|e| {
+                ImgprocError::encode("Failed to set frame delay.")
+                    .format("APNG")
+                    .cause(e)
+            })?;
+        writer.write_image_data(image.as_raw()).map_err(|e| {
+            ImgprocError::encode("Failed to write frame data.")
+                .format("APNG")
+                .cause(e)
+        })?;
     }
 
     match writer.finish() {
         Ok(_) => {
-            logs("APNG encoding finished successfully.")?;
-            Ok(Uint8Array::from(output.as_slice()))
+            logs.send(
+                ProgressEvent::new(Stage::Finalize, "encode_done")
+                    .arg("APNG")
+                    .bytes_written(output.len()),
+            )?;
+            Ok(output)
         }
-        Err(e) => Err(JsError::new(&format!(
-            "Failed to finalize APNG encoding: {}",
-            e
-        ))),
+        Err(e) => Err(ImgprocError::encode("Failed to finalize APNG encoding.")
+            .format("APNG")
+            .cause(e)),
     }
 }
 
 pub fn encode_gif(
-    mut decoder: ImgprocFrameDecoder,
-    logs: CallbackLogs,
-) -> Result<Uint8Array, JsError> {
-    logs("Starting GIF encoding...")?;
+    frames: &[DecodedFrame],
+    logs: &CallbackLogs,
+    cancel: Cancellation,
+) -> Result<Vec<u8>> {
+    logs.send(ProgressEvent::new(Stage::Encode, "encode_start").arg("GIF"))?;
     let mut output = Vec::new();
-    let mut encoder = GifEncoder::new(&mut output);
-    encoder
-        .set_repeat(Repeat::Infinite)
-        .map_err(|e| JsError::new(&format!("Failed to set GIF repeat: {}", e)))?;
+    let written = Cell::new(0);
+    let mut encoder = GifEncoder::new(ByteCounter::new(&mut output, &written));
+    encoder.set_repeat(Repeat::Infinite).map_err(|e| {
+        ImgprocError::encode("Failed to set GIF repeat.")
+            .format("GIF")
+            .cause(e)
+    })?;
 
-    let num_frames = decoder.num_frames();
-    logs(&format!("Number of frames: {}", num_frames))?;
+    let num_frames = frames.len() as u32;
+    logs.send(ProgressEvent::new(Stage::Encode, "frame_count").arg(num_frames))?;
 
     let mut frame_count = 0;
-    while let Some((image, delay_ms)) = decoder.next_frame() {
+    for (image, delay_ms) in frames {
+        cancel()?;
         frame_count += 1;
-        logs(&format!("Encoding frame {}/{}...", frame_count, num_frames))?;
-        let delay = image::Delay::from_numer_denom_ms((delay_ms as f32 / 10.0).round() as u32, 100);
-        let frame: Frame = Frame::from_parts(image, 0, 0, delay);
-        encoder
-            .encode_frame(frame)
-            .map_err(|e| JsError::new(&format!("Failed to encode a GIF frame: {}", e)))?;
+        logs.send(
+            ProgressEvent::new(Stage::Encode, "encode_frame")
+                .step(frame_count, Some(num_frames))
+                .bytes_written(written.get()),
+        )?;
+        let delay = image::Delay::from_numer_denom_ms(*delay_ms, 1);
+        let frame: Frame = Frame::from_parts(image.clone(), 0, 0, delay);
+        encoder.encode_frame(frame).map_err(|e| {
+            ImgprocError::encode("Failed to encode a GIF frame.")
+                .format("GIF")
+                .cause(e)
+        })?;
     }
 
     drop(encoder);
-    logs("GIF encoding finished successfully.")?;
-    Ok(Uint8Array::from(output.as_slice()))
+    logs.send(
+        ProgressEvent::new(Stage::Finalize, "encode_done")
+            .arg("GIF")
+            .bytes_written(output.len()),
+    )?;
+    Ok(output)
+}
+
+/// Writes an animated WebP: every frame is encoded losslessly on its own, and its `VP8L`
+/// bitstream is wrapped in an `ANMF` chunk that replaces the whole canvas.
+pub fn encode_webp(
+    frames: &[DecodedFrame],
+    logs: &CallbackLogs,
+    cancel: Cancellation,
+) -> Result<Vec<u8>> {
+    logs.send(ProgressEvent::new(Stage::Encode, "encode_start").arg("WebP"))?;
+    let (width, height) = frames
+        .first()
+        .map(|(image, _)| image.dimensions())
+        .ok_or_else(|| ImgprocError::invalid_argument("Cannot encode an image without frames."))?;
+    // The canvas size is stored as 24-bit values less one.
+    if width > 1 << 24 || height > 1 << 24 {
+        return Err(ImgprocError::encode("The image is too large for WebP.").format("WebP"));
+    }
+    let num_frames = frames.len() as u32;
+    logs.send(
+        ProgressEvent::new(Stage::Encode, "image_dimensions")
+            .arg(width)
+            .arg(height),
+    )?;
+    logs.send(ProgressEvent::new(Stage::Encode, "frame_count").arg(num_frames))?;
+
+    let mut vp8x = vec![0x10 | 0x02, 0, 0, 0];
+    vp8x.extend_from_slice(&u24(width - 1));
+    vp8x.extend_from_slice(&u24(height - 1));
+    let mut body = b"WEBP".to_vec();
+    write_chunk(&mut body, b"VP8X", &vp8x);
+    // Transparent background, looping forever.
+    write_chunk(&mut body, b"ANIM", &[0, 0, 0, 0, 0, 0]);
+
+    for (index, (image, delay_ms)) in (0..).zip(frames) {
+        cancel()?;
+        logs.send(
+            ProgressEvent::new(Stage::Encode, "encode_frame")
+                .step(index + 1, Some(num_frames))
+                .bytes_written(body.len()),
+        )?;
+        if image.dimensions() != (width, height) {
+            return Err(
+                ImgprocError::invalid_argument("Every frame must have the same size.")
+                    .format("WebP")
+                    .frame(index),
+            );
+        }
+        let mut still = Vec::new();
+        WebPEncoder::new_lossless(&mut still)
+            .write_image(image.as_raw(), width, height, ExtendedColorType::Rgba8)
+            .map_err(|e| {
+                ImgprocError::encode("Failed to encode a WebP frame.")
+                    .format("WebP")
+                    .frame(index)
+                    .cause(e)
+            })?;
+        let bitstream = find_chunk(&still, b"VP8L").ok_or_else(|| {
+            ImgprocError::internal("The WebP encoder wrote no VP8L chunk.")
+                .format("WebP")
+                .frame(index)
+        })?;
+
+        // Origin 0,0, full canvas, no blending and no disposal.
+        let mut frame = vec![0; 6];
+        frame.extend_from_slice(&u24(width - 1));
+        frame.extend_from_slice(&u24(height - 1));
+        frame.extend_from_slice(&u24((*delay_ms).min(0xff_ffff)));
+        frame.push(0x02);
+        write_chunk(&mut frame, b"VP8L", bitstream);
+        write_chunk(&mut body, b"ANMF", &frame);
+    }
+
+    let size = u32::try_from(body.len())
+        .map_err(|_| ImgprocError::encode("The animation is too large for WebP.").format("WebP"))?;
+    let mut output = Vec::with_capacity(body.len() + 8);
+    output.extend_from_slice(b"RIFF");
+    output.extend_from_slice(&size.to_le_bytes());
+    output.extend_from_slice(&body);
+    logs.send(
+        ProgressEvent::new(Stage::Finalize, "encode_done")
+            .arg("WebP")
+            .bytes_written(output.len()),
+    )?;
+    Ok(output)
+}
+
+fn u24(value: u32) -> [u8; 3] {
+    let [a, b, c, _] = value.to_le_bytes();
+    [a, b, c]
+}
+
+/// Appends a RIFF chunk, padded to an even length.
+fn write_chunk(output: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(fourcc);
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+/// The payload of the first top-level chunk named `fourcc` in a RIFF WebP file.
+fn find_chunk<'a>(webp: &'a [u8], fourcc: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut at = 12;
+    while let Some(header) = webp.get(at..at + 8) {
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let data = webp.get(at + 8..at + 8 + len)?;
+        if &header[..4] == fourcc {
+            return Some(data);
+        }
+        at += 8 + len + len % 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cancellation::never_cancelled, image_frames::decode_frames};
+    use image::{Rgba, RgbaImage};
+
+    fn frames() -> Vec<DecodedFrame> {
+        (0..3u8)
+            .map(|i| {
+                let image = RgbaImage::from_fn(5, 3, |x, y| {
+                    Rgba([x as u8 * 40, y as u8 * 80, i * 100, 255 - i * 60])
+                });
+                (image, 40 + i as u32 * 10)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn animated_webp_round_trips_losslessly() {
+        let logs = CallbackLogs::silent();
+        let webp = encode_webp(&frames(), &logs, never_cancelled()).unwrap();
+        assert_eq!(&webp[..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes(webp[4..8].try_into().unwrap()) as usize,
+            webp.len() - 8
+        );
+        assert!(crate::detect_animation::is_animated_webp(&webp).unwrap());
+
+        let decoded = decode_frames(&webp, "webp", &logs, &never_cancelled()).unwrap();
+        assert_eq!(decoded, frames());
+    }
+
+    #[test]
+    fn long_apng_delays_are_clamped_rather_than_wrapped() {
+        let logs = CallbackLogs::silent();
+        let mut frames = frames();
+        frames[0].1 = 70_000;
+        frames[1].1 = u32::MAX;
+        let apng = encode_apng(&frames, &logs, never_cancelled()).unwrap();
+        let decoded = decode_frames(&apng, "apng", &logs, &never_cancelled()).unwrap();
+        let delays: Vec<u32> = decoded.iter().map(|(_, delay)| *delay).collect();
+        assert_eq!(delays, [u16::MAX as u32, u16::MAX as u32, 60]);
+    }
+
+    #[test]
+    fn animated_webp_needs_frames_of_one_size() {
+        let logs = CallbackLogs::silent();
+        let mut frames = frames();
+        frames[1].0 = RgbaImage::new(4, 3);
+        let error = encode_webp(&frames, &logs, never_cancelled()).unwrap_err();
+        assert_eq!(error.frame_index, Some(1));
+        assert!(encode_webp(&[], &logs, never_cancelled()).is_err());
+    }
 }
@@ -0,0 +1,549 @@
+use image::{
+    codecs::avif::AvifEncoder, DynamicImage, ExtendedColorType, ImageBuffer, ImageEncoder, Rgba,
+    RgbaImage,
+};
+use oxideav_av1::{decoder::SpecDecodeSession, ColorConfig, ObuIter, ObuType};
+
+use crate::{
+    bmff::Boxes,
+    convert::ConvertOptions,
+    error::{ErrorCode, ImgprocError, Result},
+    limits::current_limits,
+};
+
+const ALPHA_URNS: [&[u8]; 2] = [
+    b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha",
+    b"urn:mpeg:hevc:2015:auxid:1",
+];
+
+/// What the HEIF container of an AVIF file says about its primary image.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AvifInfo {
+    pub width: u32,
+    pub height: u32,
+    /// 8, 10 or 12, from the `av1C` or `pixi` property.
+    pub bit_depth: u8,
+    pub has_alpha: bool,
+    /// An image sequence (`avis`) rather than a single image.
+    pub is_sequence: bool,
+}
+
+/// True for `ftyp` boxes naming an AVIF brand.
+pub fn is_avif(image_data: &[u8]) -> bool {
+    match Boxes::new(image_data, 0, "AVIF").next() {
+        Some(Ok((b"ftyp", ftyp, _))) => brands(ftyp).any(|b| b == b"avif" || b == b"avis"),
+        _ => false,
+    }
+}
+
+fn brands(ftyp: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let major = ftyp.get(0..4).into_iter();
+    let compatible = ftyp.get(8..).unwrap_or_default().chunks_exact(4);
+    major.chain(compatible)
+}
+
+pub fn avif_dimensions(image_data: &[u8]) -> Result<(u32, u32)> {
+    let info = avif_info(image_data)?;
+    Ok((info.width, info.height))
+}
+
+/// Walks `ftyp` and `meta` to find the primary item's size, depth and alpha plane, without
+/// touching the AV1 payload.
+pub fn avif_info(image_data: &[u8]) -> Result<AvifInfo> {
+    let mut info = AvifInfo::default();
+    let mut meta = None;
+    for entry in Boxes::new(image_data, 0, "AVIF") {
+        let (kind, body, offset) = entry?;
+        match kind {
+            b"ftyp" => info.is_sequence = brands(body).any(|b| b == b"avis"),
+            b"meta" => meta = Some((body, offset)),
+            _ => {}
+        }
+    }
+    let (meta, meta_offset) = meta.ok_or_else(|| corrupt("The AVIF file has no meta box.", 0))?;
+
+    // `meta` is a full box: skip version and flags.
+    let mut primary = None;
+    let mut properties: Vec<(&[u8; 4], &[u8])> = Vec::new();
+    let mut associations: Vec<(u32, Vec<u16>)> = Vec::new();
+    for entry in Boxes::new(meta.get(4..).unwrap_or_default(), meta_offset + 4, "AVIF") {
+        let (kind, body, offset) = entry?;
+        match kind {
+            b"pitm" => primary = Some(read_item_id(body, 4, body.first() == Some(&0), offset)?),
+            b"iprp" => {
+                for entry in Boxes::new(body, offset, "AVIF") {
+                    let (kind, body, offset) = entry?;
+                    match kind {
+                        b"ipco" => {
+                            for entry in Boxes::new(body, offset, "AVIF") {
+                                let (kind, body, _) = entry?;
+                                properties.push((kind, body));
+                            }
+                        }
+                        b"ipma" => associations.extend(read_ipma(body, offset)?),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let primary = primary.ok_or_else(|| corrupt("The AVIF file has no primary item.", 0))?;
+    let indices = associations
+        .iter()
+        .find(|(item, _)| *item == primary)
+        .map(|(_, indices)| indices.as_slice())
+        .unwrap_or_default();
+
+    for index in indices {
+        let Some((kind, body)) = properties.get((*index as usize).wrapping_sub(1)) else {
+            continue;
+        };
+        match *kind {
+            b"ispe" if body.len() >= 12 => {
+                info.width = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+                info.height = u32::from_be_bytes([body[8], body[9], body[10], body[11]]);
+            }
+            b"av1C" if body.len() >= 3 => {
+                info.bit_depth = match (body[2] & 0x40 != 0, body[2] & 0x20 != 0) {
+                    (true, true) => 12,
+                    (true, false) => 10,
+                    _ => 8,
+                };
+            }
+            b"pixi" if info.bit_depth == 0 && body.len() >= 6 => info.bit_depth = body[5],
+            _ => {}
+        }
+    }
+    info.has_alpha = properties.iter().any(|(kind, body)| {
+        *kind == b"auxC"
+            && ALPHA_URNS
+                .iter()
+                .any(|urn| body.get(4..).unwrap_or_default().starts_with(urn))
+    });
+
+    if info.width == 0 || info.height == 0 {
+        return Err(corrupt("The AVIF primary item has no size.", meta_offset));
+    }
+    if info.bit_depth == 0 {
+        info.bit_depth = 8;
+    }
+    Ok(info)
+}
+
+/// Item IDs are 16 bits in version 0 boxes and 32 bits otherwise.
+fn read_item_id(body: &[u8], at: usize, short: bool, offset: u64) -> Result<u32> {
+    let id = match short {
+        true => body
+            .get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as u32),
+        false => body
+            .get(at..at + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+    };
+    id.ok_or_else(|| corrupt("An AVIF item reference is truncated.", offset))
+}
+
+/// Reads the property indices associated with each item.
+fn read_ipma(body: &[u8], offset: u64) -> Result<Vec<(u32, Vec<u16>)>> {
+    let truncated = || corrupt("The AVIF ipma box is truncated.", offset);
+    let version = *body.first().ok_or_else(truncated)?;
+    let wide_index = body.get(3).ok_or_else(truncated)? & 1 != 0;
+    let count = body.get(4..8).ok_or_else(truncated)?;
+    let count = u32::from_be_bytes([count[0], count[1], count[2], count[3]]);
+
+    let mut at = 8;
+    let mut items = Vec::new();
+    for _ in 0..count {
+        let item = read_item_id(body, at, version < 1, offset)?;
+        at += if version < 1 { 2 } else { 4 };
+        let associations = *body.get(at).ok_or_else(truncated)?;
+        at += 1;
+
+        let mut indices = Vec::with_capacity(associations as usize);
+        for _ in 0..associations {
+            let index = match wide_index {
+                true => {
+                    let b = body.get(at..at + 2).ok_or_else(truncated)?;
+                    at += 2;
+                    u16::from_be_bytes([b[0], b[1]]) & 0x7fff
+                }
+                false => {
+                    let b = *body.get(at).ok_or_else(truncated)?;
+                    at += 1;
+                    (b & 0x7f) as u16
+                }
+            };
+            indices.push(index);
+        }
+        items.push((item, indices));
+    }
+    Ok(items)
+}
+
+fn corrupt(message: &str, offset: u64) -> ImgprocError {
+    ImgprocError::corrupt(message).format("AVIF").offset(offset)
+}
+
+/// One decoded AV1 item: its planes, widened to 16 bits, and how to read them.
+struct Av1Picture {
+    width: u32,
+    height: u32,
+    planes: Vec<Vec<u16>>,
+    /// Row length of each plane, in samples.
+    strides: Vec<u32>,
+    /// Log2 of the chroma subsampling in each direction.
+    subsampling: (u32, u32),
+    config: ColorConfig,
+}
+
+impl Av1Picture {
+    fn sample(&self, plane: usize, x: u32, y: u32) -> u16 {
+        let (x, y) = match plane {
+            0 => (x, y),
+            _ => (x >> self.subsampling.0, y >> self.subsampling.1),
+        };
+        self.planes[plane][(y * self.strides[plane] + x) as usize]
+    }
+
+    /// The sample scaled to 0 to 1, with limited range stretched to full range.
+    fn luma(&self, value: u16) -> f32 {
+        let max = ((1 << self.config.bit_depth) - 1) as f32;
+        match self.config.color_range {
+            true => value as f32 / max,
+            false => {
+                let scale = (1 << (self.config.bit_depth - 8)) as f32;
+                (value as f32 - 16.0 * scale) / (219.0 * scale)
+            }
+        }
+    }
+
+    /// The sample scaled to -0.5 to 0.5.
+    fn chroma(&self, value: u16) -> f32 {
+        let scale = (1 << (self.config.bit_depth - 8)) as f32;
+        match self.config.color_range {
+            true => (value as f32 - 128.0 * scale) / (255.0 * scale),
+            false => (value as f32 - 128.0 * scale) / (224.0 * scale),
+        }
+    }
+}
+
+/// Decodes the AV1 payload of one item, refusing pictures over the decode limits before
+/// the decoder allocates them.
+fn decode_av1(item: &[u8]) -> Result<Av1Picture> {
+    let failed = |e: oxideav_av1::Error| {
+        ImgprocError::corrupt("Failed to decode the AV1 image.")
+            .format("AVIF")
+            .cause(e)
+    };
+
+    let mut color = None;
+    for obu in ObuIter::new(item) {
+        let obu = obu.map_err(failed)?;
+        if obu.obu_type == ObuType::SequenceHeader {
+            let header = oxideav_av1::parse_sequence_header(obu.payload).map_err(failed)?;
+            let limits = current_limits();
+            limits.check_dimensions(
+                header.max_frame_width_minus_1 + 1,
+                header.max_frame_height_minus_1 + 1,
+                "AVIF",
+            )?;
+            color = Some(header.color_config);
+            break;
+        }
+    }
+    let color = color.ok_or_else(|| corrupt("The AV1 image has no sequence header.", 0))?;
+
+    let mut session = SpecDecodeSession::new();
+    session.set_max_picture_size(current_limits().max_pixels());
+    let frame = session
+        .decode_temporal_unit(item)
+        .map_err(failed)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| corrupt("The AV1 image has no frames.", 0))?;
+
+    let planes = frame
+        .planes
+        .into_iter()
+        .map(|plane| match frame.bit_depth {
+            8 => plane.into_iter().map(u16::from).collect(),
+            _ => plane
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .collect(),
+        })
+        .collect::<Vec<Vec<u16>>>();
+    let expected = frame
+        .plane_dims
+        .iter()
+        .map(|(w, h)| *w as usize * *h as usize);
+    if planes.len() != frame.plane_dims.len()
+        || planes
+            .iter()
+            .zip(expected)
+            .any(|(plane, len)| plane.len() != len)
+        || !matches!(planes.len(), 1 | 3)
+        || frame.plane_dims[0] != (frame.width, frame.height)
+    {
+        return Err(corrupt("The AV1 decoder returned incomplete planes.", 0));
+    }
+
+    Ok(Av1Picture {
+        width: frame.width,
+        height: frame.height,
+        planes,
+        strides: frame.plane_dims.iter().map(|(width, _)| *width).collect(),
+        subsampling: (color.subsampling_x as u32, color.subsampling_y as u32),
+        config: color,
+    })
+}
+
+/// Kr and Kb for the matrix coefficients of ITU-T H.273, or `None` for identity (GBR).
+fn luma_weights(matrix_coefficients: u8) -> Option<(f32, f32)> {
+    match matrix_coefficients {
+        0 => None,
+        1 => Some((0.2126, 0.0722)),
+        4 => Some((0.30, 0.11)),
+        7 => Some((0.212, 0.087)),
+        9 | 10 => Some((0.2627, 0.0593)),
+        // BT.601, which is also what unspecified content is assumed to be.
+        _ => Some((0.299, 0.114)),
+    }
+}
+
+/// Decodes the primary image of an AVIF file and its alpha plane. 10- and 12-bit images
+/// come back with 16 bits per channel, 8-bit images with 8.
+pub fn decode_avif(image_data: &[u8]) -> Result<DynamicImage> {
+    let info = avif_info(image_data)?;
+    current_limits().check_dimensions(info.width, info.height, "AVIF")?;
+    let avif = avif_parse::read_avif(&mut &image_data[..]).map_err(|e| {
+        ImgprocError::corrupt("Failed to read the AVIF container.")
+            .format("AVIF")
+            .cause(e)
+    })?;
+
+    let image = decode_av1(&avif.primary_item)?;
+    let alpha = match &avif.alpha_item {
+        Some(item) => Some(decode_av1(item)?),
+        None => None,
+    };
+    if let Some(alpha) = &alpha {
+        if (alpha.width, alpha.height) != (image.width, image.height) {
+            return Err(ImgprocError::new(
+                ErrorCode::UnsupportedFormat,
+                "The AVIF alpha plane is not the size of the image.",
+            )
+            .format("AVIF"));
+        }
+    }
+
+    let weights = luma_weights(image.config.matrix_coefficients);
+    let monochrome = image.planes.len() == 1;
+    let pixel = |x: u32, y: u32| -> [f32; 4] {
+        let luma = image.luma(image.sample(0, x, y));
+        let mut rgb = match (monochrome, weights) {
+            (true, _) => [luma; 3],
+            (false, None) => [
+                image.luma(image.sample(2, x, y)),
+                luma,
+                image.luma(image.sample(1, x, y)),
+            ],
+            (false, Some((kr, kb))) => {
+                let cb = image.chroma(image.sample(1, x, y));
+                let cr = image.chroma(image.sample(2, x, y));
+                let r = luma + 2.0 * (1.0 - kr) * cr;
+                let b = luma + 2.0 * (1.0 - kb) * cb;
+                let g = (luma - kr * r - kb * b) / (1.0 - kr - kb);
+                [r, g, b]
+            }
+        };
+        let a = match &alpha {
+            Some(alpha) => alpha.luma(alpha.sample(0, x, y)).clamp(0.0, 1.0),
+            None => 1.0,
+        };
+        if avif.premultiplied_alpha && a > 0.0 {
+            rgb = rgb.map(|value| value / a);
+        }
+        [rgb[0], rgb[1], rgb[2], a]
+    };
+
+    let (width, height) = (image.width, image.height);
+    let output = match image
+        .config
+        .bit_depth
+        .max(alpha.as_ref().map_or(8, |a| a.config.bit_depth))
+    {
+        8 => DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+            Rgba(pixel(x, y).map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8))
+        })),
+        _ => DynamicImage::ImageRgba16(ImageBuffer::from_fn(width, height, |x, y| {
+            Rgba(pixel(x, y).map(|value| (value.clamp(0.0, 1.0) * 65535.0).round() as u16))
+        })),
+    };
+    Ok(output)
+}
+
+/// Encodes a still AVIF with `options.quality()` and `options.speed()`, keeping alpha.
+pub fn encode_avif(image: RgbaImage, options: &ConvertOptions) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let encoder = AvifEncoder::new_with_speed_quality(
+        &mut output,
+        options.speed().clamp(1, 10),
+        options.quality().clamp(1, 100),
+    );
+    encoder
+        .write_image(
+            image.as_raw(),
+            image.width(),
+            image.height(),
+            ExtendedColorType::Rgba8,
+        )
+        .map_err(|e| ImgprocError::decode("Failed to encode AVIF.", e).format("AVIF"))?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        callback_logs::CallbackLogs, image_decode::decode_static_image, limits::set_limits,
+        limits::DecodeLimits, svg::SvgOptions,
+    };
+
+    fn gradient(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([(x * 6) as u8, (y * 12) as u8, 100, 255 - x as u8 * 2])
+        })
+    }
+
+    fn encode(image: RgbaImage) -> Vec<u8> {
+        let mut options = ConvertOptions::default();
+        options.set_quality(100);
+        encode_avif(image, &options).unwrap()
+    }
+
+    /// A 10-bit AVIF in the identity matrix, so samples survive without a colour transform.
+    fn encode_10_bit(width: u32, height: u32) -> Vec<u8> {
+        let sample = |x: u32, y: u32| ((x * 29 + y * 3) % 1024) as u16;
+        let planes = (0..height).flat_map(|y| (0..width).map(move |x| [sample(x, y); 3]));
+        let alpha = (0..height).flat_map(|y| (0..width).map(move |x| (x * 31 + y) as u16 % 1024));
+        ravif::Encoder::new()
+            .with_quality(100.0)
+            .with_alpha_quality(100.0)
+            .encode_raw_planes_10_bit(
+                width as usize,
+                height as usize,
+                planes,
+                Some(alpha),
+                ravif::PixelRange::Full,
+                ravif::MatrixCoefficients::Identity,
+            )
+            .unwrap()
+            .avif_file
+    }
+
+    #[test]
+    fn reads_the_primary_item_from_the_container() {
+        let data = encode(gradient(37, 21));
+        assert!(is_avif(&data));
+        let info = avif_info(&data).unwrap();
+        assert_eq!((info.width, info.height, info.bit_depth), (37, 21, 8));
+        assert!(info.has_alpha && !info.is_sequence);
+        assert_eq!(avif_info(&encode_10_bit(4, 4)).unwrap().bit_depth, 10);
+        assert!(!is_avif(b"\0\0\0\x10ftypheic\0\0\0\0"));
+    }
+
+    #[test]
+    fn decodes_8_bit_images_with_alpha() {
+        let source = gradient(37, 21);
+        let decoded = decode_avif(&encode(source.clone())).unwrap();
+        let DynamicImage::ImageRgba8(decoded) = decoded else {
+            panic!("8-bit AVIF decoded as {:?}", decoded.color());
+        };
+        assert_eq!(decoded.dimensions(), (37, 21));
+        for (a, b) in source.pixels().zip(decoded.pixels()) {
+            assert_eq!(a[3], b[3]);
+            for channel in 0..3 {
+                assert!(a[channel].abs_diff(b[channel]) <= 6, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_10_bit_samples_at_16_bits() {
+        let decoded = decode_avif(&encode_10_bit(24, 10)).unwrap();
+        let DynamicImage::ImageRgba16(decoded) = decoded else {
+            panic!("10-bit AVIF decoded as {:?}", decoded.color());
+        };
+        let widen = |value: u32| ((value % 1024) as f32 * 65535.0 / 1023.0).round() as u16;
+        for (x, y, pixel) in decoded.enumerate_pixels() {
+            let expected = widen(x * 29 + y * 3);
+            assert!(
+                pixel[0].abs_diff(expected) <= 64 * 4,
+                "{} != {}",
+                pixel[0],
+                expected
+            );
+            assert!(pixel[3].abs_diff(widen(x * 31 + y)) <= 64 * 4);
+        }
+        assert!(decoded.iter().any(|sample| sample % 257 != 0));
+    }
+
+    #[test]
+    fn static_decode_writes_16_bit_png_for_deep_avif() {
+        let logs = CallbackLogs::silent();
+        let png = decode_static_image(&encode_10_bit(8, 8), "avif", &SvgOptions::default(), &logs)
+            .unwrap();
+        assert_eq!(png[24], 16);
+        let png = decode_static_image(
+            &encode(gradient(8, 8)),
+            "avif",
+            &SvgOptions::default(),
+            &logs,
+        )
+        .unwrap();
+        assert_eq!(png[24], 8);
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let data = encode(gradient(16, 16));
+        for len in (0..data.len()).step_by(7) {
+            assert!(decode_avif(&data[..len]).is_err(), "{} bytes decoded", len);
+        }
+        crate::formats::tests::decode_truncations(&data, "avif");
+    }
+
+    #[test]
+    fn damaged_payloads_do_not_panic() {
+        let data = encode(gradient(16, 16));
+        let avif = avif_parse::read_avif(&mut &data[..]).unwrap();
+        let start = data.len() - avif.primary_item.len() - avif.alpha_item.map_or(0, |a| a.len());
+        for at in start..data.len() {
+            for value in [0x00, 0xff, data[at] ^ 0x10] {
+                let mut damaged = data.clone();
+                damaged[at] = value;
+                let _ = decode_avif(&damaged);
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_images_are_refused_from_the_header() {
+        let data = encode(gradient(37, 21));
+        let mut limits = DecodeLimits::default();
+        limits.set_max_width(16);
+        set_limits(limits);
+        let error = decode_avif(&data).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+    }
+
+    #[test]
+    fn container_errors_carry_the_format() {
+        let error = decode_avif(b"\0\0\0\x10ftypavif\0\0\0\0").unwrap_err();
+        assert_eq!(error.code, ErrorCode::CorruptData);
+        assert_eq!(error.format.as_deref(), Some("AVIF"));
+    }
+}
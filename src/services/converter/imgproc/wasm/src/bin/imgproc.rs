@@ -0,0 +1,252 @@
+//! Command-line front end to the imgproc core, for batch work outside the browser.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+    process::ExitCode,
+    time::Instant,
+};
+
+use imgproc::{
+    callback_logs::{CallbackLogs, ProgressEvent, ProgressSink},
+    cancellation::never_cancelled,
+    convert::ConvertOptions,
+    error::{ImgprocError, Result},
+    probe::{sniff_format, ImageInfo},
+    session::ImageSession,
+    thumbnail::ThumbnailOptions,
+};
+
+const USAGE: &str = "\
+Usage: imgproc <command> [options] <input> [output]
+
+Commands:
+  convert      Convert to the format given by --to or the output extension
+  probe        Print format, size and frame count as JSON
+  first-frame  Write the first frame as PNG
+  thumbnail    Write the first frame scaled to fit within --size pixels
+
+Use - for stdin or stdout; output defaults to stdout.
+
+Options:
+  --from <format>    Input format, sniffed from the data when omitted
+  --to <format>      Output format, taken from the output extension when omitted
+  --size <pixels>    Longest side for thumbnail [default: 256]
+  --quality <1-100>  JPEG and AVIF quality [default: 90]
+  --speed <1-10>     AVIF encoder speed [default: 6]
+  --frame <index>    Convert only this frame
+  --sprite-sheet     Convert all frames onto one still image
+  --columns <n>      Frames per row of the sprite sheet [default: square]
+  -v, --verbose      Print progress to stderr
+  -h, --help         Print this help";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Convert,
+    Probe,
+    FirstFrame,
+    Thumbnail,
+}
+
+struct Args {
+    command: Command,
+    input: String,
+    output: String,
+    from: Option<String>,
+    to: Option<String>,
+    size: u32,
+    options: ConvertOptions,
+    verbose: bool,
+}
+
+fn parse_args() -> Result<Option<Args>, lexopt::Error> {
+    use lexopt::prelude::*;
+
+    let mut parser = lexopt::Parser::from_env();
+    let mut command = None;
+    let mut paths: Vec<String> = Vec::new();
+    let mut from = None;
+    let mut to = None;
+    let mut size = 256;
+    let mut options = ConvertOptions::default();
+    let mut verbose = false;
+
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => return Ok(None),
+            Short('v') | Long("verbose") => verbose = true,
+            Long("from") => from = Some(parser.value()?.string()?),
+            Long("to") => to = Some(parser.value()?.string()?),
+            Long("size") => size = parser.value()?.parse()?,
+            Long("quality") => options.set_quality(parser.value()?.parse()?),
+            Long("speed") => options.set_speed(parser.value()?.parse()?),
+            Long("frame") => options.set_frame(Some(parser.value()?.parse()?)),
+            Long("sprite-sheet") => options.set_sprite_sheet(true),
+            Long("columns") => options.set_columns(parser.value()?.parse()?),
+            Value(value) if command.is_none() => {
+                command = Some(match value.string()?.as_str() {
+                    "convert" => Command::Convert,
+                    "probe" => Command::Probe,
+                    "first-frame" => Command::FirstFrame,
+                    "thumbnail" => Command::Thumbnail,
+                    other => return Err(format!("unknown command '{}'", other).into()),
+                });
+            }
+            Value(value) if paths.len() < 2 => paths.push(value.string()?),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    let command = command.ok_or("missing command")?;
+    let mut paths = paths.into_iter();
+    let input = paths.next().ok_or("missing input")?;
+    let output = paths.next().unwrap_or_else(|| "-".to_string());
+    if command == Command::Probe && output != "-" {
+        return Err("probe takes no output".into());
+    }
+
+    Ok(Some(Args {
+        command,
+        input,
+        output,
+        from,
+        to,
+        size,
+        options,
+        verbose,
+    }))
+}
+
+/// Prints progress events to stderr as they arrive.
+struct StderrProgress {
+    start: Instant,
+}
+
+impl ProgressSink for StderrProgress {
+    fn now_ms(&self) -> f64 {
+        self.start.elapsed().as_secs_f64() * 1000.0
+    }
+
+    fn report(&self, event: &ProgressEvent) -> Result<()> {
+        let mut line = format!("{}: {}", event.stage().as_str(), event.code());
+        for arg in event.args() {
+            line.push(' ');
+            line.push_str(arg);
+        }
+        match (event.current(), event.total()) {
+            (Some(current), Some(total)) => line.push_str(&format!(" ({}/{})", current, total)),
+            (Some(current), None) => line.push_str(&format!(" ({})", current)),
+            _ => {}
+        }
+        if let Some(bytes) = event.written() {
+            line.push_str(&format!(" [{} bytes]", bytes));
+        }
+        eprintln!("{}", line);
+        Ok(())
+    }
+}
+
+fn read_input(path: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let read = match path {
+        "-" => io::stdin().lock().read_to_end(&mut data).map(|_| ()),
+        _ => fs::read(path).map(|bytes| data = bytes),
+    };
+    read.map_err(|e| ImgprocError::invalid_argument(format!("Failed to read {}.", path)).cause(e))?;
+    Ok(data)
+}
+
+fn write_output(path: &str, data: &[u8]) -> Result<()> {
+    let written = match path {
+        "-" => io::stdout().lock().write_all(data),
+        _ => fs::write(path, data),
+    };
+    written.map_err(|e| ImgprocError::internal(format!("Failed to write {}.", path)).cause(e))
+}
+
+fn extension(path: &str) -> Option<String> {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+}
+
+fn target_type(args: &Args) -> Option<String> {
+    args.to.clone().or_else(|| extension(&args.output))
+}
+
+fn probe_json(info: &ImageInfo) -> String {
+    format!(
+        "{{\"format\":\"{}\",\"width\":{},\"height\":{},\"num_frames\":{},\"is_animated\":{},\"duration_ms\":{}}}",
+        info.format(),
+        info.width(),
+        info.height(),
+        info.num_frames(),
+        info.is_animated(),
+        info.duration_ms()
+    )
+}
+
+fn run(args: &Args) -> Result<()> {
+    let data = read_input(&args.input)?;
+    let source_type = match &args.from {
+        Some(from) => from.to_lowercase(),
+        None => sniff_format(&data)
+            .map(str::to_string)
+            .or_else(|| extension(&args.input))
+            .unwrap_or_default(),
+    };
+
+    let progress = StderrProgress {
+        start: Instant::now(),
+    };
+    let logs = match args.verbose {
+        true => CallbackLogs::new(&progress),
+        false => CallbackLogs::silent(),
+    };
+
+    let mut session = ImageSession::new(data, &source_type)?;
+    match args.command {
+        Command::Probe => {
+            let info = session.probe()?;
+            write_output("-", format!("{}\n", probe_json(&info)).as_bytes())
+        }
+        Command::Convert => {
+            let target_type = target_type(args).ok_or_else(|| {
+                ImgprocError::invalid_argument("Pass --to when writing to stdout.")
+            })?;
+            let output = session.convert(&target_type, &args.options, &logs, never_cancelled())?;
+            write_output(&args.output, &output)
+        }
+        Command::FirstFrame => write_output(&args.output, &session.first_frame()?),
+        Command::Thumbnail => {
+            let mut options = ThumbnailOptions::new();
+            options.set_size(args.size);
+            options.set_format(target_type(args).unwrap_or_else(|| "png".to_string()));
+            write_output(&args.output, &session.thumbnail(&options)?)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("imgproc: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("imgproc: {} [{}]", e, e.code.as_str());
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! The ISO base media file format box layout shared by the HEIF (AVIF) and JPEG XL
+//! containers.
+
+use crate::error::{ImgprocError, Result};
+
+/// Iterates over ISO BMFF boxes, yielding the type, the body and the body's absolute offset.
+pub(crate) struct Boxes<'a> {
+    data: &'a [u8],
+    at: usize,
+    base: u64,
+    format: &'static str,
+}
+
+impl<'a> Boxes<'a> {
+    /// `format` names the container in errors; `base` is the offset of `data` in the file.
+    pub(crate) fn new(data: &'a [u8], base: u64, format: &'static str) -> Self {
+        Boxes {
+            data,
+            at: 0,
+            base,
+            format,
+        }
+    }
+
+    /// Stops the iteration after a malformed box.
+    fn corrupt(&mut self, message: &str, offset: u64) -> ImgprocError {
+        self.at = self.data.len();
+        ImgprocError::corrupt(message)
+            .format(self.format)
+            .offset(offset)
+    }
+}
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = Result<(&'a [u8; 4], &'a [u8], u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.data.get(self.at..self.at + 8)?;
+        let offset = self.base + self.at as u64;
+        let kind: &[u8; 4] = header[4..8].try_into().ok()?;
+        let (size, header_len) =
+            match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+                0 => ((self.data.len() - self.at) as u64, 8),
+                1 => match self.data.get(self.at + 8..self.at + 16) {
+                    Some(b) => (u64::from_be_bytes(b.try_into().ok()?), 16),
+                    None => return Some(Err(self.corrupt("A box header is truncated.", offset))),
+                },
+                size => (size as u64, 8),
+            };
+
+        let end = match (self.at as u64).checked_add(size) {
+            Some(end) if size >= header_len as u64 && end <= self.data.len() as u64 => end,
+            _ => {
+                return Some(Err(
+                    self.corrupt("A box runs past the end of the file.", offset)
+                ))
+            }
+        };
+        let body = &self.data[self.at + header_len..end as usize];
+        self.at = end as usize;
+        Some(Ok((kind, body, offset + header_len as u64)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    fn boxed(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn walks_boxes_with_absolute_offsets() {
+        let mut data = boxed(b"ftyp", b"avif");
+        data.extend(boxed(b"free", b""));
+        let mut large = 1u32.to_be_bytes().to_vec();
+        large.extend_from_slice(b"mdat");
+        large.extend_from_slice(&19u64.to_be_bytes());
+        large.extend_from_slice(b"abc");
+        data.extend(large);
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(b"skip");
+        data.extend_from_slice(b"to the end");
+
+        let boxes: Vec<_> = Boxes::new(&data, 100, "AVIF")
+            .map(|entry| entry.unwrap())
+            .map(|(kind, body, offset)| (kind, body.to_vec(), offset))
+            .collect();
+        assert_eq!(
+            boxes,
+            [
+                (b"ftyp", b"avif".to_vec(), 108),
+                (b"free", vec![], 120),
+                (b"mdat", b"abc".to_vec(), 136),
+                (b"skip", b"to the end".to_vec(), 147),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_boxes_end_the_iteration_with_an_error() {
+        let mut overlong = boxed(b"ftyp", b"avif");
+        overlong[3] = 13;
+        let mut undersized = boxed(b"ftyp", b"avif");
+        undersized[3] = 7;
+        let mut huge = 1u32.to_be_bytes().to_vec();
+        huge.extend_from_slice(b"mdat");
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        let truncated_large = [0, 0, 0, 1, b'm', b'd', b'a', b't', 0, 0];
+
+        for data in [&overlong[..], &undersized, &huge, &truncated_large] {
+            let mut boxes = Boxes::new(data, 0, "JXL");
+            let error = boxes.next().unwrap().unwrap_err();
+            assert_eq!(error.code, ErrorCode::CorruptData);
+            assert_eq!(error.format.as_deref(), Some("JXL"));
+            assert!(boxes.next().is_none());
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_shorter_than_a_header_are_ignored() {
+        let mut data = boxed(b"ftyp", b"avif");
+        data.extend_from_slice(&[0, 0, 0]);
+        assert_eq!(Boxes::new(&data, 0, "AVIF").count(), 1);
+    }
+}
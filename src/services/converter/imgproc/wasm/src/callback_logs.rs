@@ -1,13 +1,341 @@
-use js_sys::Function;
-use wasm_bindgen::{JsError, JsValue};
-
-pub type CallbackLogs<'a> = Box<dyn Fn(&str) -> Result<(), JsError> + 'a>;
-
-pub fn callback_log(f: &Function) -> CallbackLogs {
-    Box::new(
-        move |s| match f.call1(&JsValue::null(), &JsValue::from_str(s)) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(JsError::new(s)),
-        },
-    )
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Display,
+    io::{self, Write},
+};
+
+use crate::error::Result;
+
+/// Minimum time between two step events, so per-frame progress doesn't flood `postMessage`.
+const STEP_INTERVAL_MS: f64 = 100.0;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Decode,
+    /// Pixel operations between decoding and encoding, such as resizing or tone mapping.
+    Transform,
+    Quantize,
+    Encode,
+    Finalize,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Transform => "transform",
+            Stage::Quantize => "quantize",
+            Stage::Encode => "encode",
+            Stage::Finalize => "finalize",
+        }
+    }
+}
+
+/// A progress report. `code` names the message and `args` fill it in, so the UI can
+/// localise it; `current`/`total` are set for per-item steps.
+pub struct ProgressEvent {
+    stage: Stage,
+    code: &'static str,
+    args: Vec<String>,
+    current: Option<u32>,
+    total: Option<u32>,
+    bytes_written: Option<usize>,
+}
+
+impl ProgressEvent {
+    pub fn new(stage: Stage, code: &'static str) -> Self {
+        ProgressEvent {
+            stage,
+            code,
+            args: Vec::new(),
+            current: None,
+            total: None,
+            bytes_written: None,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Display) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    /// Marks the event as item `current` of `total`; the total is omitted when unknown.
+    pub fn step(mut self, current: u32, total: Option<u32>) -> Self {
+        self.current = Some(current);
+        self.total = total;
+        self
+    }
+
+    pub fn bytes_written(mut self, bytes: usize) -> Self {
+        self.bytes_written = Some(bytes);
+        self
+    }
+
+    fn is_boundary(&self) -> bool {
+        match (self.current, self.total) {
+            (Some(current), total) => current <= 1 || Some(current) == total,
+            (None, _) => true,
+        }
+    }
+
+    /// Whether this is a later step of the run `earlier` belongs to.
+    fn continues(&self, earlier: &ProgressEvent) -> bool {
+        self.stage == earlier.stage && self.code == earlier.code && self.current > earlier.current
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn current(&self) -> Option<u32> {
+        self.current
+    }
+
+    pub fn total(&self) -> Option<u32> {
+        self.total
+    }
+
+    pub fn written(&self) -> Option<usize> {
+        self.bytes_written
+    }
+}
+
+/// Where `CallbackLogs` delivers events: a JS callback in the wasm build, stderr in the CLI.
+pub trait ProgressSink {
+    /// Milliseconds from any fixed point, used to throttle step events.
+    fn now_ms(&self) -> f64;
+    fn report(&self, event: &ProgressEvent) -> Result<()>;
+}
+
+/// Sends `ProgressEvent`s to a sink. Step events are throttled to one per
+/// `STEP_INTERVAL_MS`, except the first and last step of a run. Without a total the last
+/// step is only known once the run is over, so the latest throttled step is held back and
+/// sent before the next event from elsewhere, or when the logs are dropped.
+pub struct CallbackLogs<'a> {
+    sink: Option<&'a dyn ProgressSink>,
+    last_step: Cell<f64>,
+    held: RefCell<Option<ProgressEvent>>,
+}
+
+impl<'a> CallbackLogs<'a> {
+    pub fn new(sink: &'a dyn ProgressSink) -> Self {
+        CallbackLogs {
+            sink: Some(sink),
+            last_step: Cell::new(f64::NEG_INFINITY),
+            held: RefCell::new(None),
+        }
+    }
+
+    pub fn silent() -> Self {
+        CallbackLogs {
+            sink: None,
+            last_step: Cell::new(f64::NEG_INFINITY),
+            held: RefCell::new(None),
+        }
+    }
+
+    pub fn send(&self, event: ProgressEvent) -> Result<()> {
+        let sink = match self.sink {
+            Some(sink) => sink,
+            None => return Ok(()),
+        };
+
+        let held = self.held.take();
+        if let Some(held) = held.as_ref().filter(|held| !event.continues(held)) {
+            sink.report(held)?;
+        }
+
+        if event.current.is_some() {
+            let now = sink.now_ms();
+            if !event.is_boundary() && now - self.last_step.get() < STEP_INTERVAL_MS {
+                self.held.replace(Some(event));
+                return Ok(());
+            }
+            self.last_step.set(now);
+        }
+
+        sink.report(&event)
+    }
+
+    /// Sends the step the throttle is holding back, if any.
+    pub fn flush(&self) -> Result<()> {
+        match (self.sink, self.held.take()) {
+            (Some(sink), Some(held)) => sink.report(&held),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for CallbackLogs<'_> {
+    fn drop(&mut self) {
+        // Nothing is left to fail once the call is over; the step is only informational.
+        let _ = self.flush();
+    }
+}
+
+/// Counts the bytes an encoder has written so far, for `ProgressEvent::bytes_written`.
+pub struct ByteCounter<'a, W: Write> {
+    inner: W,
+    written: &'a Cell<usize>,
+}
+
+impl<'a, W: Write> ByteCounter<'a, W> {
+    pub fn new(inner: W, written: &'a Cell<usize>) -> Self {
+        ByteCounter { inner, written }
+    }
+}
+
+impl<W: Write> Write for ByteCounter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written.set(self.written.get() + n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        animation_encode::convert_animated, cancellation::never_cancelled,
+        frame_encode::encode_rgba_png,
+    };
+    use image::{Rgba, RgbaImage};
+
+    /// The TS side that renders each code; every code and stage must have an entry.
+    const PROGRESS_TS: &str = include_str!("../../progress.ts");
+
+    /// Records what reaches the UI, on a clock the test moves by hand.
+    #[derive(Default)]
+    struct FakeSink {
+        now: Cell<f64>,
+        events: RefCell<Vec<(&'static str, &'static str, Option<u32>)>>,
+    }
+
+    impl FakeSink {
+        fn advance(&self, ms: f64) {
+            self.now.set(self.now.get() + ms);
+        }
+
+        fn steps(&self) -> Vec<Option<u32>> {
+            self.events
+                .borrow()
+                .iter()
+                .map(|(_, _, step)| *step)
+                .collect()
+        }
+    }
+
+    impl ProgressSink for FakeSink {
+        fn now_ms(&self) -> f64 {
+            self.now.get()
+        }
+
+        fn report(&self, event: &ProgressEvent) -> Result<()> {
+            let entry = (event.stage().as_str(), event.code(), event.current());
+            self.events.borrow_mut().push(entry);
+            Ok(())
+        }
+    }
+
+    fn frame(current: u32, total: Option<u32>) -> ProgressEvent {
+        ProgressEvent::new(Stage::Decode, "decode_frame").step(current, total)
+    }
+
+    #[test]
+    fn throttled_steps_keep_the_last_one() {
+        let sink = FakeSink::default();
+        let logs = CallbackLogs::new(&sink);
+        for current in 1..=10 {
+            logs.send(frame(current, None)).unwrap();
+            sink.advance(30.0);
+        }
+        assert_eq!(sink.steps(), [Some(1), Some(5), Some(9)]);
+
+        // The next event from elsewhere sends the held step first.
+        logs.send(ProgressEvent::new(Stage::Decode, "decode_done"))
+            .unwrap();
+        assert_eq!(sink.steps(), [Some(1), Some(5), Some(9), Some(10), None]);
+        drop(logs);
+        assert_eq!(sink.events.borrow().len(), 5);
+    }
+
+    #[test]
+    fn held_step_is_sent_when_the_logs_are_dropped() {
+        let sink = FakeSink::default();
+        {
+            let logs = CallbackLogs::new(&sink);
+            for current in 1..=4 {
+                logs.send(frame(current, None)).unwrap();
+            }
+            assert_eq!(sink.steps(), [Some(1)]);
+        }
+        assert_eq!(sink.steps(), [Some(1), Some(4)]);
+    }
+
+    #[test]
+    fn known_totals_and_new_runs_are_not_swallowed() {
+        let sink = FakeSink::default();
+        let logs = CallbackLogs::new(&sink);
+        for current in 1..=3 {
+            logs.send(frame(current, Some(3))).unwrap();
+        }
+        // A second run of the same code starts over without losing the first's end.
+        for current in 1..=2 {
+            logs.send(frame(current, None)).unwrap();
+        }
+        logs.send(ProgressEvent::new(Stage::Encode, "encode_frame").step(1, None))
+            .unwrap();
+        logs.flush().unwrap();
+        assert_eq!(sink.steps(), [Some(1), Some(3), Some(1), Some(2), Some(1)]);
+
+        // Silent logs have nothing to hold.
+        let silent = CallbackLogs::silent();
+        silent.send(frame(2, None)).unwrap();
+        silent.flush().unwrap();
+    }
+
+    #[test]
+    fn every_event_of_a_conversion_is_known_to_the_ui() {
+        let frames: Vec<_> = (0..12)
+            .map(|i| (RgbaImage::from_pixel(4, 4, Rgba([i * 20, 0, 0, 255])), 100))
+            .collect();
+        let data = encode_rgba_png(&frames).unwrap();
+
+        let sink = FakeSink::default();
+        let logs = CallbackLogs::new(&sink);
+        convert_animated(&data, "apng", "gif", &logs, never_cancelled()).unwrap();
+        drop(logs);
+
+        let events = sink.events.borrow();
+        for code in [
+            "decode_start",
+            "decode_frame",
+            "decode_done",
+            "encode_frame",
+        ] {
+            assert!(events.iter().any(|(_, c, _)| *c == code), "{}", code);
+        }
+        // Both frame runs report their twelfth frame even though the clock never moved.
+        for code in ["decode_frame", "encode_frame"] {
+            let last = events.iter().rfind(|(_, c, _)| *c == code);
+            assert_eq!(last.unwrap().2, Some(12), "{}", code);
+        }
+        for (stage, code, _) in events.iter() {
+            assert!(PROGRESS_TS.contains(&format!("\"{}\"", stage)), "{}", stage);
+            assert!(PROGRESS_TS.contains(&format!("  {}: ", code)), "{}", code);
+        }
+    }
 }
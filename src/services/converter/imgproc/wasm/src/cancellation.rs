@@ -0,0 +1,74 @@
+use crate::error::{ErrorCode, ImgprocError, Result};
+
+/// Fails with `ErrorCode::Cancelled` once the caller wants the work to stop, so long loops
+/// can bail out with `?` and leave the module ready for the next call.
+pub type Cancellation<'a> = Box<dyn Fn() -> Result<()> + 'a>;
+
+pub fn never_cancelled() -> Cancellation<'static> {
+    Box::new(|| Ok(()))
+}
+
+pub fn cancelled() -> ImgprocError {
+    ImgprocError::new(ErrorCode::Cancelled, "The operation was cancelled.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        animation_encode::convert_animated, callback_logs::CallbackLogs,
+        frame_encode::encode_rgba_png,
+    };
+    use image::{Rgba, RgbaImage};
+    use std::cell::Cell;
+
+    fn animation(frames: u8) -> Vec<u8> {
+        let frames: Vec<_> = (0..frames)
+            .map(|i| (RgbaImage::from_pixel(4, 4, Rgba([i * 40, 0, 0, 255])), 100))
+            .collect();
+        encode_rgba_png(&frames).unwrap()
+    }
+
+    /// Cancels once the check has run `after` times, and counts every check.
+    fn cancel_after(after: usize, checks: &Cell<usize>) -> Cancellation<'_> {
+        Box::new(move || {
+            checks.set(checks.get() + 1);
+            if checks.get() > after {
+                Err(cancelled())
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    #[test]
+    fn cancelling_stops_between_frames() {
+        let data = animation(4);
+        let logs = CallbackLogs::silent();
+
+        // Every frame is checked on the way in and on the way out.
+        let checks = Cell::new(0);
+        convert_animated(
+            &data,
+            "apng",
+            "gif",
+            &logs,
+            cancel_after(usize::MAX, &checks),
+        )
+        .unwrap();
+        let per_conversion = checks.get();
+        assert!(per_conversion >= 8, "{}", per_conversion);
+
+        for after in [0, 1, per_conversion / 2, per_conversion - 1] {
+            let checks = Cell::new(0);
+            let error = convert_animated(&data, "apng", "gif", &logs, cancel_after(after, &checks))
+                .err()
+                .unwrap();
+            assert_eq!(error.code, ErrorCode::Cancelled, "after {} checks", after);
+            assert_eq!(checks.get(), after + 1);
+        }
+
+        // A cancelled conversion leaves nothing behind for the next one.
+        convert_animated(&data, "apng", "gif", &logs, never_cancelled()).unwrap();
+    }
+}
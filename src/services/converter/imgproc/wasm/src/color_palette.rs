@@ -0,0 +1,389 @@
+use image::RgbaImage;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::animation_decoder::DecodedFrame;
+
+const MAX_SAMPLED_FRAMES: usize = 8;
+const SAMPLES_PER_FRAME: u32 = 4096;
+const KMEANS_ITERATIONS: usize = 24;
+/// Oklab chroma below which a colour reads as grey.
+const GREY_CHROMA: f32 = 0.04;
+/// Share of the image weight allowed to be chromatic in a monochrome image.
+const MONOCHROME_TOLERANCE: f32 = 0.05;
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct ColorPalette {
+    colors: Vec<String>,
+    weights: Vec<f32>,
+    average_color: String,
+    is_monochrome: bool,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl ColorPalette {
+    /// Dominant colours as `#rrggbb`, most prominent first.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn colors(&self) -> Vec<String> {
+        self.colors.clone()
+    }
+
+    /// Share of the sampled pixels covered by each colour; sums to 1.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn weights(&self) -> Vec<f32> {
+        self.weights.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn average_color(&self) -> String {
+        self.average_color.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn is_monochrome(&self) -> bool {
+        self.is_monochrome
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Oklab {
+    l: f32,
+    a: f32,
+    b: f32,
+}
+
+impl Oklab {
+    fn distance_squared(&self, other: &Oklab) -> f32 {
+        let (dl, da, db) = (self.l - other.l, self.a - other.a, self.b - other.b);
+        dl * dl + da * da + db * db
+    }
+
+    fn chroma(&self) -> f32 {
+        (self.a * self.a + self.b * self.b).sqrt()
+    }
+}
+
+struct Sample {
+    color: Oklab,
+    linear: [f32; 3],
+    weight: f32,
+}
+
+/// Extracts up to `k` dominant colours with k-means in Oklab, sampling evenly across frames.
+pub fn extract_palette(frames: &[DecodedFrame], k: u32) -> ColorPalette {
+    let samples = collect_samples(frames);
+    let total_weight: f32 = samples.iter().map(|s| s.weight).sum();
+
+    if samples.is_empty() || total_weight <= 0.0 {
+        return ColorPalette {
+            colors: Vec::new(),
+            weights: Vec::new(),
+            average_color: format_color([0.0; 3]),
+            is_monochrome: true,
+        };
+    }
+
+    let mut average = [0f32; 3];
+    for sample in &samples {
+        for (channel, value) in average.iter_mut().zip(sample.linear) {
+            *channel += value * sample.weight;
+        }
+    }
+    average.iter_mut().for_each(|c| *c /= total_weight);
+
+    let chromatic_weight: f32 = samples
+        .iter()
+        .filter(|s| s.color.chroma() > GREY_CHROMA)
+        .map(|s| s.weight)
+        .sum();
+
+    let mut clusters = kmeans(&samples, k.max(1) as usize);
+    clusters.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    ColorPalette {
+        colors: clusters
+            .iter()
+            .map(|(color, _)| format_color(oklab_to_linear(color)))
+            .collect(),
+        weights: clusters.iter().map(|(_, w)| w / total_weight).collect(),
+        average_color: format_color(average),
+        is_monochrome: chromatic_weight / total_weight <= MONOCHROME_TOLERANCE,
+    }
+}
+
+fn collect_samples(frames: &[DecodedFrame]) -> Vec<Sample> {
+    let step = frames.len().div_ceil(MAX_SAMPLED_FRAMES).max(1);
+    frames
+        .iter()
+        .step_by(step)
+        .flat_map(|(image, _)| sample_frame(image))
+        .collect()
+}
+
+fn sample_frame(image: &RgbaImage) -> impl Iterator<Item = Sample> + '_ {
+    let pixels = image.width() as u64 * image.height() as u64;
+    let stride = ((pixels as f64 / SAMPLES_PER_FRAME as f64).sqrt().floor() as u32).max(1);
+
+    (0..image.height())
+        .step_by(stride as usize)
+        .flat_map(move |y| {
+            (0..image.width())
+                .step_by(stride as usize)
+                .map(move |x| image.get_pixel(x, y))
+        })
+        .filter(|p| p[3] > 0)
+        .map(|p| {
+            let linear = [
+                srgb_to_linear(p[0]),
+                srgb_to_linear(p[1]),
+                srgb_to_linear(p[2]),
+            ];
+            Sample {
+                color: linear_to_oklab(linear),
+                linear,
+                weight: p[3] as f32 / 255.0,
+            }
+        })
+}
+
+/// Weighted k-means with deterministic farthest-point seeding, returning centroids and weights.
+fn kmeans(samples: &[Sample], k: usize) -> Vec<(Oklab, f32)> {
+    let mut centroids: Vec<Oklab> = Vec::with_capacity(k);
+    let heaviest = samples
+        .iter()
+        .max_by(|a, b| a.weight.total_cmp(&b.weight))
+        .map(|s| s.color)
+        .unwrap_or_default();
+    centroids.push(heaviest);
+
+    let mut nearest: Vec<f32> = samples
+        .iter()
+        .map(|s| s.color.distance_squared(&heaviest))
+        .collect();
+    while centroids.len() < k {
+        let (index, distance) = nearest
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (i, d * samples[i].weight))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((0, 0.0));
+        if distance <= f32::EPSILON {
+            break;
+        }
+        let centroid = samples[index].color;
+        centroids.push(centroid);
+        for (d, sample) in nearest.iter_mut().zip(samples) {
+            *d = d.min(sample.color.distance_squared(&centroid));
+        }
+    }
+
+    let mut assignments = vec![0usize; samples.len()];
+    let mut weights = vec![0f32; centroids.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut changed = false;
+        for (assignment, sample) in assignments.iter_mut().zip(samples) {
+            let closest = closest_centroid(&centroids, &sample.color);
+            if closest != *assignment {
+                *assignment = closest;
+                changed = true;
+            }
+        }
+
+        let mut sums = vec![(Oklab::default(), 0f32); centroids.len()];
+        for (&assignment, sample) in assignments.iter().zip(samples) {
+            let (sum, weight) = &mut sums[assignment];
+            sum.l += sample.color.l * sample.weight;
+            sum.a += sample.color.a * sample.weight;
+            sum.b += sample.color.b * sample.weight;
+            *weight += sample.weight;
+        }
+
+        for (centroid, (sum, weight)) in centroids.iter_mut().zip(&sums) {
+            if *weight > 0.0 {
+                *centroid = Oklab {
+                    l: sum.l / weight,
+                    a: sum.a / weight,
+                    b: sum.b / weight,
+                };
+            }
+        }
+        weights = sums.iter().map(|(_, w)| *w).collect();
+
+        if !changed {
+            break;
+        }
+    }
+
+    centroids
+        .into_iter()
+        .zip(weights)
+        .filter(|(_, w)| *w > 0.0)
+        .collect()
+}
+
+fn closest_centroid(centroids: &[Oklab], color: &Oklab) -> usize {
+    centroids
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| {
+            a.distance_squared(color)
+                .total_cmp(&b.distance_squared(color))
+        })
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let c = value as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let c = value.clamp(0.0, 1.0);
+    let encoded = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
+
+fn linear_to_oklab([r, g, b]: [f32; 3]) -> Oklab {
+    let l = 0.412_221_46 * r + 0.536_332_55 * g + 0.051_445_995 * b;
+    let m = 0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b;
+    let s = 0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b;
+
+    let (l, m, s) = (l.cbrt(), m.cbrt(), s.cbrt());
+
+    Oklab {
+        l: 0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        a: 1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        b: 0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    }
+}
+
+fn oklab_to_linear(color: &Oklab) -> [f32; 3] {
+    let l = color.l + 0.396_337_78 * color.a + 0.215_803_76 * color.b;
+    let m = color.l - 0.105_561_346 * color.a - 0.063_854_17 * color.b;
+    let s = color.l - 0.089_484_18 * color.a - 1.291_485_5 * color.b;
+
+    let (l, m, s) = (l * l * l, m * m * m, s * s * s);
+
+    [
+        4.076_741_7 * l - 3.307_711_6 * m + 0.230_969_94 * s,
+        -1.268_438 * l + 2.609_757_4 * m - 0.341_319_38 * s,
+        -0.0041960863 * l - 0.703_418_6 * m + 1.707_614_7 * s,
+    ]
+}
+
+fn format_color([r, g, b]: [f32; 3]) -> String {
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        linear_to_srgb(r),
+        linear_to_srgb(g),
+        linear_to_srgb(b)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn split(left: Rgba<u8>, right: Rgba<u8>) -> RgbaImage {
+        RgbaImage::from_fn(64, 32, |x, _| if x < 16 { left } else { right })
+    }
+
+    fn weight_sum(palette: &ColorPalette) -> f32 {
+        palette.weights().iter().sum()
+    }
+
+    #[test]
+    fn weights_sum_to_one() {
+        let noisy = RgbaImage::from_fn(90, 70, |x, y| {
+            Rgba([
+                (x * 7 % 256) as u8,
+                (y * 11 % 256) as u8,
+                (x * y % 256) as u8,
+                255,
+            ])
+        });
+        for k in [1, 3, 8, 16] {
+            let palette = extract_palette(&[(noisy.clone(), 0)], k);
+            assert!(palette.colors().len() <= k as usize);
+            assert_eq!(palette.colors().len(), palette.weights().len());
+            assert!((weight_sum(&palette) - 1.0).abs() < 1e-4, "k = {}", k);
+        }
+        // Partly transparent pixels count for less but still leave a full total.
+        let faint = split(Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 40]));
+        let palette = extract_palette(&[(faint, 0)], 4);
+        assert!((weight_sum(&palette) - 1.0).abs() < 1e-4);
+        assert_eq!(palette.colors()[0], "#ff0000");
+    }
+
+    #[test]
+    fn two_colour_image_gives_those_colours() {
+        let image = split(Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+        let palette = extract_palette(&[(image, 0)], 5);
+        assert_eq!(palette.colors(), ["#0000ff", "#ff0000"]);
+        let weights = palette.weights();
+        assert!((weights[0] - 0.75).abs() < 1e-3, "{:?}", weights);
+        assert!((weights[1] - 0.25).abs() < 1e-3, "{:?}", weights);
+        assert!(!palette.is_monochrome());
+
+        // Fully transparent pixels do not count at all.
+        let image = split(Rgba([0, 255, 0, 0]), Rgba([0, 0, 255, 255]));
+        assert_eq!(extract_palette(&[(image, 0)], 5).colors(), ["#0000ff"]);
+        let empty = extract_palette(&[(RgbaImage::new(4, 4), 0)], 5);
+        assert!(empty.colors().is_empty());
+        assert_eq!(empty.average_color(), "#000000");
+    }
+
+    #[test]
+    fn grayscale_image_is_monochrome() {
+        let grey = RgbaImage::from_fn(50, 40, |x, y| {
+            let v = ((x + y) * 3) as u8;
+            Rgba([v, v, v, 255])
+        });
+        let palette = extract_palette(&[(grey.clone(), 0)], 6);
+        assert!(palette.is_monochrome());
+        assert!(palette.colors().len() > 1);
+
+        // A speck of colour is tolerated, a patch is not.
+        let mut speck = grey.clone();
+        speck.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        assert!(extract_palette(&[(speck, 0)], 6).is_monochrome());
+        let patch = RgbaImage::from_fn(50, 40, |x, y| match x < 10 {
+            true => Rgba([200, 40, 40, 255]),
+            false => *grey.get_pixel(x, y),
+        });
+        assert!(!extract_palette(&[(patch, 0)], 6).is_monochrome());
+    }
+
+    #[test]
+    fn frames_are_sampled_across_an_animation() {
+        let red = RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255]));
+        let blue = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 255, 255]));
+
+        // Blue only appears near the end, well past the first sampled frames.
+        let frames: Vec<DecodedFrame> = (0..40)
+            .map(|i| (if i < 30 { &red } else { &blue }.clone(), 100))
+            .collect();
+        let sampled = collect_samples(&frames).len() as u32;
+        assert!(sampled <= MAX_SAMPLED_FRAMES as u32 * 64, "{}", sampled);
+
+        let palette = extract_palette(&frames, 4);
+        assert_eq!(palette.colors(), ["#ff0000", "#0000ff"]);
+        assert!((weight_sum(&palette) - 1.0).abs() < 1e-4);
+
+        // Fewer frames than the limit are all sampled.
+        let frames = [(red.clone(), 100), (blue.clone(), 100), (blue, 100)];
+        assert_eq!(collect_samples(&frames).len(), 3 * 64);
+        let palette = extract_palette(&frames, 4);
+        assert_eq!(palette.colors(), ["#0000ff", "#ff0000"]);
+    }
+}
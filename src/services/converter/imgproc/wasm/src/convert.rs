@@ -0,0 +1,299 @@
+use image::{imageops, RgbaImage};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    animation_decoder::DecodedFrame,
+    callback_logs::CallbackLogs,
+    cancellation::Cancellation,
+    error::{ImgprocError, Result},
+    formats::target_format,
+    limits::current_limits,
+    session::ImageSession,
+    tone_map::ToneMap,
+};
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug)]
+pub struct ConvertOptions {
+    quality: u8,
+    speed: u8,
+    animated: bool,
+    frame: Option<u32>,
+    sprite_sheet: bool,
+    columns: u32,
+    bit_depth: u8,
+    tone_map: ToneMap,
+    exposure: f32,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        ConvertOptions {
+            quality: 90,
+            speed: 6,
+            animated: true,
+            frame: None,
+            sprite_sheet: false,
+            columns: 0,
+            bit_depth: 8,
+            tone_map: ToneMap::default(),
+            exposure: 0.0,
+        }
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl ConvertOptions {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new() -> ConvertOptions {
+        ConvertOptions::default()
+    }
+
+    /// JPEG and AVIF quality from 1 to 100.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn quality(&self) -> u8 {
+        self.quality
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_quality(&mut self, value: u8) {
+        self.quality = value;
+    }
+
+    /// AVIF encoder speed from 1, slowest and smallest, to 10.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn speed(&self) -> u8 {
+        self.speed
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_speed(&mut self, value: u8) {
+        self.speed = value;
+    }
+
+    /// Keep all frames when the target is GIF, APNG or WebP; otherwise only the first
+    /// frame is written.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn animated(&self) -> bool {
+        self.animated
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_animated(&mut self, value: bool) {
+        self.animated = value;
+    }
+
+    /// Write only this frame, as a still image, whatever the target.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn frame(&self) -> Option<u32> {
+        self.frame
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_frame(&mut self, value: Option<u32>) {
+        self.frame = value;
+    }
+
+    /// Write every frame side by side on one still image.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn sprite_sheet(&self) -> bool {
+        self.sprite_sheet
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_sprite_sheet(&mut self, value: bool) {
+        self.sprite_sheet = value;
+    }
+
+    /// Frames per row of the sprite sheet; zero keeps the sheet roughly square.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn columns(&self) -> u32 {
+        self.columns
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_columns(&mut self, value: u32) {
+        self.columns = value;
+    }
+
+    /// PNG bits per channel: 16 keeps the precision of 16-bit and HDR sources, 8 by default.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn bit_depth(&self) -> u8 {
+        self.bit_depth
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_bit_depth(&mut self, value: u8) {
+        self.bit_depth = value;
+    }
+
+    /// How HDR sources are brought into range for targets without floating-point samples.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn tone_map(&self) -> ToneMap {
+        self.tone_map
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_tone_map(&mut self, value: ToneMap) {
+        self.tone_map = value;
+    }
+
+    /// Stops to brighten, or darken if negative, HDR sources by before tone mapping.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_exposure(&mut self, value: f32) {
+        self.exposure = value;
+    }
+}
+
+impl ConvertOptions {
+    /// Fails on a setting no encoder can use. The setters take any value, as JS assigns
+    /// them one by one, so every entry point checks the options here before converting.
+    pub fn validate(&self) -> Result<()> {
+        if !self.exposure.is_finite() {
+            return Err(ImgprocError::invalid_argument(format!(
+                "Exposure must be a finite number of stops, not {}.",
+                self.exposure
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Converts between any decodable format and `target_type`. An animation written to a
+/// still format keeps its first frame unless `options` picks a frame or a sprite sheet. A
+/// still image is written as a still in every format, GIF, APNG and WebP included.
+pub fn convert(
+    image_data: &[u8],
+    source_type: &str,
+    target_type: &str,
+    options: &ConvertOptions,
+    logs: &CallbackLogs,
+    cancel: Cancellation,
+) -> Result<Vec<u8>> {
+    ImageSession::new(image_data.to_vec(), source_type)?.convert(target_type, options, logs, cancel)
+}
+
+/// Encodes a single image as `target_type`, flattening alpha for formats without it.
+pub fn encode_still(
+    image: RgbaImage,
+    target_type: &str,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>> {
+    target_format(target_type)?.encode_still(image, options)
+}
+
+/// Lays the frames out left to right, then top to bottom, on one image.
+pub fn sprite_sheet(frames: &[DecodedFrame], columns: u32) -> Result<RgbaImage> {
+    let (width, height) = frames
+        .first()
+        .map(|(image, _)| image.dimensions())
+        .ok_or_else(|| {
+            ImgprocError::invalid_argument("Cannot build a sprite sheet without frames.")
+        })?;
+    let count = frames.len() as u32;
+    let columns = match columns {
+        0 => (count as f64).sqrt().ceil() as u32,
+        columns => columns.min(count),
+    };
+    let rows = count.div_ceil(columns);
+
+    let sheet_width = width.saturating_mul(columns);
+    let sheet_height = height.saturating_mul(rows);
+    current_limits().check_dimensions(sheet_width, sheet_height, "sprite sheet")?;
+
+    let mut sheet = RgbaImage::new(sheet_width, sheet_height);
+    for (index, (image, _)) in (0..).zip(frames) {
+        let x = (index % columns) * width;
+        let y = (index / columns) * height;
+        imageops::replace(&mut sheet, image, x as i64, y as i64);
+    }
+    Ok(sheet)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{
+        codecs::png::PngEncoder, DynamicImage, ImageDecoder, ImageEncoder, ImageFormat,
+        ImageReader, Rgba,
+    };
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::cancellation::never_cancelled;
+
+    const ICC: &[u8] = b"not a real profile, but carried all the same";
+
+    fn png_with_icc(image: DynamicImage) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder = PngEncoder::new(&mut output);
+        encoder.set_icc_profile(ICC.to_vec()).unwrap();
+        let (width, height) = (image.width(), image.height());
+        encoder
+            .write_image(image.as_bytes(), width, height, image.color().into())
+            .unwrap();
+        output
+    }
+
+    fn icc_profile(data: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+        let reader = ImageReader::with_format(Cursor::new(data), format);
+        reader.into_decoder().unwrap().icc_profile().unwrap()
+    }
+
+    fn convert_to(data: &[u8], target: &str, options: &ConvertOptions) -> Vec<u8> {
+        let logs = CallbackLogs::silent();
+        convert(data, "png", target, options, &logs, never_cancelled()).unwrap()
+    }
+
+    #[test]
+    fn exposure_must_be_finite() {
+        let data = png_with_icc(DynamicImage::ImageRgba8(RgbaImage::new(2, 2)));
+        let mut options = ConvertOptions::default();
+        for exposure in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            options.set_exposure(exposure);
+            let logs = CallbackLogs::silent();
+            let error = convert(&data, "png", "png", &options, &logs, never_cancelled())
+                .err()
+                .unwrap();
+            assert_eq!(error.code, crate::error::ErrorCode::InvalidArgument);
+        }
+        options.set_exposure(-2.5);
+        assert!(!convert_to(&data, "png", &options).is_empty());
+    }
+
+    #[test]
+    fn still_output_keeps_the_icc_profile() {
+        let rgba = RgbaImage::from_pixel(3, 2, Rgba([10, 20, 30, 255]));
+        let data = png_with_icc(DynamicImage::ImageRgba8(rgba));
+        let options = ConvertOptions::default();
+        for (target, format) in [
+            ("png", ImageFormat::Png),
+            ("jpeg", ImageFormat::Jpeg),
+            ("webp", ImageFormat::WebP),
+        ] {
+            let output = convert_to(&data, target, &options);
+            assert_eq!(
+                icc_profile(&output, format).as_deref(),
+                Some(ICC),
+                "{}",
+                target
+            );
+        }
+
+        // A 16-bit source keeps both its precision and its profile.
+        let deep = DynamicImage::ImageRgba16(
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255]))).to_rgba16(),
+        );
+        let mut options = ConvertOptions::default();
+        options.set_bit_depth(16);
+        let output = convert_to(&png_with_icc(deep), "png", &options);
+        assert_eq!(icc_profile(&output, ImageFormat::Png).as_deref(), Some(ICC));
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert!(matches!(decoded, DynamicImage::ImageRgba16(_)));
+    }
+}
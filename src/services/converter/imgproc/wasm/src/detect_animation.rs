@@ -1,44 +1,68 @@
-use gif;
-use js_sys::Boolean;
-use png;
+use crate::{
+    error::{ImgprocError, Result},
+    formats::source_format,
+    limits::current_limits,
+};
 use std::io::{Cursor, Read, Seek, SeekFrom};
-use wasm_bindgen::prelude::*;
 
+pub fn is_animated(data: &[u8], source_type: &str) -> Result<bool> {
+    match source_format(source_type)?.detect_animation {
+        Some(detect) => detect(data),
+        None => Err(ImgprocError::unsupported_source(source_type)),
+    }
+}
 
-pub fn is_animated_gif(data: &[u8]) -> Result<Boolean, JsError> {
+pub fn is_animated_gif(data: &[u8]) -> Result<bool> {
     let cursor = Cursor::new(data);
-    let mut decoder = gif::DecodeOptions::new().read_info(cursor)?;
+    let mut options = gif::DecodeOptions::new();
+    // Only the frames are counted, so their LZW data is skipped rather than decoded.
+    options.skip_frame_decoding(true);
+    let mut decoder = options.read_info(cursor).map_err(|e| {
+        ImgprocError::corrupt("Failed to read GIF header.")
+            .format("GIF")
+            .cause(e)
+    })?;
+    current_limits().check_dimensions(decoder.width() as u32, decoder.height() as u32, "GIF")?;
 
     let mut frame_count = 0;
-    while let Some(_) = decoder.read_next_frame()? {
+    while decoder
+        .read_next_frame()
+        .map_err(|e| {
+            ImgprocError::corrupt("Failed to decode a GIF frame.")
+                .format("GIF")
+                .frame(frame_count)
+                .cause(e)
+        })?
+        .is_some()
+    {
         frame_count += 1;
         if frame_count > 1 {
-            return Ok(Boolean::from(true));
+            return Ok(true);
         }
     }
 
-    Ok(Boolean::from(false))
+    Ok(false)
 }
 
-pub fn is_animated_webp(data: &[u8]) -> Result<Boolean, JsError> {
+pub fn is_animated_webp(data: &[u8]) -> Result<bool> {
     const WEBP_HEADER: &[u8] = b"RIFF";
     const WEBP_SIGNATURE: &[u8] = b"WEBP";
     const ANIM_CHUNK: &[u8] = b"ANIM";
     const ANMF_CHUNK: &[u8] = b"ANMF";
 
     if !data.starts_with(WEBP_HEADER) {
-        return Ok(Boolean::from(false));
+        return Ok(false);
     }
 
     let mut cursor = Cursor::new(data);
 
     if cursor.seek(SeekFrom::Start(8)).is_err() {
-        return Ok(Boolean::from(false));
+        return Ok(false);
     }
 
     let mut signature = [0u8; 4];
-    if cursor.read_exact(&mut signature).is_err() || &signature != WEBP_SIGNATURE {
-        return Ok(Boolean::from(false));
+    if cursor.read_exact(&mut signature).is_err() || signature != WEBP_SIGNATURE {
+        return Ok(false);
     }
 
     loop {
@@ -53,30 +77,32 @@ pub fn is_animated_webp(data: &[u8]) -> Result<Boolean, JsError> {
         }
         let chunk_size = u32::from_le_bytes(chunk_size_bytes);
 
-        if &chunk_type == ANIM_CHUNK || &chunk_type == ANMF_CHUNK {
-            return Ok(Boolean::from(true));
+        if chunk_type == ANIM_CHUNK || chunk_type == ANMF_CHUNK {
+            return Ok(true);
         }
 
         if cursor.seek(SeekFrom::Current(chunk_size as i64)).is_err() {
             break;
         }
 
-        if chunk_size % 2 != 0 {
-            if cursor.seek(SeekFrom::Current(1)).is_err() {
-                break;
-            }
+        if chunk_size % 2 != 0 && cursor.seek(SeekFrom::Current(1)).is_err() {
+            break;
         }
     }
 
-    Ok(Boolean::from(false))
+    Ok(false)
 }
 
-pub fn is_animated_apng(data: &[u8]) -> Result<Boolean, JsError> {
+pub fn is_animated_apng(data: &[u8]) -> Result<bool> {
     let decoder = png::Decoder::new(data);
-    let reader = decoder.read_info()?;
+    let reader = decoder.read_info().map_err(|e| {
+        ImgprocError::corrupt("Failed to read PNG header.")
+            .format("PNG")
+            .cause(e)
+    })?;
 
     match reader.info().animation_control() {
-        Some(_) => Ok(Boolean::from(true)),
-        _ => Ok(Boolean::from(false)),
+        Some(_) => Ok(true),
+        _ => Ok(false),
     }
 }
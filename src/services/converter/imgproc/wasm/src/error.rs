@@ -0,0 +1,300 @@
+use image::ImageError;
+use std::fmt::{self, Display};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The format, or a feature of it, is not handled.
+    UnsupportedFormat,
+    /// The input could not be parsed.
+    CorruptData,
+    /// Writing the output failed.
+    EncodeFailed,
+    /// An argument from the caller is malformed or out of range.
+    InvalidArgument,
+    /// The input is larger than the configured decode limits allow.
+    LimitExceeded,
+    Cancelled,
+    /// A callback failed, or anything else that is not the input's fault.
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::UnsupportedFormat => "unsupported_format",
+            ErrorCode::CorruptData => "corrupt_data",
+            ErrorCode::EncodeFailed => "encode_failed",
+            ErrorCode::InvalidArgument => "invalid_argument",
+            ErrorCode::LimitExceeded => "limit_exceeded",
+            ErrorCode::Cancelled => "cancelled",
+            ErrorCode::Internal => "internal",
+        }
+    }
+}
+
+/// Error returned by every operation. The wasm adapter turns it into an `Error` named
+/// `ImgprocError` carrying the properties from `js_fields`.
+#[derive(Clone, Debug)]
+pub struct ImgprocError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub format: Option<String>,
+    pub frame_index: Option<u32>,
+    pub byte_offset: Option<u64>,
+    /// Text of the underlying decoder or encoder error.
+    pub cause: Option<String>,
+}
+
+pub type Result<T, E = ImgprocError> = std::result::Result<T, E>;
+
+impl ImgprocError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ImgprocError {
+            code,
+            message: message.into(),
+            format: None,
+            frame_index: None,
+            byte_offset: None,
+            cause: None,
+        }
+    }
+
+    pub fn unsupported_source(format: &str) -> Self {
+        ImgprocError::new(
+            ErrorCode::UnsupportedFormat,
+            format!("Not support source type: {}", format),
+        )
+        .format(format)
+    }
+
+    pub fn unsupported_target(format: &str) -> Self {
+        ImgprocError::new(
+            ErrorCode::UnsupportedFormat,
+            format!("Not support target type: {}", format),
+        )
+        .format(format)
+    }
+
+    /// Maps an error from the `image` crate, keeping its text as the cause.
+    pub fn decode(message: impl Into<String>, error: ImageError) -> Self {
+        let code = match error {
+            ImageError::Limits(_) => ErrorCode::LimitExceeded,
+            ImageError::Unsupported(_) => ErrorCode::UnsupportedFormat,
+            ImageError::Encoding(_) => ErrorCode::EncodeFailed,
+            _ => ErrorCode::CorruptData,
+        };
+        ImgprocError::new(code, message).cause(error)
+    }
+
+    pub fn corrupt(message: impl Into<String>) -> Self {
+        ImgprocError::new(ErrorCode::CorruptData, message)
+    }
+
+    pub fn encode(message: impl Into<String>) -> Self {
+        ImgprocError::new(ErrorCode::EncodeFailed, message)
+    }
+
+    pub fn invalid_argument(message: impl Into<String>) -> Self {
+        ImgprocError::new(ErrorCode::InvalidArgument, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ImgprocError::new(ErrorCode::Internal, message)
+    }
+
+    pub fn format(mut self, format: impl Into<String>) -> Self {
+        self.format = Some(format.into());
+        self
+    }
+
+    pub fn frame(mut self, index: u32) -> Self {
+        self.frame_index = Some(index);
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.byte_offset = Some(offset);
+        self
+    }
+
+    pub fn cause(mut self, cause: impl Display) -> Self {
+        self.cause = Some(cause.to_string());
+        self
+    }
+}
+
+/// The value of a property on the JS error.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsField {
+    Text(String),
+    Number(f64),
+}
+
+impl ImgprocError {
+    /// The properties of the JS error besides its message, under the names errors.ts reads.
+    /// Context that is not known is left out rather than set to `undefined`.
+    pub fn js_fields(&self) -> Vec<(&'static str, JsField)> {
+        let mut fields = vec![("code", JsField::Text(self.code.as_str().to_string()))];
+        if let Some(format) = &self.format {
+            fields.push(("format", JsField::Text(format.clone())));
+        }
+        if let Some(index) = self.frame_index {
+            fields.push(("frameIndex", JsField::Number(index as f64)));
+        }
+        if let Some(offset) = self.byte_offset {
+            fields.push(("byteOffset", JsField::Number(offset as f64)));
+        }
+        if let Some(cause) = &self.cause {
+            fields.push(("cause", JsField::Text(cause.clone())));
+        }
+        fields
+    }
+}
+
+impl Display for ImgprocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let has_context =
+            self.frame_index.is_some() || self.byte_offset.is_some() || self.cause.is_some();
+        if has_context {
+            write!(f, "{}", self.message.trim_end_matches('.'))?;
+        } else {
+            write!(f, "{}", self.message)?;
+        }
+        if let Some(index) = self.frame_index {
+            write!(f, " (frame {})", index)?;
+        }
+        if let Some(offset) = self.byte_offset {
+            write!(f, " (at byte {})", offset)?;
+        }
+        match &self.cause {
+            Some(cause) => write!(f, ": {}", cause),
+            None if has_context => write!(f, "."),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ImgprocError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        callback_logs::CallbackLogs, cancellation::never_cancelled, frame_encode::encode_rgba_png,
+        image_frames::decode_frames,
+    };
+    use image::{Rgba, RgbaImage};
+
+    /// The TS side that reads the error object; its names must match `js_fields`.
+    const ERRORS_TS: &str = include_str!("../../errors.ts");
+
+    const CODES: [ErrorCode; 7] = [
+        ErrorCode::UnsupportedFormat,
+        ErrorCode::CorruptData,
+        ErrorCode::EncodeFailed,
+        ErrorCode::InvalidArgument,
+        ErrorCode::LimitExceeded,
+        ErrorCode::Cancelled,
+        ErrorCode::Internal,
+    ];
+
+    fn text(error: &ImgprocError, key: &str) -> Option<String> {
+        error.js_fields().into_iter().find_map(|(k, v)| match v {
+            JsField::Text(text) if k == key => Some(text),
+            _ => None,
+        })
+    }
+
+    fn number(error: &ImgprocError, key: &str) -> Option<f64> {
+        error.js_fields().into_iter().find_map(|(k, v)| match v {
+            JsField::Number(number) if k == key => Some(number),
+            _ => None,
+        })
+    }
+
+    fn decode_error(data: &[u8], format: &str) -> ImgprocError {
+        let logs = CallbackLogs::silent();
+        decode_frames(data, format, &logs, &never_cancelled())
+            .err()
+            .unwrap()
+    }
+
+    #[test]
+    fn error_codes_match_the_ts_union() {
+        let union = ERRORS_TS
+            .split("export type ImgprocErrorCode =")
+            .nth(1)
+            .and_then(|rest| rest.split(';').next())
+            .unwrap();
+        let names: Vec<_> = union
+            .split('|')
+            .map(|name| name.trim().trim_matches('"'))
+            .filter(|name| !name.is_empty())
+            .collect();
+        let codes: Vec<_> = CODES.iter().map(ErrorCode::as_str).collect();
+        assert_eq!(names, codes);
+
+        for code in CODES {
+            let error = ImgprocError::new(code, "Failed.");
+            assert_eq!(text(&error, "code").as_deref(), Some(code.as_str()));
+        }
+    }
+
+    #[test]
+    fn fields_are_named_as_the_ts_reads_them() {
+        let error = ImgprocError::corrupt("Bad chunk.")
+            .format("PNG")
+            .frame(3)
+            .offset(1 << 40)
+            .cause("crc mismatch");
+        let fields = error.js_fields();
+        let keys: Vec<_> = fields.iter().map(|(key, _)| *key).collect();
+        assert_eq!(
+            keys,
+            ["code", "format", "frameIndex", "byteOffset", "cause"]
+        );
+        for key in keys {
+            let declared = [format!("  {}: ", key), format!("  {}?: ", key)];
+            assert!(
+                declared.iter().any(|d| ERRORS_TS.contains(d.as_str())),
+                "{} is not in ImgprocErrorInfo",
+                key
+            );
+        }
+        assert_eq!(number(&error, "byteOffset"), Some((1u64 << 40) as f64));
+        assert_eq!(
+            error.to_string(),
+            "Bad chunk (frame 3) (at byte 1099511627776): crc mismatch"
+        );
+
+        // Unknown context is left off rather than sent as `undefined`.
+        let bare = ImgprocError::invalid_argument("Bad width.");
+        assert_eq!(bare.js_fields().len(), 1);
+        assert_eq!(bare.to_string(), "Bad width.");
+    }
+
+    #[test]
+    fn decode_context_reaches_the_fields() {
+        // A truncated PSD names the format and where the data ran out.
+        let error = decode_error(b"8BPS\0\x01\0\0\0\0\0\0\0\x04", "psd");
+        assert_eq!(text(&error, "code").as_deref(), Some("corrupt_data"));
+        assert_eq!(text(&error, "format").as_deref(), Some("PSD"));
+        assert_eq!(number(&error, "byteOffset"), Some(14.0));
+        assert_eq!(number(&error, "frameIndex"), None);
+
+        // A frame that fails to decode is named, with the decoder's own text as the cause.
+        let frames: Vec<_> = (0..3)
+            .map(|i| (RgbaImage::from_pixel(8, 8, Rgba([i * 80, 0, 0, 255])), 100))
+            .collect();
+        let mut data = encode_rgba_png(&frames).unwrap();
+        let last_frame = data.windows(4).rposition(|chunk| chunk == b"fdAT").unwrap();
+        // Past the chunk type and sequence number, into the compressed pixels.
+        data[last_frame + 8] ^= 0xff;
+        let error = decode_error(&data, "apng");
+        assert_eq!(text(&error, "code").as_deref(), Some("corrupt_data"));
+        assert_eq!(text(&error, "format").as_deref(), Some("APNG"));
+        assert_eq!(number(&error, "frameIndex"), Some(2.0));
+        assert!(text(&error, "cause").is_some());
+        assert!(error.to_string().contains("(frame 2)"), "{}", error);
+    }
+}
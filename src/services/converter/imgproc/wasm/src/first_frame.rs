@@ -1,115 +1,163 @@
 use image::{
     codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
-    AnimationDecoder, RgbaImage,
+    AnimationDecoder, ImageBuffer, Rgba, RgbaImage,
 };
-use js_sys::Uint8Array;
 use png::{self, Encoder};
 use std::io::Cursor;
-use wasm_bindgen::JsError;
 
-pub fn apng_first_frame(image_data: &[u8]) -> Result<Uint8Array, JsError> {
+use crate::{
+    error::{ImgprocError, Result},
+    formats::source_format,
+    limits::limit_decoder,
+};
+
+/// Encodes the first frame of an animated image, or a whole still image, as PNG.
+pub fn first_frame(image_data: &[u8], source_type: &str) -> Result<Vec<u8>> {
+    encode_png(source_format(source_type)?.decode_first_frame(image_data)?)
+}
+
+pub fn apng_first_frame(image_data: &[u8]) -> Result<Vec<u8>> {
+    encode_png(apng_first_frame_image(image_data)?)
+}
+
+pub fn apng_first_frame_image(image_data: &[u8]) -> Result<RgbaImage> {
     let cursor = Cursor::new(image_data);
-    let decoder = match PngDecoder::new(cursor) {
+    let mut decoder = match PngDecoder::new(cursor) {
         Ok(d) => d,
         Err(e) => {
-            return Err(JsError::new(&format!(
-                "Failed to create PNG decoder: {}",
-                e
-            )))
+            return Err(ImgprocError::decode("Failed to create PNG decoder.", e).format("APNG"))
         }
     };
 
+    limit_decoder(&mut decoder, "APNG")?;
+
     let apng_decoder = match decoder.apng() {
         Ok(d) => d,
-        Err(_) => return Err(JsError::new("The provided image is not a valid APNG.")),
+        Err(e) => {
+            return Err(
+                ImgprocError::decode("The provided image is not a valid APNG.", e).format("APNG"),
+            )
+        }
     };
 
     let first_frame = match apng_decoder.into_frames().next() {
-        Some(frmae_result) => frmae_result?,
+        Some(frame_result) => frame_result.map_err(|e| {
+            ImgprocError::decode("Failed to decode a frame.", e)
+                .format("APNG")
+                .frame(0)
+        })?,
         None => {
-            return Err(JsError::new(&format!("Failed to decode a apng frame")));
+            return Err(ImgprocError::corrupt("The image has no frames.").format("APNG"));
         }
     };
 
-    let first_image = first_frame.into_buffer();
+    Ok(first_frame.into_buffer())
+}
 
-    encode_png(first_image)
+pub fn gif_first_frame(image_data: &[u8]) -> Result<Vec<u8>> {
+    encode_png(gif_first_frame_image(image_data)?)
 }
 
-pub fn gif_first_frame(image_data: &[u8]) -> Result<Uint8Array, JsError> {
+pub fn gif_first_frame_image(image_data: &[u8]) -> Result<RgbaImage> {
     let cursor = Cursor::new(image_data);
-    let decoder = match GifDecoder::new(cursor) {
+    let mut decoder = match GifDecoder::new(cursor) {
         Ok(d) => d,
         Err(e) => {
-            return Err(JsError::new(&format!(
-                "Failed to create Gif decoder: {}",
-                e
-            )))
+            return Err(ImgprocError::decode("Failed to create GIF decoder.", e).format("GIF"))
         }
     };
 
+    limit_decoder(&mut decoder, "GIF")?;
+
     let first_frame = match decoder.into_frames().next() {
-        Some(frame_result) => frame_result?,
+        Some(frame_result) => frame_result.map_err(|e| {
+            ImgprocError::decode("Failed to decode a frame.", e)
+                .format("GIF")
+                .frame(0)
+        })?,
         None => {
-            return Err(JsError::new(&format!("Failed to decode a gif frame")));
+            return Err(ImgprocError::corrupt("The image has no frames.").format("GIF"));
         }
     };
 
-    let first_image = first_frame.into_buffer();
+    Ok(first_frame.into_buffer())
+}
 
-    encode_png(first_image)
+pub fn webp_first_frame(image_data: &[u8]) -> Result<Vec<u8>> {
+    encode_png(webp_first_frame_image(image_data)?)
 }
 
-pub fn webp_first_frame(image_data: &[u8]) -> Result<Uint8Array, JsError> {
+pub fn webp_first_frame_image(image_data: &[u8]) -> Result<RgbaImage> {
     let cursor = Cursor::new(image_data);
-    let decoder = match WebPDecoder::new(cursor) {
+    let mut decoder = match WebPDecoder::new(cursor) {
         Ok(d) => d,
         Err(e) => {
-            return Err(JsError::new(&format!(
-                "Failed to create WebP decoder: {}",
-                e
-            )))
+            return Err(ImgprocError::decode("Failed to create WebP decoder.", e).format("WebP"))
         }
     };
 
+    limit_decoder(&mut decoder, "WebP")?;
+
     let first_frame = match decoder.into_frames().next() {
-        Some(frame_result) => frame_result?,
+        Some(frame_result) => frame_result.map_err(|e| {
+            ImgprocError::decode("Failed to decode a frame.", e)
+                .format("WebP")
+                .frame(0)
+        })?,
         None => {
-            return Err(JsError::new(&format!("Failed to decode a webp frame")));
+            return Err(ImgprocError::corrupt("The image has no frames.").format("WebP"));
         }
     };
 
-    let first_image = first_frame.into_buffer();
+    Ok(first_frame.into_buffer())
+}
+
+pub fn encode_png(image: RgbaImage) -> Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    write_png(&image.into_raw(), width, height, png::BitDepth::Eight)
+}
 
-    encode_png(first_image)
+/// Encodes 16 bits per channel, for sources with more precision than 8 bits.
+pub fn encode_png16(image: &ImageBuffer<Rgba<u16>, Vec<u16>>) -> Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let samples: Vec<u8> = image
+        .iter()
+        .flat_map(|sample| sample.to_be_bytes())
+        .collect();
+    write_png(&samples, width, height, png::BitDepth::Sixteen)
 }
 
-pub fn encode_png(image: RgbaImage) -> Result<Uint8Array, JsError> {
-    let width = image.width();
-    let height = image.height();
+fn write_png(samples: &[u8], width: u32, height: u32, depth: png::BitDepth) -> Result<Vec<u8>> {
     let mut output = Vec::new();
     let mut encoder = Encoder::new(&mut output, width, height);
 
     encoder.set_color(png::ColorType::Rgba);
-    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_depth(depth);
     encoder.set_compression(png::Compression::Fast);
     encoder.set_filter(png::FilterType::Sub);
 
     let mut writer = match encoder.write_header() {
         Ok(w) => w,
-        Err(e) => return Err(JsError::new(&format!("Failed to write PNG header: {}", e))),
+        Err(e) => {
+            return Err(ImgprocError::encode("Failed to write PNG header.")
+                .format("PNG")
+                .cause(e))
+        }
     };
 
-    match writer.write_image_data(&image.into_raw()) {
+    match writer.write_image_data(samples) {
         Ok(_) => {}
-        Err(e) => return Err(JsError::new(&format!("Failed to write image data: {}", e))),
+        Err(e) => {
+            return Err(ImgprocError::encode("Failed to write image data.")
+                .format("PNG")
+                .cause(e))
+        }
     }
 
     match writer.finish() {
-        Ok(_) => Ok(Uint8Array::from(output.as_slice())),
-        Err(e) => Err(JsError::new(&format!(
-            "Failed to finalize PNG encoding: {}",
-            e
-        ))),
+        Ok(_) => Ok(output),
+        Err(e) => Err(ImgprocError::encode("Failed to finalize PNG encoding.")
+            .format("PNG")
+            .cause(e)),
     }
 }
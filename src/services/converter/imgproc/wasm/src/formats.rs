@@ -0,0 +1,826 @@
+use image::{DynamicImage, ImageError, ImageFormat, ImageReader, RgbaImage};
+use std::io::Cursor;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    animation_decoder::{
+        DecodedFrame, FrameDecoder, ImgprocApngDecoder, ImgprocFrameDecoder, ImgprocGifDecoder,
+        ImgprocStillDecoder, ImgprocWebpDecoder,
+    },
+    animation_encode::{encode_apng, encode_gif, encode_webp},
+    avif::{avif_dimensions, decode_avif, encode_avif, is_avif},
+    callback_logs::CallbackLogs,
+    cancellation::Cancellation,
+    convert::ConvertOptions,
+    detect_animation::{is_animated_apng, is_animated_gif, is_animated_webp},
+    error::{ImgprocError, Result},
+    first_frame::{
+        apng_first_frame_image, encode_png, encode_png16, gif_first_frame_image,
+        webp_first_frame_image,
+    },
+    frame_encode::encode_jpeg,
+    image_decode::{psd_dimensions, psd_rgba},
+    jxl::{decode_jxl, is_animated_jxl, is_jxl, jxl_dimensions, ImgprocJxlDecoder},
+    limits::current_limits,
+    ora::{is_kra, is_ora, kra_dimensions, kra_rgba, ora_dimensions, ora_rgba},
+    raw::{
+        developed_dimensions, developed_rgba, is_arw, is_cr2, is_dng, is_nef,
+        raw_preview_dimensions, raw_preview_rgba,
+    },
+    svg::{is_svg, svg_dimensions, svg_rgba},
+    tone_map::{to_rgba16, to_rgba8, ToneMap},
+    xcf::{decode_xcf, is_xcf, xcf_dimensions},
+};
+
+pub type SniffFn = fn(&[u8]) -> bool;
+pub type DetectFn = fn(&[u8]) -> Result<bool>;
+pub type DimensionsFn = fn(&[u8]) -> Result<(u32, u32)>;
+pub type StillDecodeFn = fn(&[u8]) -> Result<RgbaImage>;
+pub type DeepDecodeFn = fn(&[u8]) -> Result<DynamicImage>;
+pub type StillEncodeFn = fn(RgbaImage, &ConvertOptions) -> Result<Vec<u8>>;
+pub type FrameDecodeFn = fn(&[u8], &CallbackLogs, &Cancellation) -> Result<ImgprocFrameDecoder>;
+pub type FrameEncodeFn = fn(&[DecodedFrame], &CallbackLogs, Cancellation) -> Result<Vec<u8>>;
+
+/// Where a format's pixels come from or go to: the `image` crate, or our own code.
+#[derive(Clone, Copy)]
+pub enum StillDecoder {
+    Image(ImageFormat),
+    Custom(StillDecodeFn),
+    /// Our own code, returning 16-bit samples where the source has them.
+    Deep(DeepDecodeFn),
+}
+
+#[derive(Clone, Copy)]
+pub enum StillEncoder {
+    Image(ImageFormat),
+    Custom(StillEncodeFn),
+}
+
+/// Everything the crate knows how to do with one format. Operations look the format up
+/// here instead of matching on names, so `capabilities()` always describes what they do.
+pub struct FormatHandler {
+    /// Lowercase name accepted as `source_type` or `target_type`.
+    pub name: &'static str,
+    /// Other accepted names; also the file extensions.
+    pub extensions: &'static [&'static str],
+    pub mime_type: &'static str,
+    /// Recognises the format from its leading bytes where `image::guess_format` can't.
+    pub sniff: Option<SniffFn>,
+    /// Reads the size from the header where the still decoder can't do it without decoding.
+    pub dimensions: Option<DimensionsFn>,
+    pub decoder: Option<StillDecoder>,
+    pub encoder: Option<StillEncoder>,
+    pub detect_animation: Option<DetectFn>,
+    pub frame_decoder: Option<FrameDecodeFn>,
+    /// Decodes only the first frame of an animation, cheaper than the full frame decoder.
+    pub first_frame: Option<StillDecodeFn>,
+    pub frame_encoder: Option<FrameEncodeFn>,
+    /// A camera raw format whose decoder develops the sensor data. NEF and ARW are not: they
+    /// decode to the camera's embedded JPEG preview.
+    pub develop: bool,
+    /// Whether ICC profiles and EXIF data are kept when a still image is written in this
+    /// format, by `convert`, `ImageSession::convert` or a pipeline. Animations are written
+    /// without them.
+    pub metadata: bool,
+    /// Names of the `ConvertOptions` fields that affect encoding.
+    pub options: &'static [&'static str],
+    /// Why support is partial, given as the cause of unsupported errors.
+    pub note: Option<&'static str>,
+}
+
+const NONE: FormatHandler = FormatHandler {
+    name: "",
+    extensions: &[],
+    mime_type: "application/octet-stream",
+    sniff: None,
+    dimensions: None,
+    decoder: None,
+    encoder: None,
+    detect_animation: None,
+    frame_decoder: None,
+    first_frame: None,
+    frame_encoder: None,
+    develop: false,
+    metadata: false,
+    options: &[],
+    note: None,
+};
+
+/// Formats read and written by the `image` crate without any handling of our own.
+const fn image_format(
+    name: &'static str,
+    extensions: &'static [&'static str],
+    mime_type: &'static str,
+    format: ImageFormat,
+) -> FormatHandler {
+    FormatHandler {
+        name,
+        extensions,
+        mime_type,
+        decoder: Some(StillDecoder::Image(format)),
+        encoder: Some(StillEncoder::Image(format)),
+        ..NONE
+    }
+}
+
+const RAW_PREVIEW_NOTE: &str =
+    "Only the embedded JPEG preview is decoded; developing NEF and ARW sensor data is not supported.";
+
+/// Frame decoders and encoders work in 8-bit RGBA, so only stills keep deeper samples.
+const STILLS_ONLY_DEPTH_NOTE: &str =
+    "Animations are decoded and written at 8 bits per channel; 16-bit precision is kept for still images only.";
+
+static FORMATS: &[FormatHandler] = &[
+    FormatHandler {
+        name: "png",
+        extensions: &["png"],
+        mime_type: "image/png",
+        decoder: Some(StillDecoder::Image(ImageFormat::Png)),
+        encoder: Some(StillEncoder::Custom(encode_png_still)),
+        detect_animation: Some(is_animated_apng),
+        frame_decoder: Some(apng_frames),
+        first_frame: Some(apng_first_frame_image),
+        metadata: true,
+        options: &["bit_depth"],
+        note: Some(STILLS_ONLY_DEPTH_NOTE),
+        ..NONE
+    },
+    FormatHandler {
+        name: "apng",
+        extensions: &["apng"],
+        mime_type: "image/apng",
+        decoder: Some(StillDecoder::Image(ImageFormat::Png)),
+        encoder: Some(StillEncoder::Custom(encode_png_still)),
+        detect_animation: Some(is_animated_apng),
+        frame_decoder: Some(apng_frames),
+        first_frame: Some(apng_first_frame_image),
+        frame_encoder: Some(encode_apng),
+        metadata: true,
+        options: &["animated"],
+        note: Some(STILLS_ONLY_DEPTH_NOTE),
+        ..NONE
+    },
+    FormatHandler {
+        name: "gif",
+        extensions: &["gif"],
+        mime_type: "image/gif",
+        decoder: Some(StillDecoder::Image(ImageFormat::Gif)),
+        encoder: Some(StillEncoder::Image(ImageFormat::Gif)),
+        detect_animation: Some(is_animated_gif),
+        frame_decoder: Some(gif_frames),
+        first_frame: Some(gif_first_frame_image),
+        frame_encoder: Some(encode_gif),
+        options: &["animated"],
+        ..NONE
+    },
+    FormatHandler {
+        name: "webp",
+        extensions: &["webp"],
+        mime_type: "image/webp",
+        decoder: Some(StillDecoder::Image(ImageFormat::WebP)),
+        encoder: Some(StillEncoder::Image(ImageFormat::WebP)),
+        detect_animation: Some(is_animated_webp),
+        frame_decoder: Some(webp_frames),
+        first_frame: Some(webp_first_frame_image),
+        frame_encoder: Some(encode_webp),
+        metadata: true,
+        options: &["animated"],
+        ..NONE
+    },
+    FormatHandler {
+        name: "jpeg",
+        extensions: &["jpg", "jpeg", "jpe", "jfif"],
+        mime_type: "image/jpeg",
+        decoder: Some(StillDecoder::Image(ImageFormat::Jpeg)),
+        encoder: Some(StillEncoder::Custom(encode_jpeg_still)),
+        metadata: true,
+        options: &["quality"],
+        ..NONE
+    },
+    FormatHandler {
+        name: "psd",
+        extensions: &["psd", "psb"],
+        mime_type: "image/vnd.adobe.photoshop",
+        sniff: Some(|data| data.starts_with(b"8BPS")),
+        dimensions: Some(psd_header_dimensions),
+        decoder: Some(StillDecoder::Custom(psd_rgba)),
+        ..NONE
+    },
+    FormatHandler {
+        name: "ora",
+        extensions: &["ora"],
+        mime_type: "image/openraster",
+        sniff: Some(is_ora),
+        dimensions: Some(ora_dimensions),
+        decoder: Some(StillDecoder::Custom(ora_rgba)),
+        ..NONE
+    },
+    FormatHandler {
+        name: "kra",
+        extensions: &["kra"],
+        mime_type: "application/x-krita",
+        sniff: Some(is_kra),
+        dimensions: Some(kra_dimensions),
+        decoder: Some(StillDecoder::Custom(kra_rgba)),
+        ..NONE
+    },
+    FormatHandler {
+        name: "xcf",
+        extensions: &["xcf"],
+        mime_type: "image/x-xcf",
+        sniff: Some(is_xcf),
+        dimensions: Some(xcf_dimensions),
+        decoder: Some(StillDecoder::Custom(decode_xcf)),
+        ..NONE
+    },
+    FormatHandler {
+        name: "svg",
+        extensions: &["svg", "svgz"],
+        mime_type: "image/svg+xml",
+        sniff: Some(is_svg),
+        dimensions: Some(svg_dimensions),
+        decoder: Some(StillDecoder::Custom(svg_rgba)),
+        ..NONE
+    },
+    FormatHandler {
+        name: "dng",
+        extensions: &["dng"],
+        mime_type: "image/x-adobe-dng",
+        sniff: Some(is_dng),
+        dimensions: Some(developed_dimensions),
+        decoder: Some(StillDecoder::Custom(developed_rgba)),
+        develop: true,
+        ..NONE
+    },
+    FormatHandler {
+        name: "cr2",
+        extensions: &["cr2"],
+        mime_type: "image/x-canon-cr2",
+        sniff: Some(is_cr2),
+        dimensions: Some(developed_dimensions),
+        decoder: Some(StillDecoder::Custom(developed_rgba)),
+        develop: true,
+        note: Some(
+            "Sensor data is developed without a colour matrix, as CR2 stores none; sRAW files are not supported.",
+        ),
+        ..NONE
+    },
+    FormatHandler {
+        name: "nef",
+        extensions: &["nef"],
+        mime_type: "image/x-nikon-nef",
+        sniff: Some(is_nef),
+        dimensions: Some(raw_preview_dimensions),
+        decoder: Some(StillDecoder::Custom(raw_preview_rgba)),
+        note: Some(RAW_PREVIEW_NOTE),
+        ..NONE
+    },
+    FormatHandler {
+        name: "arw",
+        extensions: &["arw"],
+        mime_type: "image/x-sony-arw",
+        sniff: Some(is_arw),
+        dimensions: Some(raw_preview_dimensions),
+        decoder: Some(StillDecoder::Custom(raw_preview_rgba)),
+        note: Some(RAW_PREVIEW_NOTE),
+        ..NONE
+    },
+    FormatHandler {
+        name: "avif",
+        extensions: &["avif", "avifs"],
+        mime_type: "image/avif",
+        sniff: Some(is_avif),
+        dimensions: Some(avif_dimensions),
+        decoder: Some(StillDecoder::Deep(decode_avif)),
+        encoder: Some(StillEncoder::Custom(encode_avif)),
+        options: &["quality", "speed"],
+        note: Some("AVIF image sequences decode as their primary still image."),
+        ..NONE
+    },
+    FormatHandler {
+        name: "jxl",
+        extensions: &["jxl"],
+        mime_type: "image/jxl",
+        sniff: Some(is_jxl),
+        dimensions: Some(jxl_dimensions),
+        decoder: Some(StillDecoder::Deep(decode_jxl)),
+        detect_animation: Some(is_animated_jxl),
+        frame_decoder: Some(jxl_frames),
+        note: Some(
+            "Only JPEG files are written as JPEG XL, by lossless recompression with WasmRecompressJpeg. Progressive JPEG files that scan subsampled chroma on its own cannot be recompressed. Animations are decoded at 8 bits per channel; full precision is kept for still images only.",
+        ),
+        ..NONE
+    },
+    image_format("bmp", &["bmp", "dib"], "image/bmp", ImageFormat::Bmp),
+    image_format("tiff", &["tiff", "tif"], "image/tiff", ImageFormat::Tiff),
+    image_format("ico", &["ico"], "image/x-icon", ImageFormat::Ico),
+    image_format("tga", &["tga"], "image/x-tga", ImageFormat::Tga),
+    image_format(
+        "pnm",
+        &["pnm", "pbm", "pgm", "ppm", "pam"],
+        "image/x-portable-anymap",
+        ImageFormat::Pnm,
+    ),
+    image_format("qoi", &["qoi"], "image/x-qoi", ImageFormat::Qoi),
+    image_format(
+        "ff",
+        &["ff", "farbfeld"],
+        "image/x-farbfeld",
+        ImageFormat::Farbfeld,
+    ),
+    image_format("hdr", &["hdr"], "image/vnd.radiance", ImageFormat::Hdr),
+    image_format("exr", &["exr"], "image/x-exr", ImageFormat::OpenExr),
+];
+
+impl FormatHandler {
+    pub fn can_decode(&self) -> bool {
+        self.decoder.is_some()
+    }
+
+    pub fn can_encode(&self) -> bool {
+        self.encoder.is_some()
+    }
+
+    /// Whether every frame of an animation can be decoded, not just the first.
+    pub fn can_animate(&self) -> bool {
+        self.frame_decoder.is_some()
+    }
+
+    pub fn can_encode_animation(&self) -> bool {
+        self.frame_encoder.is_some()
+    }
+
+    /// Writes floating-point samples, so HDR sources need no tone mapping.
+    pub fn keeps_hdr(&self) -> bool {
+        matches!(
+            self.encoder,
+            Some(StillEncoder::Image(ImageFormat::OpenExr | ImageFormat::Hdr))
+        )
+    }
+
+    pub fn can_detect_animation(&self) -> bool {
+        self.detect_animation.is_some()
+    }
+
+    fn unsupported(&self, error: ImgprocError) -> ImgprocError {
+        match self.note {
+            Some(note) => error.cause(note),
+            None => error,
+        }
+    }
+
+    /// Width and height without decoding the pixels.
+    pub fn dimensions(&self, image_data: &[u8]) -> Result<(u32, u32)> {
+        if let Some(dimensions) = self.dimensions {
+            return dimensions(image_data);
+        }
+        let format = match self.decoder {
+            Some(StillDecoder::Image(format)) => format,
+            _ => return Err(self.unsupported(ImgprocError::unsupported_source(self.name))),
+        };
+        ImageReader::with_format(Cursor::new(image_data), format)
+            .into_dimensions()
+            .map_err(|e| ImgprocError::decode("Failed to read image header.", e).format(self.name))
+    }
+
+    /// False for formats that never animate.
+    pub fn is_animated(&self, image_data: &[u8]) -> Result<bool> {
+        match self.detect_animation {
+            Some(detect) => detect(image_data),
+            None => Ok(false),
+        }
+    }
+
+    /// Decodes a still image, or the first frame of an animation as the format's still
+    /// decoder sees it.
+    pub fn decode_still(&self, image_data: &[u8]) -> Result<RgbaImage> {
+        match self.decoder {
+            Some(StillDecoder::Image(format)) => decode_with_image(image_data, format, self.name),
+            Some(StillDecoder::Custom(decode)) => decode(image_data),
+            Some(StillDecoder::Deep(decode)) => Ok(decode(image_data)?.to_rgba8()),
+            None => Err(self.unsupported(ImgprocError::unsupported_source(self.name))),
+        }
+    }
+
+    /// A still image at the precision it was stored with: 16-bit or floating-point samples
+    /// where the `image` crate keeps them, 8-bit RGBA otherwise.
+    pub fn decode_deep(&self, image_data: &[u8]) -> Result<DynamicImage> {
+        match self.decoder {
+            Some(StillDecoder::Image(format)) => decode_native(image_data, format, self.name),
+            Some(StillDecoder::Deep(decode)) => decode(image_data),
+            _ => Ok(DynamicImage::ImageRgba8(self.decode_still(image_data)?)),
+        }
+    }
+
+    /// The first frame of an animation, or the whole image if it is static.
+    pub fn decode_first_frame(&self, image_data: &[u8]) -> Result<RgbaImage> {
+        match self.first_frame {
+            Some(first_frame) if self.is_animated(image_data)? => first_frame(image_data),
+            _ => self.decode_still(image_data),
+        }
+    }
+
+    /// Decodes every frame of an animation; a still image comes back as a single frame.
+    pub fn frame_decoder(
+        &self,
+        image_data: &[u8],
+        logs: &CallbackLogs,
+        cancel: &Cancellation,
+    ) -> Result<ImgprocFrameDecoder> {
+        let animated = self.frame_decoder.is_some() && self.is_animated(image_data)?;
+        self.detected_frame_decoder(image_data, animated, logs, cancel)
+    }
+
+    /// Like `frame_decoder`, for a caller that has already run animation detection.
+    pub fn detected_frame_decoder(
+        &self,
+        image_data: &[u8],
+        animated: bool,
+        logs: &CallbackLogs,
+        cancel: &Cancellation,
+    ) -> Result<ImgprocFrameDecoder> {
+        match self.frame_decoder {
+            Some(decode) if animated => decode(image_data, logs, cancel),
+            _ => Ok(ImgprocStillDecoder::decode(self, image_data, logs, cancel)?),
+        }
+    }
+
+    pub fn encode_still(&self, image: RgbaImage, options: &ConvertOptions) -> Result<Vec<u8>> {
+        match self.encoder {
+            Some(StillEncoder::Image(format)) => {
+                encode_with_image(DynamicImage::ImageRgba8(image), format, self.name)
+            }
+            Some(StillEncoder::Custom(encode)) => encode(image, options),
+            None => Err(self.unsupported(ImgprocError::unsupported_target(self.name))),
+        }
+    }
+
+    /// Encodes a still image with more than 8 bits per channel, keeping what the target can
+    /// hold: floating point for EXR and Radiance HDR, 16 bits for TIFF, farbfeld and, when
+    /// `options` asks for it, PNG. Other targets get 8 bits, HDR sources being tone mapped.
+    pub fn encode_deep(&self, image: DynamicImage, options: &ConvertOptions) -> Result<Vec<u8>> {
+        let (tone_map, exposure) = (options.tone_map(), options.exposure());
+        match (self.name, self.encoder) {
+            ("png", _) if options.bit_depth() == 16 => {
+                encode_png16(&to_rgba16(&image, tone_map, exposure))
+            }
+            ("png", _) if options.bit_depth() != 8 => Err(ImgprocError::invalid_argument(format!(
+                "PNG bit depth must be 8 or 16, not {}.",
+                options.bit_depth()
+            ))),
+            (_, Some(StillEncoder::Image(format @ (ImageFormat::OpenExr | ImageFormat::Hdr)))) => {
+                encode_with_image(image, format, self.name)
+            }
+            (
+                _,
+                Some(StillEncoder::Image(format @ (ImageFormat::Tiff | ImageFormat::Farbfeld))),
+            ) => {
+                let image = DynamicImage::ImageRgba16(to_rgba16(&image, tone_map, exposure));
+                encode_with_image(image, format, self.name)
+            }
+            _ => self.encode_still(to_rgba8(&image, tone_map, exposure), options),
+        }
+    }
+
+    pub fn encode_frames(
+        &self,
+        frames: &[DecodedFrame],
+        logs: &CallbackLogs,
+        cancel: Cancellation,
+    ) -> Result<Vec<u8>> {
+        match self.frame_encoder {
+            Some(encode) => encode(frames, logs, cancel),
+            None => Err(ImgprocError::unsupported_target(self.name)),
+        }
+    }
+}
+
+pub fn formats() -> &'static [FormatHandler] {
+    FORMATS
+}
+
+/// Looks a format up by name or extension, ignoring case and a leading dot.
+pub fn find_format(name: &str) -> Option<&'static FormatHandler> {
+    let name = name.trim_start_matches('.').to_lowercase();
+    FORMATS
+        .iter()
+        .find(|handler| handler.name == name || handler.extensions.contains(&name.as_str()))
+}
+
+pub fn source_format(source_type: &str) -> Result<&'static FormatHandler> {
+    find_format(source_type).ok_or_else(|| ImgprocError::unsupported_source(source_type))
+}
+
+pub fn target_format(target_type: &str) -> Result<&'static FormatHandler> {
+    find_format(target_type).ok_or_else(|| ImgprocError::unsupported_target(target_type))
+}
+
+/// Guesses the format from the leading bytes.
+pub fn sniff_handler(image_data: &[u8]) -> Option<&'static FormatHandler> {
+    let sniffed = FORMATS
+        .iter()
+        .find(|handler| handler.sniff.is_some_and(|sniff| sniff(image_data)));
+    if sniffed.is_some() {
+        return sniffed;
+    }
+
+    let format = image::guess_format(image_data).ok()?;
+    FORMATS
+        .iter()
+        .find(|handler| matches!(handler.decoder, Some(StillDecoder::Image(f)) if f == format))
+}
+
+fn apng_frames(
+    image_data: &[u8],
+    logs: &CallbackLogs,
+    cancel: &Cancellation,
+) -> Result<ImgprocFrameDecoder> {
+    Ok(ImgprocApngDecoder::new(image_data, logs, cancel)?)
+}
+
+fn gif_frames(
+    image_data: &[u8],
+    logs: &CallbackLogs,
+    cancel: &Cancellation,
+) -> Result<ImgprocFrameDecoder> {
+    Ok(ImgprocGifDecoder::new(image_data, logs, cancel)?)
+}
+
+fn webp_frames(
+    image_data: &[u8],
+    logs: &CallbackLogs,
+    cancel: &Cancellation,
+) -> Result<ImgprocFrameDecoder> {
+    Ok(ImgprocWebpDecoder::new(image_data, logs, cancel)?)
+}
+
+fn jxl_frames(
+    image_data: &[u8],
+    logs: &CallbackLogs,
+    cancel: &Cancellation,
+) -> Result<ImgprocFrameDecoder> {
+    Ok(ImgprocJxlDecoder::new(image_data, logs, cancel)?)
+}
+
+fn psd_header_dimensions(image_data: &[u8]) -> Result<(u32, u32)> {
+    psd_dimensions(image_data)
+        .ok_or_else(|| ImgprocError::corrupt("The PSD header is truncated.").format("PSD"))
+}
+
+fn encode_png_still(image: RgbaImage, options: &ConvertOptions) -> Result<Vec<u8>> {
+    match options.bit_depth() {
+        16 => encode_png16(&DynamicImage::ImageRgba8(image).to_rgba16()),
+        _ => encode_png(image),
+    }
+}
+
+fn encode_jpeg_still(image: RgbaImage, options: &ConvertOptions) -> Result<Vec<u8>> {
+    encode_jpeg(&image, options.quality())
+}
+
+/// Decodes through the `image` crate to 8-bit RGBA, tone mapping HDR images with the
+/// default operator.
+pub(crate) fn decode_with_image(
+    image_data: &[u8],
+    format: ImageFormat,
+    name: &str,
+) -> Result<RgbaImage> {
+    let image = decode_native(image_data, format, name)?;
+    Ok(to_rgba8(&image, ToneMap::default(), 0.0))
+}
+
+/// Decodes through the `image` crate, checking the header against the decode limits first.
+fn decode_native(image_data: &[u8], format: ImageFormat, name: &str) -> Result<DynamicImage> {
+    let failed = |e: ImageError| {
+        ImgprocError::decode(format!("Failed to decode {} image.", name), e).format(name)
+    };
+
+    let limits = current_limits();
+    let (width, height) = ImageReader::with_format(Cursor::new(image_data), format)
+        .into_dimensions()
+        .map_err(failed)?;
+    limits.check_dimensions(width, height, name)?;
+
+    let mut reader = ImageReader::with_format(Cursor::new(image_data), format);
+    reader.limits(limits.image_limits());
+    reader.decode().map_err(failed)
+}
+
+/// Encodes through the `image` crate, converting to the sample type the encoder needs.
+fn encode_with_image(image: DynamicImage, format: ImageFormat, name: &str) -> Result<Vec<u8>> {
+    let image = match format {
+        ImageFormat::Hdr => DynamicImage::ImageRgb32F(image.to_rgb32f()),
+        ImageFormat::OpenExr => DynamicImage::ImageRgba32F(image.to_rgba32f()),
+        ImageFormat::Farbfeld => DynamicImage::ImageRgba16(image.to_rgba16()),
+        _ => image,
+    };
+
+    let mut output = Cursor::new(Vec::new());
+    image
+        .write_to(&mut output, format)
+        .map_err(|e| ImgprocError::decode("Failed to encode image.", e).format(name))?;
+    Ok(output.into_inner())
+}
+
+/// What one format supports, as reported by `WasmCapabilities`.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub struct FormatCapabilities {
+    name: String,
+    extensions: Vec<String>,
+    mime_type: String,
+    decode: bool,
+    encode: bool,
+    animate: bool,
+    encode_animation: bool,
+    detect_animation: bool,
+    develop: bool,
+    metadata: bool,
+    options: Vec<String>,
+    note: Option<String>,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl FormatCapabilities {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn extensions(&self) -> Vec<String> {
+        self.extensions.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn mime_type(&self) -> String {
+        self.mime_type.clone()
+    }
+
+    /// Can be read as a still image, or its first frame.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn decode(&self) -> bool {
+        self.decode
+    }
+
+    /// Can be written as a still image.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn encode(&self) -> bool {
+        self.encode
+    }
+
+    /// Every frame of an animation can be read.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn animate(&self) -> bool {
+        self.animate
+    }
+
+    /// Animations can be written.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn encode_animation(&self) -> bool {
+        self.encode_animation
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn detect_animation(&self) -> bool {
+        self.detect_animation
+    }
+
+    /// Camera raw sensor data is developed. Raw formats without it, NEF and ARW, decode to
+    /// the camera's embedded JPEG preview.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn develop(&self) -> bool {
+        self.develop
+    }
+
+    /// ICC profiles and EXIF data are kept when a still image is written. Animations are
+    /// written without them.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn metadata(&self) -> bool {
+        self.metadata
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn options(&self) -> Vec<String> {
+        self.options.clone()
+    }
+
+    /// Why support is partial, if it is.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn note(&self) -> Option<String> {
+        self.note.clone()
+    }
+}
+
+impl From<&FormatHandler> for FormatCapabilities {
+    fn from(handler: &FormatHandler) -> Self {
+        let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+        FormatCapabilities {
+            name: handler.name.to_string(),
+            extensions: strings(handler.extensions),
+            mime_type: handler.mime_type.to_string(),
+            decode: handler.can_decode(),
+            encode: handler.can_encode(),
+            animate: handler.can_animate(),
+            encode_animation: handler.can_encode_animation(),
+            detect_animation: handler.can_detect_animation(),
+            develop: handler.develop,
+            metadata: handler.metadata,
+            options: strings(handler.options),
+            note: handler.note.map(str::to_string),
+        }
+    }
+}
+
+pub fn capabilities() -> Vec<FormatCapabilities> {
+    FORMATS.iter().map(FormatCapabilities::from).collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        cancellation::never_cancelled, detect_animation::is_animated, first_frame::first_frame,
+        image_decode::decode_static_image, image_frames, probe::probe, svg::SvgOptions,
+    };
+    use image::{codecs::webp::WebPEncoder, ImageEncoder, Rgba};
+    use std::panic::{self, AssertUnwindSafe};
+
+    /// Runs every decoding entry point on each prefix of `data`, a valid `format` file.
+    /// A cut file may decode or fail, but never panic.
+    pub(crate) fn decode_truncations(data: &[u8], format: &str) {
+        let (logs, cancel) = (CallbackLogs::silent(), never_cancelled());
+        let svg = SvgOptions::default();
+        image_frames::decode_frames(data, format, &logs, &cancel).unwrap();
+
+        for len in 0..data.len() {
+            let cut = &data[..len];
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                let _ = is_animated(cut, format);
+                let _ = probe(cut, "");
+                let _ = probe(cut, format);
+                let _ = image_frames::decode_frames(cut, format, &logs, &cancel);
+                let _ = image_frames::decode_deep(cut, format, &logs, &cancel);
+                let _ = first_frame(cut, format);
+                let _ = decode_static_image(cut, format, &svg, &logs);
+            }))
+            .unwrap_or_else(|_| panic!("{} cut to {} bytes panicked", format, len));
+        }
+
+        let empty = image_frames::decode_frames(&[], format, &logs, &cancel);
+        assert!(empty.is_err(), "{}", format);
+    }
+
+    fn frames(count: u8) -> Vec<DecodedFrame> {
+        (0..count)
+            .map(|i| {
+                let image = RgbaImage::from_fn(6, 4, |x, y| {
+                    Rgba([(x * 40) as u8, (y * 60) as u8, i * 50, 255 - i * 20])
+                });
+                (image, 100)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn truncated_input_fails_without_panicking() {
+        let logs = CallbackLogs::silent();
+        let still = frames(1);
+        let mut webp = Vec::new();
+        WebPEncoder::new_lossless(&mut webp)
+            .write_image(still[0].0.as_raw(), 6, 4, image::ExtendedColorType::Rgba8)
+            .unwrap();
+
+        let samples = [
+            (
+                encode_apng(&still, &logs, never_cancelled()).unwrap(),
+                "png",
+            ),
+            (
+                encode_apng(&frames(3), &logs, never_cancelled()).unwrap(),
+                "apng",
+            ),
+            (encode_gif(&still, &logs, never_cancelled()).unwrap(), "gif"),
+            (
+                encode_gif(&frames(3), &logs, never_cancelled()).unwrap(),
+                "gif",
+            ),
+            (webp, "webp"),
+            (
+                encode_webp(&frames(3), &logs, never_cancelled()).unwrap(),
+                "webp",
+            ),
+            (encode_jpeg(&still[0].0, 90).unwrap(), "jpeg"),
+            (
+                b"<svg xmlns='http://www.w3.org/2000/svg' width='6' height='4'>\
+                  <rect width='3' height='4' fill='red'/></svg>"
+                    .to_vec(),
+                "svg",
+            ),
+        ];
+        for (data, format) in samples {
+            decode_truncations(&data, format);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use gif::{DisposalMethod, Repeat};
+use image::{codecs::jpeg::JpegEncoder, Rgb, RgbImage, RgbaImage};
+use png::{BitDepth, ColorType, Encoder};
+use std::borrow::Cow;
+
+use crate::{
+    animation_decoder::DecodedFrame,
+    error::{ErrorCode, ImgprocError, Result},
+    quantize::Palette,
+};
+
+/// Composites the image over white, since JPEG has no alpha channel.
+pub fn flatten_on_white(image: &RgbaImage) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let p = image.get_pixel(x, y);
+        let alpha = p[3] as u32;
+        let over_white = |c: u8| ((c as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8;
+        Rgb([over_white(p[0]), over_white(p[1]), over_white(p[2])])
+    })
+}
+
+pub fn encode_jpeg(image: &RgbaImage, quality: u8) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    JpegEncoder::new_with_quality(&mut output, quality.clamp(1, 100))
+        .encode_image(&flatten_on_white(image))
+        .map_err(|e| {
+            ImgprocError::encode("Failed to encode JPEG.")
+                .format("JPEG")
+                .cause(e)
+        })?;
+    Ok(output)
+}
+
+/// Writes RGBA frames losslessly as PNG, or as APNG when there is more than one frame.
+pub fn encode_rgba_png(frames: &[DecodedFrame]) -> Result<Vec<u8>> {
+    let (width, height) = frames
+        .first()
+        .map(|(image, _)| image.dimensions())
+        .ok_or_else(|| ImgprocError::invalid_argument("Cannot encode an image without frames."))?;
+
+    let mut output = Vec::new();
+    let mut encoder = Encoder::new(&mut output, width, height);
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    encoder.set_compression(png::Compression::Best);
+
+    if frames.len() > 1 {
+        encoder.set_animated(frames.len() as u32, 0).map_err(|e| {
+            ImgprocError::encode("Failed to set animation parameters.")
+                .format("PNG")
+                .cause(e)
+        })?;
+    }
+
+    let mut writer = encoder.write_header().map_err(|e| {
+        ImgprocError::encode("Failed to write PNG header.")
+            .format("PNG")
+            .cause(e)
+    })?;
+
+    for (image, delay_ms) in frames {
+        if frames.len() > 1 {
+            writer
+                .set_frame_delay((*delay_ms).min(u16::MAX as u32) as u16, 1000)
+                .map_err(|e| {
+                    ImgprocError::encode("Failed to set frame delay.")
+                        .format("PNG")
+                        .cause(e)
+                })?;
+        }
+        writer.write_image_data(image.as_raw()).map_err(|e| {
+            ImgprocError::encode("Failed to write frame data.")
+                .format("PNG")
+                .cause(e)
+        })?;
+    }
+
+    writer.finish().map_err(|e| {
+        ImgprocError::encode("Failed to finalize PNG encoding.")
+            .format("PNG")
+            .cause(e)
+    })?;
+    Ok(output)
+}
+
+/// Writes palette-indexed frames as a looping GIF. Delays are in milliseconds.
+pub fn encode_indexed_gif(
+    width: u32,
+    height: u32,
+    palette: &Palette,
+    frames: &[Vec<u8>],
+    delays: &[u32],
+) -> Result<Vec<u8>> {
+    let (width, height) = gif_dimensions(width, height)?;
+
+    let mut output = Vec::new();
+    let mut encoder =
+        gif::Encoder::new(&mut output, width, height, &palette.rgb()).map_err(|e| {
+            ImgprocError::encode("Failed to create GIF encoder.")
+                .format("GIF")
+                .cause(e)
+        })?;
+    encoder.set_repeat(Repeat::Infinite).map_err(|e| {
+        ImgprocError::encode("Failed to set GIF repeat.")
+            .format("GIF")
+            .cause(e)
+    })?;
+
+    for (indices, delay_ms) in frames.iter().zip(delays) {
+        let frame = gif::Frame {
+            width,
+            height,
+            delay: ((delay_ms + 5) / 10).min(u16::MAX as u32) as u16,
+            dispose: DisposalMethod::Background,
+            transparent: palette.transparent,
+            buffer: Cow::Borrowed(indices),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame).map_err(|e| {
+            ImgprocError::encode("Failed to encode a GIF frame.")
+                .format("GIF")
+                .cause(e)
+        })?;
+    }
+
+    drop(encoder);
+    Ok(output)
+}
+
+fn gif_dimensions(width: u32, height: u32) -> Result<(u16, u16)> {
+    match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(ImgprocError::new(
+            ErrorCode::UnsupportedFormat,
+            format!("Image is too large for GIF: {}x{}", width, height),
+        )
+        .format("GIF")),
+    }
+}
+
+/// Writes palette-indexed frames as PNG, or as APNG when there is more than one frame.
+/// The bit depth is the smallest that holds the palette.
+pub fn encode_indexed_png(
+    width: u32,
+    height: u32,
+    palette: &Palette,
+    frames: &[Vec<u8>],
+    delays: &[u32],
+) -> Result<Vec<u8>> {
+    let bit_depth = match palette.colors.len() {
+        0..=2 => BitDepth::One,
+        3..=4 => BitDepth::Two,
+        5..=16 => BitDepth::Four,
+        _ => BitDepth::Eight,
+    };
+
+    let mut output = Vec::new();
+    let mut encoder = Encoder::new(&mut output, width, height);
+    encoder.set_color(ColorType::Indexed);
+    encoder.set_depth(bit_depth);
+    encoder.set_compression(png::Compression::Best);
+    encoder.set_palette(palette.rgb());
+    if palette.has_alpha() {
+        encoder.set_trns(palette.alpha());
+    }
+
+    if frames.len() > 1 {
+        encoder.set_animated(frames.len() as u32, 0).map_err(|e| {
+            ImgprocError::encode("Failed to set animation parameters.")
+                .format("PNG")
+                .cause(e)
+        })?;
+    }
+
+    let mut writer = encoder.write_header().map_err(|e| {
+        ImgprocError::encode("Failed to write PNG header.")
+            .format("PNG")
+            .cause(e)
+    })?;
+
+    for (indices, delay_ms) in frames.iter().zip(delays) {
+        if frames.len() > 1 {
+            writer
+                .set_frame_delay((*delay_ms).min(u16::MAX as u32) as u16, 1000)
+                .map_err(|e| {
+                    ImgprocError::encode("Failed to set frame delay.")
+                        .format("PNG")
+                        .cause(e)
+                })?;
+        }
+        writer
+            .write_image_data(&pack_indices(indices, width as usize, bit_depth))
+            .map_err(|e| {
+                ImgprocError::encode("Failed to write frame data.")
+                    .format("PNG")
+                    .cause(e)
+            })?;
+    }
+
+    writer.finish().map_err(|e| {
+        ImgprocError::encode("Failed to finalize PNG encoding.")
+            .format("PNG")
+            .cause(e)
+    })?;
+    Ok(output)
+}
+
+/// Packs one index per byte into rows of `bit_depth` bits per pixel.
+fn pack_indices(indices: &[u8], width: usize, bit_depth: BitDepth) -> Vec<u8> {
+    let bits = bit_depth as usize;
+    if bits == 8 {
+        return indices.to_vec();
+    }
+
+    let per_byte = 8 / bits;
+    let mut packed = Vec::with_capacity(indices.len() / per_byte + 1);
+    for row in indices.chunks(width) {
+        for group in row.chunks(per_byte) {
+            let byte = group
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (i, index)| byte | index << (8 - bits * (i + 1)));
+            packed.push(byte);
+        }
+    }
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        callback_logs::CallbackLogs,
+        cancellation::never_cancelled,
+        image_frames::decode_frames,
+        quantize::{quantize_frames, QuantizeOptions},
+    };
+    use image::Rgba;
+
+    fn frames() -> Vec<DecodedFrame> {
+        [[255, 0, 0, 255], [0, 0, 255, 0]]
+            .into_iter()
+            .map(|color| (RgbaImage::from_pixel(5, 3, Rgba(color)), 70_000))
+            .collect()
+    }
+
+    fn decode(data: &[u8], format: &str) -> Vec<DecodedFrame> {
+        decode_frames(data, format, &CallbackLogs::silent(), &never_cancelled()).unwrap()
+    }
+
+    fn quantize(frames: &[DecodedFrame], binary_alpha: bool) -> (Palette, Vec<Vec<u8>>) {
+        let images: Vec<&RgbaImage> = frames.iter().map(|(image, _)| image).collect();
+        let options = QuantizeOptions {
+            colors: 4,
+            dithering: false,
+            lossiness: 0,
+            binary_alpha,
+        };
+        quantize_frames(&images, options)
+    }
+
+    #[test]
+    fn flattens_transparency_onto_white() {
+        let image = RgbaImage::from_fn(2, 1, |x, _| Rgba([0, 0, 0, if x == 0 { 0 } else { 255 }]));
+        let flat = flatten_on_white(&image);
+        assert_eq!(flat.get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(flat.get_pixel(1, 0).0, [0, 0, 0]);
+    }
+
+    #[test]
+    fn rgba_png_round_trips_frames_and_clamps_delays() {
+        let frames = frames();
+        let decoded = decode(&encode_rgba_png(&frames).unwrap(), "png");
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].0, frames[0].0);
+        assert_eq!(decoded[1].0, frames[1].0);
+        assert_eq!(decoded[0].1, u16::MAX as u32);
+        assert!(encode_rgba_png(&[]).is_err());
+    }
+
+    #[test]
+    fn indexed_png_packs_small_palettes() {
+        let frames = frames();
+        let (palette, indices) = quantize(&frames, false);
+        let delays = [100, 100];
+        let data = encode_indexed_png(5, 3, &palette, &indices, &delays).unwrap();
+        let decoded = decode(&data, "png");
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].0, frames[0].0);
+        assert_eq!(decoded[1].0, frames[1].0);
+
+        // Two bits per index; the odd fifth pixel of each row fills its own byte.
+        assert_eq!(
+            pack_indices(&[1, 2, 3, 0, 1, 3, 3, 3, 3, 3], 5, BitDepth::Two),
+            vec![0b0110_1100, 0b0100_0000, 0xff, 0b1100_0000]
+        );
+    }
+
+    #[test]
+    fn indexed_gif_keeps_transparency_and_rejects_large_images() {
+        let frames = frames();
+        let (palette, indices) = quantize(&frames, true);
+        let data = encode_indexed_gif(5, 3, &palette, &indices, &[100, 100]).unwrap();
+        let decoded = decode(&data, "gif");
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].0.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(decoded[1].0.get_pixel(0, 0)[3], 0);
+        assert_eq!(decoded[0].1, 100);
+
+        let error = encode_indexed_gif(70_000, 1, &palette, &[], &[])
+            .err()
+            .unwrap();
+        assert_eq!(error.code, ErrorCode::UnsupportedFormat);
+    }
+}
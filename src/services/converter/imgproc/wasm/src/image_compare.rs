@@ -0,0 +1,477 @@
+use image::{ImageBuffer, Luma, Rgba, RgbaImage};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    animation_decoder::DecodedFrame,
+    error::{ImgprocError, Result},
+    first_frame::encode_png,
+};
+
+const SSIM_WINDOW_RADIUS: i64 = 5;
+const SSIM_SIGMA: f32 = 1.5;
+const SSIM_C1: f32 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f32 = (0.03 * 255.0) * (0.03 * 255.0);
+const MS_SSIM_WEIGHTS: [f32; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+/// The smallest side a scale may have before MS-SSIM stops downsampling.
+const MS_SSIM_MIN_SIZE: u32 = 2 * SSIM_WINDOW_RADIUS as u32 + 1;
+/// Channel difference at which the heatmap saturates to white.
+const HEATMAP_SATURATION: f32 = 64.0;
+
+type LumaPlane = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Quality metrics of one aligned frame pair.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameComparison {
+    pub mse: f64,
+    pub psnr: f64,
+    pub ssim: f64,
+    pub ms_ssim: f64,
+    pub max_error: u8,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct ImageComparison {
+    frames: Vec<FrameComparison>,
+    psnr: f64,
+    ssim: f64,
+    ms_ssim: f64,
+    max_error: u8,
+    timeline_mismatch: bool,
+    heatmap: Option<Vec<u8>>,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl ImageComparison {
+    /// PSNR in dB over all compared pixels; `Infinity` when the images are identical.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn psnr(&self) -> f64 {
+        self.psnr
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn ssim(&self) -> f64 {
+        self.ssim
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn ms_ssim(&self) -> f64 {
+        self.ms_ssim
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn max_error(&self) -> u8 {
+        self.max_error
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn num_frames(&self) -> u32 {
+        self.frames.len() as u32
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn frame_psnr(&self) -> Vec<f64> {
+        self.frames.iter().map(|f| f.psnr).collect()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn frame_ssim(&self) -> Vec<f64> {
+        self.frames.iter().map(|f| f.ssim).collect()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn frame_ms_ssim(&self) -> Vec<f64> {
+        self.frames.iter().map(|f| f.ms_ssim).collect()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn frame_max_error(&self) -> Vec<u8> {
+        self.frames.iter().map(|f| f.max_error).collect()
+    }
+
+    /// Whether the animations differ in length: in total duration when both are timed, in
+    /// frame count otherwise. The tail of the longer one is scored against the last frame of
+    /// the shorter one.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn timeline_mismatch(&self) -> bool {
+        self.timeline_mismatch
+    }
+
+    /// A PNG of the per-pixel differences of the worst frame, if one was requested.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn heatmap(&self) -> Option<Vec<u8>> {
+        self.heatmap.clone()
+    }
+}
+
+pub fn compare_images(
+    a: &[DecodedFrame],
+    b: &[DecodedFrame],
+    with_heatmap: bool,
+) -> Result<ImageComparison> {
+    let Alignment {
+        pairs,
+        timeline_mismatch,
+    } = align_frames(a, b)?;
+
+    let frames: Vec<FrameComparison> = pairs.iter().map(|(a, b)| compare_frame(a, b)).collect();
+
+    let count = frames.len() as f64;
+    let mse = frames.iter().map(|f| f.mse).sum::<f64>() / count;
+
+    let heatmap = if with_heatmap {
+        let worst = frames
+            .iter()
+            .enumerate()
+            .max_by(|(_, x), (_, y)| x.mse.total_cmp(&y.mse))
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+        let (a, b) = pairs[worst];
+        Some(encode_png(difference_heatmap(a, b))?)
+    } else {
+        None
+    };
+
+    Ok(ImageComparison {
+        psnr: psnr(mse),
+        ssim: frames.iter().map(|f| f.ssim).sum::<f64>() / count,
+        ms_ssim: frames.iter().map(|f| f.ms_ssim).sum::<f64>() / count,
+        max_error: frames.iter().map(|f| f.max_error).max().unwrap_or(0),
+        frames,
+        timeline_mismatch,
+        heatmap,
+    })
+}
+
+/// The frame pairs of two animations, and whether their lengths differ.
+pub struct Alignment<'a> {
+    pub pairs: Vec<(&'a RgbaImage, &'a RgbaImage)>,
+    pub timeline_mismatch: bool,
+}
+
+/// Pairs the frames shown at the same time in both animations, so that every frame of either
+/// is compared with what the other shows alongside it. Without timing information frames pair
+/// by index. When one animation ends first, its last frame stays on screen against the rest of
+/// the other, which penalises the difference in length rather than ignoring it.
+pub fn align_frames<'a>(a: &'a [DecodedFrame], b: &'a [DecodedFrame]) -> Result<Alignment<'a>> {
+    if a.is_empty() || b.is_empty() {
+        return Err(ImgprocError::invalid_argument(
+            "Cannot compare an image without frames.",
+        ));
+    }
+
+    let (a_size, b_size) = (a[0].0.dimensions(), b[0].0.dimensions());
+    if a_size != b_size {
+        return Err(ImgprocError::invalid_argument(format!(
+            "Cannot compare images of different sizes: {}x{} and {}x{}",
+            a_size.0, a_size.1, b_size.0, b_size.1
+        )));
+    }
+
+    let timed = |frames: &[DecodedFrame]| frames.iter().any(|(_, delay)| *delay > 0);
+    if !timed(a) || !timed(b) {
+        let pairs = (0..a.len().max(b.len()))
+            .map(|i| (&a[i.min(a.len() - 1)].0, &b[i.min(b.len() - 1)].0))
+            .collect();
+        return Ok(Alignment {
+            pairs,
+            timeline_mismatch: a.len() != b.len(),
+        });
+    }
+
+    // Walk both timelines, stepping whichever frame ends first, or both when they end together.
+    let mut pairs = Vec::with_capacity(a.len() + b.len());
+    let (mut a_index, mut a_end) = (0, a[0].1 as u64);
+    let (mut b_index, mut b_end) = (0, b[0].1 as u64);
+    loop {
+        pairs.push((&a[a_index].0, &b[b_index].0));
+        let (a_more, b_more) = (a_index + 1 < a.len(), b_index + 1 < b.len());
+        if !a_more && !b_more {
+            break;
+        }
+        let step_a = a_more && (!b_more || a_end <= b_end);
+        let step_b = b_more && (!a_more || b_end <= a_end);
+        if step_a {
+            a_index += 1;
+            a_end += a[a_index].1 as u64;
+        }
+        if step_b {
+            b_index += 1;
+            b_end += b[b_index].1 as u64;
+        }
+    }
+    Ok(Alignment {
+        pairs,
+        timeline_mismatch: a_end != b_end,
+    })
+}
+
+pub fn compare_frame(a: &RgbaImage, b: &RgbaImage) -> FrameComparison {
+    let mut squared_error = 0u64;
+    let mut max_error = 0u8;
+    for (p, q) in a.pixels().zip(b.pixels()) {
+        for channel in 0..4 {
+            let difference = channel_difference(p, q, channel);
+            squared_error += difference as u64 * difference as u64;
+            max_error = max_error.max(difference);
+        }
+    }
+
+    let samples = (a.width() as u64 * a.height() as u64 * 4).max(1);
+    let mse = squared_error as f64 / samples as f64;
+
+    let (a_luma, b_luma) = (luma_plane(a), luma_plane(b));
+    FrameComparison {
+        mse,
+        psnr: psnr(mse),
+        ssim: ssim(&a_luma, &b_luma).0,
+        ms_ssim: ms_ssim(a_luma, b_luma),
+        max_error,
+    }
+}
+
+/// Colour differences under fully transparent pixels are invisible and not counted.
+fn channel_difference(p: &Rgba<u8>, q: &Rgba<u8>, channel: usize) -> u8 {
+    if channel < 3 && p[3] == 0 && q[3] == 0 {
+        return 0;
+    }
+    p[channel].abs_diff(q[channel])
+}
+
+fn psnr(mse: f64) -> f64 {
+    if mse == 0.0 {
+        return f64::INFINITY;
+    }
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// Luminance after compositing over white, which is how the browser shows transparent images.
+fn luma_plane(image: &RgbaImage) -> LumaPlane {
+    LumaPlane::from_fn(image.width(), image.height(), |x, y| {
+        let p = image.get_pixel(x, y);
+        let alpha = p[3] as f32 / 255.0;
+        let over_white = |c: u8| c as f32 * alpha + 255.0 * (1.0 - alpha);
+        Luma([0.299 * over_white(p[0]) + 0.587 * over_white(p[1]) + 0.114 * over_white(p[2])])
+    })
+}
+
+fn gaussian_kernel() -> Vec<f32> {
+    let kernel: Vec<f32> = (-SSIM_WINDOW_RADIUS..=SSIM_WINDOW_RADIUS)
+        .map(|i| (-(i * i) as f32 / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.into_iter().map(|k| k / sum).collect()
+}
+
+/// Separable Gaussian blur with edge clamping.
+fn blur(plane: &[f32], width: usize, height: usize, kernel: &[f32]) -> Vec<f32> {
+    let radius = SSIM_WINDOW_RADIUS;
+    let clamp = |v: i64, max: usize| v.clamp(0, max as i64 - 1) as usize;
+
+    let mut horizontal = vec![0f32; plane.len()];
+    for y in 0..height {
+        for x in 0..width {
+            horizontal[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| {
+                    weight * plane[y * width + clamp(x as i64 + k as i64 - radius, width)]
+                })
+                .sum();
+        }
+    }
+
+    let mut output = vec![0f32; plane.len()];
+    for y in 0..height {
+        for x in 0..width {
+            output[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| {
+                    weight * horizontal[clamp(y as i64 + k as i64 - radius, height) * width + x]
+                })
+                .sum();
+        }
+    }
+    output
+}
+
+/// Returns the mean SSIM and the mean contrast-structure term used by MS-SSIM.
+fn ssim(a: &LumaPlane, b: &LumaPlane) -> (f64, f64) {
+    let (width, height) = (a.width() as usize, a.height() as usize);
+    let kernel = gaussian_kernel();
+    let (x, y) = (a.as_raw(), b.as_raw());
+
+    let product =
+        |p: &[f32], q: &[f32]| -> Vec<f32> { p.iter().zip(q).map(|(p, q)| p * q).collect() };
+    let mu_x = blur(x, width, height, &kernel);
+    let mu_y = blur(y, width, height, &kernel);
+    let xx = blur(&product(x, x), width, height, &kernel);
+    let yy = blur(&product(y, y), width, height, &kernel);
+    let xy = blur(&product(x, y), width, height, &kernel);
+
+    let (mut ssim_sum, mut cs_sum) = (0f64, 0f64);
+    for i in 0..x.len() {
+        let sigma_x = xx[i] - mu_x[i] * mu_x[i];
+        let sigma_y = yy[i] - mu_y[i] * mu_y[i];
+        let sigma_xy = xy[i] - mu_x[i] * mu_y[i];
+
+        let luminance =
+            (2.0 * mu_x[i] * mu_y[i] + SSIM_C1) / (mu_x[i] * mu_x[i] + mu_y[i] * mu_y[i] + SSIM_C1);
+        let contrast_structure = (2.0 * sigma_xy + SSIM_C2) / (sigma_x + sigma_y + SSIM_C2);
+
+        ssim_sum += (luminance * contrast_structure) as f64;
+        cs_sum += contrast_structure as f64;
+    }
+
+    let count = x.len().max(1) as f64;
+    (ssim_sum / count, cs_sum / count)
+}
+
+fn downsample(plane: &LumaPlane) -> LumaPlane {
+    let (width, height) = ((plane.width() / 2).max(1), (plane.height() / 2).max(1));
+    LumaPlane::from_fn(width, height, |x, y| {
+        let sample = |dx: u32, dy: u32| {
+            let sx = (2 * x + dx).min(plane.width() - 1);
+            let sy = (2 * y + dy).min(plane.height() - 1);
+            plane.get_pixel(sx, sy)[0]
+        };
+        Luma([(sample(0, 0) + sample(1, 0) + sample(0, 1) + sample(1, 1)) / 4.0])
+    })
+}
+
+/// MS-SSIM with the weights of Wang et al.; small images use fewer scales with renormalised weights.
+fn ms_ssim(mut a: LumaPlane, mut b: LumaPlane) -> f64 {
+    let mut scales = 1;
+    let mut side = a.width().min(a.height());
+    while scales < MS_SSIM_WEIGHTS.len() && side / 2 >= MS_SSIM_MIN_SIZE {
+        scales += 1;
+        side /= 2;
+    }
+
+    let weights = &MS_SSIM_WEIGHTS[..scales];
+    let total: f32 = weights.iter().sum();
+
+    let mut result = 1f64;
+    for (scale, weight) in weights.iter().enumerate() {
+        let weight = (weight / total) as f64;
+        let (ssim, cs) = ssim(&a, &b);
+        if scale + 1 == scales {
+            result *= ssim.max(0.0).powf(weight);
+        } else {
+            result *= cs.max(0.0).powf(weight);
+            a = downsample(&a);
+            b = downsample(&b);
+        }
+    }
+    result
+}
+
+/// Maps the largest channel difference of each pixel onto a black-red-yellow-white ramp.
+pub fn difference_heatmap(a: &RgbaImage, b: &RgbaImage) -> RgbaImage {
+    RgbaImage::from_fn(a.width(), a.height(), |x, y| {
+        let (p, q) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        let difference = (0..4)
+            .map(|channel| channel_difference(p, q, channel))
+            .max()
+            .unwrap_or(0) as f32
+            / HEATMAP_SATURATION;
+
+        let ramp = |start: f32| ((difference * 3.0 - start).clamp(0.0, 1.0) * 255.0) as u8;
+        Rgba([ramp(0.0), ramp(1.0), ramp(2.0), 255])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([(x * 8) as u8, (y * 8) as u8, ((x + y) * 4) as u8, 255])
+        })
+    }
+
+    fn still(image: RgbaImage) -> Vec<DecodedFrame> {
+        vec![(image, 0)]
+    }
+
+    fn flat(value: u8) -> RgbaImage {
+        RgbaImage::from_pixel(16, 16, Rgba([value, value, value, 255]))
+    }
+
+    #[test]
+    fn identical_images_are_a_perfect_match() {
+        let image = still(gradient(32, 32));
+        let result = compare_images(&image, &image, true).unwrap();
+        assert_eq!(result.psnr(), f64::INFINITY);
+        assert!((result.ssim() - 1.0).abs() < 1e-6, "{}", result.ssim());
+        assert!(
+            (result.ms_ssim() - 1.0).abs() < 1e-6,
+            "{}",
+            result.ms_ssim()
+        );
+        assert_eq!(result.max_error(), 0);
+        assert!(!result.timeline_mismatch());
+        assert!(result.heatmap().is_some());
+    }
+
+    #[test]
+    fn more_distortion_scores_lower() {
+        let original = gradient(32, 32);
+        let distort = |amount: u8| {
+            let mut image = original.clone();
+            for (x, y, pixel) in image.enumerate_pixels_mut() {
+                if (x + y) % 2 == 0 {
+                    pixel[0] = pixel[0].saturating_add(amount);
+                }
+            }
+            still(image)
+        };
+
+        let reference = still(original.clone());
+        let slight = compare_images(&reference, &distort(8), false).unwrap();
+        let heavy = compare_images(&reference, &distort(64), false).unwrap();
+        assert!(slight.psnr().is_finite());
+        assert!(heavy.psnr() < slight.psnr());
+        assert!(heavy.ssim() < slight.ssim() && slight.ssim() < 1.0);
+        assert!(heavy.ms_ssim() < slight.ms_ssim());
+        assert_eq!(slight.max_error(), 8);
+    }
+
+    #[test]
+    fn size_mismatch_is_an_error() {
+        let error = compare_images(&still(gradient(32, 32)), &still(gradient(32, 16)), false)
+            .err()
+            .unwrap();
+        assert_eq!(error.code, crate::error::ErrorCode::InvalidArgument);
+        assert!(compare_images(&[], &still(gradient(4, 4)), false).is_err());
+    }
+
+    #[test]
+    fn every_frame_of_both_animations_is_compared() {
+        // Untimed: a ten-frame animation is not identical to its first three frames.
+        let long: Vec<DecodedFrame> = (0..10).map(|i| (flat(i * 20), 0)).collect();
+        let short = long[..3].to_vec();
+        let result = compare_images(&long, &short, false).unwrap();
+        assert_eq!(result.num_frames(), 10);
+        assert!(result.timeline_mismatch());
+        assert!(result.psnr().is_finite());
+
+        // Timed: frames of `b` shown after `a` has ended are still compared.
+        let a = vec![(flat(0), 100)];
+        let b = vec![(flat(0), 100), (flat(200), 100)];
+        let result = compare_images(&a, &b, false).unwrap();
+        assert_eq!(result.num_frames(), 2);
+        assert!(result.timeline_mismatch());
+        assert_eq!(result.max_error(), 200);
+
+        // Timed with the same duration: frames pair where they overlap on screen.
+        let a = vec![(flat(0), 100), (flat(100), 100)];
+        let b = vec![(flat(0), 50), (flat(0), 50), (flat(100), 100)];
+        let result = compare_images(&a, &b, false).unwrap();
+        assert_eq!(result.num_frames(), 3);
+        assert!(!result.timeline_mismatch());
+        assert_eq!(result.psnr(), f64::INFINITY);
+    }
+}
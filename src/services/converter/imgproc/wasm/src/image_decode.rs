@@ -1,26 +1,81 @@
-use crate::{callback_logs, first_frame::encode_png};
+use crate::{
+    callback_logs,
+    error::{ImgprocError, Result},
+    first_frame::{encode_png, encode_png16},
+    formats::source_format,
+    limits::current_limits,
+    psd_image::decode_psd,
+    psd_layers::layer_document,
+    svg::{rasterize_svg, SvgOptions},
+    tone_map::{is_hdr, is_high_bit_depth},
+};
 use callback_logs::*;
-use image::RgbaImage;
-use js_sys::Uint8Array;
+use image::{DynamicImage, RgbaImage};
 use psd::Psd;
-use wasm_bindgen::JsError;
 
-pub fn decode_psd(image_data: &[u8], logs: CallbackLogs) -> Result<Uint8Array, JsError> {
-    logs("Starting PSD decoding...")?;
-    let psd = Psd::from_bytes(image_data)
-        .map_err(|e| JsError::new(&format!("Failed to parse PSD: {}", e)))?;
+/// Decodes a format the browser can't display into PNG. SVGs are rendered at the size
+/// `svg_options` asks for. Stills with 16-bit or 10- and 12-bit samples come back as 16-bit
+/// PNG.
+pub fn decode_static_image(
+    image_data: &[u8],
+    source_type: &str,
+    svg_options: &SvgOptions,
+    logs: &CallbackLogs,
+) -> Result<Vec<u8>> {
+    let handler = source_format(source_type)?;
+    let name = handler.name.to_uppercase();
+    logs.send(ProgressEvent::new(Stage::Decode, "decode_start").arg(&name))?;
+    let image = match handler.name {
+        "psd" => {
+            let (image, source) = decode_psd(image_data)?;
+            logs.send(ProgressEvent::new(Stage::Decode, "psd_source").arg(source.as_str()))?;
+            DynamicImage::ImageRgba8(image)
+        }
+        "svg" => DynamicImage::ImageRgba8(rasterize_svg(image_data, svg_options)?),
+        _ if !handler.is_animated(image_data)? => handler.decode_deep(image_data)?,
+        _ => DynamicImage::ImageRgba8(handler.decode_first_frame(image_data)?),
+    };
 
-    let width = psd.width();
-    let height = psd.height();
-    let data = psd.rgba();
+    logs.send(
+        ProgressEvent::new(Stage::Decode, "decode_done")
+            .arg(1)
+            .arg(&name),
+    )?;
+    let output = match is_high_bit_depth(&image) && !is_hdr(&image) {
+        true => encode_png16(&image.to_rgba16())?,
+        false => encode_png(image.into_rgba8())?,
+    };
+    logs.send(
+        ProgressEvent::new(Stage::Finalize, "encode_done")
+            .arg("PNG")
+            .bytes_written(output.len()),
+    )?;
+    Ok(output)
+}
 
-    logs("PSD image data parsed successfully.")?;
+/// Width and height from the PSD file header, read before the layers are parsed.
+pub fn psd_dimensions(image_data: &[u8]) -> Option<(u32, u32)> {
+    let header = image_data.get(14..22)?;
+    let height = u32::from_be_bytes(header[0..4].try_into().ok()?);
+    let width = u32::from_be_bytes(header[4..8].try_into().ok()?);
+    Some((width, height))
+}
 
-    match RgbaImage::from_raw(width, height, data) {
-        Some(image) => {
-            logs("Creating RgbaImage from PSD data.")?;
-            encode_png(image)
-        }
-        None => Err(JsError::new("Failed to create RgbaImage from PSD data (likely incorrect dimensions or data length).")),
-    }
+/// Parses the layers after checking the header size against the limits and the layer records
+/// against what `psd` can read without panicking.
+pub fn parse_psd(image_data: &[u8]) -> Result<Psd> {
+    let (width, height) = psd_dimensions(image_data)
+        .ok_or_else(|| ImgprocError::corrupt("The PSD header is truncated.").format("PSD"))?;
+    current_limits().check_dimensions(width, height, "PSD")?;
+
+    let document = layer_document(image_data)?;
+    Psd::from_bytes(&document).map_err(|e| {
+        ImgprocError::corrupt("Failed to parse PSD.")
+            .format("PSD")
+            .cause(e)
+    })
+}
+
+pub fn psd_rgba(image_data: &[u8]) -> Result<RgbaImage> {
+    decode_psd(image_data).map(|(image, _)| image)
 }
@@ -0,0 +1,68 @@
+use image::{DynamicImage, RgbaImage};
+
+use crate::{
+    animation_decoder::{DecodedFrame, ImgprocFrameDecoder},
+    callback_logs::{CallbackLogs, ProgressEvent, Stage},
+    cancellation::Cancellation,
+    error::Result,
+    formats::source_format,
+};
+
+/// Decodes any supported input into RGBA frames with their delays in milliseconds.
+/// Static images are returned as a single frame with a delay of zero.
+pub fn decode_frames(
+    image_data: &[u8],
+    source_type: &str,
+    logs: &CallbackLogs,
+    cancel: &Cancellation,
+) -> Result<Vec<DecodedFrame>> {
+    let decoder = source_format(source_type)?.frame_decoder(image_data, logs, cancel)?;
+    Ok(collect_frames(decoder))
+}
+
+/// Like `decode_frames`, for a caller that already knows whether the input is animated.
+pub fn decode_detected_frames(
+    image_data: &[u8],
+    source_type: &str,
+    animated: bool,
+    logs: &CallbackLogs,
+    cancel: &Cancellation,
+) -> Result<Vec<DecodedFrame>> {
+    let handler = source_format(source_type)?;
+    let decoder = handler.detected_frame_decoder(image_data, animated, logs, cancel)?;
+    Ok(collect_frames(decoder))
+}
+
+fn collect_frames(mut decoder: ImgprocFrameDecoder) -> Vec<DecodedFrame> {
+    let mut frames = Vec::with_capacity(decoder.num_frames() as usize);
+    while let Some(frame) = decoder.next_frame() {
+        frames.push(frame);
+    }
+    frames
+}
+
+/// Decodes only the first frame of any supported input, or the whole image if it is static.
+pub fn decode_first_frame(image_data: &[u8], source_type: &str) -> Result<RgbaImage> {
+    source_format(source_type)?.decode_first_frame(image_data)
+}
+
+/// Decodes a still image at the precision it was stored with, reporting progress like the
+/// frame decoders do.
+pub fn decode_deep(
+    image_data: &[u8],
+    source_type: &str,
+    logs: &CallbackLogs,
+    cancel: &Cancellation,
+) -> Result<DynamicImage> {
+    let handler = source_format(source_type)?;
+    let name = handler.name.to_uppercase();
+    logs.send(ProgressEvent::new(Stage::Decode, "decode_start").arg(&name))?;
+    cancel()?;
+    let image = handler.decode_deep(image_data)?;
+    logs.send(
+        ProgressEvent::new(Stage::Decode, "decode_done")
+            .arg(1)
+            .arg(&name),
+    )?;
+    Ok(image)
+}
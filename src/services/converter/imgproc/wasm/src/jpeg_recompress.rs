@@ -0,0 +1,2117 @@
+//! Lossless recompression of JPEG files into JPEG XL. The quantized DCT coefficients become a
+//! VarDCT frame, and everything else needed to rebuild the file byte for byte (markers, tables,
+//! the scan script and padding bits) goes into a `jbrd` box. Only what that takes is written:
+//! one pass of 8x8 blocks, prefix codes and single-leaf modular trees.
+
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use image::metadata::Orientation;
+
+use crate::{
+    error::{ErrorCode, ImgprocError, Result},
+    jxl::CONTAINER_SIGNATURE,
+    limits::current_limits,
+};
+
+/// Row-major position of each coefficient in JPEG zigzag order.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// HF coefficient contexts by position in the block and by nonzeros left to code.
+const COEFF_FREQ_CONTEXT: [usize; 63] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 15, 16, 16, 17, 17, 18, 18, 19, 19, 20,
+    20, 21, 21, 22, 22, 23, 23, 23, 23, 24, 24, 24, 24, 25, 25, 25, 25, 26, 26, 26, 26, 27, 27, 27,
+    27, 28, 28, 28, 28, 29, 29, 29, 29, 30, 30, 30, 30,
+];
+const COEFF_NUM_NONZERO_CONTEXT: [usize; 63] = [
+    0, 31, 62, 62, 93, 93, 93, 93, 123, 123, 123, 123, 152, 152, 152, 152, 152, 152, 152, 152, 180,
+    180, 180, 180, 180, 180, 180, 180, 180, 180, 180, 180, 206, 206, 206, 206, 206, 206, 206, 206,
+    206, 206, 206, 206, 206, 206, 206, 206, 206, 206, 206, 206, 206, 206, 206, 206, 206, 206, 206,
+    206, 206, 206, 206,
+];
+
+/// The default block context map codes 8x8 luma blocks in context 0 and chroma in 7, out of 15.
+const BLOCK_CONTEXTS: [usize; 3] = [0, 7, 7];
+const NUM_BLOCK_CONTEXTS: usize = 15;
+const NUM_HF_CONTEXTS: usize = 495 * NUM_BLOCK_CONTEXTS;
+
+/// Blocks along a side of a pass group and of an LF group.
+const GROUP_BLOCKS: usize = 32;
+const LF_GROUP_BLOCKS: usize = 256;
+
+/// Histograms an entropy code may cluster its contexts into.
+const MAX_CLUSTERS: usize = 32;
+
+/// Largest values the `jbrd` fields can hold.
+const MAX_HUFFMAN_CODES: usize = 89;
+const MAX_SCAN_POINTS: usize = 20 + 0xffff;
+const MAX_BLOCK_INDEX: u32 = 3 << 26;
+const MAX_TAIL: usize = 65793 + (1 << 22) - 1;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ZERO_BLOCK: [i16; 64] = [0; 64];
+
+/// Recompresses a baseline or progressive JPEG file into a JPEG XL container. The caller checks
+/// that the result rebuilds the original, which also rules out the layouts this gets wrong.
+pub(crate) fn recompress(data: &[u8]) -> Result<Vec<u8>> {
+    let jpeg = parse_jpeg(data)?;
+    let jbrd = write_jbrd(&jpeg)?;
+    let codestream = write_codestream(&jpeg)?;
+
+    let mut file = CONTAINER_SIGNATURE.to_vec();
+    push_box(&mut file, b"ftyp", b"jxl \0\0\0\0jxl ");
+    push_box(&mut file, b"jbrd", &jbrd);
+    if let Some(exif) = jpeg.exif() {
+        push_box(&mut file, b"Exif", &[&[0; 4], exif].concat());
+    }
+    if let Some(xmp) = jpeg.xmp() {
+        push_box(&mut file, b"xml ", xmp);
+    }
+    push_box(&mut file, b"jxlc", &codestream);
+    Ok(file)
+}
+
+fn push_box(file: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    file.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+    file.extend_from_slice(kind);
+    file.extend_from_slice(body);
+}
+
+fn corrupt(message: &str) -> ImgprocError {
+    ImgprocError::corrupt(message).format("JPEG")
+}
+
+fn unsupported(message: &str) -> ImgprocError {
+    ImgprocError::new(ErrorCode::UnsupportedFormat, message).format("JPEG")
+}
+
+/// Everything about a JPEG file that the codestream and the `jbrd` box need.
+struct Jpeg<'a> {
+    geometry: Geometry,
+    components: Vec<Component>,
+    /// Marker codes in file order, without SOI; 0xFF stands for bytes between segments.
+    markers: Vec<u8>,
+    app_markers: Vec<AppMarker<'a>>,
+    /// COM segments from the length field on.
+    comments: Vec<&'a [u8]>,
+    quant_tables: Vec<QuantTable>,
+    huffman_codes: Vec<HuffmanCode>,
+    scans: Vec<Scan>,
+    restart_interval: Option<u16>,
+    intermarker: Vec<&'a [u8]>,
+    tail: &'a [u8],
+    /// Bits that pad each entropy-coded segment to a byte, last bit first per segment.
+    padding: Vec<bool>,
+}
+
+impl Jpeg<'_> {
+    /// The TIFF data of the first Exif segment, which the container stores in an `Exif` box.
+    fn exif(&self) -> Option<&[u8]> {
+        let marker = self.app_markers.iter().find(|m| m.kind == 2)?;
+        Some(&marker.segment[3 + EXIF_HEADER.len()..])
+    }
+
+    fn xmp(&self) -> Option<&[u8]> {
+        let marker = self.app_markers.iter().find(|m| m.kind == 3)?;
+        Some(&marker.segment[3 + XMP_HEADER.len()..])
+    }
+
+    /// A block of the Y, Cb or Cr channel; the chroma of a grayscale image is all zero.
+    fn block(&self, channel: usize, x: usize, y: usize) -> &[i16; 64] {
+        match self.components.get(channel) {
+            Some(component) => &component.blocks[y * component.width + x],
+            None => &ZERO_BLOCK,
+        }
+    }
+
+    /// Quantizers of the Y, Cb or Cr channel in zigzag order. Grayscale images repeat luma's.
+    fn quant(&self, channel: usize) -> &[u16; 64] {
+        let component = self.components.get(channel).unwrap_or(&self.components[0]);
+        &component.quant_values
+    }
+}
+
+/// An APPn segment from the marker code on, and how `jbrd` stores it: 0 as is, 2 as the Exif
+/// box and 3 as the XMP box.
+struct AppMarker<'a> {
+    kind: usize,
+    segment: &'a [u8],
+}
+
+struct QuantTable {
+    index: usize,
+    precision: usize,
+    is_last: bool,
+}
+
+struct HuffmanCode {
+    is_ac: bool,
+    id: usize,
+    is_last: bool,
+    counts: [u8; 16],
+    values: Vec<u8>,
+}
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    quant: usize,
+    quant_values: [u16; 64],
+    /// Block grid, padded the way the JPEG XL channel is.
+    width: usize,
+    blocks: Vec<[i16; 64]>,
+}
+
+struct Scan {
+    components: Vec<ScanComponent>,
+    ss: usize,
+    se: usize,
+    ah: usize,
+    al: usize,
+    /// Blocks before which the rebuilt scan must end an EOB run early.
+    reset_points: Vec<u32>,
+    /// Blocks whose coding ends in ZRL symbols no encoder needs, with their count.
+    extra_zero_runs: Vec<(u32, u32)>,
+}
+
+#[derive(Clone, Copy)]
+struct ScanComponent {
+    index: usize,
+    dc: usize,
+    ac: usize,
+}
+
+/// Image size and how the channels are subsampled, in Y, Cb, Cr order.
+#[derive(Clone, Copy)]
+struct Geometry {
+    width: usize,
+    height: usize,
+    /// Any channel is subsampled along that axis, which pads every channel to whole MCUs.
+    padded: (bool, bool),
+    shifts: [(bool, bool); 3],
+    /// The frame header's `jpeg_upsampling` codes.
+    upsampling: [usize; 3],
+}
+
+impl Geometry {
+    fn new(width: usize, height: usize, factors: &[(usize, usize)]) -> Self {
+        let factor = |c: usize| factors.get(c).copied().unwrap_or((1, 1));
+        let h_max = factors.iter().map(|f| f.0).max().unwrap_or(1);
+        let v_max = factors.iter().map(|f| f.1).max().unwrap_or(1);
+        Geometry {
+            width,
+            height,
+            padded: (h_max == 2, v_max == 2),
+            shifts: std::array::from_fn(|c| (factor(c).0 < h_max, factor(c).1 < v_max)),
+            upsampling: std::array::from_fn(|c| match factor(c) {
+                (2, 2) => 1,
+                (2, 1) => 2,
+                (1, 2) => 3,
+                _ => 0,
+            }),
+        }
+    }
+
+    /// Blocks across and down the image.
+    fn blocks(&self) -> (usize, usize) {
+        (self.width.div_ceil(8), self.height.div_ceil(8))
+    }
+
+    /// Blocks across and down a channel, for a region `size` luma blocks large.
+    fn channel_size(&self, channel: usize, (width, height): (usize, usize)) -> (usize, usize) {
+        let (h_shift, v_shift) = self.shifts[channel];
+        (
+            shift_size(width, self.padded.0, h_shift),
+            shift_size(height, self.padded.1, v_shift),
+        )
+    }
+}
+
+fn shift_size(size: usize, padded: bool, shifted: bool) -> usize {
+    match (padded, shifted) {
+        (false, _) => size,
+        (true, true) => size.div_ceil(2),
+        (true, false) => size.div_ceil(2) * 2,
+    }
+}
+
+fn parse_jpeg(data: &[u8]) -> Result<Jpeg<'_>> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return Err(corrupt("The JPEG start marker is missing."));
+    }
+
+    let mut jpeg = Jpeg {
+        geometry: Geometry::new(0, 0, &[]),
+        components: Vec::new(),
+        markers: Vec::new(),
+        app_markers: Vec::new(),
+        comments: Vec::new(),
+        quant_tables: Vec::new(),
+        huffman_codes: Vec::new(),
+        scans: Vec::new(),
+        restart_interval: None,
+        intermarker: Vec::new(),
+        tail: &[],
+        padding: Vec::new(),
+    };
+    let mut quant_values: [Option<[u16; 64]>; 4] = [None; 4];
+    let mut tables: [[Option<HuffmanTable>; 4]; 2] = Default::default();
+    let mut progressive = false;
+    let mut adobe_rgb = false;
+    let mut at = 2;
+    loop {
+        let start = (at..data.len().saturating_sub(1))
+            .find(|&i| data[i] == 0xff && !matches!(data[i + 1], 0x00 | 0xff))
+            .ok_or_else(|| corrupt("The JPEG file is truncated."))?;
+        if start > at {
+            jpeg.markers.push(0xff);
+            jpeg.intermarker.push(&data[at..start]);
+        }
+        let marker = data[start + 1];
+        at = start + 2;
+        match marker {
+            0xd9 => {
+                jpeg.markers.push(marker);
+                jpeg.tail = &data[at..];
+                break;
+            }
+            0xd0..=0xd7 => {
+                jpeg.markers.push(marker);
+                continue;
+            }
+            _ => {}
+        }
+
+        let length = data
+            .get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+            .filter(|&length| length >= 2)
+            .ok_or_else(|| corrupt("The JPEG file is truncated."))?;
+        let segment = data
+            .get(start + 1..at + length)
+            .ok_or_else(|| corrupt("The JPEG file is truncated."))?;
+        let body = &segment[3..];
+        at += length;
+
+        match marker {
+            0xc0..=0xc2 => {
+                if !jpeg.components.is_empty() {
+                    return Err(unsupported("The JPEG file has more than one frame."));
+                }
+                progressive = marker == 0xc2;
+                jpeg.geometry = read_frame(body, &mut jpeg.components)?;
+            }
+            0xc4 => read_huffman_tables(body, &mut tables, &mut jpeg.huffman_codes)?,
+            0xdb => read_quant_tables(body, &mut quant_values, &mut jpeg.quant_tables)?,
+            0xdd => {
+                let interval = u16::from_be_bytes([
+                    *body
+                        .first()
+                        .ok_or_else(|| corrupt("A JPEG DRI segment is malformed."))?,
+                    *body
+                        .get(1)
+                        .ok_or_else(|| corrupt("A JPEG DRI segment is malformed."))?,
+                ]);
+                if jpeg.restart_interval.is_some_and(|i| i != interval) {
+                    return Err(unsupported(
+                        "The JPEG file changes its restart interval between scans.",
+                    ));
+                }
+                jpeg.restart_interval = Some(interval);
+            }
+            0xda => {
+                let mut scan = read_scan_header(body, &jpeg, progressive, &tables)?;
+                for component in &scan.components {
+                    let component = &mut jpeg.components[component.index];
+                    if component.quant_values[0] == 0 {
+                        component.quant_values = quant_values[component.quant]
+                            .ok_or_else(|| corrupt("A JPEG quantization table is missing."))?;
+                    }
+                }
+                let interval = jpeg.restart_interval.unwrap_or(0) as usize;
+                at += decode_scan(&mut jpeg, &mut scan, &tables, interval, &data[at..])?;
+                jpeg.scans.push(scan);
+            }
+            0xe0..=0xef => {
+                let seen = |kind| jpeg.app_markers.iter().any(|m| m.kind == kind);
+                let kind = match marker {
+                    0xe1 if body.starts_with(EXIF_HEADER) && !seen(2) => 2,
+                    0xe1 if body.starts_with(XMP_HEADER) && !seen(3) => 3,
+                    _ => 0,
+                };
+                // An Adobe segment with transform 0 marks RGB samples, which are not YCbCr.
+                adobe_rgb |=
+                    marker == 0xee && body.starts_with(b"Adobe") && body.get(11) == Some(&0);
+                jpeg.app_markers.push(AppMarker { kind, segment });
+            }
+            0xfe => jpeg.comments.push(&segment[1..]),
+            0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                return Err(unsupported(
+                    "Only baseline and progressive Huffman-coded JPEG files can be recompressed.",
+                ))
+            }
+            _ => {
+                return Err(unsupported(
+                    "The JPEG file has a marker that cannot be recompressed.",
+                ))
+            }
+        }
+        jpeg.markers.push(marker);
+    }
+
+    if jpeg.scans.is_empty() {
+        return Err(corrupt("The JPEG file has no image data."));
+    }
+    let ids: Vec<u8> = jpeg.components.iter().map(|c| c.id).collect();
+    if jpeg.components.len() == 3 && (adobe_rgb || ids == b"RGB") {
+        return Err(unsupported("Only YCbCr JPEG files can be recompressed."));
+    }
+    Ok(jpeg)
+}
+
+fn read_frame(body: &[u8], components: &mut Vec<Component>) -> Result<Geometry> {
+    let malformed = || corrupt("The JPEG frame header is malformed.");
+    let &[precision, h0, h1, w0, w1, count, ..] = body else {
+        return Err(malformed());
+    };
+    let (width, height) = (
+        u16::from_be_bytes([w0, w1]) as usize,
+        u16::from_be_bytes([h0, h1]) as usize,
+    );
+    if precision != 8 {
+        return Err(unsupported("Only 8-bit JPEG files can be recompressed."));
+    }
+    if count != 1 && count != 3 {
+        return Err(unsupported(
+            "Only grayscale and YCbCr JPEG files can be recompressed.",
+        ));
+    }
+    if width == 0 || height == 0 || body.len() < 6 + count as usize * 3 {
+        return Err(malformed());
+    }
+    current_limits().check_dimensions(width as u32, height as u32, "JPEG")?;
+
+    let factors: Vec<(usize, usize)> = body[6..]
+        .chunks_exact(3)
+        .take(count as usize)
+        .map(|c| ((c[1] >> 4) as usize, (c[1] & 0x0f) as usize))
+        .collect();
+    if factors
+        .iter()
+        .any(|&(h, v)| !(1..=2).contains(&h) || !(1..=2).contains(&v))
+    {
+        return Err(unsupported(
+            "The JPEG file uses sampling factors that JPEG XL cannot store.",
+        ));
+    }
+    let geometry = Geometry::new(width, height, &factors);
+    for (c, chunk) in body[6..].chunks_exact(3).take(count as usize).enumerate() {
+        if chunk[2] > 3 {
+            return Err(malformed());
+        }
+        let (width, height) = geometry.channel_size(c, geometry.blocks());
+        components.push(Component {
+            id: chunk[0],
+            h: factors[c].0,
+            v: factors[c].1,
+            quant: chunk[2] as usize,
+            quant_values: [0; 64],
+            width,
+            blocks: vec![[0; 64]; width * height],
+        });
+    }
+    Ok(geometry)
+}
+
+fn read_quant_tables(
+    mut body: &[u8],
+    values: &mut [Option<[u16; 64]>; 4],
+    tables: &mut Vec<QuantTable>,
+) -> Result<()> {
+    let malformed = || corrupt("A JPEG quantization table is malformed.");
+    while let Some(&info) = body.first() {
+        let (precision, index) = ((info >> 4) as usize, (info & 0x0f) as usize);
+        if precision > 1 || index > 3 {
+            return Err(malformed());
+        }
+        let size = 64 << precision;
+        let table = body.get(1..1 + size).ok_or_else(malformed)?;
+        let mut quant = [0; 64];
+        for (k, q) in quant.iter_mut().enumerate() {
+            *q = match precision {
+                0 => table[k] as u16,
+                _ => u16::from_be_bytes([table[2 * k], table[2 * k + 1]]),
+            };
+        }
+        if quant.contains(&0) {
+            return Err(malformed());
+        }
+        values[index] = Some(quant);
+        tables.push(QuantTable {
+            index,
+            precision,
+            is_last: false,
+        });
+        body = &body[1 + size..];
+    }
+    match tables.last_mut() {
+        Some(table) => table.is_last = true,
+        None => return Err(malformed()),
+    }
+    if tables.len() > 4 {
+        return Err(unsupported(
+            "The JPEG file defines too many quantization tables to recompress.",
+        ));
+    }
+    Ok(())
+}
+
+fn read_huffman_tables(
+    mut body: &[u8],
+    tables: &mut [[Option<HuffmanTable>; 4]; 2],
+    codes: &mut Vec<HuffmanCode>,
+) -> Result<()> {
+    let malformed = || corrupt("A JPEG Huffman table is malformed.");
+    let first = codes.len();
+    while let Some(&info) = body.first() {
+        let (class, id) = ((info >> 4) as usize, (info & 0x0f) as usize);
+        if class > 1 || id > 3 {
+            return Err(malformed());
+        }
+        let counts: [u8; 16] = body
+            .get(1..17)
+            .and_then(|c| c.try_into().ok())
+            .ok_or_else(malformed)?;
+        let total: usize = counts.iter().map(|&c| c as usize).sum();
+        let values = body.get(17..17 + total).ok_or_else(malformed)?.to_vec();
+        // The `jbrd` box adds a sentinel code after the longest ones, so that count must
+        // have room for it.
+        match counts.iter().rev().find(|&&c| c > 0) {
+            None => return Err(unsupported("The JPEG file has an empty Huffman table.")),
+            Some(255) => return Err(unsupported("A JPEG Huffman table is too large.")),
+            _ => {}
+        }
+        tables[class][id] = Some(HuffmanTable::new(&counts, values.clone()));
+        codes.push(HuffmanCode {
+            is_ac: class == 1,
+            id,
+            is_last: false,
+            counts,
+            values,
+        });
+        body = &body[17 + total..];
+    }
+    if codes.len() == first {
+        return Err(malformed());
+    }
+    if let Some(code) = codes.last_mut() {
+        code.is_last = true;
+    }
+    Ok(())
+}
+
+fn read_scan_header(
+    body: &[u8],
+    jpeg: &Jpeg,
+    progressive: bool,
+    tables: &[[Option<HuffmanTable>; 4]; 2],
+) -> Result<Scan> {
+    let malformed = || corrupt("A JPEG scan header is malformed.");
+    if jpeg.components.is_empty() {
+        return Err(corrupt("A JPEG scan comes before the frame header."));
+    }
+    let count = *body.first().ok_or_else(malformed)? as usize;
+    if count == 0 || count > jpeg.components.len() || body.len() < 4 + 2 * count {
+        return Err(malformed());
+    }
+    let mut components: Vec<ScanComponent> = Vec::with_capacity(count);
+    for pair in body[1..1 + 2 * count].chunks_exact(2) {
+        let index = jpeg
+            .components
+            .iter()
+            .position(|c| c.id == pair[0])
+            .filter(|&index| components.iter().all(|c| c.index != index))
+            .ok_or_else(malformed)?;
+        let (dc, ac) = ((pair[1] >> 4) as usize, (pair[1] & 0x0f) as usize);
+        if dc > 3 || ac > 3 {
+            return Err(malformed());
+        }
+        components.push(ScanComponent { index, dc, ac });
+    }
+    let params = &body[1 + 2 * count..];
+    let (ss, se) = (params[0] as usize, params[1] as usize);
+    let (ah, al) = ((params[2] >> 4) as usize, (params[2] & 0x0f) as usize);
+    let valid = match progressive {
+        false => ss == 0 && se == 63 && ah == 0 && al == 0,
+        true => ss <= se && se <= 63 && (ss == 0) == (se == 0) && (ss == 0 || count == 1),
+    };
+    if !valid || ah > 13 || al > 13 {
+        return Err(malformed());
+    }
+    // Tables are only needed by the passes that decode Huffman symbols.
+    let needs_dc = ss == 0 && ah == 0;
+    let missing = components
+        .iter()
+        .any(|c| (needs_dc && tables[0][c.dc].is_none()) || (se > 0 && tables[1][c.ac].is_none()));
+    if missing {
+        return Err(corrupt("A JPEG Huffman table is missing."));
+    }
+
+    // The rebuilt scan walks a block grid derived from the scan's own components, which only
+    // matches JPEG's when they include the most sampled ones or the scan covers luma alone.
+    let (blocks_x, blocks_y) = jpeg.geometry.blocks();
+    let (width, height) = (jpeg.geometry.width, jpeg.geometry.height);
+    let h_max = jpeg.components.iter().map(|c| c.h).max().unwrap_or(1);
+    let v_max = jpeg.components.iter().map(|c| c.v).max().unwrap_or(1);
+    let supported = match components.as_slice() {
+        [only] => {
+            let component = &jpeg.components[only.index];
+            (width * component.h).div_ceil(h_max).div_ceil(8) == blocks_x
+                && (height * component.v).div_ceil(v_max).div_ceil(8) == blocks_y
+        }
+        _ => {
+            let scanned = components.iter().map(|c| &jpeg.components[c.index]);
+            scanned.clone().map(|c| c.h).max() == Some(h_max)
+                && scanned.map(|c| c.v).max() == Some(v_max)
+        }
+    };
+    if !supported {
+        return Err(unsupported(
+            "The JPEG file has a scan of subsampled components that JPEG XL cannot rebuild.",
+        ));
+    }
+
+    Ok(Scan {
+        components,
+        ss,
+        se,
+        ah,
+        al,
+        reset_points: Vec::new(),
+        extra_zero_runs: Vec::new(),
+    })
+}
+
+#[derive(Clone, Default)]
+struct HuffmanTable {
+    /// Indexed by code length minus one: the largest code of that length, or -1.
+    max_code: [i32; 16],
+    /// Offset into `values` of the first code of each length, minus that code.
+    offsets: [i32; 16],
+    values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    fn new(counts: &[u8; 16], values: Vec<u8>) -> Self {
+        let mut table = HuffmanTable {
+            max_code: [-1; 16],
+            offsets: [0; 16],
+            values,
+        };
+        let (mut code, mut index) = (0i32, 0i32);
+        for (len, &count) in counts.iter().enumerate() {
+            table.offsets[len] = index - code;
+            if count > 0 {
+                code += count as i32;
+                index += count as i32;
+                table.max_code[len] = code - 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+}
+
+/// How one block was coded, as far as rebuilding its EOB runs and ZRL symbols goes.
+#[derive(Default)]
+struct BlockCoding {
+    /// An EOB symbol was read in this block.
+    starts_run: bool,
+    /// The block has coefficients to code, which ends any EOB run before it.
+    flushes: bool,
+    /// The block is part of an EOB run.
+    ends_in_run: bool,
+    /// ZRL symbols after the last coefficient.
+    zero_runs: u32,
+}
+
+/// Decodes a scan's entropy-coded data into the component blocks and records what the `jbrd`
+/// box needs to rebuild it. Returns the number of bytes read.
+fn decode_scan(
+    jpeg: &mut Jpeg,
+    scan: &mut Scan,
+    tables: &[[Option<HuffmanTable>; 4]; 2],
+    restart_interval: usize,
+    data: &[u8],
+) -> Result<usize> {
+    let truncated = || corrupt("A JPEG scan is truncated or malformed.");
+    let geometry = jpeg.geometry;
+    let h_max = jpeg.components.iter().map(|c| c.h).max().unwrap_or(1);
+    let v_max = jpeg.components.iter().map(|c| c.v).max().unwrap_or(1);
+    let single = scan.components.len() == 1;
+    let (mcus_x, mcus_y) = match single {
+        true => {
+            let component = &jpeg.components[scan.components[0].index];
+            (
+                (geometry.width * component.h).div_ceil(h_max).div_ceil(8),
+                (geometry.height * component.v).div_ceil(v_max).div_ceil(8),
+            )
+        }
+        false => (
+            geometry.width.div_ceil(8 * h_max),
+            geometry.height.div_ceil(8 * v_max),
+        ),
+    };
+
+    let mut decoder = ScanDecoder {
+        reader: EntropyReader {
+            data,
+            position: 0,
+            byte: 0,
+            left: 0,
+        },
+        predictions: [0; 4],
+        eobrun: 0,
+        block_index: 0,
+        pending_run: 0,
+        next_restart: 0,
+        reset_points: Vec::new(),
+        extra_zero_runs: Vec::new(),
+    };
+    for mcu in 0..mcus_x * mcus_y {
+        if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+            decoder
+                .restart(&mut jpeg.padding)
+                .ok_or_else(|| corrupt("A JPEG restart marker is missing."))?;
+        }
+        let (mcu_x, mcu_y) = (mcu % mcus_x, mcu / mcus_x);
+        for (i, scanned) in scan.components.iter().enumerate() {
+            let component = &mut jpeg.components[scanned.index];
+            let (h, v) = match single {
+                true => (1, 1),
+                false => (component.h, component.v),
+            };
+            for y in mcu_y * v..mcu_y * v + v {
+                for x in mcu_x * h..mcu_x * h + h {
+                    let block = &mut component.blocks[y * component.width + x];
+                    let dc = tables[0][scanned.dc].as_ref();
+                    let ac = tables[1][scanned.ac].as_ref();
+                    let coding = match (scan.ss, scan.ah) {
+                        (0, 0) if scan.se == 63 => decoder.sequential(block, i, dc, ac),
+                        (0, 0) => decoder.dc_first(block, i, dc, scan.al),
+                        (0, _) => decoder.dc_refine(block, scan.al),
+                        (_, 0) => decoder.ac_first(block, ac, scan),
+                        _ => decoder.ac_refine(block, ac, scan),
+                    };
+                    decoder.track(coding.ok_or_else(truncated)?);
+                }
+            }
+        }
+    }
+    decoder.reader.pad(&mut jpeg.padding);
+
+    if decoder.block_index > MAX_BLOCK_INDEX {
+        return Err(unsupported("The JPEG file is too large to recompress."));
+    }
+    scan.reset_points = decoder.reset_points;
+    scan.extra_zero_runs = decoder.extra_zero_runs;
+    Ok(decoder.reader.position)
+}
+
+struct ScanDecoder<'a> {
+    reader: EntropyReader<'a>,
+    predictions: [i32; 4],
+    eobrun: u32,
+    block_index: u32,
+    /// Blocks in the EOB run the rebuilt scan has open.
+    pending_run: u32,
+    next_restart: u8,
+    reset_points: Vec<u32>,
+    extra_zero_runs: Vec<(u32, u32)>,
+}
+
+impl ScanDecoder<'_> {
+    fn sequential(
+        &mut self,
+        block: &mut [i16; 64],
+        component: usize,
+        dc: Option<&HuffmanTable>,
+        ac: Option<&HuffmanTable>,
+    ) -> Option<BlockCoding> {
+        self.dc_first(block, component, dc, 0)?;
+        let mut coding = BlockCoding::default();
+        let mut k = 1;
+        while k < 64 {
+            let symbol = self.reader.decode(ac)?;
+            let (run, size) = ((symbol >> 4) as usize, symbol & 0x0f);
+            match (run, size) {
+                (15, 0) => {
+                    k += 16;
+                    coding.zero_runs += 1;
+                    continue;
+                }
+                (_, 0) => break,
+                _ => {}
+            }
+            k += run;
+            if k > 63 || size > 10 {
+                return None;
+            }
+            block[k] = self.reader.receive(size)? as i16;
+            coding.zero_runs = 0;
+            k += 1;
+        }
+        (k <= 64).then_some(coding)
+    }
+
+    fn dc_first(
+        &mut self,
+        block: &mut [i16; 64],
+        component: usize,
+        dc: Option<&HuffmanTable>,
+        al: usize,
+    ) -> Option<BlockCoding> {
+        let size = self.reader.decode(dc)?;
+        if size > 11 {
+            return None;
+        }
+        let prediction = &mut self.predictions[component];
+        *prediction += self.reader.receive(size)?;
+        block[0] = coefficient(*prediction << al)?;
+        Some(BlockCoding::default())
+    }
+
+    fn dc_refine(&mut self, block: &mut [i16; 64], al: usize) -> Option<BlockCoding> {
+        if self.reader.bit()? == 1 {
+            block[0] |= 1 << al;
+        }
+        Some(BlockCoding::default())
+    }
+
+    fn ac_first(
+        &mut self,
+        block: &mut [i16; 64],
+        ac: Option<&HuffmanTable>,
+        scan: &Scan,
+    ) -> Option<BlockCoding> {
+        let mut coding = BlockCoding::default();
+        if self.eobrun > 0 {
+            self.eobrun -= 1;
+            coding.ends_in_run = true;
+            return Some(coding);
+        }
+        let mut k = scan.ss;
+        while k <= scan.se {
+            let symbol = self.reader.decode(ac)?;
+            let (run, size) = ((symbol >> 4) as usize, symbol & 0x0f);
+            match (run, size) {
+                (15, 0) => {
+                    k += 16;
+                    coding.zero_runs += 1;
+                    continue;
+                }
+                (_, 0) => {
+                    self.eobrun = (1 << run) + self.reader.bits(run as u8)? - 1;
+                    coding.starts_run = true;
+                    coding.ends_in_run = true;
+                    break;
+                }
+                _ => {}
+            }
+            k += run;
+            if k > scan.se || size > 10 {
+                return None;
+            }
+            block[k] = coefficient(self.reader.receive(size)? << scan.al)?;
+            coding.flushes = true;
+            coding.zero_runs = 0;
+            k += 1;
+        }
+        (k <= scan.se + 1).then_some(coding)
+    }
+
+    fn ac_refine(
+        &mut self,
+        block: &mut [i16; 64],
+        ac: Option<&HuffmanTable>,
+        scan: &Scan,
+    ) -> Option<BlockCoding> {
+        let bit = 1i32 << scan.al;
+        let mut coding = BlockCoding::default();
+        let mut k = scan.ss;
+        if self.eobrun == 0 {
+            while k <= scan.se {
+                let symbol = self.reader.decode(ac)?;
+                let (run, size) = ((symbol >> 4) as i32, symbol & 0x0f);
+                let value = match (run, size) {
+                    (_, 1) => match self.reader.bit()? {
+                        1 => bit,
+                        _ => -bit,
+                    },
+                    (15, 0) => {
+                        coding.zero_runs += 1;
+                        0
+                    }
+                    (_, 0) => {
+                        self.eobrun = (1 << run) + self.reader.bits(run as u8)?;
+                        coding.starts_run = true;
+                        break;
+                    }
+                    _ => return None,
+                };
+                // Skip `run` zeros, refining the nonzero coefficients passed on the way.
+                let mut run = run;
+                while k <= scan.se {
+                    if block[k] != 0 {
+                        self.refine(&mut block[k], bit)?;
+                    } else {
+                        run -= 1;
+                        if run < 0 {
+                            break;
+                        }
+                    }
+                    k += 1;
+                }
+                if value != 0 {
+                    *block.get_mut(k).filter(|_| k <= scan.se)? = value as i16;
+                    coding.flushes = true;
+                    coding.zero_runs = 0;
+                }
+                k += 1;
+            }
+        }
+        if self.eobrun > 0 {
+            for coefficient in &mut block[k.min(64)..=scan.se] {
+                if *coefficient != 0 {
+                    self.refine(coefficient, bit)?;
+                }
+            }
+            self.eobrun -= 1;
+            coding.ends_in_run = true;
+        }
+        Some(coding)
+    }
+
+    /// Applies a correction bit to a coefficient that is already nonzero.
+    fn refine(&mut self, value: &mut i16, bit: i32) -> Option<()> {
+        if self.reader.bit()? == 1 && (*value as i32 & bit) == 0 {
+            let value32 = *value as i32;
+            *value = coefficient(value32 + value32.signum() * bit)?;
+        }
+        Some(())
+    }
+
+    /// Ends a restart interval: pads to the byte, then expects the next RSTn marker.
+    fn restart(&mut self, padding: &mut Vec<bool>) -> Option<()> {
+        self.reader.pad(padding);
+        if self.reader.marker()? != 0xd0 + self.next_restart {
+            return None;
+        }
+        self.next_restart = (self.next_restart + 1) % 8;
+        self.predictions = [0; 4];
+        self.eobrun = 0;
+        self.pending_run = 0;
+        Some(())
+    }
+
+    /// Follows the EOB run the rebuilt scan keeps open, which it only ends when a block has
+    /// something to code. Runs the JPEG file ends sooner need a reset point, and trailing ZRL
+    /// symbols an extra zero run.
+    fn track(&mut self, coding: BlockCoding) {
+        if coding.zero_runs > 0 {
+            self.extra_zero_runs
+                .push((self.block_index, coding.zero_runs));
+        }
+        if coding.starts_run && self.pending_run > 0 && !coding.flushes && coding.zero_runs == 0 {
+            self.reset_points.push(self.block_index);
+            self.pending_run = 0;
+        }
+        if coding.flushes || coding.zero_runs > 0 {
+            self.pending_run = 0;
+        }
+        if coding.ends_in_run {
+            self.pending_run += 1;
+            if self.pending_run == 0x7fff {
+                self.pending_run = 0;
+            }
+        }
+        self.block_index = self.block_index.saturating_add(1);
+    }
+}
+
+/// A quantized coefficient, if it is in the range an 8-bit JPEG file can hold.
+fn coefficient(value: i32) -> Option<i16> {
+    (-2047..=2047).contains(&value).then_some(value as i16)
+}
+
+/// Reads entropy-coded bits, most significant first, removing the zero stuffed after 0xFF.
+struct EntropyReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    byte: u8,
+    left: u32,
+}
+
+impl EntropyReader<'_> {
+    fn bit(&mut self) -> Option<u32> {
+        if self.left == 0 {
+            let byte = *self.data.get(self.position)?;
+            if byte == 0xff {
+                if self.data.get(self.position + 1) != Some(&0) {
+                    return None;
+                }
+                self.position += 1;
+            }
+            self.position += 1;
+            self.byte = byte;
+            self.left = 8;
+        }
+        self.left -= 1;
+        Some((self.byte >> self.left) as u32 & 1)
+    }
+
+    fn bits(&mut self, count: u8) -> Option<u32> {
+        (0..count).try_fold(0, |value, _| Some(value << 1 | self.bit()?))
+    }
+
+    /// Reads a `size`-bit value, where values below half the range are negative.
+    fn receive(&mut self, size: u8) -> Option<i32> {
+        let value = self.bits(size)? as i32;
+        Some(match size > 0 && value < 1 << (size - 1) {
+            true => value - (1 << size) + 1,
+            false => value,
+        })
+    }
+
+    /// Reads a Huffman symbol. Scans only reach this with their tables defined.
+    fn decode(&mut self, table: Option<&HuffmanTable>) -> Option<u8> {
+        let table = table?;
+        let mut code = 0i32;
+        for len in 0..16 {
+            code = code << 1 | self.bit()? as i32;
+            if code <= table.max_code[len] {
+                return table
+                    .values
+                    .get((table.offsets[len] + code) as usize)
+                    .copied();
+            }
+        }
+        None
+    }
+
+    /// Skips to the byte boundary, keeping the skipped bits last first, the order `jbrd`
+    /// stores them in.
+    fn pad(&mut self, padding: &mut Vec<bool>) {
+        padding.extend((0..self.left).map(|i| (self.byte >> i) & 1 == 1));
+        self.left = 0;
+    }
+
+    fn marker(&mut self) -> Option<u8> {
+        match self.data.get(self.position..self.position + 2)? {
+            &[0xff, marker] => {
+                self.position += 2;
+                Some(marker)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn write_jbrd(jpeg: &Jpeg) -> Result<Vec<u8>> {
+    let too_complex = || unsupported("The JPEG file's layout is too complex to recompress.");
+    let mut w = BitWriter::default();
+    w.flag(jpeg.components.len() == 1);
+    for &marker in &jpeg.markers {
+        w.write(6, marker as usize - 0xc0);
+    }
+    for marker in &jpeg.app_markers {
+        w.u32(marker.kind, [(0, 0), (1, 0), (2, 1), (4, 2)]);
+        w.write(16, marker.segment.len() - 1);
+    }
+    for comment in &jpeg.comments {
+        w.write(16, comment.len() - 1);
+    }
+
+    if jpeg.quant_tables.is_empty() {
+        return Err(corrupt("A JPEG quantization table is missing."));
+    }
+    w.write(2, jpeg.quant_tables.len() - 1);
+    for table in &jpeg.quant_tables {
+        w.write(1, table.precision);
+        w.write(2, table.index);
+        w.flag(table.is_last);
+    }
+
+    let ids: Vec<u8> = jpeg.components.iter().map(|c| c.id).collect();
+    match ids.as_slice() {
+        [1] => w.write(2, 0),
+        [1, 2, 3] => w.write(2, 1),
+        _ => {
+            w.write(2, 3);
+            w.write(2, ids.len() - 1);
+            ids.iter().for_each(|&id| w.write(8, id as usize));
+        }
+    }
+    for component in &jpeg.components {
+        w.write(2, component.quant);
+    }
+
+    if !(2..=MAX_HUFFMAN_CODES).contains(&jpeg.huffman_codes.len()) {
+        return Err(too_complex());
+    }
+    w.u32(jpeg.huffman_codes.len(), [(4, 0), (2, 3), (10, 4), (26, 6)]);
+    for code in &jpeg.huffman_codes {
+        w.flag(code.is_ac);
+        w.write(2, code.id);
+        w.flag(code.is_last);
+        // Lengths start at zero, and the longest gets the sentinel code.
+        let mut counts = [0; 17];
+        counts[1..]
+            .iter_mut()
+            .zip(code.counts)
+            .for_each(|(c, n)| *c = n as usize);
+        if let Some(count) = counts.iter_mut().rev().find(|c| **c > 0) {
+            *count += 1;
+        }
+        for count in counts {
+            w.u32(count, [(0, 0), (1, 0), (2, 3), (0, 8)]);
+        }
+        for value in code.values.iter().map(|&v| v as usize).chain([256]) {
+            w.u32(value, [(0, 2), (4, 2), (8, 4), (1, 8)]);
+        }
+    }
+
+    for scan in &jpeg.scans {
+        w.write(2, scan.components.len() - 1);
+        w.write(6, scan.ss);
+        w.write(6, scan.se);
+        w.write(4, scan.al);
+        w.write(4, scan.ah);
+        for component in &scan.components {
+            w.write(2, component.index);
+            w.write(2, component.ac);
+            w.write(2, component.dc);
+        }
+        w.write(2, 0);
+    }
+    if jpeg.markers.contains(&0xdd) {
+        w.write(16, jpeg.restart_interval.unwrap_or(0) as usize);
+    }
+    for scan in &jpeg.scans {
+        let count_field = [(0, 0), (1, 2), (4, 4), (20, 16)];
+        let block_field = [(0, 0), (1, 3), (9, 5), (41, 28)];
+        if scan.reset_points.len() > MAX_SCAN_POINTS || scan.extra_zero_runs.len() > MAX_SCAN_POINTS
+        {
+            return Err(too_complex());
+        }
+        w.u32(scan.reset_points.len(), count_field);
+        let mut last = None;
+        for &block in &scan.reset_points {
+            w.u32(block_delta(block, last), block_field);
+            last = Some(block);
+        }
+        w.u32(scan.extra_zero_runs.len(), count_field);
+        let mut last = None;
+        for &(block, runs) in &scan.extra_zero_runs {
+            w.u32(runs as usize, [(1, 0), (2, 2), (5, 4), (20, 8)]);
+            w.u32(block_delta(block, last), block_field);
+            last = Some(block);
+        }
+    }
+
+    for data in &jpeg.intermarker {
+        if data.len() > 0xffff {
+            return Err(too_complex());
+        }
+        w.write(16, data.len());
+    }
+    if jpeg.tail.len() > MAX_TAIL {
+        return Err(too_complex());
+    }
+    w.u32(jpeg.tail.len(), [(0, 0), (1, 8), (257, 16), (65793, 22)]);
+    let has_padding = jpeg.padding.contains(&false);
+    w.flag(has_padding);
+    if has_padding {
+        if jpeg.padding.len() >= 1 << 24 {
+            return Err(too_complex());
+        }
+        w.write(24, jpeg.padding.len());
+        jpeg.padding.iter().for_each(|&bit| w.flag(bit));
+    }
+    w.zero_pad();
+
+    let mut data: Vec<u8> = Vec::new();
+    for marker in jpeg.app_markers.iter().filter(|m| m.kind == 0) {
+        data.extend_from_slice(marker.segment);
+    }
+    jpeg.comments.iter().for_each(|c| data.extend_from_slice(c));
+    jpeg.intermarker
+        .iter()
+        .for_each(|d| data.extend_from_slice(d));
+    data.extend_from_slice(jpeg.tail);
+    write_stored_brotli(&mut w, &data);
+    Ok(w.finish())
+}
+
+/// Block indices are stored as the distance from the previous one, less one.
+fn block_delta(block: u32, last: Option<u32>) -> usize {
+    match last {
+        Some(last) => (block - last - 1) as usize,
+        None => block as usize,
+    }
+}
+
+/// Writes `data` as a Brotli stream of uncompressed meta-blocks.
+fn write_stored_brotli(w: &mut BitWriter, data: &[u8]) {
+    w.write(1, 0); // The smallest window
+    for chunk in data.chunks(1 << 16) {
+        w.flag(false); // Not the last meta-block
+        w.write(2, 0); // Four nibbles of length
+        w.write(16, chunk.len() - 1);
+        w.flag(true); // Uncompressed
+        w.zero_pad();
+        chunk.iter().for_each(|&byte| w.write(8, byte as usize));
+    }
+    w.flag(true); // The last meta-block, and empty
+    w.flag(true);
+    w.zero_pad();
+}
+
+fn write_codestream(jpeg: &Jpeg) -> Result<Vec<u8>> {
+    let geometry = &jpeg.geometry;
+    let mut w = BitWriter::default();
+    w.write(16, 0x0aff);
+    write_image_header(&mut w, jpeg);
+    w.zero_pad();
+    write_frame_header(&mut w, geometry);
+
+    let groups_x = geometry.width.div_ceil(GROUP_BLOCKS * 8);
+    let groups_y = geometry.height.div_ceil(GROUP_BLOCKS * 8);
+    let lf_groups_x = geometry.width.div_ceil(LF_GROUP_BLOCKS * 8);
+    let lf_groups_y = geometry.height.div_ceil(LF_GROUP_BLOCKS * 8);
+    let num_groups = groups_x * groups_y;
+
+    let hf_tokens: Vec<Vec<Token>> = (0..num_groups)
+        .map(|i| hf_tokens(jpeg, i % groups_x, i / groups_x))
+        .collect();
+    let hf_code = EntropyCode::new(&hf_tokens.concat(), NUM_HF_CONTEXTS);
+
+    let mut sections = vec![write_lf_global(jpeg)];
+    for i in 0..lf_groups_x * lf_groups_y {
+        sections.push(write_lf_group(jpeg, i % lf_groups_x, i / lf_groups_x));
+    }
+    let mut hf_global = BitWriter::default();
+    write_hf_global(&mut hf_global, jpeg, num_groups, &hf_code);
+    sections.push(hf_global);
+    for tokens in &hf_tokens {
+        let mut group = BitWriter::default();
+        hf_code.write_tokens(&mut group, tokens);
+        sections.push(group);
+    }
+
+    // One group with one pass keeps every section in a single entry.
+    if num_groups == 1 {
+        let mut all = BitWriter::default();
+        sections.iter().for_each(|section| all.append(section));
+        sections = vec![all];
+    }
+    let sections: Vec<Vec<u8>> = sections.into_iter().map(BitWriter::finish).collect();
+    w.flag(false); // Sections in order
+    w.zero_pad();
+    for section in &sections {
+        if section.len() >= 4211712 + (1 << 30) {
+            return Err(unsupported("The JPEG file is too large to recompress."));
+        }
+        w.u32(
+            section.len(),
+            [(0, 10), (1024, 14), (17408, 22), (4211712, 30)],
+        );
+    }
+    w.zero_pad();
+    let mut codestream = w.finish();
+    sections
+        .iter()
+        .for_each(|s| codestream.extend_from_slice(s));
+    Ok(codestream)
+}
+
+/// The size header and image metadata: 8-bit samples in sRGB or sRGB gray, YCbCr without XYB,
+/// and the orientation from the Exif segment.
+fn write_image_header(w: &mut BitWriter, jpeg: &Jpeg) {
+    let dimension = [(1, 9), (1, 13), (1, 18), (1, 30)];
+    w.flag(false);
+    w.u32(jpeg.geometry.height, dimension);
+    w.write(3, 0);
+    w.u32(jpeg.geometry.width, dimension);
+
+    let orientation = jpeg
+        .exif()
+        .and_then(Orientation::from_exif_chunk)
+        .map_or(1, Orientation::to_exif) as usize;
+    let colour_enum = [(0, 0), (1, 0), (2, 4), (18, 6)];
+    w.flag(false); // Not all default
+    w.flag(orientation != 1);
+    if orientation != 1 {
+        w.write(3, orientation - 1);
+        w.flag(false); // No intrinsic size
+        w.flag(false); // No preview
+        w.flag(false); // Not animated
+    }
+    w.flag(false); // Integer samples
+    w.write(2, 0); // of 8 bits
+    w.flag(true); // 16-bit buffers suffice
+    w.write(2, 0); // No extra channels
+    w.flag(false); // Not XYB
+    match jpeg.components.len() {
+        1 => {
+            w.flag(false);
+            w.flag(false); // No ICC profile
+            w.u32(1, colour_enum); // Gray
+            w.u32(1, colour_enum); // D65
+            w.flag(false);
+            w.u32(13, colour_enum); // sRGB transfer function
+            w.u32(1, colour_enum); // Relative intent
+        }
+        _ => w.flag(true),
+    }
+    if orientation != 1 {
+        w.flag(true); // Default tone mapping
+    }
+    w.write(2, 0); // No extensions
+    w.flag(true); // Default upsampling weights
+}
+
+/// A regular VarDCT frame in YCbCr with the JPEG's chroma subsampling, no filters and no LF
+/// smoothing, which would change the coefficients.
+fn write_frame_header(w: &mut BitWriter, geometry: &Geometry) {
+    w.flag(false); // Not all default
+    w.write(2, 0); // Regular frame
+    w.write(1, 0); // VarDCT
+    w.write(2, 2); // Flags: skip adaptive LF smoothing
+    w.write(8, 0x80 - 17);
+    w.flag(true); // YCbCr
+    for channel in [1, 0, 2] {
+        w.write(2, geometry.upsampling[channel]);
+    }
+    w.write(2, 0); // No upsampling
+    w.write(2, 0); // One pass
+    w.flag(false); // No crop
+    w.write(2, 0); // Replace blending
+    w.flag(true); // Last frame
+    w.write(2, 0); // No name
+    w.flag(false); // Restoration filter: no Gabor-like filter and no edge-preserving filter
+    w.flag(false);
+    w.write(2, 0);
+    w.write(2, 0);
+    w.write(2, 0); // No extensions
+}
+
+fn write_lf_global(jpeg: &Jpeg) -> BitWriter {
+    let mut w = BitWriter::default();
+    // LF dequantization: the DC quantizers of Cb, Y and Cr, scaled to samples in 0..1.
+    w.flag(false);
+    for channel in [1, 0, 2] {
+        w.f16(jpeg.quant(channel)[0] as f32 * 128.0 / 2040.0);
+    }
+    w.u32(65536, [(1, 11), (2049, 11), (4097, 12), (8193, 16)]); // Global scale
+    w.u32(1, [(16, 0), (1, 5), (1, 8), (1, 16)]); // LF quantizer
+    w.flag(true); // Default block context map
+                  // No chroma from luma.
+    w.flag(false);
+    w.u32(84, [(84, 0), (256, 0), (2, 8), (258, 16)]);
+    w.f16(0.0);
+    w.f16(0.0);
+    w.write(8, 128);
+    w.write(8, 128);
+    w.flag(false); // No global modular tree
+    w
+}
+
+/// The DC coefficients of one LF group, then its block metadata: every block an 8x8 DCT with
+/// no chroma from luma.
+fn write_lf_group(jpeg: &Jpeg, group_x: usize, group_y: usize) -> BitWriter {
+    let geometry = &jpeg.geometry;
+    let group_size = LF_GROUP_BLOCKS * 8;
+    let width = (geometry.width - group_x * group_size).min(group_size);
+    let height = (geometry.height - group_y * group_size).min(group_size);
+    let blocks = (width.div_ceil(8), height.div_ceil(8));
+
+    let mut w = BitWriter::default();
+    w.write(2, 0); // No extra precision
+    let channels: Vec<Channel> = (0..3)
+        .map(|c| {
+            let (h_shift, v_shift) = geometry.shifts[c];
+            let left = (group_x * LF_GROUP_BLOCKS) >> h_shift as usize;
+            let top = (group_y * LF_GROUP_BLOCKS) >> v_shift as usize;
+            let (width, height) = geometry.channel_size(c, blocks);
+            let samples = (0..width * height)
+                .map(|i| jpeg.block(c, left + i % width, top + i / width)[0] as i32)
+                .collect();
+            Channel { width, samples }
+        })
+        .collect();
+    write_modular(&mut w, &channels, Predictor::Gradient);
+
+    let block_width = shift_size(blocks.0, geometry.padded.0, false);
+    let block_height = shift_size(blocks.1, geometry.padded.1, false);
+    let num_blocks = block_width * block_height;
+    w.write(ceil_log2(num_blocks), num_blocks - 1);
+    let zeros = |width: usize, height: usize| Channel {
+        width,
+        samples: vec![0; width * height],
+    };
+    let (cfl_width, cfl_height) = (width.div_ceil(64), height.div_ceil(64));
+    let metadata = [
+        zeros(cfl_width, cfl_height),
+        zeros(cfl_width, cfl_height),
+        zeros(num_blocks, 2),
+        zeros(block_width, block_height),
+    ];
+    write_modular(&mut w, &metadata, Predictor::Zero);
+    w
+}
+
+/// Dequantization with the JPEG tables as raw matrices, one HF preset, natural coefficient
+/// order and the entropy code of the pass groups.
+fn write_hf_global(w: &mut BitWriter, jpeg: &Jpeg, num_groups: usize, code: &EntropyCode) {
+    w.flag(false); // Not all default
+    w.write(3, 7); // Raw matrices for 8x8 DCT
+    w.f16(1.0 / 2040.0);
+    let matrices: Vec<Channel> = [1, 0, 2]
+        .into_iter()
+        .map(|channel| {
+            let mut samples = vec![0; 64];
+            for (k, &q) in jpeg.quant(channel).iter().enumerate() {
+                let (row, column) = (ZIGZAG[k] / 8, ZIGZAG[k] % 8);
+                samples[column * 8 + row] = q as i32;
+            }
+            Channel { width: 8, samples }
+        })
+        .collect();
+    write_modular(w, &matrices, Predictor::Zero);
+    for _ in 1..17 {
+        w.write(3, 0); // Library defaults for the other transforms
+    }
+    w.write(ceil_log2(num_groups), 0); // One HF preset
+    w.u32(0, [(0x5f, 0), (0x13, 0), (0, 0), (0, 13)]); // Natural orders
+    code.write_header(w);
+}
+
+/// The HF tokens of one pass group: for each block the count of nonzero AC coefficients, then
+/// the coefficients up to the last nonzero one, in zigzag order.
+fn hf_tokens(jpeg: &Jpeg, group_x: usize, group_y: usize) -> Vec<Token> {
+    let geometry = &jpeg.geometry;
+    let (blocks_x, blocks_y) = geometry.blocks();
+    let padded_x = shift_size(blocks_x, geometry.padded.0, false);
+    let padded_y = shift_size(blocks_y, geometry.padded.1, false);
+    let (left, top) = (group_x * GROUP_BLOCKS, group_y * GROUP_BLOCKS);
+    let (width, height) = (
+        (padded_x - left).min(GROUP_BLOCKS),
+        (padded_y - top).min(GROUP_BLOCKS),
+    );
+
+    // JPEG XL codes a block's coefficients in the transpose of JPEG's zigzag order.
+    let coded_order: [usize; 64] = std::array::from_fn(|k| {
+        let transposed = ZIGZAG[k] % 8 * 8 + ZIGZAG[k] / 8;
+        ZIGZAG.iter().position(|&p| p == transposed).unwrap_or(k)
+    });
+    let mut tokens = Vec::new();
+    let mut nonzero_rows: [Vec<usize>; 3] =
+        std::array::from_fn(|c| vec![0; geometry.channel_size(c, (width, height)).0]);
+    for y in 0..height {
+        for x in 0..width {
+            for (c, row) in nonzero_rows.iter_mut().enumerate() {
+                let (h_shift, v_shift) = geometry.shifts[c];
+                let (sx, sy) = (x >> h_shift as usize, y >> v_shift as usize);
+                if sx << h_shift as usize != x || sy << v_shift as usize != y {
+                    continue;
+                }
+                let block = jpeg.block(
+                    c,
+                    (left >> h_shift as usize) + sx,
+                    (top >> v_shift as usize) + sy,
+                );
+                let nonzeros = block[1..].iter().filter(|&&v| v != 0).count();
+                let predicted = match (sx, sy) {
+                    (0, 0) => 32,
+                    (_, 0) => row[sx - 1],
+                    (0, _) => row[sx],
+                    _ => (row[sx] + row[sx - 1]).div_ceil(2),
+                };
+                let index = match predicted {
+                    0..8 => predicted,
+                    _ => 4 + predicted / 2,
+                };
+                tokens.push(Token {
+                    context: BLOCK_CONTEXTS[c] + index * NUM_BLOCK_CONTEXTS,
+                    value: nonzeros as u32,
+                });
+                row[sx] = nonzeros;
+
+                let base = BLOCK_CONTEXTS[c] * 458 + 37 * NUM_BLOCK_CONTEXTS;
+                let mut remaining = nonzeros;
+                let mut previous = (nonzeros <= 4) as usize;
+                for (k, &position) in coded_order[1..].iter().enumerate() {
+                    if remaining == 0 {
+                        break;
+                    }
+                    let value = block[position];
+                    let context = COEFF_NUM_NONZERO_CONTEXT[remaining - 1] + COEFF_FREQ_CONTEXT[k];
+                    tokens.push(Token {
+                        context: base + context * 2 + previous,
+                        value: pack_signed(value as i32),
+                    });
+                    previous = (value != 0) as usize;
+                    remaining -= previous;
+                }
+            }
+        }
+    }
+    tokens
+}
+
+fn ceil_log2(value: usize) -> u32 {
+    value.next_power_of_two().trailing_zeros()
+}
+
+fn pack_signed(value: i32) -> u32 {
+    match value >= 0 {
+        true => value as u32 * 2,
+        false => value.unsigned_abs() * 2 - 1,
+    }
+}
+
+/// A modular channel's samples, row by row.
+struct Channel {
+    width: usize,
+    samples: Vec<i32>,
+}
+
+#[derive(Clone, Copy)]
+enum Predictor {
+    Zero = 0,
+    Gradient = 5,
+}
+
+/// Writes a modular image with no transforms and a one-leaf tree, so every sample is coded as
+/// its residual from `predictor` in a single context.
+fn write_modular(w: &mut BitWriter, channels: &[Channel], predictor: Predictor) {
+    w.flag(false); // Own tree
+    w.flag(true); // Default weighted predictor
+    w.write(2, 0); // No transforms
+
+    // The leaf: no property to split on, the predictor, offset 0 and multiplier 1.
+    let tree: Vec<Token> = [0, predictor as u32, 0, 0, 0]
+        .iter()
+        .enumerate()
+        .map(|(i, &value)| Token {
+            context: i + 1,
+            value,
+        })
+        .collect();
+    let code = EntropyCode::new(&tree, 6);
+    code.write_header(w);
+    code.write_tokens(w, &tree);
+
+    let mut residuals = Vec::new();
+    for channel in channels {
+        let width = channel.width;
+        let samples = &channel.samples;
+        for (i, &sample) in samples.iter().enumerate() {
+            let prediction = match predictor {
+                Predictor::Zero => 0,
+                Predictor::Gradient => {
+                    let (x, y) = (i % width, i / width);
+                    let left = match (x, y) {
+                        (0, 0) => 0,
+                        (0, _) => samples[i - width],
+                        _ => samples[i - 1],
+                    };
+                    let top = if y > 0 { samples[i - width] } else { left };
+                    let top_left = match x > 0 && y > 0 {
+                        true => samples[i - width - 1],
+                        false => left,
+                    };
+                    (top + left - top_left).clamp(top.min(left), top.max(left))
+                }
+            };
+            residuals.push(Token {
+                context: 0,
+                value: pack_signed(sample - prediction),
+            });
+        }
+    }
+    let code = EntropyCode::new(&residuals, 1);
+    code.write_header(w);
+    code.write_tokens(w, &residuals);
+}
+
+/// A value and the context it is coded in.
+#[derive(Clone, Copy)]
+struct Token {
+    context: usize,
+    value: u32,
+}
+
+/// Splits a value into a symbol and raw bits with the hybrid integer config every cluster
+/// uses: 16 direct symbols, then two mantissa bits in the symbol. Returns the symbol, the bit
+/// count and the bits.
+fn hybrid(value: u32) -> (usize, u32, usize) {
+    if value < 16 {
+        return (value as usize, 0, 0);
+    }
+    let n = 31 - value.leading_zeros();
+    let symbol = 16 + ((n - 4) << 2) + ((value >> (n - 2)) & 3);
+    (
+        symbol as usize,
+        n - 2,
+        (value & ((1 << (n - 2)) - 1)) as usize,
+    )
+}
+
+fn write_hybrid_config(w: &mut BitWriter) {
+    w.write(4, 4); // Split exponent
+    w.write(3, 2); // Mantissa bits in the symbol
+    w.write(2, 0); // Low bits in the symbol
+}
+
+/// Prefix codes for a token stream, with the contexts clustered onto shared histograms.
+struct EntropyCode {
+    clusters: Vec<usize>,
+    codes: Vec<PrefixCode>,
+}
+
+impl EntropyCode {
+    fn new(tokens: &[Token], num_contexts: usize) -> Self {
+        let mut histograms = vec![Vec::new(); num_contexts];
+        for token in tokens {
+            let histogram: &mut Vec<u32> = &mut histograms[token.context];
+            let symbol = hybrid(token.value).0;
+            if histogram.len() <= symbol {
+                histogram.resize(symbol + 1, 0);
+            }
+            histogram[symbol] += 1;
+        }
+        let (clusters, merged) = cluster(&histograms);
+        EntropyCode {
+            clusters,
+            codes: merged.iter().map(|h| PrefixCode::new(h)).collect(),
+        }
+    }
+
+    fn write_header(&self, w: &mut BitWriter) {
+        w.flag(false); // No LZ77
+        write_context_map(w, &self.clusters);
+        w.flag(true); // Prefix codes
+        self.codes.iter().for_each(|_| write_hybrid_config(w));
+        self.codes.iter().for_each(|code| code.write_count(w));
+        self.codes.iter().for_each(|code| code.write_lengths(w));
+    }
+
+    fn write_tokens(&self, w: &mut BitWriter, tokens: &[Token]) {
+        for token in tokens {
+            let (symbol, bits, extra) = hybrid(token.value);
+            self.codes[self.clusters[token.context]].write_symbol(w, symbol);
+            w.write(bits, extra);
+        }
+    }
+}
+
+/// Greedily clusters histograms, largest first, joining each to the cluster it costs least to
+/// share unless a cluster of its own is cheaper. Unused contexts take the cluster before them,
+/// and clusters are numbered by first use.
+fn cluster(histograms: &[Vec<u32>]) -> (Vec<usize>, Vec<Vec<u32>>) {
+    let total = |h: &[u32]| h.iter().map(|&c| c as u64).sum::<u64>();
+    let mut order: Vec<usize> = (0..histograms.len())
+        .filter(|&c| total(&histograms[c]) > 0)
+        .collect();
+    order.sort_by_key(|&c| Reverse(total(&histograms[c])));
+
+    let mut merged: Vec<Vec<u32>> = Vec::new();
+    let mut assignment = vec![None; histograms.len()];
+    for context in order {
+        let histogram = &histograms[context];
+        let alone = entropy(histogram) + 16.0 + 4.0 * histogram.len() as f64;
+        let best = merged
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (entropy(&add(m, histogram)) - entropy(m), i))
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+        match best {
+            Some((extra, i)) if extra <= alone || merged.len() == MAX_CLUSTERS => {
+                merged[i] = add(&merged[i], histogram);
+                assignment[context] = Some(i);
+            }
+            _ => {
+                assignment[context] = Some(merged.len());
+                merged.push(histogram.clone());
+            }
+        }
+    }
+    if merged.is_empty() {
+        merged.push(Vec::new());
+    }
+
+    let mut labels = vec![None; merged.len()];
+    let mut ordered = Vec::with_capacity(merged.len());
+    let mut previous = 0;
+    let clusters = assignment
+        .into_iter()
+        .map(|cluster| {
+            let cluster = cluster.unwrap_or(previous);
+            previous = cluster;
+            *labels[cluster].get_or_insert_with(|| {
+                ordered.push(std::mem::take(&mut merged[cluster]));
+                ordered.len() - 1
+            })
+        })
+        .collect();
+    (clusters, ordered)
+}
+
+fn add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut sum = vec![0; a.len().max(b.len())];
+    for (i, s) in sum.iter_mut().enumerate() {
+        *s = a.get(i).unwrap_or(&0) + b.get(i).unwrap_or(&0);
+    }
+    sum
+}
+
+/// Bits to code a histogram's symbols with ideal code lengths.
+fn entropy(histogram: &[u32]) -> f64 {
+    let total = histogram.iter().map(|&c| c as f64).sum::<f64>();
+    histogram
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| c as f64 * (total / c as f64).log2())
+        .sum()
+}
+
+/// Writes which cluster each context uses, listed directly when that is short and otherwise
+/// move-to-front coded with runs of repeats.
+fn write_context_map(w: &mut BitWriter, clusters: &[usize]) {
+    if clusters.len() == 1 {
+        return;
+    }
+    let bits = ceil_log2(clusters.iter().max().unwrap_or(&0) + 1);
+    if bits == 0 || (bits <= 3 && clusters.len() <= 16) {
+        w.flag(true);
+        w.write(2, bits as usize);
+        clusters.iter().for_each(|&c| w.write(bits, c));
+        return;
+    }
+    w.flag(false);
+    w.flag(true); // Move to front
+
+    let mut recent: Vec<usize> = (0..256).collect();
+    let values: Vec<u32> = clusters
+        .iter()
+        .map(|&c| {
+            let index = recent.iter().position(|&r| r == c).unwrap_or(0);
+            recent[..=index].rotate_right(1);
+            index as u32
+        })
+        .collect();
+
+    // Symbols from 32 on copy the previous value; the length is coded as a symbol for its bit
+    // count, then the bits, and is followed by a zero distance symbol.
+    const MIN_SYMBOL: usize = 32;
+    const MIN_LENGTH: usize = 3;
+    let mut symbols: Vec<(usize, u32, usize)> = Vec::new();
+    let mut i = 0;
+    while i < values.len() {
+        let run = match i {
+            0 => 0,
+            _ => values[i..]
+                .iter()
+                .take_while(|&&v| v == values[i - 1])
+                .count(),
+        };
+        if run >= MIN_LENGTH {
+            let length = run - MIN_LENGTH;
+            symbols.push(match length {
+                0 => (MIN_SYMBOL, 0, 0),
+                _ => {
+                    let n = length.ilog2();
+                    (MIN_SYMBOL + 1 + n as usize, n, length - (1 << n))
+                }
+            });
+            symbols.push((0, 0, 0));
+            i += run;
+        } else {
+            symbols.push(hybrid(values[i]));
+            i += 1;
+        }
+    }
+    let mut histogram = vec![0; symbols.iter().map(|s| s.0 + 1).max().unwrap_or(1)];
+    symbols.iter().for_each(|s| histogram[s.0] += 1);
+    let code = PrefixCode::new(&histogram);
+
+    w.flag(true); // LZ77
+    w.u32(MIN_SYMBOL, [(224, 0), (512, 0), (4096, 0), (8, 15)]);
+    w.u32(MIN_LENGTH, [(3, 0), (4, 0), (5, 2), (9, 8)]);
+    w.write(4, 0); // Lengths: every symbol after the first is a bit count
+    w.flag(true); // The distance context shares the only histogram
+    w.write(2, 0);
+    w.flag(true); // Prefix codes
+    write_hybrid_config(w);
+    code.write_count(w);
+    code.write_lengths(w);
+    for (symbol, bits, extra) in symbols {
+        code.write_symbol(w, symbol);
+        w.write(bits, extra);
+    }
+}
+
+/// A canonical prefix code of at most 15 bits per symbol.
+struct PrefixCode {
+    lengths: Vec<u8>,
+    /// Codes with their bits reversed, since the bitstream is least significant bit first.
+    codes: Vec<u32>,
+}
+
+impl PrefixCode {
+    fn new(histogram: &[u32]) -> Self {
+        let alphabet = histogram.iter().rposition(|&c| c > 0).map_or(1, |s| s + 1);
+        let histogram = &histogram[..alphabet.min(histogram.len())];
+        let lengths = match histogram.iter().filter(|&&c| c > 0).count() {
+            0 | 1 => vec![0; alphabet],
+            _ => code_lengths(histogram, 15),
+        };
+        let codes = canonical_codes(&lengths);
+        PrefixCode { lengths, codes }
+    }
+
+    fn write_count(&self, w: &mut BitWriter) {
+        let alphabet = self.lengths.len();
+        w.flag(alphabet > 1);
+        if alphabet > 1 {
+            let n = (alphabet - 1).ilog2();
+            w.write(4, n as usize);
+            w.write(n, alphabet - 1 - (1 << n));
+        }
+    }
+
+    fn write_lengths(&self, w: &mut BitWriter) {
+        let alphabet = self.lengths.len();
+        if alphabet == 1 {
+            return;
+        }
+        if self.lengths.iter().all(|&len| len == 0) {
+            // A single symbol, which is the last one.
+            w.write(2, 1);
+            w.write(2, 0);
+            w.write(ceil_log2(alphabet), alphabet - 1);
+            return;
+        }
+
+        const ORDER: [usize; 18] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        let mut histogram = [0; 18];
+        self.lengths
+            .iter()
+            .for_each(|&len| histogram[len as usize] += 1);
+        let used = histogram.iter().filter(|&&c| c > 0).count();
+        let length_code = match used {
+            1 => {
+                let mut lengths = [0; 18];
+                lengths[self.lengths[0] as usize] = 1;
+                PrefixCode {
+                    codes: vec![0; 18],
+                    lengths: lengths.to_vec(),
+                }
+            }
+            _ => {
+                let lengths = code_lengths(&histogram, 5);
+                let codes = canonical_codes(&lengths);
+                PrefixCode { lengths, codes }
+            }
+        };
+
+        w.write(2, 0); // Complex code, no lengths skipped
+        let mut space = 0;
+        for &symbol in &ORDER {
+            let len = length_code.lengths[symbol];
+            match len {
+                0 => w.write(2, 0),
+                1 => w.write(4, 0b0111),
+                2 => w.write(3, 0b011),
+                3 => w.write(2, 2),
+                4 => w.write(2, 1),
+                _ => w.write(4, 0b1111),
+            }
+            if len > 0 {
+                space += 32 >> len;
+                if used > 1 && space == 32 {
+                    break;
+                }
+            }
+        }
+        for &len in &self.lengths {
+            match used {
+                1 => {}
+                _ => length_code.write_symbol(w, len as usize),
+            }
+        }
+    }
+
+    fn write_symbol(&self, w: &mut BitWriter, symbol: usize) {
+        w.write(self.lengths[symbol] as u32, self.codes[symbol] as usize);
+    }
+}
+
+/// Huffman code lengths, limited to `limit` bits by flattening the counts until they fit.
+fn code_lengths(histogram: &[u32], limit: u8) -> Vec<u8> {
+    let mut counts = histogram.to_vec();
+    loop {
+        let lengths = huffman_lengths(&counts);
+        if lengths.iter().all(|&len| len <= limit) {
+            return lengths;
+        }
+        counts
+            .iter_mut()
+            .filter(|c| **c > 0)
+            .for_each(|c| *c = c.div_ceil(2));
+    }
+}
+
+fn huffman_lengths(counts: &[u32]) -> Vec<u8> {
+    let mut parents = vec![usize::MAX; counts.len()];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = counts
+        .iter()
+        .enumerate()
+        .filter(|(_, &c)| c > 0)
+        .map(|(symbol, &c)| Reverse((c as u64, symbol)))
+        .collect();
+    while heap.len() > 1 {
+        let Reverse((a, i)) = heap.pop().unwrap_or(Reverse((0, 0)));
+        let Reverse((b, j)) = heap.pop().unwrap_or(Reverse((0, 0)));
+        let node = parents.len();
+        parents.push(usize::MAX);
+        parents[i] = node;
+        parents[j] = node;
+        heap.push(Reverse((a + b, node)));
+    }
+    (0..counts.len())
+        .map(|symbol| {
+            let (mut node, mut depth) = (symbol, 0);
+            while parents[node] != usize::MAX {
+                node = parents[node];
+                depth += 1;
+            }
+            depth
+        })
+        .collect()
+}
+
+/// Assigns canonical codes, shortest first and by symbol within a length, bit-reversed.
+fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut symbols: Vec<usize> = (0..lengths.len()).filter(|&s| lengths[s] > 0).collect();
+    symbols.sort_by_key(|&s| (lengths[s], s));
+    let mut codes = vec![0; lengths.len()];
+    let (mut code, mut previous) = (0u32, 0);
+    for symbol in symbols {
+        let len = lengths[symbol];
+        code <<= len - previous;
+        previous = len;
+        codes[symbol] = code.reverse_bits() >> (32 - len as u32);
+        code += 1;
+    }
+    codes
+}
+
+/// Writes JPEG XL fields, least significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, bits: u32, value: usize) {
+        if bits == 0 {
+            return;
+        }
+        self.buffer |= (value as u64 & ((1 << bits) - 1)) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn flag(&mut self, value: bool) {
+        self.write(1, value as usize);
+    }
+
+    fn zero_pad(&mut self) {
+        if self.count > 0 {
+            self.write(8 - self.count, 0);
+        }
+    }
+
+    /// A `U32` field: a selector for the first of the four (offset, bits) distributions the
+    /// value fits, then the value less the offset.
+    fn u32(&mut self, value: usize, distributions: [(usize, u32); 4]) {
+        let selector = distributions
+            .iter()
+            .position(|&(offset, bits)| value >= offset && value - offset < 1 << bits)
+            .unwrap_or(3);
+        let (offset, bits) = distributions[selector];
+        self.write(2, selector);
+        self.write(bits, value.saturating_sub(offset));
+    }
+
+    fn f16(&mut self, value: f32) {
+        let bits = match value {
+            0.0 => 0,
+            _ => {
+                let bits = value.to_bits();
+                let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+                let mantissa = ((bits & 0x7f_ffff) + 0x1000) >> 13;
+                // Rounding can carry into the exponent.
+                (((exponent as u32) << 10) + mantissa) as usize
+            }
+        };
+        self.write(16, bits);
+    }
+
+    fn append(&mut self, other: &BitWriter) {
+        other
+            .bytes
+            .iter()
+            .for_each(|&byte| self.write(8, byte as usize));
+        self.write(other.count, other.buffer as usize);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.zero_pad();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jxl::{jxl_info, recompress_jpeg, reconstruct_jpeg};
+    use image::{codecs::jpeg::JpegEncoder, ExtendedColorType};
+
+    fn encode_jpeg(width: u32, height: u32) -> Vec<u8> {
+        let samples: Vec<u8> = (0..width * height * 3)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 80)
+            .encode(&samples, width, height, ExtendedColorType::Rgb8)
+            .unwrap();
+        jpeg
+    }
+
+    /// Inserts a segment right after the start marker.
+    fn with_segment(jpeg: &[u8], marker: u8, payload: &[u8]) -> Vec<u8> {
+        let length = (payload.len() as u16 + 2).to_be_bytes();
+        [&jpeg[..2], &[0xff, marker], &length, payload, &jpeg[2..]].concat()
+    }
+
+    #[test]
+    fn keeps_exif_in_a_box_and_reads_its_orientation() {
+        let mut exif = b"Exif\0\0II*\0\x08\0\0\0\x01\0".to_vec();
+        exif.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0]);
+        let jpeg = with_segment(&encode_jpeg(24, 16), 0xe1, &exif);
+        let jxl = recompress_jpeg(&jpeg).unwrap();
+        let info = jxl_info(&jxl).unwrap();
+        assert_eq!((info.orientation, info.width, info.height), (6, 16, 24));
+        assert!(jxl.windows(4).any(|w| w == b"Exif"));
+        assert_eq!(reconstruct_jpeg(&jxl).unwrap(), jpeg);
+    }
+
+    #[test]
+    fn keeps_comments_and_trailing_bytes() {
+        let mut jpeg = with_segment(&encode_jpeg(9, 9), 0xfe, b"a comment");
+        jpeg.extend_from_slice(b"trailing data");
+        assert_eq!(
+            reconstruct_jpeg(&recompress_jpeg(&jpeg).unwrap()).unwrap(),
+            jpeg
+        );
+    }
+
+    #[test]
+    fn truncated_and_damaged_files_are_errors() {
+        let jpeg = encode_jpeg(40, 24);
+        for len in 0..jpeg.len() - 2 {
+            assert!(
+                recompress(&jpeg[..len]).is_err(),
+                "{} bytes recompressed",
+                len
+            );
+        }
+        let mut damaged = jpeg.clone();
+        let scan = jpeg.windows(2).position(|w| w == [0xff, 0xda]).unwrap();
+        damaged[scan + 20..scan + 40].fill(0xff);
+        assert_eq!(
+            recompress(&damaged).unwrap_err().code,
+            ErrorCode::CorruptData
+        );
+        assert_eq!(
+            recompress(b"not a jpeg").unwrap_err().code,
+            ErrorCode::CorruptData
+        );
+    }
+
+    #[test]
+    fn refuses_what_jpeg_xl_cannot_store() {
+        let jpeg = encode_jpeg(16, 16);
+        let frame = jpeg.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        let unsupported = |edit: &dyn Fn(&mut Vec<u8>)| {
+            let mut data = jpeg.clone();
+            edit(&mut data);
+            recompress(&data).unwrap_err().code
+        };
+        // 12-bit samples, arithmetic coding and an Adobe segment marking RGB.
+        assert_eq!(
+            unsupported(&|d| d[frame + 4] = 12),
+            ErrorCode::UnsupportedFormat
+        );
+        assert_eq!(
+            unsupported(&|d| d[frame + 1] = 0xc9),
+            ErrorCode::UnsupportedFormat
+        );
+        let adobe = with_segment(&jpeg, 0xee, b"Adobe\0\x64\0\0\0\0\0");
+        assert_eq!(
+            recompress(&adobe).unwrap_err().code,
+            ErrorCode::UnsupportedFormat
+        );
+    }
+
+    #[test]
+    fn prefix_codes_are_canonical_and_length_limited() {
+        let code = PrefixCode::new(&[5, 0, 1, 1, 3]);
+        assert_eq!(code.lengths, [1, 0, 3, 3, 2]);
+        // 0, 110, 111 and 10, each written bit-reversed.
+        assert_eq!(code.codes, [0b0, 0, 0b011, 0b111, 0b01]);
+
+        let fibonacci: Vec<u32> = (0..30)
+            .scan((1, 1), |(a, b), _| {
+                (*a, *b) = (*b, *a + *b);
+                Some(*a)
+            })
+            .collect();
+        assert_eq!(huffman_lengths(&fibonacci).iter().max(), Some(&29));
+        assert!(code_lengths(&fibonacci, 15)
+            .iter()
+            .all(|&len| (1..=15).contains(&len)));
+    }
+
+    #[test]
+    fn hybrid_integers_split_into_symbols_and_bits() {
+        assert_eq!(hybrid(15), (15, 0, 0));
+        assert_eq!(hybrid(16), (16, 2, 0));
+        assert_eq!(hybrid(0b1101_0110), (16 + (3 << 2) + 0b10, 5, 0b10110));
+    }
+}
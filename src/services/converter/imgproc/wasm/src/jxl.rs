@@ -0,0 +1,535 @@
+use image::{DynamicImage, ImageBuffer, Rgba};
+use jxl_oxide::{
+    image::BitDepth, AllocTracker, FrameBufferSample, ImageStream, JpegReconstructionStatus,
+    JxlImage,
+};
+use std::{borrow::Cow, fmt::Display, vec::IntoIter};
+
+use crate::{
+    animation_decoder::{DecodedFrame, FrameDecoder},
+    bmff::Boxes,
+    callback_logs::{CallbackLogs, ProgressEvent, Stage},
+    cancellation::Cancellation,
+    error::{ErrorCode, ImgprocError, Result},
+    first_frame::encode_png,
+    jpeg_recompress,
+    limits::{current_limits, DecodeBudget},
+};
+
+const CODESTREAM_SIGNATURE: [u8; 2] = [0xff, 0x0a];
+pub(crate) const CONTAINER_SIGNATURE: [u8; 12] = [
+    0x00, 0x00, 0x00, 0x0c, b'J', b'X', b'L', b' ', 0x0d, 0x0a, 0x87, 0x0a,
+];
+
+/// Ratios of width to height that the size header can encode instead of a width.
+const RATIOS: [(u64, u64); 7] = [(1, 1), (12, 10), (4, 3), (3, 2), (16, 9), (5, 4), (2, 1)];
+
+/// What the headers of a JPEG XL file say, read without entropy decoding.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JxlInfo {
+    /// Display size, after the orientation is applied.
+    pub width: u32,
+    pub height: u32,
+    /// EXIF-style orientation, 1 to 8.
+    pub orientation: u8,
+    /// A bare codestream has no boxes and so no metadata or reconstruction data.
+    pub is_container: bool,
+    /// A `jbrd` box is present, so the original JPEG file can be rebuilt bit for bit.
+    pub has_jpeg_reconstruction: bool,
+}
+
+/// True for a bare codestream or the ISO BMFF container.
+pub fn is_jxl(image_data: &[u8]) -> bool {
+    image_data.starts_with(&CODESTREAM_SIGNATURE) || image_data.starts_with(&CONTAINER_SIGNATURE)
+}
+
+pub fn jxl_dimensions(image_data: &[u8]) -> Result<(u32, u32)> {
+    let info = jxl_info(image_data)?;
+    Ok((info.width, info.height))
+}
+
+/// Reads the size header and orientation from the codestream, finding it in `jxlc` or
+/// `jxlp` boxes when the file is a container.
+pub fn jxl_info(image_data: &[u8]) -> Result<JxlInfo> {
+    let mut info = JxlInfo::default();
+    let codestream = match image_data.starts_with(&CONTAINER_SIGNATURE) {
+        true => {
+            info.is_container = true;
+            let mut codestream: Option<Cow<[u8]>> = None;
+            for entry in Boxes::new(image_data, 0, "JXL") {
+                let (kind, body, _) = entry?;
+                match kind {
+                    b"jbrd" => info.has_jpeg_reconstruction = true,
+                    b"jxlc" => codestream = Some(Cow::Borrowed(body)),
+                    // Partial codestream boxes start with a 4-byte sequence number.
+                    b"jxlp" => {
+                        let part = body.get(4..).unwrap_or_default();
+                        match &mut codestream {
+                            Some(data) => data.to_mut().extend_from_slice(part),
+                            None => codestream = Some(Cow::Owned(part.to_vec())),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            codestream.ok_or_else(|| corrupt("The JPEG XL container has no codestream.", 0))?
+        }
+        false => Cow::Borrowed(image_data),
+    };
+
+    if !codestream.starts_with(&CODESTREAM_SIGNATURE) {
+        return Err(corrupt("The JPEG XL codestream signature is missing.", 0));
+    }
+    let mut bits = BitReader::new(&codestream[2..]);
+    let (width, height) = read_size_header(&mut bits)?;
+
+    // ImageMetadata: all_default, then extra_fields, then the orientation.
+    info.orientation = 1;
+    if !bits.flag()? && bits.flag()? {
+        info.orientation = 1 + bits.read(3)? as u8;
+    }
+    (info.width, info.height) = match info.orientation > 4 {
+        true => (height, width),
+        false => (width, height),
+    };
+    Ok(info)
+}
+
+/// True when the image header declares an animation with more than one frame to show.
+pub fn is_animated_jxl(image_data: &[u8]) -> Result<bool> {
+    let image = open_jxl(image_data)?;
+    Ok(image.image_header().metadata.animation.is_some() && image.num_loaded_keyframes() > 1)
+}
+
+/// Decodes the first frame, at 16 bits per sample when the image stores more than 8.
+pub fn decode_jxl(image_data: &[u8]) -> Result<DynamicImage> {
+    let image = open_jxl(image_data)?;
+    render_keyframe(&image, 0)
+}
+
+/// Losslessly recompresses a JPEG file into JPEG XL. The result is only returned once it has
+/// been checked to rebuild the original file byte for byte.
+pub fn recompress_jpeg(image_data: &[u8]) -> Result<Vec<u8>> {
+    let jxl = jpeg_recompress::recompress(image_data)?;
+    match reconstruct_jpeg(&jxl) {
+        Ok(jpeg) if jpeg == image_data => Ok(jxl),
+        _ => Err(ImgprocError::new(
+            ErrorCode::UnsupportedFormat,
+            "The JPEG file cannot be rebuilt exactly from JPEG XL.",
+        )
+        .format("JPEG")),
+    }
+}
+
+/// Rebuilds the JPEG file a JPEG XL file was losslessly recompressed from, byte for byte.
+pub fn reconstruct_jpeg(image_data: &[u8]) -> Result<Vec<u8>> {
+    let image = open_jxl(image_data)?;
+    match image.jpeg_reconstruction_status() {
+        JpegReconstructionStatus::Available => {}
+        JpegReconstructionStatus::Unavailable => {
+            return Err(ImgprocError::new(
+                ErrorCode::UnsupportedFormat,
+                "The JPEG XL file was not recompressed from a JPEG file.",
+            )
+            .format("JXL"))
+        }
+        _ => return Err(corrupt("The JPEG reconstruction data is invalid.", 0)),
+    }
+
+    let mut jpeg = Vec::new();
+    image
+        .reconstruct_jpeg(&mut jpeg)
+        .map_err(|e| decode_error("Failed to reconstruct the JPEG file.", e))?;
+    Ok(jpeg)
+}
+
+/// Parses the file after checking the header against the decode limits. Pixels are only
+/// decoded when a frame is rendered.
+fn open_jxl(image_data: &[u8]) -> Result<JxlImage> {
+    let limits = current_limits();
+    let info = jxl_info(image_data)?;
+    limits.check_dimensions(info.width, info.height, "JXL")?;
+
+    // The decoder keeps a 32-bit float per sample, four times the RGBA budget.
+    let tracker = AllocTracker::with_limit((limits.max_total_bytes() as usize).saturating_mul(4));
+    let image = JxlImage::builder()
+        .alloc_tracker(tracker)
+        .read(image_data)
+        .map_err(|e| decode_error("Failed to read the JPEG XL image.", e))?;
+
+    let frames = image.num_loaded_keyframes();
+    if frames == 0 {
+        return Err(corrupt("The JPEG XL image has no complete frame.", 0));
+    }
+    limits.check_frame_count(frames as u32, "JXL")?;
+    if image.pixel_format().has_black() {
+        return Err(ImgprocError::new(
+            ErrorCode::UnsupportedFormat,
+            "CMYK JPEG XL images are not supported.",
+        )
+        .format("JXL"));
+    }
+    Ok(image)
+}
+
+/// Renders one composited frame with the orientation applied.
+fn render_keyframe(image: &JxlImage, index: usize) -> Result<DynamicImage> {
+    let render = image
+        .render_frame(index)
+        .map_err(|e| decode_error("Failed to decode a frame.", e).frame(index as u32))?;
+    let mut stream = render.stream();
+    let (width, height) = (stream.width(), stream.height());
+    let deep = !matches!(
+        image.image_header().metadata.bit_depth,
+        BitDepth::IntegerSample { bits_per_sample } if bits_per_sample <= 8
+    );
+
+    let image = match deep {
+        true => ImageBuffer::from_raw(width, height, rgba_samples(&mut stream, u16::MAX))
+            .map(DynamicImage::ImageRgba16),
+        false => {
+            ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, rgba_samples(&mut stream, u8::MAX))
+                .map(DynamicImage::ImageRgba8)
+        }
+    };
+    image.ok_or_else(|| {
+        ImgprocError::internal("The decoded frame has the wrong size.").format("JXL")
+    })
+}
+
+/// Reads gray, gray and alpha, RGB or RGBA samples out as RGBA.
+fn rgba_samples<T: FrameBufferSample + Copy>(stream: &mut ImageStream, opaque: T) -> Vec<T> {
+    let channels = stream.channels() as usize;
+    let mut samples =
+        vec![T::default(); stream.width() as usize * stream.height() as usize * channels];
+    stream.write_to_buffer(&mut samples);
+    if channels == 4 {
+        return samples;
+    }
+    samples
+        .chunks_exact(channels)
+        .flat_map(|pixel| match *pixel {
+            [gray] => [gray, gray, gray, opaque],
+            [gray, alpha] => [gray, gray, gray, alpha],
+            [r, g, b, ..] => [r, g, b, opaque],
+            [] => [opaque; 4],
+        })
+        .collect()
+}
+
+/// Decodes every frame of an animated JPEG XL up front, like the other frame decoders.
+pub struct ImgprocJxlDecoder {
+    frames: IntoIter<DecodedFrame>,
+    width: u32,
+    height: u32,
+    num_frames: u32,
+    first_frame: Option<Vec<u8>>,
+}
+
+impl FrameDecoder for ImgprocJxlDecoder {
+    fn new(image_data: &[u8], logs: &CallbackLogs, cancel: &Cancellation) -> Result<Box<Self>> {
+        logs.send(ProgressEvent::new(Stage::Decode, "decode_start").arg("JXL"))?;
+        let image = open_jxl(image_data)?;
+        let mut budget = DecodeBudget::new("JXL");
+
+        // Durations are in ticks of the animation header's tick rate.
+        let (tps_numerator, tps_denominator) = match &image.image_header().metadata.animation {
+            Some(animation) => (
+                animation.tps_numerator as u64,
+                animation.tps_denominator as u64,
+            ),
+            None => (1000, 1),
+        };
+
+        let num_frames = image.num_loaded_keyframes() as u32;
+        let mut frames = Vec::new();
+        let mut first_frame = Vec::new();
+        for index in 0..num_frames {
+            cancel()?;
+            let render = render_keyframe(&image, index as usize)?.into_rgba8();
+            budget.add_frame(render.width(), render.height())?;
+            if index == 0 {
+                first_frame = encode_png(render.clone())?;
+                logs.send(ProgressEvent::new(Stage::Decode, "first_frame_encoded"))?;
+            }
+
+            let ticks = image
+                .frame_header(index as usize)
+                .map_or(0, |header| header.duration as u64);
+            let delay = match tps_numerator {
+                0 => 0,
+                _ => (ticks * 1000 * tps_denominator + tps_numerator / 2) / tps_numerator,
+            };
+            frames.push((render, delay.min(u32::MAX as u64) as u32));
+            logs.send(ProgressEvent::new(Stage::Decode, "decode_frame").step(index + 1, None))?;
+        }
+
+        logs.send(
+            ProgressEvent::new(Stage::Decode, "decode_done")
+                .arg(num_frames)
+                .arg("JXL"),
+        )?;
+
+        Ok(Box::new(ImgprocJxlDecoder {
+            width: image.width(),
+            height: image.height(),
+            frames: frames.into_iter(),
+            num_frames,
+            first_frame: Some(first_frame),
+        }))
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn num_frames(&self) -> u32 {
+        self.num_frames
+    }
+
+    fn next_frame(&mut self) -> Option<DecodedFrame> {
+        self.frames.next()
+    }
+
+    fn first_frame(&mut self) -> Result<Vec<u8>> {
+        match self.first_frame.take() {
+            Some(first_frame) => Ok(first_frame),
+            None => Err(ImgprocError::internal("The first frame was already taken.").format("JXL")),
+        }
+    }
+}
+
+fn read_size_header(bits: &mut BitReader) -> Result<(u32, u32)> {
+    let small = bits.flag()?;
+    let height = match small {
+        true => (bits.read(5)? + 1) * 8,
+        false => read_dimension(bits)?,
+    };
+    let width = match bits.read(3)? {
+        0 if small => (bits.read(5)? + 1) * 8,
+        0 => read_dimension(bits)?,
+        ratio => {
+            let (numerator, denominator) = RATIOS[ratio as usize - 1];
+            (height as u64 * numerator / denominator) as u32
+        }
+    };
+    Ok((width, height))
+}
+
+/// A `U32` field whose four distributions are 9, 13, 18 and 30 bits, offset by one.
+fn read_dimension(bits: &mut BitReader) -> Result<u32> {
+    let count = [9, 13, 18, 30][bits.read(2)? as usize];
+    Ok(bits.read(count)? + 1)
+}
+
+fn decode_error(message: &str, error: impl Display) -> ImgprocError {
+    ImgprocError::corrupt(message).format("JXL").cause(error)
+}
+
+fn corrupt(message: &str, offset: u64) -> ImgprocError {
+    ImgprocError::corrupt(message).format("JXL").offset(offset)
+}
+
+/// Reads the codestream's least-significant-bit-first fields.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, position: 0 }
+    }
+
+    fn read(&mut self, count: u32) -> Result<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self.data.get(self.position / 8).ok_or_else(|| {
+                corrupt(
+                    "The JPEG XL header is truncated.",
+                    2 + (self.position / 8) as u64,
+                )
+            })?;
+            value |= ((byte >> (self.position % 8)) as u32 & 1) << i;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    fn flag(&mut self) -> Result<bool> {
+        Ok(self.read(1)? == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cancellation::never_cancelled,
+        limits::{set_limits, DecodeLimits},
+    };
+    use image::{codecs::jpeg::JpegEncoder, ExtendedColorType};
+    use zune_core::{
+        bit_depth::BitDepth as ZuneDepth, colorspace::ColorSpace, options::EncoderOptions,
+    };
+    use zune_jpegxl::JxlSimpleEncoder;
+
+    /// A lossless JPEG XL codestream of RGB samples, 8 or 16 bits each.
+    fn encode_lossless(width: usize, height: usize, depth: ZuneDepth, samples: &[u8]) -> Vec<u8> {
+        let options = EncoderOptions::new(width, height, ColorSpace::RGB, depth);
+        let mut output = Vec::new();
+        JxlSimpleEncoder::new(samples, options)
+            .encode(&mut output)
+            .unwrap();
+        output
+    }
+
+    fn gradient(width: u32, height: u32) -> Vec<u8> {
+        (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(x * 5) as u8, (y * 7) as u8, (x + y) as u8]
+            })
+            .collect()
+    }
+
+    fn encode_jpeg(width: u32, height: u32, color: ExtendedColorType) -> Vec<u8> {
+        let channels = match color {
+            ExtendedColorType::L8 => 1,
+            _ => 3,
+        };
+        let samples: Vec<u8> = gradient(width, height)
+            .chunks_exact(3)
+            .flat_map(|pixel| pixel[..channels].to_vec())
+            .collect();
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 85)
+            .encode(&samples, width, height, color)
+            .unwrap();
+        jpeg
+    }
+
+    #[test]
+    fn bit_reader_reads_fields_least_significant_bit_first() {
+        let mut bits = BitReader::new(&[0b1010_1101, 0b0000_0011]);
+        assert_eq!(bits.read(3).unwrap(), 0b101);
+        assert!(bits.flag().unwrap());
+        assert_eq!(bits.read(6).unwrap(), 0b11_1010);
+        assert_eq!(bits.read(6).unwrap(), 0);
+        let error = bits.read(1).unwrap_err();
+        assert_eq!(error.code, ErrorCode::CorruptData);
+        assert_eq!(error.byte_offset, Some(4));
+    }
+
+    #[test]
+    fn reads_the_size_header() {
+        // A small 64x64 header, then a 300x200 one with a 3:2 ratio.
+        assert_eq!(jxl_dimensions(&[0xff, 0x0a, 0x4f, 0x00]).unwrap(), (64, 64));
+        let data = encode_lossless(300, 200, ZuneDepth::Eight, &gradient(300, 200));
+        let info = jxl_info(&data).unwrap();
+        assert_eq!((info.width, info.height, info.orientation), (300, 200, 1));
+        assert!(!info.is_container && !info.has_jpeg_reconstruction);
+        assert!(jxl_info(&data[..3]).is_err());
+        assert!(jxl_info(&CONTAINER_SIGNATURE).is_err());
+    }
+
+    #[test]
+    fn decodes_lossless_stills_exactly() {
+        let samples = gradient(37, 21);
+        let decoded = decode_jxl(&encode_lossless(37, 21, ZuneDepth::Eight, &samples)).unwrap();
+        let DynamicImage::ImageRgba8(decoded) = decoded else {
+            panic!("8-bit JPEG XL decoded as {:?}", decoded.color());
+        };
+        assert_eq!(decoded.dimensions(), (37, 21));
+        for (pixel, expected) in decoded.pixels().zip(samples.chunks_exact(3)) {
+            assert_eq!(&pixel.0, &[expected[0], expected[1], expected[2], 255]);
+        }
+        assert!(
+            !is_animated_jxl(&encode_lossless(8, 8, ZuneDepth::Eight, &gradient(8, 8))).unwrap()
+        );
+    }
+
+    #[test]
+    fn keeps_16_bit_samples() {
+        let samples: Vec<u16> = (0..16 * 4 * 3).map(|i| (i * 1021) as u16).collect();
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_ne_bytes()).collect();
+        let decoded = decode_jxl(&encode_lossless(16, 4, ZuneDepth::Sixteen, &bytes)).unwrap();
+        let DynamicImage::ImageRgba16(decoded) = decoded else {
+            panic!("16-bit JPEG XL decoded as {:?}", decoded.color());
+        };
+        for (pixel, expected) in decoded.pixels().zip(samples.chunks_exact(3)) {
+            assert_eq!(&pixel.0[..3], expected);
+        }
+    }
+
+    #[test]
+    fn frame_decoder_yields_the_still() {
+        let data = encode_lossless(12, 10, ZuneDepth::Eight, &gradient(12, 10));
+        let logs = CallbackLogs::silent();
+        let mut decoder = ImgprocJxlDecoder::new(&data, &logs, &never_cancelled()).unwrap();
+        assert_eq!(
+            (decoder.width(), decoder.height(), decoder.num_frames()),
+            (12, 10, 1)
+        );
+        assert!(decoder.first_frame().unwrap().starts_with(b"\x89PNG"));
+        let (frame, _) = decoder.next_frame().unwrap();
+        assert_eq!(frame.dimensions(), (12, 10));
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn truncated_and_oversized_files_are_errors() {
+        let data = encode_lossless(40, 30, ZuneDepth::Eight, &gradient(40, 30));
+        for len in (0..data.len()).step_by(11) {
+            assert!(decode_jxl(&data[..len]).is_err(), "{} bytes decoded", len);
+        }
+        crate::formats::tests::decode_truncations(&data, "jxl");
+
+        let mut limits = DecodeLimits::default();
+        limits.set_max_width(32);
+        set_limits(limits);
+        let error = decode_jxl(&data).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+    }
+
+    #[test]
+    fn recompressed_jpeg_files_rebuild_exactly() {
+        let cases = [
+            (8, 8, ExtendedColorType::L8),
+            (37, 23, ExtendedColorType::Rgb8),
+            (333, 275, ExtendedColorType::L8),
+            (300, 517, ExtendedColorType::Rgb8),
+        ];
+        for (width, height, color) in cases {
+            let jpeg = encode_jpeg(width, height, color);
+            let jxl = recompress_jpeg(&jpeg).unwrap();
+            let info = jxl_info(&jxl).unwrap();
+            assert!(info.is_container && info.has_jpeg_reconstruction);
+            assert_eq!((info.width, info.height), (width, height));
+            assert_eq!(reconstruct_jpeg(&jxl).unwrap(), jpeg);
+
+            // The frame itself shows the JPEG's pixels, give or take the IDCT.
+            let expected = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+            let rendered = decode_jxl(&jxl).unwrap().to_rgb8();
+            let error = expected
+                .as_raw()
+                .iter()
+                .zip(rendered.as_raw())
+                .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+                .sum::<f64>()
+                / expected.as_raw().len() as f64;
+            assert!(error < 2.0, "{width}x{height}: mean squared error {error}");
+        }
+    }
+
+    #[test]
+    fn only_recompressed_files_can_be_reconstructed() {
+        let data = encode_lossless(8, 8, ZuneDepth::Eight, &gradient(8, 8));
+        let error = reconstruct_jpeg(&data).unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnsupportedFormat);
+    }
+}
@@ -0,0 +1,330 @@
+use image::ImageDecoder;
+use std::cell::Cell;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::error::{ErrorCode, ImgprocError, Result};
+
+/// Bounds applied to every decode, checked against headers before pixel buffers are
+/// allocated. Configure with `WasmSetDecodeLimits`.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug)]
+pub struct DecodeLimits {
+    max_width: u32,
+    max_height: u32,
+    max_pixels: u32,
+    max_frames: u32,
+    max_total_bytes: u32,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_width: 16384,
+            max_height: 16384,
+            max_pixels: 100_000_000,
+            max_frames: 4096,
+            max_total_bytes: 1 << 30,
+        }
+    }
+}
+
+thread_local! {
+    static LIMITS: Cell<DecodeLimits> = Cell::new(DecodeLimits::default());
+}
+
+pub fn current_limits() -> DecodeLimits {
+    LIMITS.with(|limits| limits.get())
+}
+
+pub fn set_limits(limits: DecodeLimits) {
+    LIMITS.with(|current| current.set(limits));
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl DecodeLimits {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new() -> DecodeLimits {
+        DecodeLimits::default()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn max_width(&self) -> u32 {
+        self.max_width
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_max_width(&mut self, value: u32) {
+        self.max_width = value;
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn max_height(&self) -> u32 {
+        self.max_height
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_max_height(&mut self, value: u32) {
+        self.max_height = value;
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn max_pixels(&self) -> u32 {
+        self.max_pixels
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_max_pixels(&mut self, value: u32) {
+        self.max_pixels = value;
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn max_frames(&self) -> u32 {
+        self.max_frames
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_max_frames(&mut self, value: u32) {
+        self.max_frames = value;
+    }
+
+    /// Sum of the RGBA buffers of all decoded frames.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn max_total_bytes(&self) -> u32 {
+        self.max_total_bytes
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_max_total_bytes(&mut self, value: u32) {
+        self.max_total_bytes = value;
+    }
+}
+
+impl DecodeLimits {
+    pub fn check_dimensions(&self, width: u32, height: u32, format: &str) -> Result<()> {
+        if width == 0 || height == 0 {
+            return Err(ImgprocError::corrupt(format!(
+                "Image is {}x{}, which has no pixels.",
+                width, height
+            ))
+            .format(format));
+        }
+        if width > self.max_width || height > self.max_height {
+            return Err(limit_exceeded(
+                format!(
+                    "Image is {}x{}, larger than the {}x{} limit.",
+                    width, height, self.max_width, self.max_height
+                ),
+                format,
+            ));
+        }
+        let pixels = width as u64 * height as u64;
+        if pixels > self.max_pixels as u64 {
+            return Err(limit_exceeded(
+                format!(
+                    "Image has {} pixels, more than the {} pixel limit.",
+                    pixels, self.max_pixels
+                ),
+                format,
+            ));
+        }
+        if pixels * 4 > self.max_total_bytes as u64 {
+            return Err(limit_exceeded(
+                format!(
+                    "Image needs {} bytes, more than the {} byte limit.",
+                    pixels * 4,
+                    self.max_total_bytes
+                ),
+                format,
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn check_frame_count(&self, frames: u32, format: &str) -> Result<()> {
+        if frames > self.max_frames {
+            return Err(limit_exceeded(
+                format!(
+                    "Image has {} frames, more than the {} frame limit.",
+                    frames, self.max_frames
+                ),
+                format,
+            ));
+        }
+        Ok(())
+    }
+
+    /// The same bounds for decoders from the `image` crate.
+    pub fn image_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_total_bytes as u64);
+        limits
+    }
+}
+
+/// Checks a decoder's header dimensions and hands it the same limits before it decodes pixels.
+pub fn limit_decoder(decoder: &mut impl ImageDecoder, format: &str) -> Result<()> {
+    let limits = current_limits();
+    let (width, height) = decoder.dimensions();
+    limits.check_dimensions(width, height, format)?;
+    decoder
+        .set_limits(limits.image_limits())
+        .map_err(|e| ImgprocError::decode("Failed to apply decode limits.", e).format(format))
+}
+
+/// Counts the frames and bytes of one decode so far against the limits.
+pub struct DecodeBudget<'a> {
+    limits: DecodeLimits,
+    format: &'a str,
+    frames: u32,
+    bytes: u64,
+}
+
+impl<'a> DecodeBudget<'a> {
+    pub fn new(format: &'a str) -> Self {
+        DecodeBudget {
+            limits: current_limits(),
+            format,
+            frames: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn limits(&self) -> &DecodeLimits {
+        &self.limits
+    }
+
+    /// Accounts for one more RGBA frame of the given size, failing if it would exceed a limit.
+    pub fn add_frame(&mut self, width: u32, height: u32) -> Result<()> {
+        self.limits.check_dimensions(width, height, self.format)?;
+        self.limits
+            .check_frame_count(self.frames + 1, self.format)?;
+
+        let bytes = self.bytes + width as u64 * height as u64 * 4;
+        if bytes > self.limits.max_total_bytes as u64 {
+            return Err(limit_exceeded(
+                format!(
+                    "Decoded frames need more than the {} byte limit.",
+                    self.limits.max_total_bytes
+                ),
+                self.format,
+            )
+            .frame(self.frames));
+        }
+
+        self.frames += 1;
+        self.bytes = bytes;
+        Ok(())
+    }
+}
+
+fn limit_exceeded(message: String, format: &str) -> ImgprocError {
+    ImgprocError::new(ErrorCode::LimitExceeded, message).format(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        callback_logs::CallbackLogs, cancellation::never_cancelled, formats::source_format,
+        image_frames::decode_frames,
+    };
+    use image::ImageEncoder;
+
+    fn small_limits() -> DecodeLimits {
+        let mut limits = DecodeLimits::default();
+        limits.set_max_width(100);
+        limits.set_max_height(100);
+        limits.set_max_pixels(5000);
+        limits.set_max_frames(2);
+        limits.set_max_total_bytes(30_000);
+        limits
+    }
+
+    fn code(result: Result<()>) -> Option<ErrorCode> {
+        result.err().map(|e| e.code)
+    }
+
+    #[test]
+    fn check_dimensions_applies_every_bound() {
+        let limits = small_limits();
+        assert_eq!(code(limits.check_dimensions(50, 50, "PNG")), None);
+        assert_eq!(
+            code(limits.check_dimensions(101, 1, "PNG")),
+            Some(ErrorCode::LimitExceeded)
+        );
+        assert_eq!(
+            code(limits.check_dimensions(100, 51, "PNG")),
+            Some(ErrorCode::LimitExceeded)
+        );
+        let mut limits = DecodeLimits::default();
+        limits.set_max_total_bytes(1000);
+        assert_eq!(
+            code(limits.check_dimensions(16, 16, "PNG")),
+            Some(ErrorCode::LimitExceeded)
+        );
+    }
+
+    #[test]
+    fn check_dimensions_rejects_empty_images() {
+        let limits = DecodeLimits::default();
+        assert_eq!(
+            code(limits.check_dimensions(0, 10, "PNG")),
+            Some(ErrorCode::CorruptData)
+        );
+        assert_eq!(
+            code(limits.check_dimensions(10, 0, "PNG")),
+            Some(ErrorCode::CorruptData)
+        );
+    }
+
+    #[test]
+    fn budget_counts_frames_and_bytes() {
+        set_limits(small_limits());
+        let mut budget = DecodeBudget::new("GIF");
+        assert!(budget.add_frame(60, 60).is_ok());
+        assert!(budget.add_frame(60, 60).is_ok());
+        let error = budget.add_frame(10, 10).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+
+        let mut budget = DecodeBudget::new("GIF");
+        assert!(budget.add_frame(80, 60).is_ok());
+        let error = budget.add_frame(80, 60).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+        assert_eq!(error.frame_index, Some(1));
+    }
+
+    /// A 65535x65535 GIF would need 16 GiB; the header alone must be enough to refuse it.
+    #[test]
+    fn huge_gif_header_is_refused_before_decoding() {
+        let mut gif = b"GIF89a\xff\xff\xff\xff\x00\x00\x00".to_vec();
+        gif.extend_from_slice(b",\x00\x00\x00\x00\xff\xff\xff\xff\x00\x02\x02\x44\x01\x00;");
+        let logs = CallbackLogs::silent();
+        let error = decode_frames(&gif, "gif", &logs, &never_cancelled()).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+        let error = source_format("gif")
+            .unwrap()
+            .decode_still(&gif)
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+    }
+
+    #[test]
+    fn huge_png_header_is_refused_before_decoding() {
+        let mut png = Vec::new();
+        image::codecs::png::PngEncoder::new(&mut png)
+            .write_image(&[0; 4], 1, 1, image::ExtendedColorType::Rgba8)
+            .unwrap();
+        set_limits(small_limits());
+        let mut large = Vec::new();
+        image::codecs::png::PngEncoder::new(&mut large)
+            .write_image(&[0; 101 * 4], 101, 1, image::ExtendedColorType::Rgba8)
+            .unwrap();
+        let handler = source_format("png").unwrap();
+        assert!(handler.decode_still(&png).is_ok());
+        let error = handler.decode_still(&large).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+    }
+}
@@ -0,0 +1,580 @@
+//! The lossless (process 14) JPEG used to compress camera raw data, as in DNG tiles and
+//! CR2 files.
+
+use crate::{
+    error::{ErrorCode, ImgprocError, Result},
+    limits::current_limits,
+};
+
+/// A decoded image: `components` samples per pixel, interleaved, row by row.
+pub(crate) struct LosslessImage {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) components: usize,
+    /// Bits per sample.
+    pub(crate) precision: u32,
+    pub(crate) samples: Vec<u16>,
+}
+
+#[derive(Clone, Default)]
+struct HuffmanTable {
+    /// Indexed by code length minus one: the largest code of that length, or -1.
+    max_code: [i32; 16],
+    /// Offset into `values` of the first code of each length, minus that code.
+    offsets: [i32; 16],
+    values: Vec<u8>,
+}
+
+/// Decodes one lossless JPEG stream; `format` labels errors.
+pub(crate) fn decode_lossless_jpeg(data: &[u8], format: &str) -> Result<LosslessImage> {
+    let corrupt = |message: &str| ImgprocError::corrupt(message.to_string()).format(format);
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return Err(corrupt("A lossless JPEG tile has no start marker."));
+    }
+
+    let mut tables: [Option<HuffmanTable>; 4] = Default::default();
+    let mut frame = None;
+    let mut restart_interval = 0;
+    let mut at = 2;
+    loop {
+        // Markers may be padded with any number of 0xFF bytes.
+        while data.get(at) == Some(&0xff) && data.get(at + 1) == Some(&0xff) {
+            at += 1;
+        }
+        let (Some(0xff), Some(marker)) = (data.get(at), data.get(at + 1).copied()) else {
+            return Err(corrupt("A lossless JPEG tile is truncated."));
+        };
+        let len = data
+            .get(at + 2..at + 4)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+            .ok_or_else(|| corrupt("A lossless JPEG tile is truncated."))?;
+        let segment = data
+            .get(at + 4..at + 2 + len.max(2))
+            .ok_or_else(|| corrupt("A lossless JPEG tile is truncated."))?;
+        at += 2 + len;
+
+        match marker {
+            0xc4 => read_huffman_tables(segment, &mut tables)
+                .ok_or_else(|| corrupt("A lossless JPEG Huffman table is malformed."))?,
+            0xc3 => {
+                let header = read_frame(segment)
+                    .ok_or_else(|| corrupt("A lossless JPEG frame header is malformed."))?;
+                if header.subsampled {
+                    return Err(ImgprocError::new(
+                        ErrorCode::UnsupportedFormat,
+                        "Lossless JPEG with subsampled components, as in sRAW, is not supported.",
+                    )
+                    .format(format));
+                }
+                current_limits().check_dimensions(
+                    (header.width * header.components.len()) as u32,
+                    header.height as u32,
+                    format,
+                )?;
+                frame = Some(header);
+            }
+            0xc0..=0xcf => {
+                return Err(corrupt("A raw tile uses lossy JPEG compression."));
+            }
+            0xdd => {
+                restart_interval = segment
+                    .get(0..2)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+                    .unwrap_or(0);
+            }
+            0xda => {
+                let frame: &Frame = frame.as_ref().ok_or_else(|| {
+                    corrupt("A lossless JPEG scan comes before its frame header.")
+                })?;
+                let scan = data.get(at..).unwrap_or_default();
+                return decode_scan(frame, segment, scan, &tables, restart_interval)
+                    .ok_or_else(|| corrupt("A lossless JPEG scan is malformed."));
+            }
+            0xd9 => return Err(corrupt("A lossless JPEG tile has no scan.")),
+            _ => {}
+        }
+    }
+}
+
+struct Frame {
+    precision: u32,
+    width: usize,
+    height: usize,
+    components: Vec<u8>,
+    /// Whether any component has sampling factors other than 1×1.
+    subsampled: bool,
+}
+
+/// The width, height and component count of a lossless JPEG, from its frame header.
+pub(crate) fn lossless_jpeg_size(data: &[u8]) -> Option<(usize, usize, usize)> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut at = 2;
+    loop {
+        if *data.get(at)? != 0xff {
+            return None;
+        }
+        let marker = *data.get(at + 1)?;
+        let len = u16::from_be_bytes([*data.get(at + 2)?, *data.get(at + 3)?]) as usize;
+        match marker {
+            0xc3 => {
+                let frame = read_frame(data.get(at + 4..at + 2 + len.max(2))?)?;
+                return Some((frame.width, frame.height, frame.components.len()));
+            }
+            0xc0..=0xc2 | 0xc5..=0xcb | 0xcd..=0xcf | 0xd9 | 0xda => return None,
+            _ => at += 2 + len,
+        }
+    }
+}
+
+fn read_frame(segment: &[u8]) -> Option<Frame> {
+    let precision = *segment.first()? as u32;
+    let height = u16::from_be_bytes([*segment.get(1)?, *segment.get(2)?]) as usize;
+    let width = u16::from_be_bytes([*segment.get(3)?, *segment.get(4)?]) as usize;
+    let count = *segment.get(5)? as usize;
+    let components = (0..count)
+        .map(|i| segment.get(6 + i * 3).copied())
+        .collect::<Option<Vec<_>>>()?;
+    let sampling = (0..count)
+        .map(|i| segment.get(7 + i * 3).copied())
+        .collect::<Option<Vec<_>>>()?;
+    (2..=16).contains(&precision).then_some(Frame {
+        precision,
+        width,
+        height,
+        components,
+        subsampled: sampling.iter().any(|s| *s != 0x11),
+    })
+}
+
+fn read_huffman_tables(mut segment: &[u8], tables: &mut [Option<HuffmanTable>; 4]) -> Option<()> {
+    while !segment.is_empty() {
+        let id = (segment[0] & 0x0f) as usize;
+        let counts = segment.get(1..17)?;
+        let total: usize = counts.iter().map(|c| *c as usize).sum();
+        let values = segment.get(17..17 + total)?.to_vec();
+
+        let mut table = HuffmanTable {
+            max_code: [-1; 16],
+            offsets: [0; 16],
+            values,
+        };
+        let (mut code, mut index) = (0i32, 0i32);
+        for (len, count) in counts.iter().enumerate() {
+            table.offsets[len] = index - code;
+            if *count > 0 {
+                code += *count as i32;
+                index += *count as i32;
+                table.max_code[len] = code - 1;
+            }
+            code <<= 1;
+        }
+        *tables.get_mut(id)? = Some(table);
+        segment = &segment[17 + total..];
+    }
+    Some(())
+}
+
+fn decode_scan(
+    frame: &Frame,
+    header: &[u8],
+    data: &[u8],
+    tables: &[Option<HuffmanTable>; 4],
+    restart_interval: usize,
+) -> Option<LosslessImage> {
+    let count = *header.first()? as usize;
+    let selected = (0..count)
+        .map(|i| {
+            let id = *header.get(1 + i * 2)?;
+            let table = (*header.get(2 + i * 2)? >> 4) as usize;
+            frame.components.iter().position(|c| *c == id)?;
+            tables.get(table)?.as_ref()
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let predictor = *header.get(1 + count * 2)?;
+    let point_transform = *header.get(3 + count * 2)? as u32 & 0x0f;
+    if count != frame.components.len() || predictor > 7 || point_transform >= frame.precision {
+        return None;
+    }
+    // Restarts are only handled at row boundaries, which is where raw encoders put them.
+    if restart_interval != 0 && !restart_interval.is_multiple_of(frame.width) {
+        return None;
+    }
+
+    let (width, components) = (frame.width, count);
+    let row_len = width * components;
+    let mut samples = vec![0u16; row_len * frame.height];
+    let mut bits = BitReader::new(data);
+    let initial = 1i32 << (frame.precision - point_transform - 1);
+    let mut first_row = 0;
+
+    for y in 0..frame.height {
+        if restart_interval != 0 && y > 0 && (y * width).is_multiple_of(restart_interval) {
+            bits.restart();
+            first_row = y;
+        }
+        for x in 0..width {
+            for (c, table) in selected.iter().enumerate() {
+                let diff = decode_difference(&mut bits, table)?;
+                let at = y * row_len + x * components + c;
+                let left = || samples[at - components] as i32;
+                let above = || samples[at - row_len] as i32;
+                let prediction = match (y == first_row, x == 0) {
+                    (true, true) => initial,
+                    (true, false) => left(),
+                    (false, true) => above(),
+                    (false, false) => {
+                        let corner = samples[at - row_len - components] as i32;
+                        match predictor {
+                            1 => left(),
+                            2 => above(),
+                            3 => corner,
+                            4 => left() + above() - corner,
+                            5 => left() + ((above() - corner) >> 1),
+                            6 => above() + ((left() - corner) >> 1),
+                            7 => (left() + above()) >> 1,
+                            _ => initial,
+                        }
+                    }
+                };
+                samples[at] = (prediction + diff) as u16;
+            }
+        }
+    }
+    Some(LosslessImage {
+        width,
+        height: frame.height,
+        components,
+        precision: frame.precision,
+        samples,
+    })
+}
+
+fn decode_difference(bits: &mut BitReader, table: &HuffmanTable) -> Option<i32> {
+    let mut code = 0i32;
+    let mut category = None;
+    for len in 0..16 {
+        code = (code << 1) | bits.bit() as i32;
+        if code <= table.max_code[len] {
+            category = table
+                .values
+                .get((table.offsets[len] + code) as usize)
+                .copied();
+            break;
+        }
+    }
+    match category? {
+        0 => Some(0),
+        16 => Some(32768),
+        17.. => None,
+        size => {
+            let value = bits.bits(size as u32) as i32;
+            // Values below half the range are negative differences.
+            Some(match value < 1 << (size - 1) {
+                true => value - (1 << size) + 1,
+                false => value,
+            })
+        }
+    }
+}
+
+/// Entropy-coded bits, skipping the zero stuffed after every 0xFF byte. Past the end, or at a
+/// marker, it reads zeros.
+struct BitReader<'a> {
+    data: &'a [u8],
+    at: usize,
+    buffer: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            at: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.count <= 56 {
+            let byte = match self.data.get(self.at) {
+                Some(0xff) if self.data.get(self.at + 1) == Some(&0) => {
+                    self.at += 2;
+                    0xff
+                }
+                Some(0xff) | None => 0,
+                Some(byte) => {
+                    self.at += 1;
+                    *byte
+                }
+            };
+            self.buffer |= (byte as u64) << (56 - self.count);
+            self.count += 8;
+        }
+    }
+
+    fn bit(&mut self) -> u32 {
+        self.bits(1)
+    }
+
+    fn bits(&mut self, count: u32) -> u32 {
+        if count == 0 {
+            return 0;
+        }
+        if self.count < count {
+            self.fill();
+        }
+        let value = (self.buffer >> (64 - count)) as u32;
+        self.buffer <<= count;
+        self.count -= count;
+        value
+    }
+
+    /// Drops the buffered bits and steps over the next RSTn marker.
+    fn restart(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+        while self.at + 1 < self.data.len()
+            && !(self.data[self.at] == 0xff && (0xd0..=0xd7).contains(&self.data[self.at + 1]))
+        {
+            self.at += 1;
+        }
+        self.at = (self.at + 2).min(self.data.len());
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::limits::{set_limits, DecodeLimits};
+
+    /// Encodes samples as a lossless JPEG with predictor 1 and a Huffman table that gives
+    /// every difference category a five-bit code, for raw files built by tests.
+    pub(crate) fn encode_lossless_jpeg(
+        width: usize,
+        height: usize,
+        components: usize,
+        precision: u32,
+        samples: &[u16],
+    ) -> Vec<u8> {
+        let mut out = vec![0xff, 0xd8];
+        let mut segment = |marker: u8, body: &[u8]| {
+            out.extend([0xff, marker]);
+            out.extend(((body.len() + 2) as u16).to_be_bytes());
+            out.extend(body);
+        };
+
+        let mut table = vec![0x00];
+        table.extend((0..16).map(|len| if len == 4 { 17 } else { 0 }));
+        table.extend(0..=16);
+        segment(0xc4, &table);
+        let mut frame = vec![precision as u8];
+        frame.extend((height as u16).to_be_bytes());
+        frame.extend((width as u16).to_be_bytes());
+        frame.push(components as u8);
+        for c in 0..components {
+            frame.extend([c as u8 + 1, 0x11, 0]);
+        }
+        segment(0xc3, &frame);
+        let mut scan = vec![components as u8];
+        for c in 0..components {
+            scan.extend([c as u8 + 1, 0x00]);
+        }
+        scan.extend([1, 0, 0]);
+        segment(0xda, &scan);
+
+        let (mut bits, mut count) = (0u64, 0u32);
+        let mut entropy = Vec::new();
+        let mut put = |value: u32, len: u32, entropy: &mut Vec<u8>| {
+            bits = (bits << len) | value as u64;
+            count += len;
+            while count >= 8 {
+                count -= 8;
+                let byte = (bits >> count) as u8;
+                entropy.push(byte);
+                if byte == 0xff {
+                    entropy.push(0);
+                }
+            }
+        };
+        let row_len = width * components;
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..components {
+                    let at = y * row_len + x * components + c;
+                    let prediction = match (y, x) {
+                        (0, 0) => 1 << (precision - 1),
+                        (_, 0) => samples[at - row_len] as i32,
+                        _ => samples[at - components] as i32,
+                    };
+                    let mut diff = (samples[at] as i32 - prediction).rem_euclid(65536);
+                    if diff > 32768 {
+                        diff -= 65536;
+                    }
+                    let size = match diff {
+                        0 => 0,
+                        32768 => 16,
+                        _ => 32 - diff.unsigned_abs().leading_zeros(),
+                    };
+                    put(size, 5, &mut entropy);
+                    if (1..16).contains(&size) {
+                        let extra = if diff > 0 {
+                            diff
+                        } else {
+                            diff + (1 << size) - 1
+                        };
+                        put(extra as u32, size, &mut entropy);
+                    }
+                }
+            }
+        }
+        put(0x7f, 7, &mut entropy);
+        out.extend(entropy);
+        out.extend([0xff, 0xd9]);
+        out
+    }
+
+    fn noise(len: usize, precision: u32) -> Vec<u16> {
+        let mut state = 0x2545_f491u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> (32 - precision)) as u16
+            })
+            .collect()
+    }
+
+    fn code(result: Result<LosslessImage>) -> Option<ErrorCode> {
+        result.err().map(|error| error.code)
+    }
+
+    #[test]
+    fn encoded_samples_decode_unchanged() {
+        for (width, height, components, precision) in [(1, 1, 1, 8), (17, 9, 2, 12), (5, 7, 4, 16)]
+        {
+            let samples = noise(width * height * components, precision);
+            let data = encode_lossless_jpeg(width, height, components, precision, &samples);
+            assert_eq!(lossless_jpeg_size(&data), Some((width, height, components)));
+            let image = decode_lossless_jpeg(&data, "DNG").unwrap();
+            assert_eq!(
+                (image.width, image.height, image.components, image.precision),
+                (width, height, components, precision)
+            );
+            assert_eq!(image.samples, samples);
+        }
+    }
+
+    #[test]
+    fn every_predictor_decodes_a_known_stream() {
+        // A 2×2 single-component image whose differences are all +1, each coded as category
+        // 1 (00001) followed by the bit 1.
+        // Only the last sample is predicted from more than one neighbour.
+        for (predictor, last) in [(1, 131), (2, 131), (3, 130), (4, 132), (5, 131), (7, 131)] {
+            let mut data = encode_lossless_jpeg(2, 2, 1, 8, &[129, 130, 130, 131]);
+            let sos = data.windows(2).position(|w| w == [0xff, 0xda]).unwrap();
+            data[sos + 7] = predictor;
+            let image = decode_lossless_jpeg(&data, "DNG").unwrap();
+            assert_eq!(
+                image.samples,
+                [129, 130, 130, last],
+                "predictor {}",
+                predictor
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_streams_are_errors() {
+        let data = encode_lossless_jpeg(6, 4, 2, 14, &noise(48, 14));
+        let scan = data.windows(2).position(|w| w == [0xff, 0xda]).unwrap();
+        for len in 0..scan + 12 {
+            assert_eq!(
+                code(decode_lossless_jpeg(&data[..len], "DNG")),
+                Some(ErrorCode::CorruptData),
+                "{} bytes",
+                len
+            );
+        }
+        // Inside the entropy-coded data missing bits read as zeros.
+        for len in scan + 12..data.len() {
+            let _ = decode_lossless_jpeg(&data[..len], "DNG");
+        }
+    }
+
+    #[test]
+    fn malformed_headers_are_errors() {
+        let data = encode_lossless_jpeg(4, 4, 1, 8, &noise(16, 8));
+        let sof = data.windows(2).position(|w| w == [0xff, 0xc3]).unwrap();
+        let sos = data.windows(2).position(|w| w == [0xff, 0xda]).unwrap();
+        let dht = data.windows(2).position(|w| w == [0xff, 0xc4]).unwrap();
+
+        // A point transform as large as the precision.
+        let mut bad = data.clone();
+        bad[sos + 9] = 8;
+        assert_eq!(
+            code(decode_lossless_jpeg(&bad, "DNG")),
+            Some(ErrorCode::CorruptData)
+        );
+
+        // Difference categories above 16.
+        let mut bad = data.clone();
+        for value in &mut bad[dht + 21..dht + 38] {
+            *value = 0xf0;
+        }
+        assert_eq!(
+            code(decode_lossless_jpeg(&bad, "DNG")),
+            Some(ErrorCode::CorruptData)
+        );
+
+        // A precision outside 2..=16.
+        let mut bad = data.clone();
+        bad[sof + 4] = 17;
+        assert_eq!(
+            code(decode_lossless_jpeg(&bad, "DNG")),
+            Some(ErrorCode::CorruptData)
+        );
+
+        // A lossy frame.
+        let mut bad = data.clone();
+        bad[sof + 1] = 0xc0;
+        assert_eq!(
+            code(decode_lossless_jpeg(&bad, "DNG")),
+            Some(ErrorCode::CorruptData)
+        );
+
+        // Subsampled components, as in sRAW.
+        let mut bad = data.clone();
+        bad[sof + 11] = 0x21;
+        assert_eq!(
+            code(decode_lossless_jpeg(&bad, "CR2")),
+            Some(ErrorCode::UnsupportedFormat)
+        );
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected_before_decoding() {
+        let mut limits = DecodeLimits::default();
+        limits.set_max_width(16);
+        set_limits(limits);
+        // 9 pixels of two components is 18 samples a row, over the limit.
+        let data = encode_lossless_jpeg(9, 2, 2, 8, &noise(36, 8));
+        assert_eq!(
+            code(decode_lossless_jpeg(&data, "DNG")),
+            Some(ErrorCode::LimitExceeded)
+        );
+
+        // The size in the header is checked, not the data that follows.
+        let mut data = encode_lossless_jpeg(1, 1, 1, 8, &[0]);
+        let sof = data.windows(2).position(|w| w == [0xff, 0xc3]).unwrap();
+        data[sof + 7..sof + 9].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(
+            code(decode_lossless_jpeg(&data, "DNG")),
+            Some(ErrorCode::LimitExceeded)
+        );
+    }
+}
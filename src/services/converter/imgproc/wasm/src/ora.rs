@@ -0,0 +1,629 @@
+//! OpenRaster (`.ora`, MyPaint and others) and Krita (`.kra`) documents. Both are ZIP
+//! archives that start with a stored `mimetype` entry and hold the flattened image as
+//! `mergedimage.png`; the layer stack is described by `stack.xml` or Krita's `maindoc.xml`.
+
+use image::{ImageFormat, RgbaImage};
+use roxmltree::{Document, Node, ParsingOptions};
+use std::io::{Cursor, Read};
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{
+    error::{ErrorCode, ImgprocError, Result},
+    formats::decode_with_image,
+    limits::current_limits,
+    psd_layers::PsdLayerInfo,
+    xml_nesting::check_nesting,
+};
+
+const ORA_MIME_TYPE: &[u8] = b"image/openraster";
+const KRA_MIME_TYPE: &[u8] = b"application/x-krita";
+
+/// Which of the two archive formats a document is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Ora,
+    Kra,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Ora => "ORA",
+            Kind::Kra => "KRA",
+        }
+    }
+
+    fn stack_file(&self) -> &'static str {
+        match self {
+            Kind::Ora => "stack.xml",
+            Kind::Kra => "maindoc.xml",
+        }
+    }
+}
+
+/// The contents of the leading `mimetype` entry, which both formats store uncompressed.
+fn archive_mime_type(data: &[u8]) -> Option<&[u8]> {
+    if !data.starts_with(b"PK\x03\x04") || data.get(8..10)? != [0, 0] {
+        return None;
+    }
+    let name_len = u16::from_le_bytes(data.get(26..28)?.try_into().ok()?) as usize;
+    let extra_len = u16::from_le_bytes(data.get(28..30)?.try_into().ok()?) as usize;
+    let size = u32::from_le_bytes(data.get(18..22)?.try_into().ok()?) as usize;
+    if data.get(30..30 + name_len)? != b"mimetype" {
+        return None;
+    }
+    let start = 30 + name_len + extra_len;
+    data.get(start..start.checked_add(size)?)
+}
+
+pub fn is_ora(data: &[u8]) -> bool {
+    archive_mime_type(data).is_some_and(|mime| mime.trim_ascii() == ORA_MIME_TYPE)
+}
+
+pub fn is_kra(data: &[u8]) -> bool {
+    archive_mime_type(data).is_some_and(|mime| mime.trim_ascii() == KRA_MIME_TYPE)
+}
+
+fn kind(data: &[u8]) -> Result<Kind> {
+    match (is_ora(data), is_kra(data)) {
+        (true, _) => Ok(Kind::Ora),
+        (_, true) => Ok(Kind::Kra),
+        _ => Err(ImgprocError::corrupt(
+            "Not an OpenRaster or Krita document: the mimetype entry is missing.",
+        )),
+    }
+}
+
+pub fn ora_dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    Archive::open(data, Kind::Ora)?.dimensions()
+}
+
+pub fn kra_dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    Archive::open(data, Kind::Kra)?.dimensions()
+}
+
+pub fn ora_rgba(data: &[u8]) -> Result<RgbaImage> {
+    Archive::open(data, Kind::Ora)?.merged_image()
+}
+
+pub fn kra_rgba(data: &[u8]) -> Result<RgbaImage> {
+    Archive::open(data, Kind::Kra)?.merged_image()
+}
+
+/// Lists the groups, each before the group enclosing it, then the layers from the top of the
+/// stack down, as `list_psd_layers` does. Krita keeps layer pixels in its own tile format, so
+/// its layers are listed with a zero size.
+pub fn list_ora_layers(data: &[u8]) -> Result<Vec<PsdLayerInfo>> {
+    let kind = kind(data)?;
+    let mut archive = Archive::open(data, kind)?;
+    let xml = archive.read_stack()?;
+    let document = archive.parse(&xml)?;
+    let root = archive.stack_root(&document)?;
+
+    let mut stack = Stack {
+        groups: Vec::new(),
+        layers: Vec::new(),
+        group_count: 0,
+    };
+    match kind {
+        Kind::Ora => stack.walk_ora(&mut archive, root, None)?,
+        Kind::Kra => stack.walk_kra(root, None),
+    }
+    stack.groups.append(&mut stack.layers);
+    Ok(stack.groups)
+}
+
+struct Archive<'a> {
+    zip: ZipArchive<Cursor<&'a [u8]>>,
+    kind: Kind,
+}
+
+impl<'a> Archive<'a> {
+    fn open(data: &'a [u8], kind: Kind) -> Result<Self> {
+        let zip = ZipArchive::new(Cursor::new(data)).map_err(|e| {
+            ImgprocError::corrupt(format!("Failed to open the {} archive.", kind.name()))
+                .format(kind.name())
+                .cause(e)
+        })?;
+        Ok(Archive { zip, kind })
+    }
+
+    fn corrupt(&self, message: impl Into<String>) -> ImgprocError {
+        ImgprocError::corrupt(message).format(self.kind.name())
+    }
+
+    /// Reads a whole entry, refusing entries larger than the decode byte limit.
+    fn read(&mut self, name: &str) -> Result<Vec<u8>> {
+        let format = self.kind.name();
+        let limit = current_limits().max_total_bytes() as u64;
+        let mut entry = self
+            .zip
+            .by_name(name)
+            .map_err(|e| entry_error(e, name, format))?;
+        if entry.size() > limit {
+            return Err(ImgprocError::new(
+                ErrorCode::LimitExceeded,
+                format!(
+                    "{} unpacks to {} bytes, more than the {} byte limit.",
+                    name,
+                    entry.size(),
+                    limit
+                ),
+            )
+            .format(format));
+        }
+
+        // The size is only the archive's claim, so the buffer grows with what is read.
+        let mut bytes = Vec::new();
+        entry
+            .by_ref()
+            .take(limit)
+            .read_to_end(&mut bytes)
+            .map_err(|e| read_failed(name, format).cause(e))?;
+        Ok(bytes)
+    }
+
+    fn read_stack(&mut self) -> Result<String> {
+        let bytes = self.read(self.kind.stack_file())?;
+        String::from_utf8(bytes)
+            .map_err(|_| self.corrupt(format!("{} is not UTF-8.", self.kind.stack_file())))
+    }
+
+    fn parse<'x>(&self, xml: &'x str) -> Result<Document<'x>> {
+        check_nesting(xml, self.kind.stack_file(), self.kind.name())?;
+        // Krita writes a DOCTYPE declaration.
+        let options = ParsingOptions {
+            allow_dtd: true,
+            ..ParsingOptions::default()
+        };
+        Document::parse_with_options(xml, options).map_err(|e| {
+            self.corrupt(format!("Failed to parse {}.", self.kind.stack_file()))
+                .cause(e)
+        })
+    }
+
+    /// The `<image>` element of `stack.xml`, or the `<IMAGE>` element of `maindoc.xml`.
+    fn stack_root<'x, 'i>(&self, document: &'x Document<'i>) -> Result<Node<'x, 'i>> {
+        let root = document.root_element();
+        let image = match self.kind {
+            Kind::Ora => Some(root).filter(|node| node.has_tag_name("image")),
+            Kind::Kra => child(root, "IMAGE"),
+        };
+        image.ok_or_else(|| {
+            self.corrupt(format!("{} has no image element.", self.kind.stack_file()))
+        })
+    }
+
+    fn dimensions(&mut self) -> Result<(u32, u32)> {
+        let xml = self.read_stack()?;
+        let document = self.parse(&xml)?;
+        let image = self.stack_root(&document)?;
+        let (width, height) = match self.kind {
+            Kind::Ora => ("w", "h"),
+            Kind::Kra => ("width", "height"),
+        };
+        match (number(image, width), number(image, height)) {
+            (Some(width), Some(height)) => Ok((width, height)),
+            _ => Err(self.corrupt("The document size is missing.")),
+        }
+    }
+
+    fn merged_image(&mut self) -> Result<RgbaImage> {
+        let png = self.read("mergedimage.png")?;
+        decode_with_image(&png, ImageFormat::Png, self.kind.name())
+    }
+
+    /// Width and height from the IHDR chunk of a layer PNG, without inflating the rest.
+    fn png_size(&mut self, name: &str) -> Result<(u32, u32)> {
+        let format = self.kind.name();
+        let mut header = Vec::with_capacity(24);
+        self.zip
+            .by_name(name)
+            .map_err(|e| entry_error(e, name, format))?
+            .take(24)
+            .read_to_end(&mut header)
+            .map_err(|e| read_failed(name, format).cause(e))?;
+        let be_u32 = |at: usize| {
+            let bytes = header.get(at..at + 4)?;
+            Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        match (header.starts_with(b"\x89PNG"), be_u32(16), be_u32(20)) {
+            (true, Some(width), Some(height)) => Ok((width, height)),
+            _ => Err(self.corrupt(format!("{} is not a PNG.", name))),
+        }
+    }
+}
+
+fn entry_error(error: ZipError, name: &str, format: &str) -> ImgprocError {
+    match error {
+        ZipError::FileNotFound => {
+            ImgprocError::corrupt(format!("The archive has no {}.", name)).format(format)
+        }
+        e => read_failed(name, format).cause(e),
+    }
+}
+
+fn read_failed(name: &str, format: &str) -> ImgprocError {
+    ImgprocError::corrupt(format!("Failed to read {} from the archive.", name)).format(format)
+}
+
+struct Stack {
+    groups: Vec<PsdLayerInfo>,
+    layers: Vec<PsdLayerInfo>,
+    group_count: u32,
+}
+
+impl Stack {
+    /// `<stack>` elements are groups and `<layer>` elements point at a PNG by `src`; the
+    /// first child is the top of the stack.
+    fn walk_ora(
+        &mut self,
+        archive: &mut Archive,
+        node: Node,
+        parent_id: Option<u32>,
+    ) -> Result<()> {
+        let stack = match node.has_tag_name("image") {
+            true => child(node, "stack"),
+            false => Some(node),
+        };
+        for item in stack.iter().flat_map(|stack| stack.children()) {
+            let visible = item.attribute("visibility") != Some("hidden");
+            let opacity = item
+                .attribute("opacity")
+                .and_then(|value| value.trim().parse::<f32>().ok())
+                .map_or(255, |value| (value.clamp(0.0, 1.0) * 255.0).round() as u8);
+            let blend_mode = match item.attribute("composite-op").unwrap_or("svg:src-over") {
+                "svg:src-over" => "normal".to_string(),
+                op => op.split(':').next_back().unwrap_or(op).replace('-', "_"),
+            };
+
+            if item.has_tag_name("stack") {
+                let id = self.group_count;
+                self.group_count += 1;
+                self.walk_ora(archive, item, Some(id))?;
+                self.groups
+                    .push(group(id, parent_id, item, visible, opacity, blend_mode));
+            } else if item.has_tag_name("layer") {
+                let (width, height) = match item.attribute("src") {
+                    Some(src) => archive.png_size(src)?,
+                    None => (0, 0),
+                };
+                self.layers.push(PsdLayerInfo {
+                    kind: "layer",
+                    id: self.layers.len() as u32,
+                    parent_id,
+                    name: item.attribute("name").unwrap_or_default().to_string(),
+                    visible,
+                    opacity,
+                    blend_mode,
+                    left: signed(item, "x"),
+                    top: signed(item, "y"),
+                    width,
+                    height,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Krita nests a `<layers>` list in `<IMAGE>` and in every `nodetype="grouplayer"`.
+    fn walk_kra(&mut self, node: Node, parent_id: Option<u32>) {
+        for item in child(node, "layers")
+            .iter()
+            .flat_map(|layers| layers.children())
+            .filter(|item| item.has_tag_name("layer"))
+        {
+            let visible = item.attribute("visible") != Some("0");
+            let opacity = number(item, "opacity").map_or(255, |value| value.min(255) as u8);
+            let blend_mode = item
+                .attribute("compositeop")
+                .unwrap_or("normal")
+                .replace('-', "_");
+
+            if item.attribute("nodetype") == Some("grouplayer") {
+                let id = self.group_count;
+                self.group_count += 1;
+                self.walk_kra(item, Some(id));
+                self.groups
+                    .push(group(id, parent_id, item, visible, opacity, blend_mode));
+            } else {
+                self.layers.push(PsdLayerInfo {
+                    kind: "layer",
+                    id: self.layers.len() as u32,
+                    parent_id,
+                    name: item.attribute("name").unwrap_or_default().to_string(),
+                    visible,
+                    opacity,
+                    blend_mode,
+                    left: signed(item, "x"),
+                    top: signed(item, "y"),
+                    width: 0,
+                    height: 0,
+                });
+            }
+        }
+    }
+}
+
+fn group(
+    id: u32,
+    parent_id: Option<u32>,
+    node: Node,
+    visible: bool,
+    opacity: u8,
+    blend_mode: String,
+) -> PsdLayerInfo {
+    PsdLayerInfo {
+        kind: "group",
+        id,
+        parent_id,
+        name: node.attribute("name").unwrap_or_default().to_string(),
+        visible,
+        opacity,
+        blend_mode,
+        left: 0,
+        top: 0,
+        width: 0,
+        height: 0,
+    }
+}
+
+fn child<'x, 'i>(node: Node<'x, 'i>, name: &str) -> Option<Node<'x, 'i>> {
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+fn number(node: Node, name: &str) -> Option<u32> {
+    node.attribute(name)?.trim().parse().ok()
+}
+
+fn signed(node: Node, name: &str) -> i32 {
+    node.attribute(name)
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use image::Rgba;
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+    use crate::{
+        first_frame::encode_png,
+        limits::{set_limits, DecodeLimits},
+        xml_nesting::MAX_NESTING,
+    };
+
+    /// A stored `mimetype` entry first, then the entries deflated.
+    fn archive(mime: &[u8], entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("mimetype", stored).unwrap();
+        zip.write_all(mime).unwrap();
+        for (name, data) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn png(width: u32, height: u32, color: [u8; 4]) -> Vec<u8> {
+        encode_png(RgbaImage::from_pixel(width, height, Rgba(color))).unwrap()
+    }
+
+    const ORA_STACK: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<image version="0.0.5" w="4" h="3">
+  <stack>
+    <layer name="top" src="data/top.png" x="1" y="-2" opacity="0.5"
+      composite-op="svg:color-dodge"/>
+    <stack name="group" visibility="hidden">
+      <layer name="inner" src="data/inner.png"/>
+      <stack name="nested"/>
+    </stack>
+  </stack>
+</image>"#;
+
+    const KRA_STACK: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE DOC PUBLIC '-//KDE//DTD krita 2.0//EN' 'http://www.calligra.org/DTD/krita-2.0.dtd'>
+<DOC syntaxVersion="2">
+  <IMAGE width="5" height="6" name="drawing">
+    <layers>
+      <layer name="folder" nodetype="grouplayer" visible="0" opacity="128">
+        <layers>
+          <layer name="paint" nodetype="paintlayer" x="3" y="4" compositeop="multiply"/>
+        </layers>
+      </layer>
+      <layer name="background" nodetype="paintlayer"/>
+    </layers>
+  </IMAGE>
+</DOC>"#;
+
+    fn ora(stack: &str) -> Vec<u8> {
+        let (top, inner) = (png(2, 1, [0; 4]), png(1, 1, [0; 4]));
+        let merged = png(4, 3, [10, 20, 30, 255]);
+        archive(
+            ORA_MIME_TYPE,
+            &[
+                ("stack.xml", stack.as_bytes()),
+                ("mergedimage.png", &merged),
+                ("data/top.png", &top),
+                ("data/inner.png", &inner),
+            ],
+        )
+    }
+
+    fn kra(stack: &str) -> Vec<u8> {
+        let merged = png(5, 6, [1, 2, 3, 4]);
+        archive(
+            KRA_MIME_TYPE,
+            &[
+                ("maindoc.xml", stack.as_bytes()),
+                ("mergedimage.png", &merged),
+            ],
+        )
+    }
+
+    type Summary<'a> = (&'a str, u32, Option<u32>, &'a str, bool, u8, &'a str);
+
+    fn summary(entries: &[PsdLayerInfo]) -> Vec<Summary<'_>> {
+        entries
+            .iter()
+            .map(|e| {
+                let (name, mode) = (e.name.as_str(), e.blend_mode.as_str());
+                (e.kind, e.id, e.parent_id, name, e.visible, e.opacity, mode)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sniffs_and_decodes_merged_images() {
+        let (ora, kra) = (ora(ORA_STACK), kra(KRA_STACK));
+        assert!(is_ora(&ora) && !is_kra(&ora));
+        assert!(is_kra(&kra) && !is_ora(&kra));
+        assert!(!is_ora(&png(1, 1, [0; 4])));
+
+        assert_eq!(ora_dimensions(&ora).unwrap(), (4, 3));
+        assert_eq!(kra_dimensions(&kra).unwrap(), (5, 6));
+        let image = ora_rgba(&ora).unwrap();
+        assert_eq!(image.dimensions(), (4, 3));
+        assert_eq!(image.get_pixel(3, 2).0, [10, 20, 30, 255]);
+        assert_eq!(kra_rgba(&kra).unwrap().get_pixel(0, 0).0, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn lists_ora_groups_then_layers() {
+        let entries = list_ora_layers(&ora(ORA_STACK)).unwrap();
+        assert_eq!(
+            summary(&entries),
+            [
+                ("group", 1, Some(0), "nested", true, 255, "normal"),
+                ("group", 0, None, "group", false, 255, "normal"),
+                ("layer", 0, None, "top", true, 128, "color_dodge"),
+                ("layer", 1, Some(0), "inner", true, 255, "normal"),
+            ]
+        );
+        let top = &entries[2];
+        assert_eq!((top.left, top.top, top.width, top.height), (1, -2, 2, 1));
+    }
+
+    #[test]
+    fn lists_kra_groups_then_layers() {
+        let entries = list_ora_layers(&kra(KRA_STACK)).unwrap();
+        assert_eq!(
+            summary(&entries),
+            [
+                ("group", 0, None, "folder", false, 128, "normal"),
+                ("layer", 0, Some(0), "paint", true, 255, "multiply"),
+                ("layer", 1, None, "background", true, 255, "normal"),
+            ]
+        );
+        let paint = &entries[1];
+        assert_eq!((paint.left, paint.top, paint.width), (3, 4, 0));
+    }
+
+    #[test]
+    fn rejects_malformed_archives() {
+        let merged = png(1, 1, [0; 4]);
+        let corrupt = [
+            b"PK\x03\x04 not really".to_vec(),
+            archive(ORA_MIME_TYPE, &[("mergedimage.png", &merged)]),
+            archive(ORA_MIME_TYPE, &[("stack.xml", b"<image w='1'")]),
+            archive(ORA_MIME_TYPE, &[("stack.xml", b"<image w='\xff'/>")]),
+            archive(ORA_MIME_TYPE, &[("stack.xml", b"<stack w='1' h='1'/>")]),
+            archive(ORA_MIME_TYPE, &[("stack.xml", b"<image w='1'/>")]),
+            archive(ORA_MIME_TYPE, &[("stack.xml", b"<image w='-1' h='1'/>")]),
+            archive(KRA_MIME_TYPE, &[("maindoc.xml", b"<DOC/>")]),
+        ];
+        for data in &corrupt {
+            for result in [ora_dimensions(data), kra_dimensions(data)] {
+                assert_eq!(result.unwrap_err().code, ErrorCode::CorruptData);
+            }
+        }
+
+        let layers = |src: &str, data: &[u8]| {
+            let stack = format!(
+                "<image w='1' h='1'><stack><layer src='{}'/></stack></image>",
+                src
+            );
+            let data = archive(
+                ORA_MIME_TYPE,
+                &[("stack.xml", stack.as_bytes()), ("a.png", data)],
+            );
+            list_ora_layers(&data).unwrap_err().code
+        };
+        assert_eq!(layers("missing.png", &merged), ErrorCode::CorruptData);
+        assert_eq!(layers("a.png", b"GIF89a"), ErrorCode::CorruptData);
+        assert_eq!(layers("a.png", b"\x89PNG"), ErrorCode::CorruptData);
+
+        let stack = ("stack.xml", ORA_STACK.as_bytes());
+        for merged in [&[][..], b"\x89PNG\r\n\x1a\n", &merged[..merged.len() - 20]] {
+            let data = archive(ORA_MIME_TYPE, &[stack, ("mergedimage.png", merged)]);
+            assert_eq!(ora_rgba(&data).unwrap_err().code, ErrorCode::CorruptData);
+        }
+        let data = archive(ORA_MIME_TYPE, &[stack]);
+        assert_eq!(ora_rgba(&data).unwrap_err().code, ErrorCode::CorruptData);
+        assert_eq!(
+            list_ora_layers(&png(1, 1, [0; 4])).unwrap_err().code,
+            ErrorCode::CorruptData
+        );
+    }
+
+    #[test]
+    fn truncated_archives_fail_without_panicking() {
+        for (data, format) in [(ora(ORA_STACK), "ora"), (kra(KRA_STACK), "kra")] {
+            for len in 0..data.len() {
+                let data = &data[..len];
+                assert!(list_ora_layers(data).is_err(), "length {}", len);
+                assert!(ora_dimensions(data).is_err(), "length {}", len);
+                assert!(kra_rgba(data).is_err(), "length {}", len);
+            }
+            crate::formats::tests::decode_truncations(&data, format);
+        }
+    }
+
+    #[test]
+    fn deep_nesting_and_entities_are_refused() {
+        let nested = |depth| {
+            let stack = format!(
+                "<image w='1' h='1'>{}{}</image>",
+                "<stack>".repeat(depth),
+                "</stack>".repeat(depth)
+            );
+            list_ora_layers(&ora(&stack))
+        };
+        assert_eq!(nested(MAX_NESTING - 1).unwrap().len(), MAX_NESTING - 2);
+
+        let depth = 100_000;
+        assert_eq!(nested(depth).unwrap_err().code, ErrorCode::LimitExceeded);
+
+        let layers = "<layers><layer nodetype='grouplayer'>".repeat(depth);
+        let stack = format!(
+            "<DOC><IMAGE>{}{}</IMAGE></DOC>",
+            layers,
+            "</layer></layers>".repeat(depth)
+        );
+        let error = list_ora_layers(&kra(&stack)).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+
+        let stack = KRA_STACK.replace("dtd'>", "dtd' [<!ENTITY e '<a/>'>]>");
+        let error = list_ora_layers(&kra(&stack)).unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnsupportedFormat);
+    }
+
+    #[test]
+    fn limits_are_checked_before_inflating() {
+        let data = ora(ORA_STACK);
+        let mut limits = DecodeLimits::default();
+        limits.set_max_width(3);
+        set_limits(limits);
+        assert_eq!(ora_rgba(&data).unwrap_err().code, ErrorCode::LimitExceeded);
+
+        let mut limits = DecodeLimits::default();
+        limits.set_max_total_bytes(ORA_STACK.len() as u32 - 1);
+        set_limits(limits);
+        let error = ora_dimensions(&data).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+    }
+}
@@ -0,0 +1,267 @@
+use image::{imageops::FilterType, RgbaImage};
+use std::f32::consts::PI;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::error::{ImgprocError, Result};
+
+const HASH_SIZE: u32 = 8;
+const DCT_SIZE: u32 = 32;
+
+/// The 64-bit average, difference and DCT hashes of a single frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FrameHashes {
+    pub ahash: u64,
+    pub dhash: u64,
+    pub phash: u64,
+}
+
+impl FrameHashes {
+    pub fn new(image: &RgbaImage) -> Self {
+        FrameHashes {
+            ahash: average_hash(image),
+            dhash: difference_hash(image),
+            phash: dct_hash(image),
+        }
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct PerceptualHashes {
+    frames: Vec<FrameHashes>,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl PerceptualHashes {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn ahash(&self) -> String {
+        format_hash(self.frames[0].ahash)
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn dhash(&self) -> String {
+        format_hash(self.frames[0].dhash)
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn phash(&self) -> String {
+        format_hash(self.frames[0].phash)
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn num_frames(&self) -> u32 {
+        self.frames.len() as u32
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn ahash_frames(&self) -> Vec<String> {
+        self.frames.iter().map(|f| format_hash(f.ahash)).collect()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn dhash_frames(&self) -> Vec<String> {
+        self.frames.iter().map(|f| format_hash(f.dhash)).collect()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn phash_frames(&self) -> Vec<String> {
+        self.frames.iter().map(|f| format_hash(f.phash)).collect()
+    }
+}
+
+impl PerceptualHashes {
+    pub fn new(frames: Vec<FrameHashes>) -> Result<Self> {
+        if frames.is_empty() {
+            return Err(ImgprocError::invalid_argument(
+                "Cannot hash an image without frames.",
+            ));
+        }
+        Ok(PerceptualHashes { frames })
+    }
+}
+
+pub fn format_hash(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+/// Up to 16 hex digits, as written by `format_hash`.
+pub fn parse_hash(hash: &str) -> Result<u64> {
+    let digits = hash.trim();
+    let invalid = || ImgprocError::invalid_argument(format!("Invalid perceptual hash '{}'.", hash));
+    if digits.len() > 16 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    u64::from_str_radix(digits, 16).map_err(|e| invalid().cause(e))
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// aHash: each bit tells whether a cell of an 8x8 thumbnail is brighter than the mean.
+pub fn average_hash(image: &RgbaImage) -> u64 {
+    let luma = luma_grid(image, HASH_SIZE, HASH_SIZE);
+    let mean = luma.iter().sum::<f32>() / luma.len() as f32;
+    pack_bits(luma.iter().map(|&v| v > mean))
+}
+
+/// dHash: each bit tells whether a cell of a 9x8 thumbnail is brighter than its right neighbour.
+pub fn difference_hash(image: &RgbaImage) -> u64 {
+    let width = HASH_SIZE + 1;
+    let luma = luma_grid(image, width, HASH_SIZE);
+    let bits = (0..HASH_SIZE).flat_map(|y| {
+        let row = &luma[(y * width) as usize..((y + 1) * width) as usize];
+        (0..HASH_SIZE as usize).map(move |x| row[x] > row[x + 1])
+    });
+    pack_bits(bits)
+}
+
+/// pHash: compares the 8x8 lowest frequencies of a 32x32 DCT against their median,
+/// which makes it robust against rescaling and re-encoding.
+pub fn dct_hash(image: &RgbaImage) -> u64 {
+    let size = DCT_SIZE as usize;
+    let luma = luma_grid(image, DCT_SIZE, DCT_SIZE);
+
+    let cosines: Vec<f32> = (0..size * size)
+        .map(|i| {
+            let (u, x) = (i / size, i % size);
+            ((2 * x + 1) as f32 * u as f32 * PI / (2 * size) as f32).cos()
+        })
+        .collect();
+
+    let hash_size = HASH_SIZE as usize;
+    let mut rows = vec![0f32; size * hash_size];
+    for y in 0..size {
+        for u in 0..hash_size {
+            rows[y * hash_size + u] = (0..size)
+                .map(|x| luma[y * size + x] * cosines[u * size + x])
+                .sum();
+        }
+    }
+
+    let mut coefficients = vec![0f32; hash_size * hash_size];
+    for v in 0..hash_size {
+        for u in 0..hash_size {
+            coefficients[v * hash_size + u] = (0..size)
+                .map(|y| rows[y * hash_size + u] * cosines[v * size + y])
+                .sum();
+        }
+    }
+
+    let mut sorted: Vec<f32> = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+
+    pack_bits(coefficients.iter().map(|&c| c > median))
+}
+
+/// Downscales the image and returns its luminance, compositing transparency over white so
+/// that the same picture hashes alike whether or not it carries an alpha channel.
+fn luma_grid(image: &RgbaImage, width: u32, height: u32) -> Vec<f32> {
+    let resized = image::imageops::resize(image, width, height, FilterType::Triangle);
+    resized
+        .pixels()
+        .map(|p| {
+            let alpha = p[3] as f32 / 255.0;
+            let over_white = |c: u8| c as f32 * alpha + 255.0 * (1.0 - alpha);
+            0.299 * over_white(p[0]) + 0.587 * over_white(p[1]) + 0.114 * over_white(p[2])
+        })
+        .collect()
+}
+
+fn pack_bits(bits: impl Iterator<Item = bool>) -> u64 {
+    bits.fold(0u64, |hash, bit| (hash << 1) | bit as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use image::Rgba;
+
+    /// Luma falling from left to right, with a bright square in the top-left quarter.
+    fn pattern(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            let value = match x < width / 4 && y < height / 4 {
+                true => 255,
+                false => 230 - (x * 200 / width) as u8,
+            };
+            Rgba([value, value, value, 255])
+        })
+    }
+
+    #[test]
+    fn hashes_format_and_parse_back() {
+        for hash in [0, 1, 0x0123_4567_89ab_cdef, u64::MAX] {
+            let text = format_hash(hash);
+            assert_eq!(text.len(), 16);
+            assert_eq!(parse_hash(&text).unwrap(), hash);
+        }
+        assert_eq!(parse_hash(" FF ").unwrap(), 0xff);
+    }
+
+    #[test]
+    fn malformed_hashes_are_errors() {
+        for text in ["", "+1", "-1", "0x12", "g", "00000000000000000", "ff\u{e9}"] {
+            assert_eq!(
+                parse_hash(text).err().map(|e| e.code),
+                Some(ErrorCode::InvalidArgument),
+                "{:?}",
+                text
+            );
+        }
+        assert_eq!(
+            PerceptualHashes::new(Vec::new()).err().map(|e| e.code),
+            Some(ErrorCode::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn known_images_give_known_hashes() {
+        // The right half is brighter than the mean, so each row of the aHash is 0x0f.
+        let halves = RgbaImage::from_fn(64, 64, |x, _| match x < 32 {
+            true => Rgba([0, 0, 0, 255]),
+            false => Rgba([255, 255, 255, 255]),
+        });
+        assert_eq!(average_hash(&halves), 0x0f0f_0f0f_0f0f_0f0f);
+
+        // Every cell is brighter than its right neighbour.
+        let falling = RgbaImage::from_fn(90, 80, |x, _| {
+            let value = 255 - (x * 2) as u8;
+            Rgba([value, value, value, 255])
+        });
+        assert_eq!(difference_hash(&falling), u64::MAX);
+
+        let flat = RgbaImage::from_pixel(40, 40, Rgba([90, 90, 90, 255]));
+        assert_eq!(average_hash(&flat), 0);
+        assert_eq!(difference_hash(&flat), 0);
+    }
+
+    #[test]
+    fn transparency_hashes_like_white() {
+        let clear = RgbaImage::from_pixel(16, 16, Rgba([0, 0, 0, 0]));
+        let white = RgbaImage::from_pixel(16, 16, Rgba([255, 255, 255, 255]));
+        assert_eq!(FrameHashes::new(&clear), FrameHashes::new(&white));
+    }
+
+    #[test]
+    fn rescaled_copies_are_near_duplicates() {
+        let original = FrameHashes::new(&pattern(400, 300));
+        let smaller = FrameHashes::new(&pattern(123, 92));
+        assert!(hamming_distance(original.ahash, smaller.ahash) <= 4);
+        assert!(hamming_distance(original.dhash, smaller.dhash) <= 4);
+        assert!(hamming_distance(original.phash, smaller.phash) <= 4);
+
+        let mirrored = image::imageops::flip_horizontal(&pattern(400, 300));
+        let mirrored = FrameHashes::new(&mirrored);
+        assert!(hamming_distance(original.dhash, mirrored.dhash) > 32);
+        assert!(hamming_distance(original.phash, mirrored.phash) > 10);
+    }
+
+    #[test]
+    fn tiny_images_hash_without_panicking() {
+        for (width, height) in [(0, 0), (1, 1), (1, 50), (50, 1), (3, 2)] {
+            FrameHashes::new(&pattern(width, height));
+        }
+    }
+}
@@ -0,0 +1,974 @@
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    metadata::Orientation,
+    DynamicImage, ImageDecoder, ImageEncoder, ImageFormat, ImageReader, Rgba, RgbaImage,
+};
+use serde::Deserialize;
+use std::{io::Cursor, mem};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    animation_decoder::DecodedFrame,
+    callback_logs::{CallbackLogs, ProgressEvent, Stage},
+    cancellation::Cancellation,
+    convert::ConvertOptions,
+    error::{ImgprocError, Result},
+    formats::{find_format, source_format, target_format, FormatHandler},
+    frame_encode::{encode_indexed_gif, encode_indexed_png, flatten_on_white},
+    image_frames::{decode_deep, decode_detected_frames},
+    probe::sniff_format,
+    quantize::{quantize_frames, Palette, QuantizeOptions},
+    tone_map::{is_high_bit_depth, to_rgba16, to_rgba8, ToneMap},
+};
+
+/// One operation of a recipe, written as `{"op": "resize", "width": 800}`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    /// `format` is sniffed from the data when omitted.
+    Decode {
+        #[serde(default)]
+        format: Option<String>,
+    },
+    /// Applies the EXIF orientation to the pixels and resets the tag.
+    AutoOrient,
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// With one side given the other follows the aspect ratio.
+    Resize {
+        #[serde(default)]
+        width: Option<u32>,
+        #[serde(default)]
+        height: Option<u32>,
+        #[serde(default)]
+        fit: Fit,
+        #[serde(default)]
+        filter: Filter,
+    },
+    /// Drops the ICC profile and EXIF data, which are otherwise carried into the output.
+    StripMetadata,
+    Quantize {
+        #[serde(default = "default_colors")]
+        colors: u32,
+        #[serde(default = "default_dithering")]
+        dithering: bool,
+    },
+    Encode {
+        format: String,
+        #[serde(default)]
+        quality: Option<u8>,
+        /// AVIF encoder speed, 1 to 10.
+        #[serde(default)]
+        speed: Option<u8>,
+        /// PNG bits per channel, 8 or 16.
+        #[serde(default)]
+        bit_depth: Option<u8>,
+        /// How an HDR image is brought into range for a target without floating point.
+        #[serde(default)]
+        tone_map: Option<ToneMap>,
+        /// Stops to scale an HDR image by before tone mapping.
+        #[serde(default)]
+        exposure: Option<f32>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fit {
+    /// Scale to fit inside the box, keeping the aspect ratio.
+    #[default]
+    Contain,
+    /// Scale to cover the box and crop the overflow from the centre.
+    Cover,
+    /// Stretch to exactly the given size.
+    Fill,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl From<Filter> for FilterType {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::CatmullRom => FilterType::CatmullRom,
+            Filter::Gaussian => FilterType::Gaussian,
+            Filter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+fn default_colors() -> u32 {
+    256
+}
+
+fn default_dithering() -> bool {
+    true
+}
+
+impl Step {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::Decode { .. } => "decode",
+            Step::AutoOrient => "auto_orient",
+            Step::Crop { .. } => "crop",
+            Step::Resize { .. } => "resize",
+            Step::StripMetadata => "strip_metadata",
+            Step::Quantize { .. } => "quantize",
+            Step::Encode { .. } => "encode",
+        }
+    }
+
+    fn stage(&self) -> Stage {
+        match self {
+            Step::Decode { .. } => Stage::Decode,
+            Step::AutoOrient | Step::Crop { .. } | Step::Resize { .. } | Step::StripMetadata => {
+                Stage::Transform
+            }
+            Step::Quantize { .. } => Stage::Quantize,
+            Step::Encode { .. } => Stage::Encode,
+        }
+    }
+
+    /// The encoder settings of an `encode` step; the defaults for any other step.
+    fn convert_options(&self) -> ConvertOptions {
+        let mut options = ConvertOptions::default();
+        if let Step::Encode {
+            quality,
+            speed,
+            bit_depth,
+            tone_map,
+            exposure,
+            ..
+        } = self
+        {
+            if let Some(quality) = quality {
+                options.set_quality(*quality);
+            }
+            if let Some(speed) = speed {
+                options.set_speed(*speed);
+            }
+            if let Some(bit_depth) = bit_depth {
+                options.set_bit_depth(*bit_depth);
+            }
+            if let Some(tone_map) = tone_map {
+                options.set_tone_map(*tone_map);
+            }
+            if let Some(exposure) = exposure {
+                options.set_exposure(*exposure);
+            }
+        }
+        options
+    }
+}
+
+/// A validated list of steps, starting with `decode` and ending with `encode`.
+#[derive(Clone, Debug)]
+pub struct Recipe {
+    steps: Vec<Step>,
+}
+
+impl Recipe {
+    pub fn from_json(json: &str) -> Result<Recipe> {
+        let steps: Vec<Step> = serde_json::from_str(json)
+            .map_err(|e| ImgprocError::invalid_argument("Invalid pipeline recipe.").cause(e))?;
+        Recipe::new(steps)
+    }
+
+    pub fn new(steps: Vec<Step>) -> Result<Recipe> {
+        let last = steps.len().saturating_sub(1);
+        let mut quantized = false;
+
+        for (index, step) in steps.iter().enumerate() {
+            let invalid = |message: &str| {
+                ImgprocError::invalid_argument(format!(
+                    "Step {} ({}): {}",
+                    index,
+                    step.name(),
+                    message
+                ))
+            };
+
+            match step {
+                Step::Decode { .. } if index != 0 => {
+                    return Err(invalid("decode must be the first step."));
+                }
+                Step::Encode { .. } if index != last => {
+                    return Err(invalid("encode must be the last step."));
+                }
+                Step::AutoOrient | Step::Crop { .. } | Step::Resize { .. } if quantized => {
+                    return Err(invalid(
+                        "only strip_metadata and encode may follow quantize.",
+                    ));
+                }
+                Step::Crop { width, height, .. } if *width == 0 || *height == 0 => {
+                    return Err(invalid("the crop must be at least 1x1."));
+                }
+                Step::Resize { width, height, .. } => match (width, height) {
+                    (None, None) => return Err(invalid("give a width, a height or both.")),
+                    (Some(0), _) | (_, Some(0)) => {
+                        return Err(invalid("the size must be at least 1x1."))
+                    }
+                    _ => {}
+                },
+                Step::Quantize { colors, .. } => {
+                    if !(2..=256).contains(colors) {
+                        return Err(invalid("colors must be between 2 and 256."));
+                    }
+                    quantized = true;
+                }
+                Step::Encode {
+                    format,
+                    quality,
+                    speed,
+                    bit_depth,
+                    ..
+                } => {
+                    if !can_encode(format) {
+                        return Err(invalid(&format!("cannot encode {}.", format)));
+                    }
+                    if matches!(quality, Some(q) if !(1..=100).contains(q)) {
+                        return Err(invalid("quality must be between 1 and 100."));
+                    }
+                    if matches!(speed, Some(s) if !(1..=10).contains(s)) {
+                        return Err(invalid("speed must be between 1 and 10."));
+                    }
+                    if matches!(bit_depth, Some(d) if *d != 8 && *d != 16) {
+                        return Err(invalid("bit_depth must be 8 or 16."));
+                    }
+                    if let Err(error) = step.convert_options().validate() {
+                        return Err(invalid(&error.message));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match (steps.first(), steps.last()) {
+            (Some(Step::Decode { .. }), Some(Step::Encode { .. })) => Ok(Recipe { steps }),
+            _ => Err(ImgprocError::invalid_argument(
+                "A pipeline must start with decode and end with encode.",
+            )),
+        }
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+}
+
+fn can_encode(format: &str) -> bool {
+    find_format(format).is_some_and(|handler| handler.can_encode())
+}
+
+/// What one step did, for the caller's timing and debugging.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub struct StepReport {
+    op: String,
+    duration_ms: f64,
+    width: u32,
+    height: u32,
+    num_frames: u32,
+    detail: Option<String>,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl StepReport {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn op(&self) -> String {
+        self.op.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn duration_ms(&self) -> f64 {
+        self.duration_ms
+    }
+
+    /// Size of the frames after the step.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn num_frames(&self) -> u32 {
+        self.num_frames
+    }
+
+    /// Step-specific notes, such as the detected format or the output size.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn detail(&self) -> Option<String> {
+        self.detail.clone()
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct PipelineResult {
+    output: Vec<u8>,
+    format: String,
+    steps: Vec<StepReport>,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl PipelineResult {
+    #[cfg_attr(feature = "wasm", wasm_bindgen)]
+    pub fn output(self) -> Vec<u8> {
+        self.output
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn format(&self) -> String {
+        self.format.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn steps(&self) -> Vec<StepReport> {
+        self.steps.clone()
+    }
+
+    /// Sum of the step durations.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn duration_ms(&self) -> f64 {
+        self.steps.iter().map(|step| step.duration_ms).sum()
+    }
+}
+
+/// What the source says about its pixels, carried into still output.
+#[derive(Clone, Default)]
+pub(crate) struct Metadata {
+    icc: Option<Vec<u8>>,
+    exif: Option<Vec<u8>>,
+    orientation: Option<Orientation>,
+}
+
+/// The frames between steps, with what is needed to encode them at the end.
+struct Canvas {
+    frames: Vec<DecodedFrame>,
+    /// A still image with more than 8 bits per channel. The steps work on it, and its only
+    /// frame is an 8-bit copy for quantizing and reporting.
+    deep: Option<DynamicImage>,
+    metadata: Metadata,
+    quantized: Option<(Palette, Vec<Vec<u8>>)>,
+    output: Option<(Vec<u8>, String)>,
+}
+
+impl Canvas {
+    fn dimensions(&self) -> (u32, u32) {
+        self.frames
+            .first()
+            .map(|(image, _)| image.dimensions())
+            .unwrap_or((0, 0))
+    }
+
+    fn map_frames(
+        &mut self,
+        cancel: &Cancellation,
+        f: impl Fn(DynamicImage) -> DynamicImage,
+    ) -> Result<()> {
+        if let Some(deep) = self.deep.take() {
+            cancel()?;
+            let deep = f(deep);
+            for (image, _) in self.frames.iter_mut() {
+                *image = to_rgba8(&deep, ToneMap::default(), 0.0);
+            }
+            self.deep = Some(deep);
+            return Ok(());
+        }
+        for (image, _) in self.frames.iter_mut() {
+            cancel()?;
+            *image = f(DynamicImage::ImageRgba8(mem::take(image))).into_rgba8();
+        }
+        Ok(())
+    }
+}
+
+/// Runs every step of `recipe` on `image_data` without leaving the module. `now` reads a
+/// millisecond clock for the step timings.
+pub fn run_pipeline(
+    image_data: &[u8],
+    recipe: &Recipe,
+    logs: &CallbackLogs,
+    cancel: Cancellation,
+    now: fn() -> f64,
+) -> Result<PipelineResult> {
+    let mut canvas = Canvas {
+        frames: Vec::new(),
+        deep: None,
+        metadata: Metadata::default(),
+        quantized: None,
+        output: None,
+    };
+    let mut reports = Vec::with_capacity(recipe.steps.len());
+
+    for (index, step) in recipe.steps.iter().enumerate() {
+        cancel()?;
+        logs.send(
+            ProgressEvent::new(step.stage(), "pipeline_step")
+                .arg(step.name())
+                .step(index as u32 + 1, Some(recipe.steps.len() as u32)),
+        )?;
+
+        let start = now();
+        let detail = run_step(&mut canvas, step, image_data, logs, &cancel)?;
+        let (width, height) = canvas.dimensions();
+        reports.push(StepReport {
+            op: step.name().to_string(),
+            duration_ms: now() - start,
+            width,
+            height,
+            num_frames: canvas.frames.len() as u32,
+            detail,
+        });
+    }
+
+    let (output, format) = canvas
+        .output
+        .ok_or_else(|| ImgprocError::internal("The pipeline produced no output."))?;
+    Ok(PipelineResult {
+        output,
+        format,
+        steps: reports,
+    })
+}
+
+fn run_step(
+    canvas: &mut Canvas,
+    step: &Step,
+    image_data: &[u8],
+    logs: &CallbackLogs,
+    cancel: &Cancellation,
+) -> Result<Option<String>> {
+    match step {
+        Step::Decode { format } => {
+            let format = match format {
+                Some(format) => format.to_lowercase(),
+                None => sniff_format(image_data)
+                    .ok_or_else(|| ImgprocError::unsupported_source("unknown"))?
+                    .to_string(),
+            };
+            if source_format(&format)?.is_animated(image_data)? {
+                canvas.frames = decode_detected_frames(image_data, &format, true, logs, cancel)?;
+            } else {
+                let image = decode_deep(image_data, &format, logs, cancel)?;
+                canvas.frames = vec![(to_rgba8(&image, ToneMap::default(), 0.0), 0)];
+                canvas.deep = is_high_bit_depth(&image).then_some(image);
+            }
+            canvas.metadata = read_metadata(image_data, &format);
+            Ok(Some(format))
+        }
+        Step::AutoOrient => {
+            let orientation = match canvas.metadata.orientation.take() {
+                Some(orientation) if orientation != Orientation::NoTransforms => orientation,
+                _ => return Ok(None),
+            };
+            canvas.map_frames(cancel, |mut image| {
+                image.apply_orientation(orientation);
+                image
+            })?;
+            if let Some(exif) = canvas.metadata.exif.as_mut() {
+                reset_exif_orientation(exif);
+            }
+            Ok(Some(format!("{:?}", orientation)))
+        }
+        Step::Crop {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            let (canvas_width, canvas_height) = canvas.dimensions();
+            if x.saturating_add(*width) > canvas_width || y.saturating_add(*height) > canvas_height
+            {
+                return Err(ImgprocError::invalid_argument(format!(
+                    "Crop {}x{} at ({}, {}) is outside the {}x{} image.",
+                    width, height, x, y, canvas_width, canvas_height
+                )));
+            }
+            canvas.map_frames(cancel, |image| image.crop_imm(*x, *y, *width, *height))?;
+            Ok(None)
+        }
+        Step::Resize {
+            width,
+            height,
+            fit,
+            filter,
+        } => {
+            let (width, height) = target_size(canvas.dimensions(), *width, *height);
+            let filter = FilterType::from(*filter);
+            canvas.map_frames(cancel, |image| match fit {
+                Fit::Contain => image.resize(width, height, filter),
+                Fit::Cover => image.resize_to_fill(width, height, filter),
+                Fit::Fill => image.resize_exact(width, height, filter),
+            })?;
+            Ok(None)
+        }
+        Step::StripMetadata => {
+            let mut removed = Vec::new();
+            if canvas.metadata.icc.take().is_some() {
+                removed.push("icc");
+            }
+            if canvas.metadata.exif.take().is_some() {
+                removed.push("exif");
+            }
+            Ok((!removed.is_empty()).then(|| removed.join(", ")))
+        }
+        Step::Quantize { colors, dithering } => {
+            let images: Vec<&RgbaImage> = canvas.frames.iter().map(|(image, _)| image).collect();
+            let (palette, indices) = quantize_frames(
+                &images,
+                QuantizeOptions {
+                    colors: *colors,
+                    dithering: *dithering,
+                    lossiness: 0,
+                    binary_alpha: false,
+                },
+            );
+            for ((image, _), indices) in canvas.frames.iter_mut().zip(&indices) {
+                for (pixel, index) in image.pixels_mut().zip(indices) {
+                    *pixel = Rgba(palette.colors[*index as usize]);
+                }
+            }
+            let detail = format!("{} colors", palette.colors.len());
+            canvas.deep = None;
+            canvas.quantized = Some((palette, indices));
+            Ok(Some(detail))
+        }
+        Step::Encode { format, .. } => {
+            let output = encode(canvas, format, &step.convert_options(), logs, cancel)?;
+            let detail = format!("{} bytes", output.len());
+            canvas.output = Some((output, format.to_lowercase()));
+            Ok(Some(detail))
+        }
+    }
+}
+
+/// Fills in a missing side from the aspect ratio of the current frames.
+fn target_size((width, height): (u32, u32), w: Option<u32>, h: Option<u32>) -> (u32, u32) {
+    let scale = |side: u32, from: u32, to: u32| {
+        ((side as u64 * to as u64 + from as u64 / 2) / from.max(1) as u64).max(1) as u32
+    };
+    match (w, h) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, scale(height, width, w)),
+        (None, Some(h)) => (scale(width, height, h), h),
+        (None, None) => (width, height),
+    }
+}
+
+fn encode(
+    canvas: &Canvas,
+    format: &str,
+    options: &ConvertOptions,
+    logs: &CallbackLogs,
+    cancel: &Cancellation,
+) -> Result<Vec<u8>> {
+    let handler = target_format(format)?;
+    let target = handler.name;
+    let (width, height) = canvas.dimensions();
+    let delays: Vec<u32> = canvas.frames.iter().map(|(_, delay)| *delay).collect();
+    let animated = canvas.frames.len() > 1;
+
+    match (&canvas.quantized, target) {
+        (Some((palette, indices)), "gif") => {
+            return encode_indexed_gif(width, height, palette, indices, &delays)
+        }
+        (Some((palette, indices)), "png" | "apng") => {
+            return encode_indexed_png(width, height, palette, indices, &delays)
+        }
+        _ => {}
+    }
+
+    if animated && handler.can_encode_animation() {
+        return handler.encode_frames(&canvas.frames, logs, Box::new(cancel));
+    }
+
+    let (image, _) = canvas
+        .frames
+        .first()
+        .ok_or_else(|| ImgprocError::invalid_argument("Cannot encode an image without frames."))?;
+
+    let image = match &canvas.deep {
+        Some(deep) => deep.clone(),
+        None => DynamicImage::ImageRgba8(image.clone()),
+    };
+    encode_with_metadata(handler, image, &canvas.metadata, options)
+}
+
+/// Encodes a still image, with the ICC profile and EXIF data attached if the format keeps
+/// them. An image with more than 8 bits per channel keeps what the format can hold, as
+/// `FormatHandler::encode_deep` does.
+pub(crate) fn encode_with_metadata(
+    handler: &FormatHandler,
+    image: DynamicImage,
+    metadata: &Metadata,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>> {
+    let deep = is_high_bit_depth(&image);
+    if !handler.metadata || (metadata.icc.is_none() && metadata.exif.is_none()) {
+        return match deep {
+            true => handler.encode_deep(image, options),
+            false => handler.encode_still(image.into_rgba8(), options),
+        };
+    }
+    let (tone_map, exposure) = (options.tone_map(), options.exposure());
+    let target = handler.name;
+    match target {
+        "png" | "apng" => {
+            let image = match options.bit_depth() {
+                16 => DynamicImage::ImageRgba16(to_rgba16(&image, tone_map, exposure)),
+                _ => DynamicImage::ImageRgba8(to_rgba8(&image, tone_map, exposure)),
+            };
+            let mut output = Vec::new();
+            write_with_metadata(PngEncoder::new(&mut output), &image, metadata, target)?;
+            Ok(output)
+        }
+        "jpeg" => {
+            let mut output = Vec::new();
+            let encoder =
+                JpegEncoder::new_with_quality(&mut output, options.quality().clamp(1, 100));
+            let image = to_rgba8(&image, tone_map, exposure);
+            let flattened = DynamicImage::ImageRgb8(flatten_on_white(&image));
+            write_with_metadata(encoder, &flattened, metadata, target)?;
+            Ok(output)
+        }
+        "webp" => {
+            let mut output = Vec::new();
+            write_with_metadata(
+                WebPEncoder::new_lossless(&mut output),
+                &DynamicImage::ImageRgba8(to_rgba8(&image, tone_map, exposure)),
+                metadata,
+                target,
+            )?;
+            Ok(output)
+        }
+        _ => match deep {
+            true => handler.encode_deep(image, options),
+            false => handler.encode_still(image.into_rgba8(), options),
+        },
+    }
+}
+
+/// Encodes with the ICC profile and EXIF data attached where the encoder supports them.
+fn write_with_metadata(
+    mut encoder: impl ImageEncoder,
+    image: &DynamicImage,
+    metadata: &Metadata,
+    format: &str,
+) -> Result<()> {
+    if let Some(icc) = &metadata.icc {
+        let _ = encoder.set_icc_profile(icc.clone());
+    }
+    if let Some(exif) = &metadata.exif {
+        let _ = encoder.set_exif_metadata(exif.clone());
+    }
+
+    let (width, height) = (image.width(), image.height());
+    encoder
+        .write_image(image.as_bytes(), width, height, image.color().into())
+        .map_err(|e| ImgprocError::decode("Failed to encode image.", e).format(format))
+}
+
+/// Reads the ICC profile, EXIF data and orientation where the format's decoder exposes them.
+/// Missing or unreadable metadata is not an error.
+pub(crate) fn read_metadata(image_data: &[u8], format: &str) -> Metadata {
+    let image_format = match format {
+        "apng" => Some(ImageFormat::Png),
+        _ => ImageFormat::from_extension(format),
+    };
+    let mut decoder = match image_format.and_then(|f| {
+        ImageReader::with_format(Cursor::new(image_data), f)
+            .into_decoder()
+            .ok()
+    }) {
+        Some(decoder) => decoder,
+        None => return Metadata::default(),
+    };
+
+    Metadata {
+        icc: decoder.icc_profile().ok().flatten(),
+        exif: decoder.exif_metadata().ok().flatten(),
+        orientation: decoder.orientation().ok(),
+    }
+}
+
+/// Sets the orientation tag in IFD0 of an EXIF block to 1, once the pixels are upright.
+fn reset_exif_orientation(exif: &mut [u8]) {
+    const ORIENTATION_TAG: u16 = 0x0112;
+
+    let start = if exif.starts_with(b"Exif\0\0") { 6 } else { 0 };
+    let tiff = &mut exif[start..];
+    let big_endian = match tiff.get(0..2) {
+        Some(b"MM") => true,
+        Some(b"II") => false,
+        _ => return,
+    };
+    let read_u16 = |bytes: &[u8], at: usize| -> Option<u16> {
+        let b: [u8; 2] = bytes.get(at..at + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    };
+    let read_u32 = |bytes: &[u8], at: usize| -> Option<u32> {
+        let b: [u8; 4] = bytes.get(at..at + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    };
+
+    let Some(ifd) = read_u32(tiff, 4).map(|offset| offset as usize) else {
+        return;
+    };
+    let Some(count) = read_u16(tiff, ifd) else {
+        return;
+    };
+    for entry in 0..count as usize {
+        let at = ifd + 2 + entry * 12;
+        if read_u16(tiff, at) == Some(ORIENTATION_TAG) {
+            let one = if big_endian { [0, 1] } else { [1, 0] };
+            if let Some(value) = tiff.get_mut(at + 8..at + 10) {
+                value.copy_from_slice(&one);
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{animation_encode::encode_gif, cancellation::never_cancelled, error::ErrorCode};
+    use image::{ImageBuffer, Rgba};
+
+    const ICC: &[u8] = b"a stand-in profile";
+
+    /// An 8x4 16-bit PNG with an ICC profile.
+    fn deep_png() -> Vec<u8> {
+        let image = ImageBuffer::<Rgba<u16>, _>::from_fn(8, 4, |x, y| {
+            Rgba([x as u16 * 8000, y as u16 * 16000, 40000, 65535])
+        });
+        let mut output = Vec::new();
+        let mut encoder = PngEncoder::new(&mut output);
+        encoder.set_icc_profile(ICC.to_vec()).unwrap();
+        encoder
+            .write_image(
+                DynamicImage::ImageRgba16(image).as_bytes(),
+                8,
+                4,
+                image::ExtendedColorType::Rgba16,
+            )
+            .unwrap();
+        output
+    }
+
+    fn run(image_data: &[u8], recipe: &str) -> Result<PipelineResult> {
+        let recipe = Recipe::from_json(recipe)?;
+        let logs = CallbackLogs::silent();
+        run_pipeline(image_data, &recipe, &logs, never_cancelled(), || 0.0)
+    }
+
+    fn decoder(data: &[u8], format: ImageFormat) -> Box<dyn ImageDecoder> {
+        let reader = ImageReader::with_format(Cursor::new(data.to_vec()), format);
+        Box::new(reader.into_decoder().unwrap())
+    }
+
+    #[test]
+    fn still_images_are_decoded_resized_and_encoded() {
+        let result = run(
+            &deep_png(),
+            r#"[{"op":"decode"},{"op":"resize","width":4},
+                {"op":"encode","format":"png","bit_depth":16}]"#,
+        )
+        .unwrap();
+        let steps: Vec<_> = result
+            .steps()
+            .iter()
+            .map(|s| (s.op(), s.width(), s.height(), s.num_frames()))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                ("decode".to_string(), 8, 4, 1),
+                ("resize".to_string(), 4, 2, 1),
+                ("encode".to_string(), 4, 2, 1),
+            ]
+        );
+        assert_eq!(result.steps()[0].detail().as_deref(), Some("png"));
+        assert_eq!(result.format(), "png");
+
+        // The 16-bit samples and the profile survive the resize.
+        let mut output = decoder(&result.output(), ImageFormat::Png);
+        assert_eq!(output.dimensions(), (4, 2));
+        assert_eq!(output.color_type(), image::ColorType::Rgba16);
+        assert_eq!(output.icc_profile().unwrap().as_deref(), Some(ICC));
+    }
+
+    #[test]
+    fn deep_images_are_brought_down_to_8_bits_for_jpeg() {
+        let result = run(
+            &deep_png(),
+            r#"[{"op":"decode","format":"png"},{"op":"crop","x":2,"y":0,"width":2,"height":2},
+                {"op":"encode","format":"jpeg","quality":95,"tone_map":"reinhard","exposure":1}]"#,
+        )
+        .unwrap();
+        let output = result.output();
+        let mut jpeg = decoder(&output, ImageFormat::Jpeg);
+        assert_eq!(jpeg.dimensions(), (2, 2));
+        assert_eq!(jpeg.icc_profile().unwrap().as_deref(), Some(ICC));
+
+        // Tone mapping is for floating-point sources; 16-bit samples are only rescaled.
+        let image = DynamicImage::from_decoder(jpeg).unwrap().to_rgb8();
+        let expected = [(2 * 8000 / 257) as u8, 0, (40000 / 257) as u8];
+        for (channel, value) in image.get_pixel(0, 0).0.iter().enumerate() {
+            assert!(
+                value.abs_diff(expected[channel]) <= 3,
+                "{:?}",
+                image.get_pixel(0, 0)
+            );
+        }
+    }
+
+    #[test]
+    fn strip_metadata_drops_the_profile() {
+        let result = run(
+            &deep_png(),
+            r#"[{"op":"decode"},{"op":"strip_metadata"},{"op":"encode","format":"webp"}]"#,
+        )
+        .unwrap();
+        assert_eq!(result.steps()[1].detail().as_deref(), Some("icc"));
+        let mut webp = decoder(&result.output(), ImageFormat::WebP);
+        assert_eq!(webp.icc_profile().unwrap(), None);
+    }
+
+    #[test]
+    fn animations_keep_every_frame() {
+        let frames: Vec<DecodedFrame> = [[255, 0, 0, 255], [0, 0, 255, 255], [0, 255, 0, 255]]
+            .into_iter()
+            .map(|color| (RgbaImage::from_pixel(8, 4, Rgba(color)), 100))
+            .collect();
+        let logs = CallbackLogs::silent();
+        let gif = encode_gif(&frames, &logs, never_cancelled()).unwrap();
+
+        for recipe in [
+            r#"[{"op":"decode"},{"op":"resize","width":4},{"op":"encode","format":"gif"}]"#,
+            r#"[{"op":"decode"},{"op":"resize","width":4},{"op":"quantize","colors":4},
+                {"op":"encode","format":"gif"}]"#,
+        ] {
+            let result = run(&gif, recipe).unwrap();
+            assert!(result.steps().iter().all(|s| s.num_frames() == 3));
+            let output = crate::image_frames::decode_frames(
+                &result.output(),
+                "gif",
+                &logs,
+                &never_cancelled(),
+            )
+            .unwrap();
+            assert_eq!(output.len(), 3);
+            for ((image, delay), (source, _)) in output.iter().zip(&frames) {
+                assert_eq!(image.dimensions(), (4, 2));
+                assert_eq!(*delay, 100);
+                assert_eq!(image.get_pixel(1, 1), source.get_pixel(0, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn steps_fail_on_images_they_do_not_fit() {
+        let error = run(
+            &deep_png(),
+            r#"[{"op":"decode"},{"op":"crop","x":6,"y":0,"width":4,"height":4},
+                {"op":"encode","format":"png"}]"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.code, ErrorCode::InvalidArgument);
+
+        let error = run(
+            b"not an image",
+            r#"[{"op":"decode"},{"op":"encode","format":"png"}]"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.code, ErrorCode::UnsupportedFormat);
+    }
+
+    #[test]
+    fn steps_report_the_stage_they_work_in() {
+        let recipe = Recipe::from_json(
+            r#"[{"op":"decode"},{"op":"auto_orient"},{"op":"crop","x":0,"y":0,"width":1,"height":1},
+                {"op":"resize","width":1},{"op":"strip_metadata"},{"op":"quantize"},
+                {"op":"encode","format":"png"}]"#,
+        )
+        .unwrap();
+        let stages: Vec<&str> = recipe.steps().iter().map(|s| s.stage().as_str()).collect();
+        assert_eq!(
+            stages,
+            [
+                "decode",
+                "transform",
+                "transform",
+                "transform",
+                "transform",
+                "quantize",
+                "encode"
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_options_are_checked_with_the_recipe() {
+        let encode = |exposure| Step::Encode {
+            format: "png".to_string(),
+            quality: None,
+            speed: None,
+            bit_depth: None,
+            tone_map: None,
+            exposure: Some(exposure),
+        };
+        let decode = Step::Decode { format: None };
+        let error = Recipe::new(vec![decode.clone(), encode(f32::NAN)])
+            .err()
+            .unwrap();
+        assert_eq!(error.code, ErrorCode::InvalidArgument);
+        assert!(
+            error.message.starts_with("Step 1 (encode): Exposure"),
+            "{}",
+            error.message
+        );
+        assert!(Recipe::new(vec![decode, encode(1.5)]).is_ok());
+    }
+
+    #[test]
+    fn recipes_must_start_with_decode_and_end_with_encode() {
+        assert!(Recipe::from_json(r#"[{"op":"encode","format":"png"}]"#).is_err());
+        assert!(Recipe::from_json(r#"[{"op":"decode"}]"#).is_err());
+        assert!(Recipe::from_json(
+            r#"[{"op":"decode"},{"op":"quantize"},{"op":"resize","width":4},{"op":"encode","format":"png"}]"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use crate::animation_decoder::DecodedFrame;
+
+const DIGEST_CONTEXT: &str = "imgstor pixel digest v1";
+
+/// Hashes the visual content of decoded frames with BLAKE3, independent of the container.
+///
+/// Pixels are canonicalised before hashing: fully transparent pixels collapse to zero,
+/// consecutive identical frames are merged with their delays summed, and a lone frame
+/// carries no delay. Width, height and frame boundaries are part of the digest.
+pub fn pixel_digest(frames: &[DecodedFrame]) -> String {
+    let mut hasher = blake3::Hasher::new_derive_key(DIGEST_CONTEXT);
+
+    let merged = merge_identical_frames(frames);
+    let (width, height) = merged
+        .first()
+        .map(|(image, _)| image.dimensions())
+        .unwrap_or((0, 0));
+
+    hasher.update(&width.to_le_bytes());
+    hasher.update(&height.to_le_bytes());
+    hasher.update(&(merged.len() as u32).to_le_bytes());
+
+    let single_frame = merged.len() == 1;
+    let mut canonical = Vec::new();
+    for (image, delay) in merged {
+        let delay = if single_frame { 0 } else { delay };
+        hasher.update(&delay.to_le_bytes());
+
+        canonical.clear();
+        for pixel in image.as_raw().chunks_exact(4) {
+            if pixel[3] == 0 {
+                canonical.extend_from_slice(&[0; 4]);
+            } else {
+                canonical.extend_from_slice(pixel);
+            }
+        }
+        hasher.update(&canonical);
+    }
+
+    hasher.finalize().to_hex().to_string()
+}
+
+fn merge_identical_frames(frames: &[DecodedFrame]) -> Vec<(&image::RgbaImage, u32)> {
+    let mut merged: Vec<(&image::RgbaImage, u32)> = Vec::with_capacity(frames.len());
+    for (image, delay) in frames {
+        match merged.last_mut() {
+            Some((previous, previous_delay)) if same_pixels(previous, image) => {
+                *previous_delay = previous_delay.saturating_add(*delay);
+            }
+            _ => merged.push((image, *delay)),
+        }
+    }
+    merged
+}
+
+fn same_pixels(a: &image::RgbaImage, b: &image::RgbaImage) -> bool {
+    a.dimensions() == b.dimensions()
+        && a.as_raw()
+            .chunks_exact(4)
+            .zip(b.as_raw().chunks_exact(4))
+            .all(|(p, q)| p == q || (p[3] == 0 && q[3] == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        animation_encode::encode_webp, callback_logs::CallbackLogs, cancellation::never_cancelled,
+        first_frame::encode_png, image_frames::decode_frames,
+    };
+    use image::{codecs::webp::WebPEncoder, ExtendedColorType, ImageEncoder, Rgba, RgbaImage};
+
+    fn gradient() -> RgbaImage {
+        RgbaImage::from_fn(7, 5, |x, y| {
+            Rgba([x as u8 * 30, y as u8 * 50, 90, if x == 0 { 0 } else { 200 }])
+        })
+    }
+
+    fn digest_of(data: &[u8], format: &str) -> String {
+        let logs = CallbackLogs::silent();
+        pixel_digest(&decode_frames(data, format, &logs, &never_cancelled()).unwrap())
+    }
+
+    #[test]
+    fn same_pixels_in_png_and_lossless_webp_match() {
+        let image = gradient();
+        let mut webp = Vec::new();
+        WebPEncoder::new_lossless(&mut webp)
+            .write_image(image.as_raw(), 7, 5, ExtendedColorType::Rgba8)
+            .unwrap();
+        let png = encode_png(image.clone()).unwrap();
+        assert_eq!(digest_of(&png, "png"), digest_of(&webp, "webp"));
+        assert_eq!(digest_of(&png, "png"), pixel_digest(&[(image, 0)]));
+    }
+
+    #[test]
+    fn colour_under_full_transparency_is_ignored() {
+        // The left column is fully transparent and takes a different colour each time.
+        let clear = |rgb: u8| {
+            RgbaImage::from_fn(4, 4, |x, _| match x {
+                0 => Rgba([rgb, rgb, rgb, 0]),
+                x => Rgba([10, 20, 30, x as u8 * 80]),
+            })
+        };
+        assert_eq!(
+            pixel_digest(&[(clear(0), 0)]),
+            pixel_digest(&[(clear(255), 0)])
+        );
+        // Only fully transparent pixels collapse; these differ at alpha 1.
+        let faint = |rgb: u8| RgbaImage::from_pixel(4, 4, Rgba([rgb, 0, 0, 1]));
+        assert_ne!(
+            pixel_digest(&[(faint(0), 0)]),
+            pixel_digest(&[(faint(255), 0)])
+        );
+    }
+
+    #[test]
+    fn one_changed_pixel_changes_the_digest() {
+        let image = gradient();
+        let mut changed = image.clone();
+        changed.get_pixel_mut(3, 2)[1] ^= 1;
+        assert_ne!(pixel_digest(&[(image, 0)]), pixel_digest(&[(changed, 0)]));
+    }
+
+    #[test]
+    fn repeated_frames_merge_and_lone_frames_drop_their_delay() {
+        let (a, b) = (gradient(), RgbaImage::new(7, 5));
+        let split = [(a.clone(), 50), (a.clone(), 50), (b.clone(), 100)];
+        let merged = [(a.clone(), 100), (b.clone(), 100)];
+        assert_eq!(pixel_digest(&split), pixel_digest(&merged));
+        assert_ne!(
+            pixel_digest(&merged),
+            pixel_digest(&[(a.clone(), 100), (b, 200)])
+        );
+        assert_eq!(
+            pixel_digest(&[(a.clone(), 0)]),
+            pixel_digest(&[(a.clone(), 70)])
+        );
+
+        // The same animation from two containers digests the same.
+        let logs = CallbackLogs::silent();
+        let webp = encode_webp(&merged, &logs, never_cancelled()).unwrap();
+        assert_eq!(digest_of(&webp, "webp"), pixel_digest(&merged));
+
+        // Merged delays saturate rather than overflow.
+        let long = [(a.clone(), u32::MAX), (a, u32::MAX)];
+        assert!(!pixel_digest(&long).is_empty());
+    }
+}
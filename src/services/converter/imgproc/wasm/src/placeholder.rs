@@ -0,0 +1,559 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::{imageops::FilterType, RgbaImage};
+use std::f32::consts::PI;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    error::{ImgprocError, Result},
+    limits::current_limits,
+};
+
+/// BlurHash only needs a coarse image; larger inputs are downscaled first.
+const BLURHASH_SAMPLE_SIZE: u32 = 64;
+/// ThumbHash is defined for images of at most 100x100 pixels.
+const THUMBHASH_MAX_SIZE: u32 = 100;
+/// The longer side of a rendered ThumbHash.
+const THUMBHASH_RENDER_SIZE: f32 = 32.0;
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct ImagePlaceholder {
+    blurhash: String,
+    thumbhash: String,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl ImagePlaceholder {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn blurhash(&self) -> String {
+        self.blurhash.clone()
+    }
+
+    /// The ThumbHash bytes as standard base64.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn thumbhash(&self) -> String {
+        self.thumbhash.clone()
+    }
+}
+
+pub fn create_placeholder(image: &RgbaImage) -> Result<ImagePlaceholder> {
+    if image.width() == 0 || image.height() == 0 {
+        return Err(ImgprocError::invalid_argument(
+            "Cannot create a placeholder for an empty image.",
+        ));
+    }
+
+    Ok(ImagePlaceholder {
+        blurhash: encode_blurhash(image)?,
+        thumbhash: STANDARD.encode(encode_thumbhash(image)),
+    })
+}
+
+fn fit_within(image: &RgbaImage, max_size: u32) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    if width <= max_size && height <= max_size {
+        return image.clone();
+    }
+    let scale = max_size as f32 / width.max(height) as f32;
+    let new_width = ((width as f32 * scale).round() as u32).max(1);
+    let new_height = ((height as f32 * scale).round() as u32).max(1);
+    image::imageops::resize(image, new_width, new_height, FilterType::Triangle)
+}
+
+pub fn encode_blurhash(image: &RgbaImage) -> Result<String> {
+    let sample = fit_within(image, BLURHASH_SAMPLE_SIZE);
+    let (components_x, components_y) = if sample.width() >= sample.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+
+    blurhash::encode(
+        components_x,
+        components_y,
+        sample.width(),
+        sample.height(),
+        sample.as_raw(),
+    )
+    .map_err(|e| ImgprocError::encode("Failed to encode BlurHash.").cause(e))
+}
+
+/// Renders a BlurHash at the caller's size, which is held to the decode limits as the
+/// pixels are allocated up front.
+pub fn decode_blurhash(hash: &str, width: u32, height: u32) -> Result<RgbaImage> {
+    if width == 0 || height == 0 {
+        return Err(ImgprocError::invalid_argument(format!(
+            "Cannot render a BlurHash at {}x{}.",
+            width, height
+        )));
+    }
+    current_limits().check_dimensions(width, height, "BlurHash")?;
+
+    let pixels = blurhash::decode(hash, width, height, 1.0)
+        .map_err(|e| ImgprocError::invalid_argument("Failed to decode BlurHash.").cause(e))?;
+
+    RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| ImgprocError::internal("Decoded BlurHash has an unexpected size."))
+}
+
+/// A decoded AC coefficient with its horizontal and vertical frequency.
+type AcTerm = (usize, usize, f32);
+
+/// The AC frequencies of an `nx` by `ny` channel in hash order: a triangle that skips the DC term.
+fn ac_positions(nx: usize, ny: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..ny).flat_map(move |cy| {
+        let start = if cy > 0 { 0 } else { 1 };
+        (start..)
+            .take_while(move |cx| cx * ny < nx * (ny - cy))
+            .map(move |cx| (cx, cy))
+    })
+}
+
+struct ChannelCoefficients {
+    dc: f64,
+    ac: Vec<f64>,
+    scale: f64,
+}
+
+/// Works in `f64` and sums pixel by pixel like the reference, so coefficients close to a
+/// rounding boundary are quantised the same way.
+fn encode_channel(
+    channel: &[f64],
+    width: usize,
+    height: usize,
+    nx: usize,
+    ny: usize,
+) -> ChannelCoefficients {
+    let mut ac = Vec::new();
+    let mut scale = 0f64;
+    let mut fx = vec![0f64; width];
+
+    let mut coefficient = |cx: usize, cy: usize| -> f64 {
+        for (x, f) in fx.iter_mut().enumerate() {
+            *f = (std::f64::consts::PI / width as f64 * cx as f64 * (x as f64 + 0.5)).cos();
+        }
+
+        let mut sum = 0.0;
+        for (y, row) in channel.chunks_exact(width).enumerate() {
+            let fy = (std::f64::consts::PI / height as f64 * cy as f64 * (y as f64 + 0.5)).cos();
+            for (v, f) in row.iter().zip(&fx) {
+                sum += v * f * fy;
+            }
+        }
+        sum / (width * height) as f64
+    };
+
+    let dc = coefficient(0, 0);
+    for (cx, cy) in ac_positions(nx, ny) {
+        let f = coefficient(cx, cy);
+        ac.push(f);
+        scale = scale.max(f.abs());
+    }
+
+    if scale > 0.0 {
+        for value in ac.iter_mut() {
+            *value = 0.5 + 0.5 / scale * *value;
+        }
+    }
+
+    ChannelCoefficients { dc, ac, scale }
+}
+
+/// Encodes an image as a ThumbHash, following the reference implementation by Evan Wallace.
+pub fn encode_thumbhash(image: &RgbaImage) -> Vec<u8> {
+    let image = fit_within(image, THUMBHASH_MAX_SIZE);
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let pixels = image.as_raw();
+
+    let (mut avg_r, mut avg_g, mut avg_b, mut avg_a) = (0f64, 0f64, 0f64, 0f64);
+    for pixel in pixels.chunks_exact(4) {
+        let alpha = pixel[3] as f64 / 255.0;
+        avg_r += alpha / 255.0 * pixel[0] as f64;
+        avg_g += alpha / 255.0 * pixel[1] as f64;
+        avg_b += alpha / 255.0 * pixel[2] as f64;
+        avg_a += alpha;
+    }
+    if avg_a > 0.0 {
+        avg_r /= avg_a;
+        avg_g /= avg_a;
+        avg_b /= avg_a;
+    }
+
+    let has_alpha = avg_a < (width * height) as f64;
+    let l_limit = if has_alpha { 5.0 } else { 7.0 };
+    let longest = width.max(height) as f64;
+    let lx = ((l_limit * width as f64 / longest).round() as usize).max(1);
+    let ly = ((l_limit * height as f64 / longest).round() as usize).max(1);
+
+    let count = width * height;
+    let (mut l, mut p, mut q, mut a) = (
+        Vec::with_capacity(count),
+        Vec::with_capacity(count),
+        Vec::with_capacity(count),
+        Vec::with_capacity(count),
+    );
+    for pixel in pixels.chunks_exact(4) {
+        let alpha = pixel[3] as f64 / 255.0;
+        let r = avg_r * (1.0 - alpha) + alpha / 255.0 * pixel[0] as f64;
+        let g = avg_g * (1.0 - alpha) + alpha / 255.0 * pixel[1] as f64;
+        let b = avg_b * (1.0 - alpha) + alpha / 255.0 * pixel[2] as f64;
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(alpha);
+    }
+
+    let l = encode_channel(&l, width, height, lx.max(3), ly.max(3));
+    let p = encode_channel(&p, width, height, 3, 3);
+    let q = encode_channel(&q, width, height, 3, 3);
+    let a = has_alpha.then(|| encode_channel(&a, width, height, 5, 5));
+
+    let is_landscape = width > height;
+    let header24 = (63.0 * l.dc).round() as u32
+        | ((31.5 + 31.5 * p.dc).round() as u32) << 6
+        | ((31.5 + 31.5 * q.dc).round() as u32) << 12
+        | ((31.0 * l.scale).round() as u32) << 18
+        | (has_alpha as u32) << 23;
+    let header16 = (if is_landscape { ly } else { lx }) as u32
+        | ((63.0 * p.scale).round() as u32) << 3
+        | ((63.0 * q.scale).round() as u32) << 9
+        | (is_landscape as u32) << 15;
+
+    let mut hash = vec![
+        (header24 & 255) as u8,
+        ((header24 >> 8) & 255) as u8,
+        (header24 >> 16) as u8,
+        (header16 & 255) as u8,
+        (header16 >> 8) as u8,
+    ];
+    if let Some(a) = &a {
+        hash.push((15.0 * a.dc).round() as u8 | ((15.0 * a.scale).round() as u8) << 4);
+    }
+
+    let ac_start = hash.len();
+    let channels = [Some(&l), Some(&p), Some(&q), a.as_ref()];
+    let mut ac_index = 0;
+    for channel in channels.into_iter().flatten() {
+        for f in &channel.ac {
+            let byte = ac_start + (ac_index >> 1);
+            if byte >= hash.len() {
+                hash.push(0);
+            }
+            hash[byte] |= ((15.0 * f).round() as u8) << ((ac_index & 1) << 2);
+            ac_index += 1;
+        }
+    }
+
+    hash
+}
+
+/// Renders a base64 ThumbHash back to RGBA; the longer side is 32 pixels.
+pub fn decode_thumbhash(thumbhash: &str) -> Result<RgbaImage> {
+    let hash = STANDARD
+        .decode(thumbhash.trim())
+        .map_err(|e| ImgprocError::invalid_argument("Invalid ThumbHash.").cause(e))?;
+    if hash.len() < 5 {
+        return Err(ImgprocError::invalid_argument("Invalid ThumbHash.").cause("too short"));
+    }
+
+    let header24 = hash[0] as u32 | (hash[1] as u32) << 8 | (hash[2] as u32) << 16;
+    let header16 = hash[3] as u32 | (hash[4] as u32) << 8;
+    let l_dc = (header24 & 63) as f32 / 63.0;
+    let p_dc = ((header24 >> 6) & 63) as f32 / 31.5 - 1.0;
+    let q_dc = ((header24 >> 12) & 63) as f32 / 31.5 - 1.0;
+    let l_scale = ((header24 >> 18) & 31) as f32 / 31.0;
+    let has_alpha = (header24 >> 23) != 0;
+    let p_scale = ((header16 >> 3) & 63) as f32 / 63.0;
+    let q_scale = ((header16 >> 9) & 63) as f32 / 63.0;
+    let is_landscape = (header16 >> 15) != 0;
+
+    let l_limit = if has_alpha { 5 } else { 7 };
+    let lx = (if is_landscape {
+        l_limit
+    } else {
+        header16 as usize & 7
+    })
+    .max(3);
+    let ly = (if is_landscape {
+        header16 as usize & 7
+    } else {
+        l_limit
+    })
+    .max(3);
+
+    if has_alpha && hash.len() < 6 {
+        return Err(
+            ImgprocError::invalid_argument("Invalid ThumbHash.").cause("missing alpha header")
+        );
+    }
+    let (a_dc, a_scale) = if has_alpha {
+        ((hash[5] & 15) as f32 / 15.0, (hash[5] >> 4) as f32 / 15.0)
+    } else {
+        (1.0, 0.0)
+    };
+
+    let ac_start = if has_alpha { 6 } else { 5 };
+    let mut ac_index = 0;
+    let mut decode_channel = |nx: usize, ny: usize, scale: f32| -> Result<Vec<AcTerm>> {
+        let mut terms = Vec::new();
+        for (cx, cy) in ac_positions(nx, ny) {
+            let byte = hash.get(ac_start + (ac_index >> 1)).ok_or_else(|| {
+                ImgprocError::invalid_argument("Invalid ThumbHash.").cause("truncated coefficients")
+            })?;
+            let value = (byte >> ((ac_index & 1) << 2)) & 15;
+            terms.push((cx, cy, (value as f32 / 7.5 - 1.0) * scale));
+            ac_index += 1;
+        }
+        Ok(terms)
+    };
+
+    let l_ac = decode_channel(lx, ly, l_scale)?;
+    let p_ac = decode_channel(3, 3, p_scale * 1.25)?;
+    let q_ac = decode_channel(3, 3, q_scale * 1.25)?;
+    let a_ac = if has_alpha {
+        decode_channel(5, 5, a_scale)?
+    } else {
+        Vec::new()
+    };
+
+    let ratio = thumbhash_aspect_ratio(&hash);
+    let (width, height) = if ratio > 1.0 {
+        (THUMBHASH_RENDER_SIZE, THUMBHASH_RENDER_SIZE / ratio)
+    } else {
+        (THUMBHASH_RENDER_SIZE * ratio, THUMBHASH_RENDER_SIZE)
+    };
+    let (width, height) = (
+        (width.round() as u32).max(1),
+        (height.round() as u32).max(1),
+    );
+
+    let components = if has_alpha { 5 } else { 3 };
+    let mut fx = vec![0f32; lx.max(components)];
+    let mut fy = vec![0f32; ly.max(components)];
+    let mut image = RgbaImage::new(width, height);
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        for (cx, f) in fx.iter_mut().enumerate() {
+            *f = (PI / width as f32 * (x as f32 + 0.5) * cx as f32).cos();
+        }
+        for (cy, f) in fy.iter_mut().enumerate() {
+            *f = (PI / height as f32 * (y as f32 + 0.5) * cy as f32).cos();
+        }
+
+        let (mut l, mut p, mut q, mut a) = (l_dc, p_dc, q_dc, a_dc);
+
+        let sum = |terms: &[AcTerm]| -> f32 {
+            terms
+                .iter()
+                .map(|&(cx, cy, coefficient)| coefficient * fx[cx] * fy[cy] * 2.0)
+                .sum()
+        };
+        l += sum(&l_ac);
+        p += sum(&p_ac);
+        q += sum(&q_ac);
+        a += sum(&a_ac);
+
+        let b = l - 2.0 / 3.0 * p;
+        let r = (3.0 * l - b + q) / 2.0;
+        let g = r - q;
+        // Truncated rather than rounded, as the reference stores into a `Uint8Array`.
+        let to_byte = |v: f32| (255.0 * v.clamp(0.0, 1.0)) as u8;
+        *pixel = image::Rgba([to_byte(r), to_byte(g), to_byte(b), to_byte(a)]);
+    }
+
+    Ok(image)
+}
+
+fn thumbhash_aspect_ratio(hash: &[u8]) -> f32 {
+    let header = hash[3];
+    let has_alpha = (hash[2] & 0x80) != 0;
+    let is_landscape = (hash[4] & 0x80) != 0;
+    let l_limit = if has_alpha { 5 } else { 7 };
+    let lx = if is_landscape { l_limit } else { header & 7 };
+    let ly = if is_landscape { header & 7 } else { l_limit };
+    lx as f32 / ly.max(1) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::ErrorCode,
+        limits::{set_limits, DecodeLimits},
+    };
+    use image::Rgba;
+
+    // Expected hashes and pixels come from the reference JavaScript implementations,
+    // woltapp/blurhash and evanw/thumbhash, run on the same images.
+    fn flat() -> RgbaImage {
+        RgbaImage::from_pixel(40, 30, Rgba([30, 140, 220, 255]))
+    }
+
+    fn opaque() -> RgbaImage {
+        RgbaImage::from_fn(32, 24, |x, y| {
+            Rgba([
+                ((x * 37 + y * 11) % 256) as u8,
+                ((x * y * 7 + 40) % 256) as u8,
+                ((y * 53 + x * x) % 256) as u8,
+                255,
+            ])
+        })
+    }
+
+    fn translucent() -> RgbaImage {
+        RgbaImage::from_fn(20, 30, |x, y| {
+            Rgba([
+                ((x * 29 + 100) % 256) as u8,
+                ((y * y * 3) % 256) as u8,
+                ((x * y * 5) % 256) as u8,
+                ((x * 13 + y * 17) % 256) as u8,
+            ])
+        })
+    }
+
+    fn assert_close(actual: &Rgba<u8>, expected: [u8; 4]) {
+        let close = actual
+            .0
+            .iter()
+            .zip(expected)
+            .all(|(a, e)| a.abs_diff(e) <= 1);
+        assert!(close, "{:?} is not {:?}", actual, expected);
+    }
+
+    /// The top-left, centre and bottom-right pixels.
+    fn assert_pixels(image: &RgbaImage, expected: [[u8; 4]; 3]) {
+        let (width, height) = image.dimensions();
+        assert_close(image.get_pixel(0, 0), expected[0]);
+        assert_close(image.get_pixel(width / 2, height / 2), expected[1]);
+        assert_close(image.get_pixel(width - 1, height - 1), expected[2]);
+    }
+
+    fn base83(digits: &str) -> u32 {
+        const CHARS: &str =
+            "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+        digits
+            .chars()
+            .fold(0, |value, c| value * 83 + CHARS.find(c).unwrap() as u32)
+    }
+
+    #[test]
+    fn blurhash_matches_the_reference() {
+        for (image, hash, pixels) in [
+            (
+                flat(),
+                "L73fqEk[fQk[k]fkfQfkfQfQfQfQ",
+                [
+                    [32, 151, 236, 255],
+                    [30, 140, 220, 255],
+                    [28, 132, 207, 255],
+                ],
+            ),
+            (
+                opaque(),
+                "L3HU^N@,mG[};UH?EuD$0xmU1Iv4",
+                [
+                    [147, 119, 143, 255],
+                    [151, 152, 154, 255],
+                    [136, 143, 143, 255],
+                ],
+            ),
+            (
+                translucent(),
+                "TMHn,0=pJgv5jDa^dre-fOupjFa~",
+                [
+                    [172, 0, 28, 255],
+                    [162, 161, 163, 255],
+                    [121, 143, 140, 255],
+                ],
+            ),
+        ] {
+            // The blurhash crate rounds the average colour after adding a half, so it may be
+            // one above the reference; the components and every coefficient match exactly.
+            let encoded = encode_blurhash(&image).unwrap();
+            assert_eq!(encoded[..2], hash[..2]);
+            assert_eq!(encoded[6..], hash[6..]);
+            let (dc, expected) = (base83(&encoded[2..6]), base83(&hash[2..6]));
+            for shift in [16, 8, 0] {
+                let channel = ((dc >> shift) & 255) as i32 - ((expected >> shift) & 255) as i32;
+                assert!((0..=1).contains(&channel), "{} is not {}", encoded, hash);
+            }
+
+            let rendered = decode_blurhash(hash, 8, 6).unwrap();
+            assert_eq!(rendered.dimensions(), (8, 6));
+            assert_pixels(&rendered, pixels);
+        }
+    }
+
+    #[test]
+    fn thumbhash_matches_the_reference() {
+        for (image, hash, size, pixels) in [
+            (
+                opaque(),
+                "3wcCBYJSFCUgJTdQUyNUYgXr8u9J",
+                (32, 23),
+                [
+                    [139, 108, 128, 255],
+                    [123, 125, 128, 255],
+                    [120, 127, 128, 255],
+                ],
+            ),
+            (
+                translucent(),
+                "XSiGEwQIeZAoCTnfhYf01od3R3B3d4I=",
+                (19, 32),
+                [
+                    [111, 67, 49, 136],
+                    [125, 111, 119, 136],
+                    [130, 130, 117, 136],
+                ],
+            ),
+        ] {
+            assert_eq!(STANDARD.encode(encode_thumbhash(&image)), hash);
+
+            let rendered = decode_thumbhash(hash).unwrap();
+            assert_eq!(rendered.dimensions(), size, "{}", hash);
+            assert_pixels(&rendered, pixels);
+        }
+
+        // A flat image has no detail, so only its colour and shape come back.
+        let placeholder = create_placeholder(&flat()).unwrap();
+        let rendered = decode_thumbhash(&placeholder.thumbhash()).unwrap();
+        assert_eq!(rendered.dimensions(), (32, 23));
+        assert_pixels(&rendered, [[30, 139, 218, 255]; 3]);
+    }
+
+    #[test]
+    fn invalid_hashes_are_rejected() {
+        for hash in [
+            "",
+            "L",
+            "LEHV6nWB2yk8pyo0adR*.7kCMdn",
+            "L\u{e9}HV6nWB2yk8pyo0adR*.7kCMdnj",
+        ] {
+            let error = decode_blurhash(hash, 8, 8).err().unwrap();
+            assert_eq!(error.code, ErrorCode::InvalidArgument, "{:?}", hash);
+        }
+        for hash in ["", "!!!", "4CMB", "XSiGEw==", "3wcCBYJSFCUgJTdQ"] {
+            let error = decode_thumbhash(hash).err().unwrap();
+            assert_eq!(error.code, ErrorCode::InvalidArgument, "{:?}", hash);
+        }
+    }
+
+    #[test]
+    fn blurhash_size_is_held_to_the_limits() {
+        let hash = "LEHV6nWB2yk8pyo0adR*.7kCMdnj";
+        let mut limits = DecodeLimits::default();
+        limits.set_max_width(64);
+        limits.set_max_pixels(1000);
+        set_limits(limits);
+
+        let code = |width, height| decode_blurhash(hash, width, height).err().map(|e| e.code);
+        assert_eq!(code(65, 1), Some(ErrorCode::LimitExceeded));
+        assert_eq!(code(40, 40), Some(ErrorCode::LimitExceeded));
+        assert_eq!(code(u32::MAX, u32::MAX), Some(ErrorCode::LimitExceeded));
+        assert_eq!(code(0, 10), Some(ErrorCode::InvalidArgument));
+        assert_eq!(code(40, 20), None);
+
+        set_limits(DecodeLimits::default());
+    }
+}
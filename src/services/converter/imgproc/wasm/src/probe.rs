@@ -0,0 +1,163 @@
+use std::io::Cursor;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    error::{ErrorCode, ImgprocError, Result},
+    formats::{sniff_handler, source_format},
+};
+
+/// What can be read from an image's headers without decoding its pixels.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub struct ImageInfo {
+    format: String,
+    width: u32,
+    height: u32,
+    num_frames: u32,
+    duration_ms: u32,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl ImageInfo {
+    /// Lowercase format name, `apng` for animated PNGs.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn format(&self) -> String {
+        self.format.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn num_frames(&self) -> u32 {
+        self.num_frames
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn is_animated(&self) -> bool {
+        self.num_frames > 1
+    }
+
+    /// Total of the frame delays; zero for still images.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn duration_ms(&self) -> u32 {
+        self.duration_ms
+    }
+}
+
+/// Guesses the format from the leading bytes, returning the name used for `source_type`.
+pub fn sniff_format(image_data: &[u8]) -> Option<&'static str> {
+    sniff_handler(image_data).map(|handler| handler.name)
+}
+
+/// Reads the format, size and frame count of an image. `source_type` is sniffed when empty.
+pub fn probe(image_data: &[u8], source_type: &str) -> Result<ImageInfo> {
+    let handler = match source_type {
+        "" => sniff_handler(image_data).ok_or_else(|| {
+            ImgprocError::new(ErrorCode::UnsupportedFormat, "Unrecognised image format.")
+        })?,
+        _ => source_format(source_type)?,
+    };
+    let mut format = handler.name.to_string();
+
+    let animated = handler.is_animated(image_data)?;
+    if format == "png" && animated {
+        format = "apng".to_string();
+    }
+
+    let (width, height) = handler.dimensions(image_data)?;
+
+    let (num_frames, duration_ms) = match format.as_str() {
+        "gif" if animated => gif_timing(image_data)?,
+        "apng" => apng_timing(image_data),
+        "webp" if animated => webp_timing(image_data),
+        _ => (1, 0),
+    };
+
+    Ok(ImageInfo {
+        format,
+        width,
+        height,
+        num_frames,
+        duration_ms,
+    })
+}
+
+/// Counts GIF frames and sums their delays without decompressing the pixel data.
+fn gif_timing(image_data: &[u8]) -> Result<(u32, u32)> {
+    let mut options = gif::DecodeOptions::new();
+    options.skip_frame_decoding(true);
+    let mut decoder = options.read_info(Cursor::new(image_data)).map_err(|e| {
+        ImgprocError::corrupt("Failed to read GIF header.")
+            .format("GIF")
+            .cause(e)
+    })?;
+
+    let (mut frames, mut duration) = (0u32, 0u32);
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| {
+        ImgprocError::corrupt("Failed to read a GIF frame.")
+            .format("GIF")
+            .frame(frames)
+            .cause(e)
+    })? {
+        frames += 1;
+        duration = duration.saturating_add(frame.delay as u32 * 10);
+    }
+    Ok((frames, duration))
+}
+
+/// Reads the frame count from `acTL` and the delays from each `fcTL` chunk.
+fn apng_timing(image_data: &[u8]) -> (u32, u32) {
+    let (mut frames, mut duration) = (0u32, 0u32);
+    let mut offset = 8;
+    while let Some(header) = image_data.get(offset..offset + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let data = match image_data.get(offset + 8..offset + 8 + length) {
+            Some(data) => data,
+            None => break,
+        };
+        match &header[4..8] {
+            b"acTL" if data.len() >= 4 => {
+                frames = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+            }
+            b"fcTL" if data.len() >= 24 => {
+                let numerator = u16::from_be_bytes([data[20], data[21]]) as u32;
+                let denominator = match u16::from_be_bytes([data[22], data[23]]) {
+                    0 => 100,
+                    d => d as u32,
+                };
+                duration = duration.saturating_add(numerator * 1000 / denominator);
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        offset += 12 + length;
+    }
+    (frames, duration)
+}
+
+/// Counts the `ANMF` chunks of an animated WebP and sums their durations.
+fn webp_timing(image_data: &[u8]) -> (u32, u32) {
+    let (mut frames, mut duration) = (0u32, 0u32);
+    let mut offset = 12;
+    while let Some(header) = image_data.get(offset..offset + 8) {
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if &header[0..4] == b"ANMF" {
+            if let Some(data) = image_data.get(offset + 8..offset + 24) {
+                frames += 1;
+                duration =
+                    duration.saturating_add(u32::from_le_bytes([data[12], data[13], data[14], 0]));
+            }
+        }
+        offset += 8 + length + length % 2;
+    }
+    (frames, duration)
+}
@@ -0,0 +1,854 @@
+//! Reads the flattened image Photoshop stores after the layers, for every colour mode and
+//! depth that has an RGB meaning, in both PSD and large-document PSB files.
+
+use image::RgbaImage;
+use miniz_oxide::inflate::decompress_to_vec_zlib_with_limit;
+
+use crate::{
+    error::{ErrorCode, ImgprocError, Result},
+    image_decode::parse_psd,
+    limits::current_limits,
+    psd_layers::composite_visible_layers,
+};
+
+/// Image resource holding the writer version and whether the merged image is real.
+const VERSION_INFO: u16 = 1057;
+/// The palette index that indexed documents treat as transparent.
+const TRANSPARENCY_INDEX: u16 = 1047;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PsdColorMode {
+    Bitmap,
+    Grayscale,
+    Indexed,
+    Rgb,
+    Cmyk,
+    Multichannel,
+    Duotone,
+    Lab,
+}
+
+impl PsdColorMode {
+    fn from_u16(mode: u16) -> Option<Self> {
+        Some(match mode {
+            0 => PsdColorMode::Bitmap,
+            1 => PsdColorMode::Grayscale,
+            2 => PsdColorMode::Indexed,
+            3 => PsdColorMode::Rgb,
+            4 => PsdColorMode::Cmyk,
+            7 => PsdColorMode::Multichannel,
+            8 => PsdColorMode::Duotone,
+            9 => PsdColorMode::Lab,
+            _ => return None,
+        })
+    }
+
+    /// Channels that carry colour; the next one, if present, is taken as alpha.
+    fn color_channels(&self) -> usize {
+        match self {
+            PsdColorMode::Rgb | PsdColorMode::Lab => 3,
+            PsdColorMode::Cmyk => 4,
+            _ => 1,
+        }
+    }
+}
+
+/// The fixed-size file header.
+#[derive(Clone, Copy, Debug)]
+pub struct PsdHeader {
+    /// A PSB file, whose section lengths are 64-bit.
+    pub is_psb: bool,
+    pub channels: u16,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per channel: 1, 8, 16 or 32.
+    pub depth: u16,
+    pub color_mode: PsdColorMode,
+}
+
+/// Which part of the file a decoded PSD came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PsdSource {
+    /// The flattened image stored in the Image Data section.
+    MergedImage,
+    /// The visible layers, composited because the file has no real merged image.
+    Layers,
+}
+
+impl PsdSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PsdSource::MergedImage => "merged_image",
+            PsdSource::Layers => "layers",
+        }
+    }
+}
+
+pub fn read_psd_header(image_data: &[u8]) -> Result<PsdHeader> {
+    let mut reader = Reader::new(image_data);
+    if reader.bytes(4)? != b"8BPS" {
+        return Err(corrupt("The PSD signature is missing.", 0));
+    }
+    let is_psb = match reader.u16()? {
+        1 => false,
+        2 => true,
+        version => {
+            return Err(corrupt(format!("Unknown PSD version {}.", version), 4));
+        }
+    };
+    reader.bytes(6)?;
+    let channels = reader.u16()?;
+    let height = reader.u32()?;
+    let width = reader.u32()?;
+    let depth = reader.u16()?;
+    if ![1, 8, 16, 32].contains(&depth) {
+        return Err(corrupt(format!("Unknown PSD bit depth {}.", depth), 22));
+    }
+    let mode = reader.u16()?;
+    let color_mode = PsdColorMode::from_u16(mode)
+        .ok_or_else(|| corrupt(format!("Unknown PSD colour mode {}.", mode), 24))?;
+
+    Ok(PsdHeader {
+        is_psb,
+        channels,
+        width,
+        height,
+        depth,
+        color_mode,
+    })
+}
+
+/// Decodes the merged image, or composites the visible layers when Photoshop saved the file
+/// without one ("Maximize Compatibility" off).
+pub fn decode_psd(image_data: &[u8]) -> Result<(RgbaImage, PsdSource)> {
+    let header = read_psd_header(image_data)?;
+    current_limits().check_dimensions(header.width, header.height, "PSD")?;
+    let sections = read_sections(image_data, &header)?;
+
+    if !has_real_merged_data(sections.resources) {
+        let psd = parse_psd(image_data)?;
+        if !psd.layers().is_empty() {
+            return Ok((composite_visible_layers(&psd)?, PsdSource::Layers));
+        }
+    }
+    Ok((merged_image(&header, &sections)?, PsdSource::MergedImage))
+}
+
+/// The variable-length sections between the header and the merged image.
+pub(crate) struct Sections<'a> {
+    pub(crate) color_data: &'a [u8],
+    pub(crate) resources: &'a [u8],
+    /// The layer and mask information, without its length.
+    pub(crate) layers: &'a [u8],
+    pub(crate) layers_offset: usize,
+    /// From the compression method to the end of the file.
+    pub(crate) image_data: &'a [u8],
+    pub(crate) image_data_offset: usize,
+}
+
+pub(crate) fn read_sections<'a>(image_data: &'a [u8], header: &PsdHeader) -> Result<Sections<'a>> {
+    let mut reader = Reader::new(image_data);
+    reader.at = 26;
+    let color_data_len = reader.u32()? as usize;
+    let color_data = reader.bytes(color_data_len)?;
+    let resources_len = reader.u32()? as usize;
+    let resources = reader.bytes(resources_len)?;
+    let layers_len = match header.is_psb {
+        true => reader.u64()?,
+        false => reader.u32()? as u64,
+    };
+    let layers_offset = reader.at;
+    let layers = reader.bytes(usize::try_from(layers_len).unwrap_or(usize::MAX))?;
+
+    Ok(Sections {
+        color_data,
+        resources,
+        layers,
+        layers_offset,
+        image_data: &image_data[reader.at..],
+        image_data_offset: reader.at,
+    })
+}
+
+fn merged_image(header: &PsdHeader, sections: &Sections) -> Result<RgbaImage> {
+    let mut reader = Reader::new(sections.image_data);
+    reader.base = sections.image_data_offset;
+    let compression = reader.u16()?;
+
+    let (width, height) = (header.width as usize, header.height as usize);
+    let row_bytes = (width * header.depth as usize).div_ceil(8);
+    let needed = match header.color_mode {
+        PsdColorMode::Multichannel => {
+            return Err(ImgprocError::new(
+                ErrorCode::UnsupportedFormat,
+                "Multichannel PSD documents have no RGB meaning.",
+            )
+            .format("PSD"));
+        }
+        PsdColorMode::Bitmap | PsdColorMode::Indexed => 1,
+        mode => mode.color_channels(),
+    };
+    if (header.channels as usize) < needed {
+        return Err(corrupt(
+            "The PSD has fewer channels than its colour mode needs.",
+            12,
+        ));
+    }
+    // Bitmap documents are 1-bit, indexed ones 8-bit and the rest at least 8-bit.
+    let depth_fits = match header.color_mode {
+        PsdColorMode::Bitmap => header.depth == 1,
+        PsdColorMode::Indexed => header.depth == 8,
+        _ => header.depth != 1,
+    };
+    if !depth_fits {
+        return Err(corrupt(
+            format!(
+                "A {}-bit PSD cannot have the {:?} colour mode.",
+                header.depth, header.color_mode
+            ),
+            22,
+        ));
+    }
+    // Colour, then alpha if there is another channel.
+    let used = match header.color_mode {
+        PsdColorMode::Bitmap | PsdColorMode::Indexed => 1,
+        _ => (needed + 1).min(header.channels as usize),
+    };
+
+    let planes = match compression {
+        0 => (0..used)
+            .map(|_| {
+                reader
+                    .bytes(row_bytes.saturating_mul(height))
+                    .map(<[u8]>::to_vec)
+            })
+            .collect::<Result<Vec<_>>>()?,
+        1 => read_rle_planes(&mut reader, header, used, row_bytes)?,
+        2 | 3 => read_zip_planes(&reader, header, used, row_bytes, compression == 3)?,
+        _ => {
+            return Err(corrupt(
+                format!("Unknown PSD compression {}.", compression),
+                sections.image_data_offset as u64,
+            ));
+        }
+    };
+    let (color_data, resources) = (sections.color_data, sections.resources);
+
+    let mut image = RgbaImage::new(header.width, header.height);
+    let value = |plane: usize, i: usize| sample(&planes[plane], header.depth, i);
+    match header.color_mode {
+        PsdColorMode::Bitmap => {
+            for (i, pixel) in image.pixels_mut().enumerate() {
+                let (x, y) = (i % width, i / width);
+                let black = planes[0][y * row_bytes + x / 8] & (0x80 >> (x % 8)) != 0;
+                let value = if black { 0 } else { 255 };
+                pixel.0 = [value, value, value, 255];
+            }
+        }
+        PsdColorMode::Indexed => {
+            if color_data.len() < 768 {
+                return Err(corrupt("The indexed PSD has no palette.", 26));
+            }
+            let transparent = transparency_index(resources);
+            for (i, pixel) in image.pixels_mut().enumerate() {
+                let index = planes[0][i] as usize;
+                let alpha = if Some(index) == transparent { 0 } else { 255 };
+                pixel.0 = [
+                    color_data[index],
+                    color_data[256 + index],
+                    color_data[512 + index],
+                    alpha,
+                ];
+            }
+        }
+        mode => {
+            let linear = header.depth == 32;
+            let alpha_plane = (used > mode.color_channels()).then_some(used - 1);
+            for (i, pixel) in image.pixels_mut().enumerate() {
+                let channel = |plane| match linear {
+                    true => srgb_encode(value(plane, i)),
+                    false => value(plane, i),
+                };
+                let rgb = match mode {
+                    PsdColorMode::Rgb => [channel(0), channel(1), channel(2)],
+                    // Stored inverted, so 1.0 is no ink. Without the document's ICC profile
+                    // this is the plain subtractive conversion.
+                    PsdColorMode::Cmyk => {
+                        let k = value(3, i);
+                        [value(0, i) * k, value(1, i) * k, value(2, i) * k]
+                    }
+                    PsdColorMode::Lab => lab_to_srgb(value(0, i), value(1, i), value(2, i)),
+                    // Grayscale, and duotone, whose plates Photoshop stores as grayscale.
+                    _ => [channel(0); 3],
+                };
+                let alpha = alpha_plane.map_or(1.0, |plane| value(plane, i));
+                pixel.0 = [to_u8(rgb[0]), to_u8(rgb[1]), to_u8(rgb[2]), to_u8(alpha)];
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// PackBits rows, preceded by a table of every row's compressed length: 16-bit in PSD files
+/// and 32-bit in PSB files. Only the first `used` channels are decompressed.
+fn read_rle_planes(
+    reader: &mut Reader,
+    header: &PsdHeader,
+    used: usize,
+    row_bytes: usize,
+) -> Result<Vec<Vec<u8>>> {
+    let height = header.height as usize;
+    // The table is read whole first, so a channel count it has no room for fails here.
+    let size = if header.is_psb { 4 } else { 2 };
+    let lengths = reader.bytes((size * header.channels as usize).saturating_mul(height))?;
+    let length = |row: usize| {
+        let b = &lengths[row * size..row * size + size];
+        match header.is_psb {
+            true => u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize,
+            false => u16::from_be_bytes([b[0], b[1]]) as usize,
+        }
+    };
+
+    let mut planes = Vec::with_capacity(used);
+    for channel in 0..used {
+        let mut plane = Vec::with_capacity(row_bytes * height);
+        for row in 0..height {
+            let offset = reader.offset();
+            let packed = reader.bytes(length(channel * height + row))?;
+            unpack_bits(packed, row_bytes, &mut plane)
+                .ok_or_else(|| corrupt("A PSD image row is malformed.", offset))?;
+        }
+        planes.push(plane);
+    }
+    Ok(planes)
+}
+
+/// One zlib stream holding every channel. With prediction, each row stores the difference
+/// from the previous sample, and 32-bit rows are split into planes of first, second, third
+/// and fourth bytes before that.
+fn read_zip_planes(
+    reader: &Reader,
+    header: &PsdHeader,
+    used: usize,
+    row_bytes: usize,
+    prediction: bool,
+) -> Result<Vec<Vec<u8>>> {
+    let height = header.height as usize;
+    let plane_len = row_bytes * height;
+    let offset = reader.offset();
+    let mut data = decompress_to_vec_zlib_with_limit(
+        &reader.data[reader.at..],
+        plane_len.saturating_mul(header.channels as usize),
+    )
+    .map_err(|e| {
+        corrupt("Failed to inflate the PSD merged image.", offset).cause(format!("{:?}", e.status))
+    })?;
+    if data.len() < plane_len * used {
+        return Err(corrupt("The PSD merged image is truncated.", offset));
+    }
+    data.truncate(plane_len * used);
+
+    if prediction {
+        for row in data.chunks_exact_mut(row_bytes) {
+            match header.depth {
+                16 => {
+                    for i in (2..row.len()).step_by(2) {
+                        let previous = u16::from_be_bytes([row[i - 2], row[i - 1]]);
+                        let delta = u16::from_be_bytes([row[i], row[i + 1]]);
+                        row[i..i + 2].copy_from_slice(&previous.wrapping_add(delta).to_be_bytes());
+                    }
+                }
+                _ => {
+                    for i in 1..row.len() {
+                        row[i] = row[i].wrapping_add(row[i - 1]);
+                    }
+                }
+            }
+            if header.depth == 32 {
+                let width = row.len() / 4;
+                let planar = row.to_vec();
+                for (x, sample) in row.chunks_exact_mut(4).enumerate() {
+                    for (byte, value) in sample.iter_mut().enumerate() {
+                        *value = planar[byte * width + x];
+                    }
+                }
+            }
+        }
+    }
+    Ok(data.chunks_exact(plane_len).map(<[u8]>::to_vec).collect())
+}
+
+/// Appends exactly `len` bytes unpacked from one row, or `None` if the row is malformed.
+fn unpack_bits(packed: &[u8], len: usize, out: &mut Vec<u8>) -> Option<()> {
+    let end = out.len() + len;
+    let mut at = 0;
+    while out.len() < end {
+        let header = *packed.get(at)? as i8;
+        at += 1;
+        match header {
+            0..=127 => {
+                let run = packed.get(at..at + header as usize + 1)?;
+                out.extend_from_slice(run);
+                at += run.len();
+            }
+            -127..=-1 => {
+                let value = *packed.get(at)?;
+                out.extend(std::iter::repeat_n(value, (1 - header as isize) as usize));
+                at += 1;
+            }
+            -128 => {}
+        }
+    }
+    (out.len() == end).then_some(())
+}
+
+/// Sample `i` of a plane, from 0 to 1.
+fn sample(plane: &[u8], depth: u16, i: usize) -> f32 {
+    match depth {
+        16 => u16::from_be_bytes([plane[i * 2], plane[i * 2 + 1]]) as f32 / 65535.0,
+        32 => {
+            let b = &plane[i * 4..i * 4 + 4];
+            f32::from_be_bytes([b[0], b[1], b[2], b[3]]).clamp(0.0, 1.0)
+        }
+        _ => plane[i] as f32 / 255.0,
+    }
+}
+
+fn to_u8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// 32-bit documents are linear light.
+fn srgb_encode(linear: f32) -> f32 {
+    match linear <= 0.003_130_8 {
+        true => linear * 12.92,
+        false => 1.055 * linear.powf(1.0 / 2.4) - 0.055,
+    }
+}
+
+/// CIELAB (D50) with all three channels scaled to 0..1, to gamma-encoded sRGB.
+fn lab_to_srgb(l: f32, a: f32, b: f32) -> [f32; 3] {
+    let (l, a, b) = (l * 100.0, a * 255.0 - 128.0, b * 255.0 - 128.0);
+    let f = |t: f32| match t > 6.0 / 29.0 {
+        true => t * t * t,
+        false => 3.0 * (6.0f32 / 29.0).powi(2) * (t - 4.0 / 29.0),
+    };
+    let fy = (l + 16.0) / 116.0;
+    let (x, y, z) = (
+        0.964_22 * f(fy + a / 500.0),
+        f(fy),
+        0.825_21 * f(fy - b / 200.0),
+    );
+    // XYZ (D50) to linear sRGB, with Bradford adaptation to D65.
+    let r = 3.133_856 * x - 1.616_867 * y - 0.490_615 * z;
+    let g = -0.978_768 * x + 1.916_142 * y + 0.033_454 * z;
+    let b = 0.071_945 * x - 0.228_991 * y + 1.405_243 * z;
+    [
+        srgb_encode(r.max(0.0)),
+        srgb_encode(g.max(0.0)),
+        srgb_encode(b.max(0.0)),
+    ]
+}
+
+/// Looks through the `8BIM` image resources for one by ID.
+fn find_resource(resources: &[u8], id: u16) -> Option<&[u8]> {
+    let mut reader = Reader::new(resources);
+    while reader.bytes(4).ok()? == b"8BIM" {
+        let resource_id = reader.u16().ok()?;
+        // Pascal name padded to an even length, counting the length byte.
+        let name_len = reader.bytes(1).ok()?[0] as usize;
+        reader.bytes(name_len + (name_len + 1) % 2).ok()?;
+        let size = reader.u32().ok()? as usize;
+        let data = reader.bytes(size).ok()?;
+        reader.bytes(size % 2).ok()?;
+        if resource_id == id {
+            return Some(data);
+        }
+    }
+    None
+}
+
+fn transparency_index(resources: &[u8]) -> Option<usize> {
+    let data = find_resource(resources, TRANSPARENCY_INDEX)?;
+    Some(u16::from_be_bytes([*data.first()?, *data.get(1)?]) as usize)
+}
+
+/// The version info resource says whether the merged image is real; files saved without
+/// "Maximize Compatibility" store a blank one. Absent means it is real.
+fn has_real_merged_data(resources: &[u8]) -> bool {
+    find_resource(resources, VERSION_INFO)
+        .and_then(|data| data.get(4))
+        .is_none_or(|real| *real != 0)
+}
+
+pub(crate) fn corrupt(message: impl Into<String>, offset: u64) -> ImgprocError {
+    ImgprocError::corrupt(message).format("PSD").offset(offset)
+}
+
+/// Big-endian reads that fail with the offset of the truncation.
+#[derive(Clone)]
+pub(crate) struct Reader<'a> {
+    pub(crate) data: &'a [u8],
+    pub(crate) at: usize,
+    /// Offset of `data` in the file, for errors.
+    pub(crate) base: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Reader {
+            data,
+            at: 0,
+            base: 0,
+        }
+    }
+
+    /// Offset of the next read in the file.
+    pub(crate) fn offset(&self) -> u64 {
+        (self.base + self.at) as u64
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .at
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.at..end))
+            .ok_or_else(|| corrupt("The PSD is truncated.", self.offset()))?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let b = self.bytes(8)?;
+        Ok(u64::from_be_bytes(b.try_into().unwrap_or_default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+
+    use super::*;
+    use crate::{
+        formats::tests::decode_truncations,
+        limits::{set_limits, DecodeLimits},
+        psd_layers::tests::{layer_info, Document, Layer},
+    };
+
+    /// PackBits, with runs of two or more bytes repeated.
+    fn pack_bits(mut row: &[u8]) -> Vec<u8> {
+        let mut packed = Vec::new();
+        while let Some(&first) = row.first() {
+            let run = row.iter().take(128).take_while(|b| **b == first).count();
+            let len = match run {
+                1 => (1..row.len().min(128))
+                    .find(|&i| row.get(i + 1) == Some(&row[i]))
+                    .unwrap_or(row.len().min(128)),
+                _ => run,
+            };
+            match run {
+                1 => {
+                    packed.push(len as u8 - 1);
+                    packed.extend_from_slice(&row[..len]);
+                }
+                _ => packed.extend_from_slice(&[(1 - run as i32) as u8, first]),
+            }
+            row = &row[len..];
+        }
+        packed
+    }
+
+    fn raw(planes: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0, 0];
+        for plane in planes {
+            data.extend_from_slice(plane);
+        }
+        data
+    }
+
+    fn rle(planes: &[&[u8]], row_bytes: usize, psb: bool) -> Vec<u8> {
+        let rows: Vec<_> = planes
+            .iter()
+            .flat_map(|plane| plane.chunks(row_bytes).map(pack_bits))
+            .collect();
+        let mut data = vec![0, 1];
+        for row in &rows {
+            match psb {
+                true => data.extend_from_slice(&(row.len() as u32).to_be_bytes()),
+                false => data.extend_from_slice(&(row.len() as u16).to_be_bytes()),
+            }
+        }
+        data.extend(rows.concat());
+        data
+    }
+
+    fn zip(planes: &[&[u8]], prediction: bool) -> Vec<u8> {
+        let mut data = vec![0, if prediction { 3 } else { 2 }];
+        data.extend(compress_to_vec_zlib(&planes.concat(), 6));
+        data
+    }
+
+    /// An image resource block.
+    fn resource(id: u16, data: &[u8]) -> Vec<u8> {
+        let mut block = b"8BIM".to_vec();
+        block.extend_from_slice(&id.to_be_bytes());
+        block.extend_from_slice(&[0, 0]);
+        block.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        block.extend_from_slice(data);
+        if !data.len().is_multiple_of(2) {
+            block.push(0);
+        }
+        block
+    }
+
+    fn decode(document: Document) -> Result<(RgbaImage, PsdSource)> {
+        decode_psd(&document.to_bytes())
+    }
+
+    fn pixels(document: Document) -> Vec<[u8; 4]> {
+        let (image, source) = decode(document).unwrap();
+        assert_eq!(source, PsdSource::MergedImage);
+        image.pixels().map(|pixel| pixel.0).collect()
+    }
+
+    fn error(document: Document) -> ErrorCode {
+        decode(document).unwrap_err().code
+    }
+
+    const RED: [u8; 6] = [255, 255, 0, 0, 10, 200];
+    const GREEN: [u8; 6] = [0, 0, 255, 255, 20, 100];
+    const BLUE: [u8; 6] = [0, 0, 0, 0, 30, 0];
+    const ALPHA: [u8; 6] = [255, 255, 255, 128, 128, 0];
+
+    #[test]
+    fn reads_raw_and_rle_rgba() {
+        let planes: &[&[u8]] = &[&RED, &GREEN, &BLUE, &ALPHA];
+        let expected: Vec<_> = (0..6)
+            .map(|i| [RED[i], GREEN[i], BLUE[i], ALPHA[i]])
+            .collect();
+        for (version, image_data) in [
+            (1, raw(planes)),
+            (1, rle(planes, 3, false)),
+            (2, rle(planes, 3, true)),
+        ] {
+            let document = Document {
+                version,
+                channels: 4,
+                image_data,
+                ..Document::rgb(3, 2)
+            };
+            assert_eq!(pixels(document), expected, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn reads_zip_with_and_without_prediction() {
+        let document = Document {
+            image_data: zip(&[&RED[..3], &GREEN[..3], &BLUE[..3]], false),
+            ..Document::rgb(3, 1)
+        };
+        assert_eq!(
+            pixels(document),
+            [[255, 0, 0, 255], [255, 0, 0, 255], [0, 255, 0, 255]]
+        );
+
+        // Each 16-bit sample stores the difference from the one before it.
+        let gray: &[u8] = &[0x00, 0x00, 0x80, 0x00, 0x7f, 0xff];
+        let document = Document {
+            channels: 1,
+            depth: 16,
+            mode: 1,
+            image_data: zip(&[gray], true),
+            ..Document::rgb(3, 1)
+        };
+        let values: Vec<_> = pixels(document).iter().map(|p| p[0]).collect();
+        assert_eq!(values, [0, 128, 255]);
+
+        // 32-bit rows are split into byte planes before the differences are taken.
+        let samples = [0.0f32, 0.216, 1.0].map(f32::to_be_bytes);
+        let mut planar: Vec<u8> = (0..4)
+            .flat_map(|byte| samples.iter().map(move |s| s[byte]))
+            .collect();
+        for i in (1..planar.len()).rev() {
+            planar[i] = planar[i].wrapping_sub(planar[i - 1]);
+        }
+        let document = Document {
+            channels: 1,
+            depth: 32,
+            mode: 1,
+            image_data: zip(&[&planar], true),
+            ..Document::rgb(3, 1)
+        };
+        let values: Vec<_> = pixels(document).iter().map(|p| p[0]).collect();
+        assert_eq!(values, [0, 128, 255]);
+    }
+
+    #[test]
+    fn converts_cmyk_lab_indexed_and_bitmap() {
+        // Stored inverted: 255 is no ink.
+        let cmyk = Document {
+            channels: 4,
+            mode: 4,
+            image_data: raw(&[&[255, 0], &[255, 255], &[255, 255], &[255, 255]]),
+            ..Document::rgb(2, 1)
+        };
+        assert_eq!(pixels(cmyk), [[255, 255, 255, 255], [0, 255, 255, 255]]);
+
+        let lab = Document {
+            mode: 9,
+            image_data: raw(&[&[255, 0], &[128, 128], &[128, 128]]),
+            ..Document::rgb(2, 1)
+        };
+        assert_eq!(pixels(lab), [[255, 255, 255, 255], [0, 0, 0, 255]]);
+
+        let mut palette = vec![0; 768];
+        palette[1] = 10;
+        palette[256 + 1] = 20;
+        palette[512 + 1] = 30;
+        let indexed = Document {
+            channels: 1,
+            mode: 2,
+            color_data: palette,
+            resources: resource(TRANSPARENCY_INDEX, &[0, 2]),
+            image_data: raw(&[&[1, 2]]),
+            ..Document::rgb(2, 1)
+        };
+        assert_eq!(pixels(indexed), [[10, 20, 30, 255], [0, 0, 0, 0]]);
+
+        // Set bits are black.
+        let bitmap = Document {
+            channels: 1,
+            depth: 1,
+            mode: 0,
+            image_data: raw(&[&[0b1000_0001, 0b1000_0000]]),
+            ..Document::rgb(9, 1)
+        };
+        let values: Vec<_> = pixels(bitmap).iter().map(|p| p[0]).collect();
+        assert_eq!(values, [0, 255, 255, 255, 255, 255, 255, 0, 0]);
+    }
+
+    #[test]
+    fn composites_layers_without_a_real_merged_image() {
+        let layered = |real: u8| Document {
+            resources: resource(VERSION_INFO, &[0, 0, 0, 1, real]),
+            layers: layer_info(&[Layer::new("red", 0, 0, 1, &[[255, 0, 0, 255]])]),
+            ..Document::rgb(1, 1)
+        };
+        let (image, source) = decode(layered(0)).unwrap();
+        assert_eq!(source, PsdSource::Layers);
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(pixels(layered(1)), [[0, 0, 0, 255]]);
+    }
+
+    #[test]
+    fn rejects_malformed_merged_images() {
+        let rgb = || Document::rgb(2, 1);
+        let corrupt = [
+            Document {
+                image_data: vec![0, 5],
+                ..rgb()
+            },
+            Document {
+                version: 3,
+                ..rgb()
+            },
+            Document { depth: 7, ..rgb() },
+            Document { mode: 5, ..rgb() },
+            Document {
+                depth: 1,
+                mode: 1,
+                channels: 1,
+                ..rgb()
+            },
+            Document {
+                depth: 16,
+                mode: 2,
+                channels: 1,
+                ..rgb()
+            },
+            Document {
+                channels: 4,
+                mode: 4,
+                ..rgb()
+            },
+            Document {
+                channels: 1,
+                mode: 2,
+                ..rgb()
+            },
+            // Rows that unpack to one byte too few, and one too many.
+            Document {
+                image_data: vec![0, 1, 0, 2, 0, 2, 0, 2, 0, 9, 0, 9, 0, 9],
+                ..rgb()
+            },
+            Document {
+                image_data: vec![0, 1, 0, 2, 0, 2, 0, 2, 0xfe, 9, 0xfe, 9, 0xfe, 9],
+                ..rgb()
+            },
+            Document {
+                image_data: vec![0, 2, 0x78, 0x9c, 1, 2, 3],
+                ..rgb()
+            },
+        ];
+        for document in corrupt {
+            assert_eq!(error(document), ErrorCode::CorruptData);
+        }
+        let multichannel = Document { mode: 7, ..rgb() };
+        assert_eq!(error(multichannel), ErrorCode::UnsupportedFormat);
+    }
+
+    #[test]
+    fn truncated_documents_fail_without_panicking() {
+        let planes: &[&[u8]] = &[&RED, &GREEN, &BLUE, &ALPHA];
+        for image_data in [raw(planes), rle(planes, 3, false)] {
+            let file = Document {
+                channels: 4,
+                image_data,
+                ..Document::rgb(3, 2)
+            }
+            .to_bytes();
+            for len in 0..file.len() {
+                assert!(decode_psd(&file[..len]).is_err(), "length {}", len);
+            }
+            decode_truncations(&file, "psd");
+        }
+    }
+
+    #[test]
+    fn limits_are_checked_from_the_header() {
+        let document = Document {
+            width: 40_000,
+            height: 40_000,
+            ..Document::rgb(1, 1)
+        };
+        let header = &document.to_bytes()[..26];
+        assert_eq!(
+            decode_psd(header).unwrap_err().code,
+            ErrorCode::LimitExceeded
+        );
+
+        // The row length table of a huge channel count is not read before it is checked.
+        let channels = Document {
+            channels: u16::MAX,
+            height: 16_384,
+            image_data: vec![0, 1],
+            ..Document::rgb(1, 1)
+        };
+        assert_eq!(error(channels), ErrorCode::CorruptData);
+
+        let mut limits = DecodeLimits::default();
+        limits.set_max_height(1);
+        set_limits(limits);
+        assert_eq!(error(Document::rgb(1, 2)), ErrorCode::LimitExceeded);
+    }
+}
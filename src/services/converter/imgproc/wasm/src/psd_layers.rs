@@ -0,0 +1,1131 @@
+use std::collections::BTreeSet;
+
+use image::{imageops, RgbaImage};
+use psd::{Psd, PsdChannelKind, PsdDepth};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    callback_logs::{CallbackLogs, ProgressEvent, Stage},
+    error::{ErrorCode, ImgprocError, Result},
+    first_frame::encode_png,
+    image_decode::parse_psd,
+    psd_image::{corrupt, decode_psd, read_psd_header, read_sections, Reader},
+};
+
+/// One layer or group of a PSD, OpenRaster or Krita document.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub struct PsdLayerInfo {
+    pub(crate) kind: &'static str,
+    pub(crate) id: u32,
+    pub(crate) parent_id: Option<u32>,
+    pub(crate) name: String,
+    pub(crate) visible: bool,
+    pub(crate) opacity: u8,
+    pub(crate) blend_mode: String,
+    pub(crate) left: i32,
+    pub(crate) top: i32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl PsdLayerInfo {
+    /// `layer` or `group`.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn kind(&self) -> String {
+        self.kind.to_string()
+    }
+
+    /// The layer index or group ID that `PsdRenderOptions` selects by.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The enclosing group, if any.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn parent_id(&self) -> Option<u32> {
+        self.parent_id
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// The layer's own eye toggle, regardless of its groups.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn visible(&self) -> bool {
+        self.visible
+    }
+
+    /// From 0, transparent, to 255.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn opacity(&self) -> u8 {
+        self.opacity
+    }
+
+    /// Snake-case mode name, such as `normal`, `multiply` or `pass_through`. OpenRaster's
+    /// `svg:` prefix is dropped.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn blend_mode(&self) -> String {
+        self.blend_mode.clone()
+    }
+
+    /// Bounds on the canvas; may extend past it. The size is zero for groups and for Krita
+    /// layers.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn left(&self) -> i32 {
+        self.left
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn top(&self) -> i32 {
+        self.top
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug, Default)]
+pub struct PsdRenderOptions {
+    layers: Vec<u32>,
+    groups: Vec<u32>,
+    split: bool,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl PsdRenderOptions {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new() -> PsdRenderOptions {
+        PsdRenderOptions::default()
+    }
+
+    /// Layer indices to render. With no layers or groups, every layer is rendered.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn layers(&self) -> Vec<u32> {
+        self.layers.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_layers(&mut self, value: Vec<u32>) {
+        self.layers = value;
+    }
+
+    /// Group IDs whose layers, nested groups included, are rendered.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn groups(&self) -> Vec<u32> {
+        self.groups.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_groups(&mut self, value: Vec<u32>) {
+        self.groups = value;
+    }
+
+    /// Export each selected layer as its own PNG, trimmed to its opaque pixels, instead of
+    /// compositing them.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn split(&self) -> bool {
+        self.split
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_split(&mut self, value: bool) {
+        self.split = value;
+    }
+}
+
+/// A rendered PNG and where it sits on the canvas.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub struct PsdImage {
+    layer: Option<u32>,
+    name: String,
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+    png: Vec<u8>,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl PsdImage {
+    /// The layer index, unset for a composite.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn layer(&self) -> Option<u32> {
+        self.layer
+    }
+
+    /// The layer name, or `composite`.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn left(&self) -> u32 {
+        self.left
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn top(&self) -> u32 {
+        self.top
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn png(&self) -> Vec<u8> {
+        self.png.clone()
+    }
+}
+
+/// Lists the groups, each before the group enclosing it, then the layers from the top of the
+/// stack down.
+/// The hierarchy is given by `parent_id`.
+pub fn list_psd_layers(image_data: &[u8]) -> Result<Vec<PsdLayerInfo>> {
+    let psd = parse_psd(image_data)?;
+    let mut entries = Vec::with_capacity(psd.groups().len() + psd.layers().len());
+
+    for id in psd.group_ids_in_order() {
+        let Some(group) = psd.groups().get(id) else {
+            continue;
+        };
+        entries.push(PsdLayerInfo {
+            kind: "group",
+            id: group.id(),
+            parent_id: group.parent_id(),
+            name: group.name().to_string(),
+            visible: !group.visible(),
+            opacity: group.opacity(),
+            blend_mode: blend_mode_name(group.blend_mode()),
+            left: 0,
+            top: 0,
+            width: 0,
+            height: 0,
+        });
+    }
+    for (index, layer) in psd.layers().iter().enumerate() {
+        entries.push(PsdLayerInfo {
+            kind: "layer",
+            id: index as u32,
+            parent_id: layer.parent_id(),
+            name: layer.name().to_string(),
+            visible: !layer.visible(),
+            opacity: layer.opacity(),
+            blend_mode: blend_mode_name(layer.blend_mode()),
+            left: layer.layer_left(),
+            top: layer.layer_top(),
+            width: layer.width() as u32,
+            height: layer.height() as u32,
+        });
+    }
+    Ok(entries)
+}
+
+/// `psd` doesn't export its blend mode type, so the name comes from its `Debug` output.
+fn blend_mode_name(mode: impl std::fmt::Debug) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", mode).chars() {
+        if c.is_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+/// Composites the selected layers onto the canvas, or exports each as a trimmed PNG.
+///
+/// Hidden layers stay hidden when compositing, but a selected group is drawn even if it or
+/// an enclosing group is switched off, so variants kept in hidden groups can be rendered.
+pub fn render_psd(
+    image_data: &[u8],
+    options: &PsdRenderOptions,
+    logs: &CallbackLogs,
+) -> Result<Vec<PsdImage>> {
+    logs.send(ProgressEvent::new(Stage::Decode, "decode_start").arg("PSD"))?;
+    if options.split() {
+        let psd = parse_psd(image_data)?;
+        let selected = selected_layers(&psd, options)?;
+        let total = selected.len() as u32;
+        let mut images = Vec::with_capacity(selected.len());
+        for (i, index) in selected.iter().enumerate() {
+            let name = psd.layers()[*index].name();
+            logs.send(
+                ProgressEvent::new(Stage::Encode, "export_layer")
+                    .arg(name)
+                    .step(i as u32 + 1, Some(total)),
+            )?;
+            images.extend(layer_image(&psd, *index)?);
+        }
+        return Ok(images);
+    }
+
+    let image = if options.layers.is_empty() && options.groups.is_empty() {
+        // Photoshop's merged image already is the composite of every visible layer.
+        let (image, source) = decode_psd(image_data)?;
+        logs.send(ProgressEvent::new(Stage::Decode, "psd_source").arg(source.as_str()))?;
+        image
+    } else {
+        let psd = parse_psd(image_data)?;
+        let shown: Vec<usize> = selected_layers(&psd, options)?
+            .into_iter()
+            .filter(|index| is_shown(&psd, *index, options))
+            .collect();
+        logs.send(ProgressEvent::new(Stage::Encode, "composite_layers").arg(shown.len()))?;
+        composite(&psd, &shown)?
+    };
+
+    Ok(vec![PsdImage {
+        layer: None,
+        name: "composite".to_string(),
+        left: 0,
+        top: 0,
+        width: image.width(),
+        height: image.height(),
+        png: encode_png(image)?,
+    }])
+}
+
+/// Every layer that is switched on, composited; for files whose merged image can't be read.
+pub(crate) fn composite_visible_layers(psd: &Psd) -> Result<RgbaImage> {
+    let options = PsdRenderOptions::default();
+    let shown: Vec<usize> = (0..psd.layers().len())
+        .filter(|index| is_shown(psd, *index, &options))
+        .collect();
+    composite(psd, &shown)
+}
+
+fn composite(psd: &Psd, shown: &[usize]) -> Result<RgbaImage> {
+    for index in shown {
+        check_renderable(psd, *index)?;
+    }
+    let pixels = psd
+        .flatten_layers_rgba(&|(index, _)| shown.contains(&index))
+        .map_err(|e| {
+            ImgprocError::corrupt("Failed to composite PSD layers.")
+                .format("PSD")
+                .cause(e)
+        })?;
+    canvas_image(psd, pixels)
+}
+
+/// Whether a layer and every group around it are switched on, counting selected groups as on.
+/// `psd` reads flag bit 1 as `visible()`, but Photoshop sets it on hidden layers.
+fn is_shown(psd: &Psd, index: usize, options: &PsdRenderOptions) -> bool {
+    let layer = &psd.layers()[index];
+    if layer.visible() || layer.opacity() == 0 {
+        return false;
+    }
+    let mut parent = layer.parent_id();
+    while let Some(id) = parent {
+        let Some(group) = psd.groups().get(&id) else {
+            break;
+        };
+        if options.groups.contains(&id) {
+            return true;
+        }
+        if group.visible() {
+            return false;
+        }
+        parent = group.parent_id();
+    }
+    true
+}
+
+/// `psd` draws 8-bit samples only and unwraps the red channel. It indexes past the end of its
+/// buffer for layers reaching beyond the right or bottom edge of the canvas, and wraps pixels
+/// left of the canvas onto the row above.
+fn check_renderable(psd: &Psd, index: usize) -> Result<()> {
+    if psd.depth() != PsdDepth::Eight {
+        return Err(ImgprocError::new(
+            ErrorCode::UnsupportedFormat,
+            format!(
+                "Rendering the layers of {}-bit PSD documents is not supported yet.",
+                psd.depth() as u8
+            ),
+        )
+        .format("PSD"));
+    }
+    let layer = &psd.layers()[index];
+    if layer.compression(PsdChannelKind::Red).is_err() {
+        return Err(ImgprocError::corrupt(format!(
+            "Layer {} ({}) has no colour channel.",
+            index,
+            layer.name()
+        ))
+        .format("PSD"));
+    }
+    let right = layer.layer_left() as i64 + layer.width() as i64;
+    let bottom = layer.layer_top() as i64 + layer.height() as i64;
+    if layer.layer_left() < 0 || right > psd.width() as i64 || bottom > psd.height() as i64 {
+        return Err(ImgprocError::new(
+            ErrorCode::UnsupportedFormat,
+            format!(
+                "Layer {} ({}) extends past the canvas, which is not supported yet.",
+                index,
+                layer.name()
+            ),
+        )
+        .format("PSD"));
+    }
+    Ok(())
+}
+
+/// Layer indices picked by `options`, top to bottom as `psd` orders them.
+fn selected_layers(psd: &Psd, options: &PsdRenderOptions) -> Result<BTreeSet<usize>> {
+    let count = psd.layers().len();
+    if options.layers.is_empty() && options.groups.is_empty() {
+        return Ok((0..count).collect());
+    }
+
+    let mut selected = BTreeSet::new();
+    for index in &options.layers {
+        if *index as usize >= count {
+            return Err(ImgprocError::invalid_argument(format!(
+                "Layer {} is out of range; the document has {} layers.",
+                index, count
+            ))
+            .format("PSD"));
+        }
+        selected.insert(*index as usize);
+    }
+    for id in &options.groups {
+        if !psd.groups().contains_key(id) {
+            return Err(ImgprocError::invalid_argument(format!(
+                "The document has no group {}.",
+                id
+            ))
+            .format("PSD"));
+        }
+    }
+    for (index, layer) in psd.layers().iter().enumerate() {
+        let mut parent = layer.parent_id();
+        while let Some(id) = parent {
+            if options.groups.contains(&id) {
+                selected.insert(index);
+                break;
+            }
+            parent = psd.groups().get(&id).and_then(|group| group.parent_id());
+        }
+    }
+    Ok(selected)
+}
+
+fn canvas_image(psd: &Psd, pixels: Vec<u8>) -> Result<RgbaImage> {
+    RgbaImage::from_raw(psd.width(), psd.height(), pixels).ok_or_else(|| {
+        ImgprocError::corrupt("PSD layer data does not match the canvas size.").format("PSD")
+    })
+}
+
+/// One layer cropped to its bounds on the canvas and then to its non-transparent pixels.
+/// `None` for a layer with nothing visible on the canvas.
+fn layer_image(psd: &Psd, index: usize) -> Result<Option<PsdImage>> {
+    check_renderable(psd, index)?;
+    let layer = &psd.layers()[index];
+    let canvas = canvas_image(psd, layer.rgba())?;
+
+    let clamp_x = |x: i64| x.clamp(0, canvas.width() as i64) as u32;
+    let clamp_y = |y: i64| y.clamp(0, canvas.height() as i64) as u32;
+    let (left, top) = (layer.layer_left() as i64, layer.layer_top() as i64);
+    let (mut x0, mut y0) = (clamp_x(left), clamp_y(top));
+    let (mut x1, mut y1) = (
+        clamp_x(left + layer.width() as i64),
+        clamp_y(top + layer.height() as i64),
+    );
+
+    let opaque = |x: u32, y: u32| canvas.get_pixel(x, y)[3] != 0;
+    while y0 < y1 && (x0..x1).all(|x| !opaque(x, y0)) {
+        y0 += 1;
+    }
+    while y1 > y0 && (x0..x1).all(|x| !opaque(x, y1 - 1)) {
+        y1 -= 1;
+    }
+    while x0 < x1 && (y0..y1).all(|y| !opaque(x0, y)) {
+        x0 += 1;
+    }
+    while x1 > x0 && (y0..y1).all(|y| !opaque(x1 - 1, y)) {
+        x1 -= 1;
+    }
+    if x0 == x1 || y0 == y1 {
+        return Ok(None);
+    }
+
+    let image = imageops::crop_imm(&canvas, x0, y0, x1 - x0, y1 - y0).to_image();
+    Ok(Some(PsdImage {
+        layer: Some(index as u32),
+        name: layer.name().to_string(),
+        left: x0,
+        top: y0,
+        width: image.width(),
+        height: image.height(),
+        png: encode_png(image)?,
+    }))
+}
+
+/// A copy of the document for `psd`, which indexes its input unchecked and panics on much
+/// malformed data. Its reads of the layer records are retraced first, and the image
+/// resources and merged image, which it would parse too but `psd_image` reads, are left out.
+pub(crate) fn layer_document(image_data: &[u8]) -> Result<Vec<u8>> {
+    let header = read_psd_header(image_data)?;
+    if header.is_psb {
+        return Err(ImgprocError::new(
+            ErrorCode::UnsupportedFormat,
+            "Layers of PSB documents are not supported yet.",
+        )
+        .format("PSD"));
+    }
+    let sections = read_sections(image_data, &header)?;
+    check_layer_records(sections.layers, sections.layers_offset, header.depth)?;
+
+    let mut document = Vec::with_capacity(40 + sections.color_data.len() + sections.layers.len());
+    document.extend_from_slice(&image_data[..26]);
+    for section in [sections.color_data, &[], sections.layers] {
+        document.extend_from_slice(&(section.len() as u32).to_be_bytes());
+        document.extend_from_slice(section);
+    }
+    // A raw merged image with no samples.
+    document.extend_from_slice(&[0, 0]);
+    Ok(document)
+}
+
+/// What the checks need from one layer record.
+struct LayerRecord {
+    /// Channel IDs and the lengths of their data after the compression method.
+    channels: Vec<(i16, u32)>,
+    width: u32,
+    height: u32,
+    /// The section divider type, if the record has one.
+    divider: Option<i32>,
+}
+
+/// Fails where `psd` would slice out of bounds, overflow or reach an unimplemented case
+/// reading the layer info, and where it would draw more samples than a layer holds.
+fn check_layer_records(layers: &[u8], offset: usize, depth: u16) -> Result<()> {
+    if layers.is_empty() {
+        return Ok(());
+    }
+    let mut reader = Reader::new(layers);
+    reader.base = offset;
+    // `psd` skips the layer info length and reads a count even when it is zero.
+    reader.u32()?;
+    let count = reader.u16()? as i16;
+    if count == i16::MIN {
+        return Err(corrupt(
+            "The PSD layer count is out of range.",
+            offset as u64 + 4,
+        ));
+    }
+    let records = (0..count.unsigned_abs())
+        .map(|_| read_layer_record(&mut reader))
+        .collect::<Result<Vec<_>>>()?;
+
+    for record in &records {
+        for &(id, len) in &record.channels {
+            let at = reader.offset();
+            let compression = reader.u16()?;
+            let data = reader.bytes(len as usize)?;
+            if data.is_empty() {
+                continue;
+            }
+            let samples = match compression {
+                0 => data.len(),
+                1 => data
+                    .get(2 * record.height as usize..)
+                    .map(unpacked_len)
+                    .ok_or_else(|| corrupt("A PSD layer channel is truncated.", at))?,
+                2 | 3 => {
+                    return Err(ImgprocError::new(
+                        ErrorCode::UnsupportedFormat,
+                        "ZIP-compressed PSD layers are not supported yet.",
+                    )
+                    .format("PSD")
+                    .offset(at));
+                }
+                _ => {
+                    return Err(corrupt(
+                        format!("Unknown PSD layer compression {}.", compression),
+                        at,
+                    ));
+                }
+            };
+            let drawn = (-1..=2).contains(&id) && !matches!(record.divider, Some(1..=3));
+            if depth == 8 && drawn && samples > record.width as usize * record.height as usize {
+                return Err(corrupt(
+                    "A PSD layer channel holds more samples than the layer.",
+                    at,
+                ));
+            }
+        }
+    }
+
+    // Bottom up, group ends come before their starts; `psd` unwraps its stack of open groups.
+    let mut open = 0;
+    for record in records.iter().rev() {
+        match record.divider {
+            Some(1 | 2) => open += 1,
+            Some(3) if open == 0 => {
+                return Err(corrupt(
+                    "A PSD layer group ends before it starts.",
+                    offset as u64,
+                ));
+            }
+            Some(3) => open -= 1,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn read_layer_record(reader: &mut Reader) -> Result<LayerRecord> {
+    let at = reader.offset();
+    let mut edges = [0; 4];
+    for edge in &mut edges {
+        *edge = reader.u32()? as i32;
+    }
+    let [top, left, bottom, right] = edges;
+    // `psd` makes the far edges inclusive unless they are zero, and keeps sizes as 16-bit.
+    let size = |near: i32, far: i32| {
+        let far = if far == 0 {
+            Some(0)
+        } else {
+            far.checked_sub(1)
+        };
+        far?.checked_sub(near)
+            .filter(|span| (0..u16::MAX as i32).contains(span))
+            .map(|span| span as u32 + 1)
+    };
+    let (Some(width), Some(height)) = (size(left, right), size(top, bottom)) else {
+        return Err(corrupt("A PSD layer has invalid bounds.", at));
+    };
+
+    let mut channels = Vec::new();
+    for _ in 0..reader.u16()? {
+        let id = reader.u16()? as i16;
+        let len = reader.u32()?;
+        if len < 2 {
+            return Err(corrupt(
+                "A PSD layer channel is too short.",
+                reader.offset() - 4,
+            ));
+        }
+        channels.push((id, len - 2));
+    }
+    // Blend mode signature and key, opacity, clipping, flags, filler and extra data length.
+    reader.bytes(16)?;
+    // Layer mask and blending ranges.
+    for _ in 0..2 {
+        let len = reader.u32()? as usize;
+        reader.bytes(len)?;
+    }
+    let name_len = reader.bytes(1)?[0] as usize;
+    // `psd` overflows a byte working out the padding after a 255-byte name.
+    if name_len == 255 {
+        return Err(corrupt(
+            "A PSD layer name is too long.",
+            reader.offset() - 1,
+        ));
+    }
+    reader.bytes(name_len + (4 - (name_len + 1) % 4) % 4)?;
+
+    let mut divider = None;
+    while matches!(reader.clone().bytes(4)?, b"8BIM" | b"8B64") {
+        reader.bytes(4)?;
+        let key = reader.bytes(4)?;
+        let len = reader.u32()? as usize;
+        match key {
+            b"luni" => {
+                let end = reader.at.saturating_add(len);
+                let chars = reader.u32()? as usize;
+                let name = reader.bytes(chars.saturating_mul(2))?;
+                let units = name
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]));
+                if char::decode_utf16(units).any(|c| c.is_err()) {
+                    return Err(corrupt(
+                        "A PSD layer name is not valid UTF-16.",
+                        reader.offset(),
+                    ));
+                }
+                reader.at = end;
+            }
+            // `psd` reads the fields it knows of and not up to the stated length.
+            b"lsct" => {
+                divider = Some(reader.u32()? as i32);
+                if len >= 12 {
+                    reader.bytes(8)?;
+                }
+                if len >= 16 {
+                    reader.bytes(4)?;
+                }
+            }
+            _ => {
+                reader.bytes(len)?;
+            }
+        }
+    }
+
+    Ok(LayerRecord {
+        channels,
+        width,
+        height,
+        divider,
+    })
+}
+
+/// How many samples `psd` unpacks from a layer's PackBits data, which it reads as one stream
+/// up to the first truncated packet.
+fn unpacked_len(packed: &[u8]) -> usize {
+    let (mut at, mut len) = (0, 0);
+    while let Some(&header) = packed.get(at) {
+        at += 1;
+        let (read, written) = match header as i8 {
+            -128 => (0, 0),
+            header @ 0..=127 => (header as usize + 1, header as usize + 1),
+            header => (1, (1 - header as isize) as usize),
+        };
+        if at + read > packed.len() {
+            break;
+        }
+        at += read;
+        len += written;
+    }
+    len
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::limits::{set_limits, DecodeLimits};
+
+    /// File offset of the first layer record when the colour data and resources are empty.
+    const FIRST_RECORD: usize = 44;
+
+    /// A layer record with raw 8-bit RGBA samples, or a group divider.
+    pub(crate) struct Layer {
+        name: String,
+        /// Top, left, bottom and right, as stored.
+        bounds: [i32; 4],
+        pixels: Vec<[u8; 4]>,
+        hidden: bool,
+        compression: u16,
+        blocks: Vec<([u8; 4], Vec<u8>)>,
+    }
+
+    impl Layer {
+        pub(crate) fn new(name: &str, left: i32, top: i32, width: i32, pixels: &[[u8; 4]]) -> Self {
+            let height = pixels.len() as i32 / width;
+            Layer {
+                name: name.to_string(),
+                bounds: [top, left, top + height, left + width],
+                pixels: pixels.to_vec(),
+                hidden: false,
+                compression: 0,
+                blocks: Vec::new(),
+            }
+        }
+
+        pub(crate) fn group_start(name: &str) -> Self {
+            Layer::new(name, 0, 0, 1, &[]).block(*b"lsct", &1u32.to_be_bytes())
+        }
+
+        pub(crate) fn group_end() -> Self {
+            Layer::new("</Layer group>", 0, 0, 1, &[]).block(*b"lsct", &3u32.to_be_bytes())
+        }
+
+        pub(crate) fn hidden(mut self) -> Self {
+            self.hidden = true;
+            self
+        }
+
+        pub(crate) fn bounds(mut self, bounds: [i32; 4]) -> Self {
+            self.bounds = bounds;
+            self
+        }
+
+        /// The compression method written before each channel; the samples stay raw.
+        pub(crate) fn compression(mut self, compression: u16) -> Self {
+            self.compression = compression;
+            self
+        }
+
+        /// An additional layer information block.
+        pub(crate) fn block(mut self, key: [u8; 4], data: &[u8]) -> Self {
+            self.blocks.push((key, data.to_vec()));
+            self
+        }
+
+        /// The record, and the channel data that follows all the records.
+        fn write(&self) -> (Vec<u8>, Vec<u8>) {
+            let mut record = Vec::new();
+            for edge in self.bounds {
+                record.extend_from_slice(&edge.to_be_bytes());
+            }
+            let channels: &[(i16, usize)] = match self.pixels.is_empty() {
+                true => &[],
+                false => &[(-1, 3), (0, 0), (1, 1), (2, 2)],
+            };
+            record.extend_from_slice(&(channels.len() as u16).to_be_bytes());
+            let mut data = Vec::new();
+            for (id, component) in channels {
+                record.extend_from_slice(&id.to_be_bytes());
+                record.extend_from_slice(&(2 + self.pixels.len() as u32).to_be_bytes());
+                data.extend_from_slice(&self.compression.to_be_bytes());
+                data.extend(self.pixels.iter().map(|pixel| pixel[*component]));
+            }
+
+            let mut extra = vec![0; 8];
+            extra.push(self.name.len() as u8);
+            extra.extend_from_slice(self.name.as_bytes());
+            // The name is padded to a multiple of four bytes with its length.
+            while !extra.len().is_multiple_of(4) {
+                extra.push(0);
+            }
+            for (key, block) in &self.blocks {
+                extra.extend_from_slice(b"8BIM");
+                extra.extend_from_slice(key);
+                extra.extend_from_slice(&(block.len() as u32).to_be_bytes());
+                extra.extend_from_slice(block);
+            }
+            record.extend_from_slice(b"8BIMnorm");
+            record.extend_from_slice(&[255, 0, if self.hidden { 2 } else { 0 }, 0]);
+            record.extend_from_slice(&(extra.len() as u32).to_be_bytes());
+            record.extend_from_slice(&extra);
+            (record, data)
+        }
+    }
+
+    /// The layer and mask information for `layers`, bottom of the stack first as files store
+    /// them.
+    pub(crate) fn layer_info(layers: &[Layer]) -> Vec<u8> {
+        let mut info = (layers.len() as u16).to_be_bytes().to_vec();
+        let written: Vec<_> = layers.iter().map(Layer::write).collect();
+        for (record, _) in &written {
+            info.extend_from_slice(record);
+        }
+        for (_, data) in &written {
+            info.extend_from_slice(data);
+        }
+        if !info.len().is_multiple_of(2) {
+            info.push(0);
+        }
+        let mut section = (info.len() as u32).to_be_bytes().to_vec();
+        section.extend_from_slice(&info);
+        // No global layer mask.
+        section.extend_from_slice(&[0; 4]);
+        section
+    }
+
+    /// The sections of a document, written in order by `to_bytes`.
+    pub(crate) struct Document {
+        pub(crate) version: u16,
+        pub(crate) channels: u16,
+        pub(crate) width: u32,
+        pub(crate) height: u32,
+        pub(crate) depth: u16,
+        pub(crate) mode: u16,
+        pub(crate) color_data: Vec<u8>,
+        pub(crate) resources: Vec<u8>,
+        /// Without its length.
+        pub(crate) layers: Vec<u8>,
+        /// From the compression method on.
+        pub(crate) image_data: Vec<u8>,
+    }
+
+    impl Document {
+        /// An 8-bit RGB document with a raw, black merged image and no layers.
+        pub(crate) fn rgb(width: u32, height: u32) -> Self {
+            let mut image_data = vec![0; 2];
+            image_data.resize(2 + 3 * (width * height) as usize, 0);
+            Document {
+                version: 1,
+                channels: 3,
+                width,
+                height,
+                depth: 8,
+                mode: 3,
+                color_data: Vec::new(),
+                resources: Vec::new(),
+                layers: Vec::new(),
+                image_data,
+            }
+        }
+
+        pub(crate) fn to_bytes(&self) -> Vec<u8> {
+            let mut file = b"8BPS".to_vec();
+            file.extend_from_slice(&self.version.to_be_bytes());
+            file.extend_from_slice(&[0; 6]);
+            file.extend_from_slice(&self.channels.to_be_bytes());
+            file.extend_from_slice(&self.height.to_be_bytes());
+            file.extend_from_slice(&self.width.to_be_bytes());
+            file.extend_from_slice(&self.depth.to_be_bytes());
+            file.extend_from_slice(&self.mode.to_be_bytes());
+            for section in [&self.color_data, &self.resources] {
+                file.extend_from_slice(&(section.len() as u32).to_be_bytes());
+                file.extend_from_slice(section);
+            }
+            if self.version == 2 {
+                file.extend_from_slice(&(self.layers.len() as u64).to_be_bytes());
+            } else {
+                file.extend_from_slice(&(self.layers.len() as u32).to_be_bytes());
+            }
+            file.extend_from_slice(&self.layers);
+            file.extend_from_slice(&self.image_data);
+            file
+        }
+    }
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    /// A 2x2 canvas: red at the top left inside group 1, blue at the bottom right above it.
+    /// `psd` takes the group's flags from its closing divider; Photoshop sets them on both.
+    fn grouped(hidden: bool) -> Vec<u8> {
+        let (mut start, mut end) = (Layer::group_start("group"), Layer::group_end());
+        if hidden {
+            (start, end) = (start.hidden(), end.hidden());
+        }
+        Document {
+            layers: layer_info(&[
+                end,
+                Layer::new("red", 0, 0, 1, &[RED]),
+                start,
+                Layer::new("blue", 1, 1, 1, &[BLUE]),
+            ]),
+            ..Document::rgb(2, 2)
+        }
+        .to_bytes()
+    }
+
+    fn render(file: &[u8], layers: &[u32], groups: &[u32], split: bool) -> Result<Vec<PsdImage>> {
+        let mut options = PsdRenderOptions::new();
+        options.set_layers(layers.to_vec());
+        options.set_groups(groups.to_vec());
+        options.set_split(split);
+        render_psd(file, &options, &CallbackLogs::silent())
+    }
+
+    fn pixels(image: &PsdImage) -> Vec<[u8; 4]> {
+        let decoded = image::load_from_memory(&image.png()).unwrap().to_rgba8();
+        decoded.pixels().map(|pixel| pixel.0).collect()
+    }
+
+    fn list_error(file: &[u8]) -> ImgprocError {
+        list_psd_layers(file).unwrap_err()
+    }
+
+    #[test]
+    fn lists_groups_then_layers_top_down() {
+        let entries = list_psd_layers(&grouped(true)).unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| (e.kind, e.id, e.parent_id, e.name.as_str(), e.visible))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("group", 1, None, "group", false),
+                ("layer", 0, None, "blue", true),
+                ("layer", 1, Some(1), "red", true),
+            ]
+        );
+        let blue = &entries[1];
+        assert_eq!((blue.left, blue.top, blue.width, blue.height), (1, 1, 1, 1));
+        assert_eq!(blue.blend_mode, "normal");
+    }
+
+    #[test]
+    fn unicode_names_replace_pascal_names() {
+        let name: Vec<u8> = [0, 0, 0, 2, 0, 0xe9, 0x4e, 0x2d].to_vec();
+        let file = Document {
+            layers: layer_info(&[Layer::new("x", 0, 0, 1, &[RED]).block(*b"luni", &name)]),
+            ..Document::rgb(1, 1)
+        }
+        .to_bytes();
+        assert_eq!(list_psd_layers(&file).unwrap()[0].name, "é中");
+    }
+
+    #[test]
+    fn composites_selected_layers_and_groups() {
+        let file = grouped(true);
+        let transparent = [0; 4];
+
+        // Selected groups are drawn even when switched off.
+        let images = render(&file, &[], &[1], false).unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].layer(), None);
+        assert_eq!(
+            pixels(&images[0]),
+            [RED, transparent, transparent, transparent]
+        );
+
+        // Selected layers in a hidden group stay hidden.
+        let images = render(&file, &[0, 1], &[], false).unwrap();
+        assert_eq!(
+            pixels(&images[0]),
+            [transparent, transparent, transparent, BLUE]
+        );
+    }
+
+    #[test]
+    fn splits_layers_trimmed_to_their_pixels() {
+        let file = grouped(false);
+        let images = render(&file, &[], &[], true).unwrap();
+        let placed: Vec<_> = images
+            .iter()
+            .map(|i| {
+                (
+                    i.layer(),
+                    i.name(),
+                    i.left(),
+                    i.top(),
+                    i.width(),
+                    i.height(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                (Some(0), "blue".to_string(), 1, 1, 1, 1),
+                (Some(1), "red".to_string(), 0, 0, 1, 1),
+            ]
+        );
+        assert_eq!(pixels(&images[1]), [RED]);
+    }
+
+    #[test]
+    fn rejects_unknown_layers_and_groups() {
+        let file = grouped(false);
+        for (layers, groups) in [(&[2][..], &[][..]), (&[], &[7])] {
+            let error = render(&file, layers, groups, false).unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn rejects_layers_past_the_canvas() {
+        for (left, top) in [(-1, 0), (1, 0), (0, 1)] {
+            let file = Document {
+                layers: layer_info(&[Layer::new("wide", left, top, 2, &[RED, RED])]),
+                ..Document::rgb(2, 1)
+            }
+            .to_bytes();
+            let error = render(&file, &[0], &[], false).unwrap_err();
+            assert_eq!(error.code, ErrorCode::UnsupportedFormat);
+        }
+    }
+
+    #[test]
+    fn lists_but_does_not_render_sixteen_bit_layers() {
+        let file = Document {
+            depth: 16,
+            layers: layer_info(&[Layer::new("deep", 0, 0, 1, &[RED, RED])]),
+            ..Document::rgb(1, 1)
+        }
+        .to_bytes();
+        assert_eq!(list_psd_layers(&file).unwrap().len(), 1);
+        let error = render(&file, &[0], &[], false).unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnsupportedFormat);
+    }
+
+    #[test]
+    fn rejects_malformed_layer_records() {
+        let layers = |layers: &[Layer]| {
+            Document {
+                layers: layer_info(layers),
+                ..Document::rgb(2, 2)
+            }
+            .to_bytes()
+        };
+        let layer = || Layer::new("layer", 0, 0, 1, &[RED]);
+        let corrupt = [
+            layers(&[Layer::group_end()]),
+            layers(&[layer().bounds([2, 0, 1, 1])]),
+            layers(&[layer().bounds([0, 0, 1, i32::MIN])]),
+            layers(&[layer().bounds([0, 0, 2, 2])].map(|l| Layer {
+                pixels: vec![RED; 5],
+                ..l
+            })),
+            layers(&[layer().compression(7)]),
+            layers(&[layer().block(*b"luni", &[0, 0, 0, 1, 0xdc, 0])]),
+            layers(&[layer().block(*b"luni", &[0xff, 0, 0, 0])]),
+            layers(&[Layer::new(&"n".repeat(255), 0, 0, 1, &[RED])]),
+        ];
+        for file in &corrupt {
+            assert_eq!(list_error(file).code, ErrorCode::CorruptData);
+        }
+
+        let mut short_channel = layers(&[layer()]);
+        short_channel[FIRST_RECORD + 20..FIRST_RECORD + 24].copy_from_slice(&1u32.to_be_bytes());
+        assert_eq!(list_error(&short_channel).code, ErrorCode::CorruptData);
+        let mut negative_count = layers(&[layer()]);
+        negative_count[FIRST_RECORD - 2..FIRST_RECORD].copy_from_slice(&[0x80, 0]);
+        assert_eq!(list_error(&negative_count).code, ErrorCode::CorruptData);
+
+        let zip = layers(&[layer().compression(3)]);
+        assert_eq!(list_error(&zip).code, ErrorCode::UnsupportedFormat);
+        let psb = Document {
+            version: 2,
+            ..Document::rgb(1, 1)
+        };
+        assert_eq!(
+            list_error(&psb.to_bytes()).code,
+            ErrorCode::UnsupportedFormat
+        );
+    }
+
+    #[test]
+    fn truncated_documents_fail_without_panicking() {
+        let file = grouped(false);
+        let image_data = file.len() - Document::rgb(2, 2).image_data.len();
+        for len in 0..file.len() {
+            let listed = list_psd_layers(&file[..len]);
+            assert_eq!(listed.is_ok(), len >= image_data, "length {}", len);
+            let _ = render(&file[..len], &[], &[1], false);
+            let _ = render(&file[..len], &[], &[], true);
+        }
+        crate::formats::tests::decode_truncations(&file, "psd");
+    }
+
+    #[test]
+    fn limits_are_checked_from_the_header() {
+        // Nothing after the header, so parsing it would fail on truncation instead.
+        let document = Document {
+            width: 40_000,
+            height: 40_000,
+            ..Document::rgb(1, 1)
+        };
+        let header = &document.to_bytes()[..26];
+        assert_eq!(list_error(header).code, ErrorCode::LimitExceeded);
+        let error = render(header, &[0], &[], false).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+
+        let mut limits = DecodeLimits::default();
+        limits.set_max_width(1);
+        set_limits(limits);
+        let error = list_error(&grouped(false));
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+    }
+}
@@ -0,0 +1,352 @@
+use image::RgbaImage;
+use std::{cell::RefCell, collections::HashMap};
+
+/// Pixels sampled from all frames when building a palette.
+const MAX_PALETTE_SAMPLES: u64 = 1 << 18;
+/// Alpha below which a pixel becomes the transparent index when alpha is binary.
+const ALPHA_THRESHOLD: u8 = 128;
+
+#[derive(Clone, Copy, Debug)]
+pub struct QuantizeOptions {
+    /// Palette size including the transparent entry, between 2 and 256.
+    pub colors: u32,
+    /// Floyd-Steinberg error diffusion.
+    pub dithering: bool,
+    /// Largest colour distance at which a pixel reuses the previous index to lengthen runs,
+    /// which trades accuracy for better LZW and deflate compression. Zero disables it.
+    pub lossiness: u32,
+    /// Reduce alpha to fully opaque or fully transparent, as GIF requires.
+    pub binary_alpha: bool,
+}
+
+pub struct Palette {
+    pub colors: Vec<[u8; 4]>,
+    pub transparent: Option<u8>,
+    cache: RefCell<HashMap<u32, u8>>,
+}
+
+impl Palette {
+    pub fn rgb(&self) -> Vec<u8> {
+        self.colors
+            .iter()
+            .flat_map(|c| [c[0], c[1], c[2]])
+            .collect()
+    }
+
+    pub fn alpha(&self) -> Vec<u8> {
+        self.colors.iter().map(|c| c[3]).collect()
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        self.colors.iter().any(|c| c[3] < 255)
+    }
+
+    fn nearest(&self, color: [u8; 4]) -> u8 {
+        let key = u32::from_le_bytes(color);
+        if let Some(&index) = self.cache.borrow().get(&key) {
+            return index;
+        }
+
+        let index = self
+            .colors
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.transparent != Some(*i as u8))
+            .min_by_key(|(_, c)| distance_squared(c, &color))
+            .map(|(i, _)| i as u8)
+            .unwrap_or(0);
+        self.cache.borrow_mut().insert(key, index);
+        index
+    }
+}
+
+fn distance_squared(a: &[u8; 4], b: &[u8; 4]) -> u32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (*a as i32 - *b as i32).pow(2) as u32)
+        .sum()
+}
+
+/// Builds one palette shared by all frames with median cut and maps every frame onto it.
+pub fn quantize_frames(frames: &[&RgbaImage], options: QuantizeOptions) -> (Palette, Vec<Vec<u8>>) {
+    let palette = median_cut(frames, options);
+    let indices = frames
+        .iter()
+        .map(|image| map_frame(image, &palette, options))
+        .collect();
+    (palette, indices)
+}
+
+fn median_cut(frames: &[&RgbaImage], options: QuantizeOptions) -> Palette {
+    let total_pixels: u64 = frames
+        .iter()
+        .map(|f| f.width() as u64 * f.height() as u64)
+        .sum();
+    let step = total_pixels.div_ceil(MAX_PALETTE_SAMPLES).max(1) as usize;
+
+    let mut histogram: HashMap<[u8; 4], u32> = HashMap::new();
+    let mut has_transparency = false;
+    for frame in frames {
+        for pixel in frame.pixels().step_by(step) {
+            let mut color = pixel.0;
+            if options.binary_alpha {
+                if color[3] < ALPHA_THRESHOLD {
+                    has_transparency = true;
+                    continue;
+                }
+                color[3] = 255;
+            }
+            *histogram.entry(color).or_insert(0) += 1;
+        }
+    }
+
+    let max_colors = options.colors.clamp(2, 256) as usize;
+    let opaque_colors = if has_transparency {
+        max_colors - 1
+    } else {
+        max_colors
+    };
+
+    let mut boxes: Vec<Vec<([u8; 4], u32)>> = vec![histogram.into_iter().collect()];
+    boxes[0].sort_unstable();
+    while boxes.len() < opaque_colors {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| (i, widest_channel(b)))
+            .max_by_key(|(_, (_, range))| *range);
+
+        let (index, (channel, _)) = match widest {
+            Some(widest) => widest,
+            None => break,
+        };
+
+        let mut cut = boxes.swap_remove(index);
+        cut.sort_unstable_by_key(|(color, _)| color[channel]);
+        let half = cut.iter().map(|(_, count)| *count as u64).sum::<u64>() / 2;
+        let mut running = 0u64;
+        let split = cut
+            .iter()
+            .position(|(_, count)| {
+                running += *count as u64;
+                running >= half
+            })
+            .unwrap_or(0)
+            .clamp(0, cut.len() - 2)
+            + 1;
+        let upper = cut.split_off(split);
+        boxes.push(cut);
+        boxes.push(upper);
+    }
+
+    let mut colors: Vec<[u8; 4]> = boxes
+        .iter()
+        .filter(|b| !b.is_empty())
+        .map(|b| average(b))
+        .collect();
+    if colors.is_empty() {
+        colors.push([0, 0, 0, 255]);
+    }
+
+    let transparent = has_transparency.then(|| {
+        colors.push([0, 0, 0, 0]);
+        (colors.len() - 1) as u8
+    });
+
+    Palette {
+        colors,
+        transparent,
+        cache: RefCell::new(HashMap::new()),
+    }
+}
+
+fn widest_channel(colors: &[([u8; 4], u32)]) -> (usize, u8) {
+    (0..4)
+        .map(|channel| {
+            let (min, max) = colors.iter().fold((255u8, 0u8), |(min, max), (c, _)| {
+                (min.min(c[channel]), max.max(c[channel]))
+            });
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+fn average(colors: &[([u8; 4], u32)]) -> [u8; 4] {
+    let mut sum = [0u64; 4];
+    let mut total = 0u64;
+    for (color, count) in colors {
+        for (s, c) in sum.iter_mut().zip(color) {
+            *s += *c as u64 * *count as u64;
+        }
+        total += *count as u64;
+    }
+    sum.map(|s| (s as f64 / total.max(1) as f64).round() as u8)
+}
+
+fn map_frame(image: &RgbaImage, palette: &Palette, options: QuantizeOptions) -> Vec<u8> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut indices = Vec::with_capacity(width * height);
+    let mut errors = vec![[0f32; 4]; width + 2];
+    let mut next_errors = vec![[0f32; 4]; width + 2];
+    let lossiness = options.lossiness * options.lossiness;
+    let mut previous: Option<u8> = None;
+
+    for y in 0..height {
+        for x in 0..width {
+            let mut color = image.get_pixel(x as u32, y as u32).0;
+
+            if options.binary_alpha {
+                if color[3] < ALPHA_THRESHOLD {
+                    let index = palette.transparent.unwrap_or(0);
+                    indices.push(index);
+                    previous = Some(index);
+                    continue;
+                }
+                color[3] = 255;
+            }
+
+            if options.dithering {
+                for (channel, value) in color.iter_mut().enumerate() {
+                    *value = (*value as f32 + errors[x + 1][channel])
+                        .round()
+                        .clamp(0.0, 255.0) as u8;
+                }
+            }
+
+            let index = match previous {
+                Some(p)
+                    if lossiness > 0
+                        && palette.transparent != Some(p)
+                        && distance_squared(&palette.colors[p as usize], &color) <= lossiness =>
+                {
+                    p
+                }
+                _ => palette.nearest(color),
+            };
+            indices.push(index);
+            previous = Some(index);
+
+            if options.dithering {
+                let chosen = palette.colors[index as usize];
+                for channel in 0..4 {
+                    let error = color[channel] as f32 - chosen[channel] as f32;
+                    errors[x + 2][channel] += error * 7.0 / 16.0;
+                    next_errors[x][channel] += error * 3.0 / 16.0;
+                    next_errors[x + 1][channel] += error * 5.0 / 16.0;
+                    next_errors[x + 2][channel] += error / 16.0;
+                }
+            }
+        }
+
+        std::mem::swap(&mut errors, &mut next_errors);
+        next_errors.iter_mut().for_each(|e| *e = [0.0; 4]);
+    }
+
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn options(colors: u32) -> QuantizeOptions {
+        QuantizeOptions {
+            colors,
+            dithering: false,
+            lossiness: 0,
+            binary_alpha: false,
+        }
+    }
+
+    fn gradient() -> RgbaImage {
+        RgbaImage::from_fn(32, 32, |x, y| {
+            Rgba([(x * 8) as u8, (y * 8) as u8, 128, 255])
+        })
+    }
+
+    #[test]
+    fn palette_holds_at_most_the_requested_colors() {
+        let image = gradient();
+        for colors in [2, 16, 256] {
+            let (palette, indices) = quantize_frames(&[&image], options(colors));
+            assert!(palette.colors.len() <= colors as usize);
+            assert_eq!(indices[0].len(), 32 * 32);
+            assert!(indices[0]
+                .iter()
+                .all(|i| (*i as usize) < palette.colors.len()));
+        }
+    }
+
+    #[test]
+    fn few_colors_are_kept_exactly() {
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 128]];
+        let image = RgbaImage::from_fn(9, 3, |x, _| Rgba(colors[x as usize % 3]));
+        let (palette, indices) = quantize_frames(&[&image], options(256));
+        assert_eq!(palette.colors.len(), 3);
+        assert!(palette.has_alpha());
+        for (pixel, index) in image.pixels().zip(&indices[0]) {
+            assert_eq!(palette.colors[*index as usize], pixel.0);
+        }
+    }
+
+    #[test]
+    fn binary_alpha_reserves_a_transparent_index() {
+        let image = RgbaImage::from_fn(4, 4, |x, _| {
+            Rgba(if x < 2 {
+                [10, 20, 30, 40]
+            } else {
+                [200, 100, 50, 250]
+            })
+        });
+        let (palette, indices) = quantize_frames(
+            &[&image],
+            QuantizeOptions {
+                binary_alpha: true,
+                ..options(4)
+            },
+        );
+        let transparent = palette.transparent.unwrap();
+        assert_eq!(palette.colors[transparent as usize], [0, 0, 0, 0]);
+        assert_eq!(indices[0][0], transparent);
+        assert_eq!(palette.colors[indices[0][3] as usize], [200, 100, 50, 255]);
+    }
+
+    #[test]
+    fn lossiness_lengthens_runs() {
+        let image = RgbaImage::from_fn(64, 1, |x, _| Rgba([(x * 4) as u8, 0, 0, 255]));
+        let runs = |lossiness| {
+            let (_, indices) = quantize_frames(
+                &[&image],
+                QuantizeOptions {
+                    lossiness,
+                    ..options(64)
+                },
+            );
+            indices[0].windows(2).filter(|w| w[0] != w[1]).count()
+        };
+        assert!(runs(40) < runs(0));
+    }
+
+    #[test]
+    fn dithering_mixes_colors_across_a_gradient() {
+        let image = RgbaImage::from_fn(32, 32, |x, _| Rgba([(x * 8) as u8, 0, 0, 255]));
+        let distinct = |dithering| {
+            let (_, indices) = quantize_frames(
+                &[&image],
+                QuantizeOptions {
+                    dithering,
+                    ..options(2)
+                },
+            );
+            // Without dithering each column maps to one index.
+            (0..32)
+                .filter(|x| (0..32).any(|y| indices[0][y * 32 + x] != indices[0][*x]))
+                .count()
+        };
+        assert_eq!(distinct(false), 0);
+        assert!(distinct(true) > 0);
+    }
+}
@@ -0,0 +1,1347 @@
+//! Camera raw files: DNG, Canon CR2, Nikon NEF and Sony ARW. DNG and CR2 sensor data is
+//! developed here; NEF and ARW compress and describe their sensor data in camera-specific
+//! ways, so for them only the embedded JPEG preview is available.
+
+use image::{imageops, metadata::Orientation, DynamicImage, ImageFormat, RgbaImage};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    callback_logs::{CallbackLogs, ProgressEvent, Stage},
+    convert::ConvertOptions,
+    error::{ErrorCode, ImgprocError, Result},
+    formats::{decode_with_image, target_format},
+    limits::current_limits,
+    lossless_jpeg::{decode_lossless_jpeg, lossless_jpeg_size},
+    thumbnail::scale_to_fit,
+    tiff_ifd::*,
+};
+
+const CFA_REPEAT_PATTERN_DIM: u16 = 0x828d;
+const CFA_PATTERN: u16 = 0x828e;
+const DNG_VERSION: u16 = 0xc612;
+const UNIQUE_CAMERA_MODEL: u16 = 0xc614;
+const LINEARIZATION_TABLE: u16 = 0xc618;
+const BLACK_LEVEL_REPEAT_DIM: u16 = 0xc619;
+const BLACK_LEVEL: u16 = 0xc61a;
+const WHITE_LEVEL: u16 = 0xc61d;
+const DEFAULT_CROP_ORIGIN: u16 = 0xc61f;
+const DEFAULT_CROP_SIZE: u16 = 0xc620;
+const COLOR_MATRIX_1: u16 = 0xc621;
+const COLOR_MATRIX_2: u16 = 0xc622;
+const AS_SHOT_NEUTRAL: u16 = 0xc628;
+const CALIBRATION_ILLUMINANT_2: u16 = 0xc65b;
+const ACTIVE_AREA: u16 = 0xc68d;
+const CR2_SLICE: u16 = 0xc640;
+
+/// Canon maker note tags.
+const CANON_SENSOR_INFO: u16 = 0x00e0;
+const CANON_COLOR_DATA: u16 = 0x4001;
+
+const PHOTOMETRIC_CFA: f64 = 32803.0;
+const PHOTOMETRIC_LINEAR_RAW: f64 = 34892.0;
+const ILLUMINANT_D65: f64 = 21.0;
+
+/// Linear sRGB to XYZ, both D65.
+const SRGB_TO_XYZ: [[f64; 3]; 3] = [
+    [0.412_453, 0.357_580, 0.180_423],
+    [0.212_671, 0.715_160, 0.072_169],
+    [0.019_334, 0.119_193, 0.950_227],
+];
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub struct RawOptions {
+    preview: bool,
+    size: u32,
+    format: String,
+    quality: u8,
+}
+
+impl Default for RawOptions {
+    fn default() -> Self {
+        RawOptions {
+            preview: false,
+            size: 0,
+            format: "jpeg".to_string(),
+            quality: 90,
+        }
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl RawOptions {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new() -> RawOptions {
+        RawOptions::default()
+    }
+
+    /// Use the camera's embedded JPEG instead of developing the sensor data. Much faster,
+    /// and the only choice for NEF and ARW files.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn preview(&self) -> bool {
+        self.preview
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_preview(&mut self, value: bool) {
+        self.preview = value;
+    }
+
+    /// Longest side of the output in pixels, or 0 for the full size.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_size(&mut self, value: u32) {
+        self.size = value;
+    }
+
+    /// Output format, `jpeg` or `png` for instance.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn format(&self) -> String {
+        self.format.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_format(&mut self, value: String) {
+        self.format = value;
+    }
+
+    /// JPEG quality from 1 to 100.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn quality(&self) -> u8 {
+        self.quality
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_quality(&mut self, value: u8) {
+        self.quality = value;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RawKind {
+    Dng,
+    Cr2,
+    Nef,
+    Arw,
+}
+
+impl RawKind {
+    fn name(&self) -> &'static str {
+        match self {
+            RawKind::Dng => "DNG",
+            RawKind::Cr2 => "CR2",
+            RawKind::Nef => "NEF",
+            RawKind::Arw => "ARW",
+        }
+    }
+}
+
+/// DNG by its version tag, CR2 by the signature after the TIFF header, and NEF and ARW by
+/// the camera make on a file that holds CFA data.
+fn raw_kind(data: &[u8]) -> Option<RawKind> {
+    if !Tiff::is_tiff(data) {
+        return None;
+    }
+    if data.get(8..11) == Some(b"CR\x02") {
+        return Some(RawKind::Cr2);
+    }
+    let tiff = Tiff::new(data, "TIFF").ok()?;
+    let ifd = tiff.first_ifd().ok()?;
+    if ifd.get(DNG_VERSION).is_some() {
+        return Some(RawKind::Dng);
+    }
+    let make = tiff.string(&ifd, MAKE)?.to_uppercase();
+    let kind = match make {
+        _ if make.starts_with("NIKON") => RawKind::Nef,
+        _ if make.starts_with("SONY") => RawKind::Arw,
+        _ => return None,
+    };
+    let has_cfa = tiff
+        .all_ifds()
+        .ok()?
+        .iter()
+        .any(|ifd| tiff.number(ifd, PHOTOMETRIC).ok().flatten() == Some(PHOTOMETRIC_CFA));
+    has_cfa.then_some(kind)
+}
+
+pub fn is_dng(data: &[u8]) -> bool {
+    raw_kind(data) == Some(RawKind::Dng)
+}
+
+pub fn is_cr2(data: &[u8]) -> bool {
+    raw_kind(data) == Some(RawKind::Cr2)
+}
+
+pub fn is_nef(data: &[u8]) -> bool {
+    raw_kind(data) == Some(RawKind::Nef)
+}
+
+pub fn is_arw(data: &[u8]) -> bool {
+    raw_kind(data) == Some(RawKind::Arw)
+}
+
+/// Develops a DNG or CR2, or decodes the embedded preview, and encodes the result as
+/// `options.format`. An unrotated full-size preview requested as JPEG is returned as stored.
+pub fn develop_raw(
+    image_data: &[u8],
+    options: &RawOptions,
+    logs: &CallbackLogs,
+) -> Result<Vec<u8>> {
+    let raw = Raw::parse(image_data)?;
+    let target = target_format(&options.format)?;
+    let name = raw.kind.name();
+
+    let image = match options.preview {
+        true => {
+            logs.send(ProgressEvent::new(Stage::Decode, "raw_preview").arg(name))?;
+            let preview = raw.preview()?;
+            if target.name == "jpeg" && options.size == 0 && raw.orientation == 1 {
+                return Ok(preview.to_vec());
+            }
+            decode_with_image(preview, ImageFormat::Jpeg, name)?
+        }
+        false => {
+            logs.send(ProgressEvent::new(Stage::Decode, "raw_develop").arg(raw.camera()))?;
+            raw.develop()?
+        }
+    };
+    let image = raw.orient(image);
+    let image = match options.size {
+        0 => image,
+        size => scale_to_fit(image, size)?,
+    };
+
+    logs.send(ProgressEvent::new(Stage::Encode, "encode_start").arg(target.name.to_uppercase()))?;
+    let mut convert_options = ConvertOptions::new();
+    convert_options.set_quality(options.quality);
+    target.encode_still(image, &convert_options)
+}
+
+/// The developed size of a DNG or CR2, after cropping and rotation.
+pub fn developed_dimensions(image_data: &[u8]) -> Result<(u32, u32)> {
+    let raw = Raw::parse(image_data)?;
+    let (width, height) = raw.developed_size()?;
+    Ok(raw.oriented_size(width, height))
+}
+
+pub fn developed_rgba(image_data: &[u8]) -> Result<RgbaImage> {
+    let raw = Raw::parse(image_data)?;
+    Ok(raw.orient(raw.develop()?))
+}
+
+/// The size of the largest embedded preview, after rotation.
+pub fn raw_preview_dimensions(image_data: &[u8]) -> Result<(u32, u32)> {
+    let raw = Raw::parse(image_data)?;
+    let (width, height) = jpeg_size(raw.preview()?).unwrap_or_default();
+    Ok(raw.oriented_size(width, height))
+}
+
+pub fn raw_preview_rgba(image_data: &[u8]) -> Result<RgbaImage> {
+    let raw = Raw::parse(image_data)?;
+    let image = decode_with_image(raw.preview()?, ImageFormat::Jpeg, raw.kind.name())?;
+    Ok(raw.orient(image))
+}
+
+struct Raw<'a> {
+    kind: RawKind,
+    tiff: Tiff<'a>,
+    first: Ifd,
+    ifds: Vec<Ifd>,
+    orientation: u8,
+}
+
+impl<'a> Raw<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        let kind = raw_kind(data).ok_or_else(|| {
+            ImgprocError::new(
+                ErrorCode::UnsupportedFormat,
+                "Not a DNG, CR2, NEF or ARW camera raw file.",
+            )
+        })?;
+        let tiff = Tiff::new(data, kind.name())?;
+        let first = tiff.first_ifd()?;
+        let ifds = tiff.all_ifds()?;
+        let orientation = match tiff.number(&first, ORIENTATION)? {
+            Some(value) if (1.0..=8.0).contains(&value) => value as u8,
+            _ => 1,
+        };
+        Ok(Raw {
+            kind,
+            tiff,
+            first,
+            ifds,
+            orientation,
+        })
+    }
+
+    fn corrupt(&self, message: impl Into<String>) -> ImgprocError {
+        ImgprocError::corrupt(message).format(self.kind.name())
+    }
+
+    fn unsupported(&self, message: impl Into<String>) -> ImgprocError {
+        ImgprocError::new(ErrorCode::UnsupportedFormat, message).format(self.kind.name())
+    }
+
+    /// The camera, for progress messages.
+    fn camera(&self) -> String {
+        self.tiff
+            .string(&self.first, UNIQUE_CAMERA_MODEL)
+            .or_else(|| self.tiff.string(&self.first, MODEL))
+            .unwrap_or_else(|| self.kind.name().to_string())
+    }
+
+    fn orient(&self, image: RgbaImage) -> RgbaImage {
+        match Orientation::from_exif(self.orientation) {
+            Some(orientation) if orientation != Orientation::NoTransforms => {
+                let mut image = DynamicImage::ImageRgba8(image);
+                image.apply_orientation(orientation);
+                image.to_rgba8()
+            }
+            _ => image,
+        }
+    }
+
+    fn oriented_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match self.orientation > 4 {
+            true => (height, width),
+            false => (width, height),
+        }
+    }
+
+    /// The largest baseline JPEG in the file: referenced by the JPEG offset tags, or stored
+    /// as the only strip of an IFD that is not sensor data.
+    fn preview(&self) -> Result<&'a [u8]> {
+        let mut best: Option<(u64, &[u8])> = None;
+        for ifd in &self.ifds {
+            let mut candidates = Vec::new();
+            let offset = self.tiff.number(ifd, JPEG_OFFSET)?;
+            let length = self.tiff.number(ifd, JPEG_LENGTH)?;
+            if let (Some(offset), Some(length)) = (offset, length) {
+                candidates.push((offset, length));
+            }
+            let compression = self.tiff.number(ifd, COMPRESSION)?;
+            let photometric = self.tiff.number(ifd, PHOTOMETRIC)?.unwrap_or(0.0);
+            if matches!(compression, Some(6.0 | 7.0))
+                && photometric != PHOTOMETRIC_CFA
+                && photometric != PHOTOMETRIC_LINEAR_RAW
+            {
+                let offsets = self.tiff.numbers(ifd, STRIP_OFFSETS)?;
+                let lengths = self.tiff.numbers(ifd, STRIP_BYTE_COUNTS)?;
+                if let ([offset], [length]) = (&offsets[..], &lengths[..]) {
+                    candidates.push((*offset, *length));
+                }
+            }
+
+            for (offset, length) in candidates {
+                let (offset, length) = (offset as usize, length as usize);
+                let Some(jpeg) = offset
+                    .checked_add(length)
+                    .and_then(|end| self.tiff.data.get(offset..end))
+                else {
+                    continue;
+                };
+                if let Some((width, height)) = jpeg_size(jpeg) {
+                    let area = width as u64 * height as u64;
+                    if best.is_none_or(|(best_area, _)| area > best_area) {
+                        best = Some((area, jpeg));
+                    }
+                }
+            }
+        }
+        best.map(|(_, jpeg)| jpeg)
+            .ok_or_else(|| self.unsupported("The file has no embedded JPEG preview."))
+    }
+
+    fn not_developed(&self) -> ImgprocError {
+        self.unsupported(format!(
+            "Developing {} sensor data is not supported; only DNG and CR2 files are developed.",
+            self.kind.name()
+        ))
+        .cause("Use the embedded preview, or convert the file to DNG first.")
+    }
+
+    /// The IFD holding the full-resolution sensor data of a DNG.
+    fn dng_ifd(&self) -> Result<&Ifd> {
+        for ifd in &self.ifds {
+            let subfile = self.tiff.number(ifd, NEW_SUBFILE_TYPE)?.unwrap_or(0.0);
+            let photometric = self.tiff.number(ifd, PHOTOMETRIC)?;
+            if subfile == 0.0
+                && matches!(photometric, Some(PHOTOMETRIC_CFA | PHOTOMETRIC_LINEAR_RAW))
+            {
+                return Ok(ifd);
+            }
+        }
+        Err(self.corrupt("The DNG has no raw image."))
+    }
+
+    /// `ActiveArea` as top, left, bottom, right.
+    fn active_area(&self, ifd: &Ifd, width: u32, height: u32) -> Result<[u32; 4]> {
+        let area = self.tiff.numbers(ifd, ACTIVE_AREA)?;
+        let [top, left, bottom, right] = match area[..] {
+            [top, left, bottom, right] => [top, left, bottom, right].map(|v| v as u32),
+            _ => [0, 0, height, width],
+        };
+        if top >= bottom || left >= right || bottom > height || right > width {
+            return Err(self.corrupt("The DNG active area is outside the image."));
+        }
+        Ok([top, left, bottom, right])
+    }
+
+    /// `DefaultCropOrigin` and `DefaultCropSize`, relative to the active area and clamped
+    /// to it.
+    fn default_crop(&self, ifd: &Ifd, width: u32, height: u32) -> Result<[u32; 4]> {
+        let origin = self.tiff.numbers(ifd, DEFAULT_CROP_ORIGIN)?;
+        let size = self.tiff.numbers(ifd, DEFAULT_CROP_SIZE)?;
+        let (x, y) = match origin[..] {
+            [x, y] => ((x as u32).min(width - 1), (y as u32).min(height - 1)),
+            _ => (0, 0),
+        };
+        let (w, h) = match size[..] {
+            [w, h] => (
+                (w as u32).clamp(1, width - x),
+                (h as u32).clamp(1, height - y),
+            ),
+            _ => (width - x, height - y),
+        };
+        Ok([x, y, w, h])
+    }
+
+    fn developed_size(&self) -> Result<(u32, u32)> {
+        match self.kind {
+            RawKind::Dng => {
+                let ifd = self.dng_ifd()?;
+                let width = self.tiff.number(ifd, IMAGE_WIDTH)?.unwrap_or(0.0) as u32;
+                let height = self.tiff.number(ifd, IMAGE_LENGTH)?.unwrap_or(0.0) as u32;
+                let [top, left, bottom, right] = self.active_area(ifd, width, height)?;
+                let [_, _, width, height] = self.default_crop(ifd, right - left, bottom - top)?;
+                Ok((width, height))
+            }
+            RawKind::Cr2 => {
+                let [top, left, bottom, right] = self.cr2_layout()?.active;
+                Ok((right - left, bottom - top))
+            }
+            RawKind::Nef | RawKind::Arw => Err(self.not_developed()),
+        }
+    }
+
+    fn develop(&self) -> Result<RgbaImage> {
+        let sensor = match self.kind {
+            RawKind::Dng => self.dng_sensor()?,
+            RawKind::Cr2 => self.cr2_sensor()?,
+            RawKind::Nef | RawKind::Arw => return Err(self.not_developed()),
+        };
+        Ok(sensor.render())
+    }
+
+    /// Linearises the DNG sensor data and applies its black and white levels. White balance
+    /// comes from `AsShotNeutral` and colour from the colour matrix for D65.
+    fn dng_sensor(&self) -> Result<Sensor> {
+        let ifd = self.dng_ifd()?;
+        let tiff = &self.tiff;
+        let width = tiff.number(ifd, IMAGE_WIDTH)?.unwrap_or(0.0) as u32;
+        let height = tiff.number(ifd, IMAGE_LENGTH)?.unwrap_or(0.0) as u32;
+        let bits = tiff.number(ifd, BITS_PER_SAMPLE)?.unwrap_or(1.0) as u32;
+        let channels = tiff.number(ifd, SAMPLES_PER_PIXEL)?.unwrap_or(1.0) as usize;
+        current_limits().check_dimensions(width, height, self.kind.name())?;
+        if width == 0 || height == 0 || !(1..=16).contains(&bits) || !(1..=4).contains(&channels) {
+            return Err(self.unsupported(format!(
+                "DNG raw data with {} samples of {} bits is not supported.",
+                channels, bits
+            )));
+        }
+
+        let cfa = match tiff.number(ifd, PHOTOMETRIC)? == Some(PHOTOMETRIC_CFA) {
+            true => Some(self.cfa_pattern(ifd, channels)?),
+            false if channels == 3 => None,
+            false => {
+                return Err(self.unsupported("Linear DNG data must have three samples per pixel."))
+            }
+        };
+
+        let samples = self.read_samples(ifd, width, height, bits, channels)?;
+        let [top, left, bottom, right] = self.active_area(ifd, width, height)?;
+        let (active_width, active_height) = (right - left, bottom - top);
+
+        // Sample values scaled so the black level is 0 and the white level 1.
+        let linearization: Vec<f64> = tiff.numbers(ifd, LINEARIZATION_TABLE)?;
+        let black = tiff.numbers(ifd, BLACK_LEVEL)?;
+        let (black_rows, black_cols) = match tiff.numbers(ifd, BLACK_LEVEL_REPEAT_DIM)?[..] {
+            [rows, cols] if (1.0..=16.0).contains(&rows) && (1.0..=16.0).contains(&cols) => {
+                (rows as usize, cols as usize)
+            }
+            _ => (1, 1),
+        };
+        let white = tiff.numbers(ifd, WHITE_LEVEL)?;
+        let default_white = ((1u32 << bits) - 1) as f64;
+        let mut plane = vec![0f32; active_width as usize * active_height as usize * channels];
+        for y in 0..active_height as usize {
+            let source_row = (top as usize + y) * width as usize;
+            for x in 0..active_width as usize {
+                let source = (source_row + left as usize + x) * channels;
+                for c in 0..channels {
+                    let mut value = samples[source + c] as f64;
+                    if !linearization.is_empty() {
+                        value = linearization[(value as usize).min(linearization.len() - 1)];
+                    }
+                    let black_index =
+                        ((y % black_rows) * black_cols + x % black_cols) * channels + c;
+                    let black = black
+                        .get(black_index)
+                        .or(black.first())
+                        .copied()
+                        .unwrap_or(0.0);
+                    let white = white
+                        .get(c)
+                        .or(white.first())
+                        .copied()
+                        .unwrap_or(default_white);
+                    let range = (white - black).max(1.0);
+                    plane[(y * active_width as usize + x) * channels + c] =
+                        ((value - black) / range).clamp(0.0, 1.0) as f32;
+                }
+            }
+        }
+        drop(samples);
+
+        Ok(Sensor {
+            width: active_width,
+            height: active_height,
+            plane,
+            cfa,
+            multipliers: self.white_balance(ifd)?,
+            camera_to_srgb: self.camera_to_srgb()?,
+            crop: self.default_crop(ifd, active_width, active_height)?,
+        })
+    }
+
+    /// Finds the CR2 sensor data: the strip that is a lossless JPEG rather than a preview,
+    /// the vertical slices it is coded in, and the exposed area from the maker note.
+    fn cr2_layout(&self) -> Result<Cr2Layout<'a>> {
+        let mut found = None;
+        for ifd in &self.ifds {
+            if self.tiff.number(ifd, COMPRESSION)? != Some(6.0) {
+                continue;
+            }
+            let offsets = self.tiff.numbers(ifd, STRIP_OFFSETS)?;
+            let lengths = self.tiff.numbers(ifd, STRIP_BYTE_COUNTS)?;
+            let ([offset], [length]) = (&offsets[..], &lengths[..]) else {
+                continue;
+            };
+            let (offset, length) = (*offset as usize, *length as usize);
+            let Some(jpeg) = offset
+                .checked_add(length)
+                .and_then(|end| self.tiff.data.get(offset..end))
+            else {
+                continue;
+            };
+            if let Some(size) = lossless_jpeg_size(jpeg) {
+                found = Some((ifd, jpeg, size));
+                break;
+            }
+        }
+        let (ifd, jpeg, (jpeg_width, jpeg_height, components)) =
+            found.ok_or_else(|| self.corrupt("The CR2 has no lossless JPEG sensor data."))?;
+
+        // `CR2Slice` is the number of full slices, their width and the width of the last one.
+        // Without it the JPEG rows are the sensor rows.
+        let total = jpeg_width * components * jpeg_height;
+        let (count, slice_width, last_width) = match self.tiff.numbers(ifd, CR2_SLICE)?[..] {
+            [count, width, last] if count > 0.0 || last > 0.0 => {
+                (count as usize, width as usize, last as usize)
+            }
+            _ => (0, 0, jpeg_width * components),
+        };
+        let width = count
+            .checked_mul(slice_width)
+            .and_then(|width| width.checked_add(last_width))
+            .filter(|width| *width > 0 && total % width == 0 && (count == 0 || slice_width > 0))
+            .ok_or_else(|| self.corrupt("The CR2 slices do not match the sensor data."))?;
+        let height = total / width;
+        current_limits().check_dimensions(width as u32, height as u32, self.kind.name())?;
+        let slices = (0..count)
+            .map(|i| (i * slice_width, slice_width))
+            .chain(std::iter::once((count * slice_width, last_width)))
+            .collect();
+
+        // SensorInfo holds the borders of the exposed area, inclusive, from index 5.
+        let mut active = [0, 0, height as u32, width as u32];
+        let borders = match self.maker_note() {
+            Some(note) => self
+                .tiff
+                .numbers(&note, CANON_SENSOR_INFO)
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+        if let [_, _, _, _, _, left, top, right, bottom, ..] = borders[..] {
+            let [top, left, bottom, right] =
+                [top, left, bottom + 1.0, right + 1.0].map(|v| v as u32);
+            if top < bottom && left < right && bottom as usize <= height && right as usize <= width
+            {
+                active = [top, left, bottom, right];
+            }
+        }
+        Ok(Cr2Layout {
+            jpeg,
+            width,
+            height,
+            slices,
+            active,
+        })
+    }
+
+    /// Reassembles the CR2 sensor from its slices. Black is the mean of the masked pixels
+    /// left of and above the exposed area, for each CFA position; white is the brightest
+    /// sample when the sensor clipped, and full scale otherwise. CR2 stores no colour matrix,
+    /// so camera RGB is taken as sRGB.
+    fn cr2_sensor(&self) -> Result<Sensor> {
+        let layout = self.cr2_layout()?;
+        let jpeg = decode_lossless_jpeg(layout.jpeg, self.kind.name())?;
+        let (width, height) = (layout.width, layout.height);
+        if jpeg.samples.len() < width * height {
+            return Err(self.corrupt("The CR2 sensor data is smaller than its slices."));
+        }
+        let mut samples = vec![0u16; width * height];
+        let mut coded = jpeg.samples.iter().copied();
+        for (x, slice_width) in &layout.slices {
+            for y in 0..height {
+                let at = y * width + x;
+                for (sample, value) in samples[at..at + slice_width].iter_mut().zip(&mut coded) {
+                    *sample = value;
+                }
+            }
+        }
+        drop(jpeg.samples);
+
+        let [top, left, bottom, right] = layout.active.map(|v| v as usize);
+        // The CFA position relative to the exposed area; adding the origin instead of
+        // subtracting it gives the same parity without going negative.
+        let phase = |x: usize, y: usize| (y + top) % 2 * 2 + (x + left) % 2;
+        let (mut sums, mut counts) = ([0u64; 4], [0u64; 4]);
+        for y in 0..height {
+            let masked = if y < top { width } else { left };
+            for x in 0..masked {
+                sums[phase(x, y)] += samples[y * width + x] as u64;
+                counts[phase(x, y)] += 1;
+            }
+        }
+        let black = [0, 1, 2, 3].map(|p| match counts[p] {
+            0 => 0.0,
+            count => sums[p] as f64 / count as f64,
+        });
+        let full = ((1u32 << jpeg.precision) - 1) as f64;
+        let brightest = (top..bottom)
+            .flat_map(|y| samples[y * width + left..y * width + right].iter().copied())
+            .max()
+            .unwrap_or(0) as f64;
+        let white = match brightest >= full * 0.75 {
+            true => brightest,
+            false => full,
+        };
+
+        let (active_width, active_height) = (right - left, bottom - top);
+        let mut plane = vec![0f32; active_width * active_height];
+        for y in 0..active_height {
+            for x in 0..active_width {
+                let value = samples[(top + y) * width + left + x] as f64;
+                let black = black[phase(left + x, top + y)];
+                plane[y * active_width + x] =
+                    ((value - black) / (white - black).max(1.0)).clamp(0.0, 1.0) as f32;
+            }
+        }
+
+        // Canon sensors start with a red pixel.
+        const RGGB: [u8; 4] = [0, 1, 1, 2];
+        let colors = (0..4)
+            .map(|i| RGGB[(i / 2 + top) % 2 * 2 + (i % 2 + left) % 2])
+            .collect();
+        Ok(Sensor {
+            width: active_width as u32,
+            height: active_height as u32,
+            plane,
+            cfa: Some(Cfa {
+                rows: 2,
+                cols: 2,
+                colors,
+            }),
+            multipliers: self.cr2_white_balance(),
+            camera_to_srgb: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            crop: [0, 0, active_width as u32, active_height as u32],
+        })
+    }
+
+    /// The Canon maker note, an IFD in the Exif IFD that uses file offsets. `None` when it
+    /// is missing or unreadable, as development does without it.
+    fn maker_note(&self) -> Option<Ifd> {
+        let exif = self.tiff.number(&self.first, EXIF_IFD).ok()??;
+        let exif = self.tiff.ifd_at(exif as usize).ok()?;
+        self.tiff.ifd_at(exif.get(MAKER_NOTE)?.offset()).ok()
+    }
+
+    /// Gains from `WB_RGGBLevelsAsShot` in the maker note's colour data, which moves between
+    /// camera generations; the length of the data tells them apart.
+    fn cr2_white_balance(&self) -> [f64; 3] {
+        let data = match self.maker_note() {
+            Some(note) => self
+                .tiff
+                .numbers(&note, CANON_COLOR_DATA)
+                .unwrap_or_default(),
+            None => return [1.0; 3],
+        };
+        let at = match data.len() {
+            582 => 25,
+            653 => 24,
+            1816 | 1820 | 1824 | 5120 => 71,
+            2024 | 3656 => 85,
+            3778 | 3973 => 105,
+            _ => 63,
+        };
+        let Some(&[red, green_1, green_2, blue]) = data.get(at..at + 4) else {
+            return [1.0; 3];
+        };
+        let green = (green_1 + green_2) / 2.0;
+        let gains = [red / green, 1.0, blue / green];
+        // Anything else is not where this camera keeps its levels.
+        if !gains.iter().all(|gain| (0.1..=10.0).contains(gain)) {
+            return [1.0; 3];
+        }
+        let smallest = gains.iter().copied().fold(f64::MAX, f64::min);
+        gains.map(|gain| gain / smallest)
+    }
+
+    fn cfa_pattern(&self, ifd: &Ifd, channels: usize) -> Result<Cfa> {
+        let (rows, cols) = match self.tiff.numbers(ifd, CFA_REPEAT_PATTERN_DIM)?[..] {
+            [rows, cols] => (rows as usize, cols as usize),
+            _ => (2, 2),
+        };
+        let colors: Vec<u8> = self
+            .tiff
+            .numbers(ifd, CFA_PATTERN)?
+            .iter()
+            .map(|c| *c as u8)
+            .collect();
+        if channels != 1 || rows == 0 || cols == 0 || rows.checked_mul(cols) != Some(colors.len()) {
+            return Err(self.corrupt("The DNG CFA pattern is malformed."));
+        }
+        if colors.iter().any(|c| *c > 2) {
+            return Err(self.unsupported("Only red, green and blue CFA sensors are supported."));
+        }
+        Ok(Cfa { rows, cols, colors })
+    }
+
+    /// Camera channel multipliers that make a neutral grey neutral, the smallest being 1.
+    fn white_balance(&self, ifd: &Ifd) -> Result<[f64; 3]> {
+        let neutral = match self.tiff.numbers(&self.first, AS_SHOT_NEUTRAL)?[..] {
+            [r, g, b] => [r, g, b],
+            _ => match self.tiff.numbers(ifd, AS_SHOT_NEUTRAL)?[..] {
+                [r, g, b] => [r, g, b],
+                _ => [1.0; 3],
+            },
+        };
+        if neutral.iter().any(|n| *n <= 0.0) {
+            return Ok([1.0; 3]);
+        }
+        let multipliers = neutral.map(|n| 1.0 / n);
+        let smallest = multipliers.iter().copied().fold(f64::MAX, f64::min);
+        Ok(multipliers.map(|m| m / smallest))
+    }
+
+    /// Inverts the DNG colour matrix, which maps XYZ to camera space, through sRGB. Rows are
+    /// normalised first so white-balanced camera white comes out as sRGB white.
+    fn camera_to_srgb(&self) -> Result<[[f64; 3]; 3]> {
+        let tiff = &self.tiff;
+        let matrix_2 = tiff.numbers(&self.first, COLOR_MATRIX_2)?;
+        let matrix_1 = tiff.numbers(&self.first, COLOR_MATRIX_1)?;
+        let illuminant_2 = tiff.number(&self.first, CALIBRATION_ILLUMINANT_2)?;
+        let xyz_to_camera = match (matrix_1.len(), matrix_2.len()) {
+            (_, 9) if illuminant_2 == Some(ILLUMINANT_D65) => matrix_2,
+            (9, _) => matrix_1,
+            (_, 9) => matrix_2,
+            _ => return Ok([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]),
+        };
+
+        let mut srgb_to_camera = [[0.0; 3]; 3];
+        for (row, out) in srgb_to_camera.iter_mut().enumerate() {
+            for (col, value) in out.iter_mut().enumerate() {
+                *value = (0..3)
+                    .map(|k| xyz_to_camera[row * 3 + k] * SRGB_TO_XYZ[k][col])
+                    .sum();
+            }
+            let sum: f64 = out.iter().sum();
+            if sum.abs() > f64::EPSILON {
+                out.iter_mut().for_each(|value| *value /= sum);
+            }
+        }
+        invert(srgb_to_camera).ok_or_else(|| self.corrupt("The DNG colour matrix is singular."))
+    }
+
+    /// Every sample of the raw IFD, from strips or tiles, uncompressed or lossless JPEG.
+    fn read_samples(
+        &self,
+        ifd: &Ifd,
+        width: u32,
+        height: u32,
+        bits: u32,
+        channels: usize,
+    ) -> Result<Vec<u16>> {
+        let tiff = &self.tiff;
+        let (block_width, block_height, offsets, lengths) = match ifd.get(TILE_OFFSETS) {
+            Some(_) => (
+                tiff.number(ifd, TILE_WIDTH)?.unwrap_or(0.0) as u32,
+                tiff.number(ifd, TILE_LENGTH)?.unwrap_or(0.0) as u32,
+                tiff.numbers(ifd, TILE_OFFSETS)?,
+                tiff.numbers(ifd, TILE_BYTE_COUNTS)?,
+            ),
+            None => (
+                width,
+                tiff.number(ifd, ROWS_PER_STRIP)?
+                    .map_or(height, |rows| rows as u32),
+                tiff.numbers(ifd, STRIP_OFFSETS)?,
+                tiff.numbers(ifd, STRIP_BYTE_COUNTS)?,
+            ),
+        };
+        let compression = tiff.number(ifd, COMPRESSION)?.unwrap_or(1.0) as u32;
+        if compression != 1 && compression != 7 {
+            return Err(
+                self.unsupported(format!("DNG compression {} is not supported.", compression))
+            );
+        }
+        let (block_width, block_height) = (block_width.min(width), block_height.min(height));
+        let across = width.div_ceil(block_width.max(1)) as usize;
+        let down = height.div_ceil(block_height.max(1)) as usize;
+        if block_width == 0
+            || block_height == 0
+            || offsets.len() < across * down
+            || lengths.len() < offsets.len()
+        {
+            return Err(self.corrupt("The DNG strip or tile layout is malformed."));
+        }
+
+        let row_len = width as usize * channels;
+        let mut samples = vec![0u16; row_len * height as usize];
+        for (index, (offset, length)) in
+            offsets.iter().zip(&lengths).take(across * down).enumerate()
+        {
+            let (offset, length) = (*offset as usize, *length as usize);
+            let data = offset
+                .checked_add(length)
+                .and_then(|end| tiff.data.get(offset..end))
+                .ok_or_else(|| {
+                    self.corrupt("A DNG strip or tile points past the end of the file.")
+                })?;
+            let block = match compression {
+                1 => self.unpack(
+                    data,
+                    block_width as usize * channels,
+                    block_height as usize,
+                    bits,
+                ),
+                _ => {
+                    // Encoders often pack two CFA columns into one two-component JPEG
+                    // column, so only the row length in samples has to match.
+                    let tile = decode_lossless_jpeg(data, self.kind.name())?;
+                    if tile.width * tile.components != block_width as usize * channels
+                        || tile.height < block_height as usize
+                    {
+                        return Err(self.corrupt("A DNG lossless JPEG tile has the wrong size."));
+                    }
+                    tile.samples
+                }
+            };
+
+            let x = (index % across) * block_width as usize;
+            let y = (index / across) * block_height as usize;
+            let block_row = block_width as usize * channels;
+            let copy = (width as usize - x).min(block_width as usize) * channels;
+            for (row, source) in block.chunks(block_row).enumerate() {
+                if y + row >= height as usize || source.len() < copy {
+                    break;
+                }
+                let at = (y + row) * row_len + x * channels;
+                samples[at..at + copy].copy_from_slice(&source[..copy]);
+            }
+        }
+        Ok(samples)
+    }
+
+    /// Uncompressed samples: bytes, 16-bit words in the file's byte order, or other widths
+    /// packed most significant bit first with each row starting on a byte.
+    fn unpack(&self, data: &[u8], row_samples: usize, rows: usize, bits: u32) -> Vec<u16> {
+        let mut samples = Vec::with_capacity(row_samples * rows);
+        match bits {
+            8 => samples.extend(data.iter().map(|b| *b as u16)),
+            16 => samples.extend(
+                data.chunks_exact(2)
+                    .map(|b| match self.tiff.little_endian() {
+                        true => u16::from_le_bytes([b[0], b[1]]),
+                        false => u16::from_be_bytes([b[0], b[1]]),
+                    }),
+            ),
+            _ => {
+                let row_bytes = (row_samples * bits as usize).div_ceil(8);
+                for row in data.chunks(row_bytes).take(rows) {
+                    let mut acc = 0u32;
+                    let mut count = 0;
+                    let mut bytes = row.iter();
+                    for _ in 0..row_samples {
+                        while count < bits {
+                            acc = (acc << 8) | *bytes.next().unwrap_or(&0) as u32;
+                            count += 8;
+                        }
+                        count -= bits;
+                        samples.push(((acc >> count) & ((1 << bits) - 1)) as u16);
+                    }
+                }
+            }
+        }
+        samples
+    }
+}
+
+/// Sensor data cropped to the exposed area and scaled so black is 0 and white 1, with what
+/// it takes to render it.
+struct Sensor {
+    width: u32,
+    height: u32,
+    /// One sample per pixel behind a CFA, three for linear data.
+    plane: Vec<f32>,
+    cfa: Option<Cfa>,
+    /// Camera channel gains that make a neutral grey neutral, the smallest being 1.
+    multipliers: [f64; 3],
+    camera_to_srgb: [[f64; 3]; 3],
+    /// x, y, width and height of the developed image within the exposed area.
+    crop: [u32; 4],
+}
+
+impl Sensor {
+    /// Demosaics the CFA bilinearly, white balances, converts to sRGB and crops.
+    fn render(self) -> RgbaImage {
+        let (width, height) = (self.width, self.height);
+        let mut image = RgbaImage::new(width, height);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let camera = match &self.cfa {
+                Some(cfa) => cfa.interpolate(&self.plane, width, height, x, y),
+                None => {
+                    let at = (y as usize * width as usize + x as usize) * 3;
+                    [self.plane[at], self.plane[at + 1], self.plane[at + 2]]
+                }
+            };
+            let camera = [0, 1, 2].map(|c| (camera[c] as f64 * self.multipliers[c]).min(1.0));
+            let rgb = self.camera_to_srgb.map(|row| {
+                let linear = row[0] * camera[0] + row[1] * camera[1] + row[2] * camera[2];
+                (srgb_encode(linear.clamp(0.0, 1.0)) * 255.0).round() as u8
+            });
+            pixel.0 = [rgb[0], rgb[1], rgb[2], 255];
+        }
+
+        let [x, y, crop_width, crop_height] = self.crop;
+        if self.crop == [0, 0, width, height] {
+            return image;
+        }
+        imageops::crop_imm(&image, x, y, crop_width, crop_height).to_image()
+    }
+}
+
+/// Where a CR2 keeps its sensor data and how it is laid out.
+struct Cr2Layout<'a> {
+    /// The lossless JPEG holding every sensor sample.
+    jpeg: &'a [u8],
+    width: usize,
+    height: usize,
+    /// The first column and width of each slice, in coded order. Each covers every row.
+    slices: Vec<(usize, usize)>,
+    /// The exposed area as top, left, bottom, right.
+    active: [u32; 4],
+}
+
+struct Cfa {
+    rows: usize,
+    cols: usize,
+    /// 0 red, 1 green, 2 blue, for each position of the repeating pattern.
+    colors: Vec<u8>,
+}
+
+impl Cfa {
+    fn color(&self, x: usize, y: usize) -> usize {
+        self.colors[(y % self.rows) * self.cols + x % self.cols] as usize
+    }
+
+    /// The pixel's own colour, and the mean of each other colour among its eight neighbours.
+    fn interpolate(&self, plane: &[f32], width: u32, height: u32, x: u32, y: u32) -> [f32; 3] {
+        let (width, height, x, y) = (width as usize, height as usize, x as usize, y as usize);
+        let mut sums = [0f32; 3];
+        let mut counts = [0u32; 3];
+        for ny in y.saturating_sub(1)..(y + 2).min(height) {
+            for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                let color = self.color(nx, ny);
+                sums[color] += plane[ny * width + nx];
+                counts[color] += 1;
+            }
+        }
+        let own = self.color(x, y);
+        let mut rgb = [0f32; 3];
+        for c in 0..3 {
+            rgb[c] = match (c == own, counts[c]) {
+                (true, _) => plane[y * width + x],
+                (false, 0) => 0.0,
+                (false, count) => sums[c] / count as f32,
+            };
+        }
+        rgb
+    }
+}
+
+/// Width and height from the frame header of a baseline or progressive JPEG; `None` for
+/// anything else, including the lossless JPEG of raw data.
+fn jpeg_size(jpeg: &[u8]) -> Option<(u32, u32)> {
+    if !jpeg.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut at = 2;
+    loop {
+        while *jpeg.get(at)? == 0xff && *jpeg.get(at + 1)? == 0xff {
+            at += 1;
+        }
+        if *jpeg.get(at)? != 0xff {
+            return None;
+        }
+        let marker = *jpeg.get(at + 1)?;
+        if matches!(marker, 0x01 | 0xd0..=0xd7) {
+            at += 2;
+            continue;
+        }
+        let segment = jpeg.get(at + 4..)?;
+        match marker {
+            0xc0..=0xc2 => {
+                let height = u16::from_be_bytes([*segment.get(1)?, *segment.get(2)?]) as u32;
+                let width = u16::from_be_bytes([*segment.get(3)?, *segment.get(4)?]) as u32;
+                return Some((width, height));
+            }
+            0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf | 0xd9 | 0xda => return None,
+            _ => {}
+        }
+        let len = u16::from_be_bytes([*jpeg.get(at + 2)?, *jpeg.get(at + 3)?]) as usize;
+        at += 2 + len;
+    }
+}
+
+fn invert(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let cofactor =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    Some([
+        [
+            cofactor(1, 2, 1, 2) / det,
+            -cofactor(0, 2, 1, 2) / det,
+            cofactor(0, 1, 1, 2) / det,
+        ],
+        [
+            -cofactor(1, 2, 0, 2) / det,
+            cofactor(0, 2, 0, 2) / det,
+            -cofactor(0, 1, 0, 2) / det,
+        ],
+        [
+            cofactor(1, 2, 0, 1) / det,
+            -cofactor(0, 2, 0, 1) / det,
+            cofactor(0, 1, 0, 1) / det,
+        ],
+    ])
+}
+
+fn srgb_encode(linear: f64) -> f64 {
+    match linear <= 0.003_130_8 {
+        true => linear * 12.92,
+        false => 1.055 * linear.powf(1.0 / 2.4) - 0.055,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        formats::{find_format, tests::decode_truncations},
+        frame_encode::encode_jpeg,
+        limits::{set_limits, DecodeLimits},
+        lossless_jpeg::tests::encode_lossless_jpeg,
+        tiff_ifd::tests::*,
+    };
+
+    /// A 4×4 RGGB DNG whose 12-bit samples are 1000 above a black level of 100 for green
+    /// and 500 above it for red and blue, with the as-shot neutral to balance them.
+    fn dng(compression: u16, crop: bool) -> Vec<u8> {
+        let samples: Vec<u16> = (0..16)
+            .map(|i| match (i % 4 % 2, i / 4 % 2) {
+                (0, 1) | (1, 0) => 1100,
+                _ => 600,
+            })
+            .collect();
+        let mut builder = TiffBuilder::new(&[]);
+        let strip = match compression {
+            7 => builder.blob(&encode_lossless_jpeg(4, 4, 1, 12, &samples)),
+            _ => builder.blob(
+                &samples
+                    .iter()
+                    .flat_map(|s| s.to_le_bytes())
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        let length = builder.blob(&[]) - strip;
+        let mut fields = vec![
+            byte(DNG_VERSION, &[1, 4, 0, 0]),
+            ascii(MAKE, "Maker"),
+            ascii(UNIQUE_CAMERA_MODEL, "Maker Test"),
+            long(NEW_SUBFILE_TYPE, &[0]),
+            long(IMAGE_WIDTH, &[4]),
+            long(IMAGE_LENGTH, &[4]),
+            short(BITS_PER_SAMPLE, &[if compression == 7 { 12 } else { 16 }]),
+            short(COMPRESSION, &[compression]),
+            short(PHOTOMETRIC, &[32803]),
+            long(STRIP_OFFSETS, &[strip]),
+            short(SAMPLES_PER_PIXEL, &[1]),
+            long(ROWS_PER_STRIP, &[4]),
+            long(STRIP_BYTE_COUNTS, &[length]),
+            short(CFA_REPEAT_PATTERN_DIM, &[2, 2]),
+            byte(CFA_PATTERN, &[0, 1, 1, 2]),
+            short(BLACK_LEVEL, &[100]),
+            short(WHITE_LEVEL, &[4095]),
+            rational(AS_SHOT_NEUTRAL, &[(1, 2), (1, 1), (1, 2)]),
+        ];
+        if crop {
+            fields.push(long(DEFAULT_CROP_ORIGIN, &[1, 1]));
+            fields.push(long(DEFAULT_CROP_SIZE, &[2, 3]));
+        }
+        let ifd = builder.ifd(fields, 0);
+        builder.finish(ifd)
+    }
+
+    const SENSOR_WIDTH: usize = 8;
+    const SENSOR_HEIGHT: usize = 6;
+
+    /// An 8×6 CR2 sensor: two masked columns at 256, then an RGGB area whose samples are
+    /// `active(x, y)`, coded as two slices of four columns in a two-component JPEG.
+    fn cr2(active: impl Fn(usize, usize) -> u16, maker_note: bool) -> Vec<u8> {
+        let sensor: Vec<u16> = (0..SENSOR_WIDTH * SENSOR_HEIGHT)
+            .map(|i| match i % SENSOR_WIDTH {
+                x if x < 2 => 256,
+                x => active(x, i / SENSOR_WIDTH),
+            })
+            .collect();
+        let coded: Vec<u16> = [0, 4]
+            .iter()
+            .flat_map(|x| {
+                let sensor = &sensor;
+                (0..SENSOR_HEIGHT)
+                    .flat_map(move |y| &sensor[y * SENSOR_WIDTH + x..y * SENSOR_WIDTH + x + 4])
+            })
+            .copied()
+            .collect();
+
+        let mut builder = TiffBuilder::new(b"CR\x02\0\0\0\0\0");
+        let strip = builder.blob(&encode_lossless_jpeg(4, 6, 2, 12, &coded));
+        let length = builder.blob(&[]) - strip;
+        let raw = builder.ifd(
+            vec![
+                short(COMPRESSION, &[6]),
+                long(STRIP_OFFSETS, &[strip]),
+                long(STRIP_BYTE_COUNTS, &[length]),
+                short(CR2_SLICE, &[1, 4, 4]),
+            ],
+            0,
+        );
+
+        let mut first = vec![ascii(MAKE, "Canon"), ascii(MODEL, "Canon EOS Test")];
+        if maker_note {
+            let mut sensor_info = [0u16; 17];
+            sensor_info[1..9].copy_from_slice(&[8, 6, 0, 0, 2, 0, 7, 5]);
+            let mut color_data = [0u16; 582];
+            color_data[25..29].copy_from_slice(&[2048, 1024, 1024, 2048]);
+            let start = builder.blob(&[]);
+            let note = builder.ifd(
+                vec![
+                    short(CANON_SENSOR_INFO, &sensor_info),
+                    short(CANON_COLOR_DATA, &color_data),
+                ],
+                0,
+            );
+            let size = builder.blob(&[]) - start;
+            let exif = builder.ifd(vec![undefined_at(MAKER_NOTE, size, note)], 0);
+            first.push(long(EXIF_IFD, &[exif]));
+        }
+        let first = builder.ifd(first, raw);
+        builder.finish(first)
+    }
+
+    /// A NEF or ARW, by `make`, holding only a 6×4 JPEG preview.
+    fn with_preview(make: &str) -> Vec<u8> {
+        let mut builder = TiffBuilder::new(&[]);
+        let preview = encode_jpeg(&RgbaImage::new(6, 4), 90).unwrap();
+        let offset = builder.blob(&preview);
+        let ifd = builder.ifd(
+            vec![
+                ascii(MAKE, make),
+                short(PHOTOMETRIC, &[32803]),
+                long(JPEG_OFFSET, &[offset]),
+                long(JPEG_LENGTH, &[preview.len() as u32]),
+            ],
+            0,
+        );
+        builder.finish(ifd)
+    }
+
+    fn code<T>(result: Result<T>) -> Option<ErrorCode> {
+        result.err().map(|error| error.code)
+    }
+
+    /// Red and blue 500 above black and green 1000, which the white balance evens out.
+    fn balanced(x: usize, y: usize) -> u16 {
+        match (x % 2, y % 2) {
+            (0, 1) | (1, 0) => 1256,
+            _ => 756,
+        }
+    }
+
+    #[test]
+    fn dng_sensor_data_develops_to_neutral_grey() {
+        for compression in [1, 7] {
+            let data = dng(compression, false);
+            assert!(is_dng(&data));
+            assert_eq!(developed_dimensions(&data).unwrap(), (4, 4));
+            let image = developed_rgba(&data).unwrap();
+            // 1000 of a 3995 range is a quarter of full scale.
+            let grey = (srgb_encode(1000.0 / 3995.0) * 255.0).round() as u8;
+            for pixel in image.pixels() {
+                assert_eq!(
+                    pixel.0,
+                    [grey, grey, grey, 255],
+                    "compression {}",
+                    compression
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dng_default_crop_sets_the_size() {
+        let data = dng(1, true);
+        assert_eq!(developed_dimensions(&data).unwrap(), (2, 3));
+        assert_eq!(developed_rgba(&data).unwrap().dimensions(), (2, 3));
+    }
+
+    #[test]
+    fn cr2_slices_are_reassembled_and_black_is_subtracted() {
+        let data = cr2(|x, y| 1000 + (y * SENSOR_WIDTH + x) as u16, true);
+        assert!(is_cr2(&data));
+        let raw = Raw::parse(&data).unwrap();
+        let sensor = raw.cr2_sensor().unwrap();
+        assert_eq!((sensor.width, sensor.height), (6, 6));
+        for y in 0..6 {
+            for x in 0..6 {
+                let value = 1000.0 + (y * SENSOR_WIDTH + x + 2) as f32;
+                let expected = (value - 256.0) / (4095.0 - 256.0);
+                let actual = sensor.plane[y * 6 + x];
+                assert!((actual - expected).abs() < 1e-6, "({}, {})", x, y);
+            }
+        }
+        // The exposed area starts on an even column, so it is RGGB too.
+        assert_eq!(sensor.cfa.unwrap().colors, [0, 1, 1, 2]);
+    }
+
+    #[test]
+    fn cr2_white_balance_comes_from_the_maker_note() {
+        let data = cr2(balanced, true);
+        assert_eq!(developed_dimensions(&data).unwrap(), (6, 6));
+        let image = developed_rgba(&data).unwrap();
+        let grey = (srgb_encode(1000.0 / 3839.0) * 255.0).round() as u8;
+        for pixel in image.pixels() {
+            assert_eq!(pixel.0, [grey, grey, grey, 255]);
+        }
+
+        // Without a maker note the whole sensor is developed, unbalanced.
+        let data = cr2(balanced, false);
+        assert_eq!(developed_dimensions(&data).unwrap(), (8, 6));
+        let image = developed_rgba(&data).unwrap();
+        let pixel = image.get_pixel(4, 2).0;
+        assert!(pixel[1] > pixel[0] && pixel[1] > pixel[2]);
+    }
+
+    #[test]
+    fn nef_and_arw_are_not_developed() {
+        let mut builder = TiffBuilder::new(&[]);
+        let ifd = builder.ifd(
+            vec![
+                ascii(MAKE, "NIKON CORPORATION"),
+                short(PHOTOMETRIC, &[32803]),
+            ],
+            0,
+        );
+        let data = builder.finish(ifd);
+        assert!(is_nef(&data));
+        assert_eq!(
+            code(developed_rgba(&data)),
+            Some(ErrorCode::UnsupportedFormat)
+        );
+        assert_eq!(
+            code(developed_dimensions(&data)),
+            Some(ErrorCode::UnsupportedFormat)
+        );
+
+        // The registry says so, as their decoders return the embedded preview.
+        let develops: Vec<_> = ["dng", "cr2", "nef", "arw"]
+            .iter()
+            .map(|name| find_format(name).unwrap().develop)
+            .collect();
+        assert_eq!(develops, [true, true, false, false]);
+    }
+
+    #[test]
+    fn truncated_raw_files_are_errors() {
+        for (data, format) in [(dng(1, false), "dng"), (cr2(balanced, true), "cr2")] {
+            decode_truncations(&data, format);
+        }
+        for (make, format) in [("NIKON CORPORATION", "nef"), ("SONY", "arw")] {
+            decode_truncations(&with_preview(make), format);
+        }
+
+        for data in [dng(1, false), dng(7, false), cr2(balanced, true)] {
+            // The first IFD is written last; cuts after its entries may only lose strings.
+            let first = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+            for len in 0..data.len() {
+                let result = developed_rgba(&data[..len]);
+                if len < first + 2 {
+                    assert!(result.is_err(), "{} bytes", len);
+                    assert!(developed_dimensions(&data[..len]).is_err(), "{} bytes", len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_sensors_are_rejected_before_decoding() {
+        let mut limits = DecodeLimits::default();
+        limits.set_max_width(7);
+        set_limits(limits);
+        assert_eq!(
+            code(developed_rgba(&cr2(balanced, true))),
+            Some(ErrorCode::LimitExceeded)
+        );
+        assert_eq!(
+            code(developed_dimensions(&cr2(balanced, true))),
+            Some(ErrorCode::LimitExceeded)
+        );
+        limits.set_max_width(3);
+        set_limits(limits);
+        assert_eq!(
+            code(developed_rgba(&dng(1, false))),
+            Some(ErrorCode::LimitExceeded)
+        );
+    }
+}
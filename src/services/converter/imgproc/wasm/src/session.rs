@@ -0,0 +1,316 @@
+use image::{DynamicImage, RgbaImage};
+
+use crate::{
+    animation_decoder::DecodedFrame,
+    callback_logs::{CallbackLogs, ProgressEvent, Stage},
+    cancellation::Cancellation,
+    convert::{encode_still, sprite_sheet, ConvertOptions},
+    error::{ErrorCode, ImgprocError, Result},
+    first_frame::encode_png,
+    formats::{source_format, target_format},
+    image_frames::{decode_detected_frames, decode_first_frame},
+    pipeline::{encode_with_metadata, read_metadata, Metadata},
+    probe::{probe, sniff_format, ImageInfo},
+    thumbnail::{scale_to_fit, ThumbnailOptions},
+    tone_map::{is_hdr, is_high_bit_depth, to_rgba8, ToneMap},
+};
+
+/// One input image, parsed and decoded at most once across calls.
+///
+/// The header is read on the first `probe`, the first frame on the first call that needs
+/// pixels, and the full frame list only when an animation is converted or a later frame
+/// is asked for.
+pub struct ImageSession {
+    data: Vec<u8>,
+    format: String,
+    info: Option<ImageInfo>,
+    first_frame: Option<RgbaImage>,
+    frames: Option<Vec<DecodedFrame>>,
+    /// A still image with more than 8 bits per channel, kept at full precision; `Some(None)`
+    /// once a still image turned out to have 8.
+    deep: Option<Option<DynamicImage>>,
+    /// The ICC profile and EXIF data written into still output, read on the first convert.
+    metadata: Option<Metadata>,
+}
+
+impl ImageSession {
+    /// `source_type` is sniffed from the data when empty.
+    pub fn new(image_data: Vec<u8>, source_type: &str) -> Result<Self> {
+        let format = match source_type {
+            "" => sniff_format(&image_data)
+                .ok_or_else(|| {
+                    ImgprocError::new(ErrorCode::UnsupportedFormat, "Unrecognised image format.")
+                })?
+                .to_string(),
+            _ => source_type.to_lowercase(),
+        };
+
+        Ok(ImageSession {
+            data: image_data,
+            format,
+            info: None,
+            first_frame: None,
+            frames: None,
+            deep: None,
+            metadata: None,
+        })
+    }
+
+    pub fn probe(&mut self) -> Result<ImageInfo> {
+        if let Some(info) = &self.info {
+            return Ok(info.clone());
+        }
+        let info = probe(&self.data, &self.format)?;
+        self.format = info.format();
+        self.info = Some(info.clone());
+        Ok(info)
+    }
+
+    pub fn is_animated(&mut self) -> Result<bool> {
+        Ok(self.probe()?.is_animated())
+    }
+
+    /// Every frame with its delay in milliseconds, decoded on first use.
+    pub fn frames(
+        &mut self,
+        logs: &CallbackLogs,
+        cancel: &Cancellation,
+    ) -> Result<&[DecodedFrame]> {
+        if self.frames.is_none() {
+            let animated = self.is_animated()?;
+            let frames = decode_detected_frames(&self.data, &self.format, animated, logs, cancel)?;
+            self.first_frame = None;
+            self.frames = Some(frames);
+        }
+        Ok(self.frames.as_deref().unwrap_or_default())
+    }
+
+    /// A still image decoded once at full precision, with its 8-bit first frame cached
+    /// alongside. `None` for animations and 8-bit images.
+    fn deep_image(&mut self) -> Result<Option<&DynamicImage>> {
+        if self.deep.is_none() {
+            let deep = match self.is_animated()? {
+                true => None,
+                false => {
+                    let image = source_format(&self.format)?.decode_deep(&self.data)?;
+                    if self.frames.is_none() && self.first_frame.is_none() {
+                        self.first_frame = Some(to_rgba8(&image, ToneMap::default(), 0.0));
+                    }
+                    is_high_bit_depth(&image).then_some(image)
+                }
+            };
+            self.deep = Some(deep);
+        }
+        Ok(self.deep.as_ref().and_then(Option::as_ref))
+    }
+
+    fn first_frame_image(&mut self) -> Result<&RgbaImage> {
+        if self.frames.is_none() && self.first_frame.is_none() {
+            self.probe()?;
+            self.first_frame = Some(decode_first_frame(&self.data, &self.format)?);
+        }
+        match (&self.frames, &self.first_frame) {
+            (Some(frames), _) if !frames.is_empty() => Ok(&frames[0].0),
+            (_, Some(image)) => Ok(image),
+            _ => Err(ImgprocError::corrupt("The image has no frames.").format(&self.format)),
+        }
+    }
+
+    fn metadata(&mut self) -> &Metadata {
+        self.metadata
+            .get_or_insert_with(|| read_metadata(&self.data, &self.format))
+    }
+
+    /// The first frame as PNG.
+    pub fn first_frame(&mut self) -> Result<Vec<u8>> {
+        encode_png(self.first_frame_image()?.clone())
+    }
+
+    fn frame_image(
+        &mut self,
+        index: u32,
+        logs: &CallbackLogs,
+        cancel: &Cancellation,
+    ) -> Result<&RgbaImage> {
+        if index == 0 {
+            return self.first_frame_image();
+        }
+        let format = self.format.clone();
+        let frames = self.frames(logs, cancel)?;
+        match frames.get(index as usize) {
+            Some((image, _)) => Ok(image),
+            None => Err(ImgprocError::invalid_argument(format!(
+                "Frame {} is out of range; the image has {} frames.",
+                index,
+                frames.len()
+            ))
+            .format(format)),
+        }
+    }
+
+    /// Frame `index` as PNG.
+    pub fn frame(
+        &mut self,
+        index: u32,
+        logs: &CallbackLogs,
+        cancel: &Cancellation,
+    ) -> Result<Vec<u8>> {
+        encode_png(self.frame_image(index, logs, cancel)?.clone())
+    }
+
+    /// Writes every frame of an animation when the target can animate. Otherwise writes one
+    /// still image, with the source's ICC profile and EXIF data where the target keeps them:
+    /// the frame or sprite sheet `options` asks for, or the first frame.
+    pub fn convert(
+        &mut self,
+        target_type: &str,
+        options: &ConvertOptions,
+        logs: &CallbackLogs,
+        cancel: Cancellation,
+    ) -> Result<Vec<u8>> {
+        options.validate()?;
+        let target = target_format(target_type)?;
+        let animate = options.animated() && target.can_encode_animation() && self.is_animated()?;
+        let still = !options.sprite_sheet() && options.frame().unwrap_or(0) == 0 && !animate;
+        if still {
+            if let Some(image) = self.deep_image()? {
+                let image = image.clone();
+                let metadata = self.metadata().clone();
+                return encode_deep(image, target_type, options, &metadata, logs, cancel);
+            }
+        }
+
+        let image = if options.sprite_sheet() {
+            sprite_sheet(self.frames(logs, &cancel)?, options.columns())?
+        } else if let Some(index) = options.frame() {
+            self.frame_image(index, logs, &cancel)?.clone()
+        } else if animate {
+            let frames = self.frames(logs, &cancel)?;
+            return target.encode_frames(frames, logs, cancel);
+        } else {
+            self.first_frame_image()?.clone()
+        };
+
+        cancel()?;
+        logs.send(ProgressEvent::new(Stage::Encode, "encode_start").arg(target_type))?;
+        let output = encode_with_metadata(
+            target,
+            DynamicImage::ImageRgba8(image),
+            self.metadata(),
+            options,
+        )?;
+        logs.send(
+            ProgressEvent::new(Stage::Finalize, "encode_done")
+                .arg(target_type)
+                .bytes_written(output.len()),
+        )?;
+        Ok(output)
+    }
+
+    pub fn thumbnail(&mut self, options: &ThumbnailOptions) -> Result<Vec<u8>> {
+        let image = scale_to_fit(self.first_frame_image()?.clone(), options.size())?;
+        encode_still(image, &options.format(), &ConvertOptions::default())
+    }
+}
+
+/// Encodes a full-precision still image, reporting when an HDR image is tone mapped.
+fn encode_deep(
+    image: DynamicImage,
+    target_type: &str,
+    options: &ConvertOptions,
+    metadata: &Metadata,
+    logs: &CallbackLogs,
+    cancel: Cancellation,
+) -> Result<Vec<u8>> {
+    let target = target_format(target_type)?;
+    cancel()?;
+    if is_hdr(&image) && !target.keeps_hdr() {
+        logs.send(ProgressEvent::new(Stage::Transform, "tone_map").arg(options.tone_map().name()))?;
+    }
+    logs.send(ProgressEvent::new(Stage::Encode, "encode_start").arg(target_type))?;
+    let output = encode_with_metadata(target, image, metadata, options)?;
+    logs.send(
+        ProgressEvent::new(Stage::Finalize, "encode_done")
+            .arg(target_type)
+            .bytes_written(output.len()),
+    )?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        animation_encode::encode_gif,
+        cancellation::never_cancelled,
+        limits::{set_limits, DecodeLimits},
+    };
+    use image::Rgba;
+
+    /// Three 8x4 frames of one colour each.
+    fn animation() -> Vec<u8> {
+        let frames: Vec<DecodedFrame> = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]]
+            .into_iter()
+            .map(|color| (RgbaImage::from_pixel(8, 4, Rgba(color)), 100))
+            .collect();
+        encode_gif(&frames, &CallbackLogs::silent(), never_cancelled()).unwrap()
+    }
+
+    fn convert(
+        session: &mut ImageSession,
+        target: &str,
+        options: &ConvertOptions,
+    ) -> Result<Vec<u8>> {
+        session.convert(target, options, &CallbackLogs::silent(), never_cancelled())
+    }
+
+    #[test]
+    fn decoded_frames_are_reused_across_calls() {
+        let mut session = ImageSession::new(animation(), "").unwrap();
+        assert_eq!(session.probe().unwrap().format(), "gif");
+        let gif = convert(&mut session, "gif", &ConvertOptions::default()).unwrap();
+
+        // With the source gone, everything has to come from what was decoded already.
+        session.data = b"GIF89a, cut short".to_vec();
+        let info = session.probe().unwrap();
+        assert!(info.is_animated());
+        assert_eq!(
+            convert(&mut session, "gif", &ConvertOptions::default()).unwrap(),
+            gif
+        );
+        assert!(!session
+            .thumbnail(&ThumbnailOptions::default())
+            .unwrap()
+            .is_empty());
+        let mut options = ConvertOptions::default();
+        options.set_frame(Some(2));
+        assert!(!convert(&mut session, "png", &options).unwrap().is_empty());
+        assert!(!session.first_frame().unwrap().is_empty());
+    }
+
+    #[test]
+    fn first_frame_is_decoded_without_the_rest() {
+        let mut session = ImageSession::new(animation(), "gif").unwrap();
+        session.thumbnail(&ThumbnailOptions::default()).unwrap();
+        assert!(session.first_frame.is_some());
+        assert!(session.frames.is_none());
+    }
+
+    #[test]
+    fn sprite_sheets_are_held_to_the_limits() {
+        let mut limits = DecodeLimits::default();
+        limits.set_max_width(16);
+        set_limits(limits);
+
+        let mut session = ImageSession::new(animation(), "gif").unwrap();
+        let mut options = ConvertOptions::default();
+        options.set_sprite_sheet(true);
+        let sheet = convert(&mut session, "png", &options).unwrap();
+        let sheet = image::load_from_memory(&sheet).unwrap();
+        assert_eq!((sheet.width(), sheet.height()), (16, 8));
+
+        options.set_columns(3);
+        let error = convert(&mut session, "png", &options).err().unwrap();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+    }
+}
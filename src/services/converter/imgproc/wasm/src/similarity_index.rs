@@ -0,0 +1,527 @@
+use std::{cmp::Ordering, collections::BinaryHeap, collections::HashMap};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    error::{ErrorCode, ImgprocError, Result},
+    perceptual_hash::{hamming_distance, parse_hash},
+};
+
+const INDEX_MAGIC: &[u8] = b"IMGSIDX";
+const INDEX_VERSION: u8 = 1;
+const MAX_DISTANCE: u32 = u64::BITS;
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone)]
+pub struct SimilarityMatch {
+    id: String,
+    distance: u32,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl SimilarityMatch {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn distance(&self) -> u32 {
+        self.distance
+    }
+}
+
+impl PartialEq for SimilarityMatch {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SimilarityMatch {}
+
+impl PartialOrd for SimilarityMatch {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SimilarityMatch {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .cmp(&other.distance)
+            .then_with(|| self.id.cmp(&other.id))
+    }
+}
+
+struct Node {
+    hash: u64,
+    ids: Vec<String>,
+    children: Vec<(u32, usize)>,
+}
+
+/// A BK-tree over 64-bit perceptual hashes keyed by image id.
+///
+/// Removed ids leave their node in place as a routing point; the tree is rebuilt once more
+/// than half of its nodes are empty.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Default)]
+pub struct SimilarityIndex {
+    nodes: Vec<Node>,
+    hashes: HashMap<String, u64>,
+    /// Nodes whose ids have all been removed.
+    empty_nodes: usize,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl SimilarityIndex {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new() -> SimilarityIndex {
+        SimilarityIndex::default()
+    }
+
+    /// Builds an index from parallel arrays of image ids and hex hashes, as read from the database.
+    #[cfg_attr(feature = "wasm", wasm_bindgen)]
+    pub fn load(ids: Vec<String>, hashes: Vec<String>) -> Result<SimilarityIndex> {
+        if ids.len() != hashes.len() {
+            return Err(ImgprocError::invalid_argument(format!(
+                "Mismatched index input: {} ids but {} hashes",
+                ids.len(),
+                hashes.len()
+            )));
+        }
+
+        let mut index = SimilarityIndex::new();
+        for (id, hash) in ids.into_iter().zip(hashes) {
+            index.insert_hash(id, parse_hash(&hash)?);
+        }
+        Ok(index)
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen)]
+    pub fn from_bytes(data: &[u8]) -> Result<SimilarityIndex> {
+        let invalid = |offset: usize| {
+            ImgprocError::corrupt("Invalid similarity index data.").offset(offset as u64)
+        };
+
+        let header_len = INDEX_MAGIC.len() + 1;
+        if data.len() < header_len + 4 || &data[..INDEX_MAGIC.len()] != INDEX_MAGIC {
+            return Err(invalid(0));
+        }
+        if data[INDEX_MAGIC.len()] != INDEX_VERSION {
+            return Err(ImgprocError::new(
+                ErrorCode::UnsupportedFormat,
+                format!(
+                    "Unsupported similarity index version: {}",
+                    data[INDEX_MAGIC.len()]
+                ),
+            )
+            .offset(INDEX_MAGIC.len() as u64));
+        }
+
+        let mut position = header_len;
+        // Returns the next `len` bytes and the offset they start at.
+        let mut take = |len: usize| -> Result<(&[u8], usize)> {
+            let start = position;
+            let bytes = data
+                .get(start..)
+                .and_then(|rest| rest.get(..len))
+                .ok_or_else(|| invalid(start))?;
+            position += len;
+            Ok((bytes, start))
+        };
+        let le_bytes = |bytes: &[u8]| -> [u8; 8] {
+            let mut array = [0; 8];
+            array[..bytes.len()].copy_from_slice(bytes);
+            array
+        };
+
+        let count = u64::from_le_bytes(le_bytes(take(4)?.0));
+        let mut index = SimilarityIndex::new();
+        for _ in 0..count {
+            let hash = u64::from_le_bytes(le_bytes(take(8)?.0));
+            let id_len = u64::from_le_bytes(le_bytes(take(4)?.0)) as usize;
+            let (id_bytes, id_start) = take(id_len)?;
+            let id =
+                std::str::from_utf8(id_bytes).map_err(|e| invalid(id_start + e.valid_up_to()))?;
+            index.insert_hash(id.to_string(), hash);
+        }
+        if position != data.len() {
+            return Err(invalid(position));
+        }
+        Ok(index)
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = Vec::from(INDEX_MAGIC);
+        output.push(INDEX_VERSION);
+        output.extend_from_slice(&(self.hashes.len() as u32).to_le_bytes());
+
+        let mut entries: Vec<(&String, &u64)> = self.hashes.iter().collect();
+        entries.sort();
+        for (id, hash) in entries {
+            output.extend_from_slice(&hash.to_le_bytes());
+            output.extend_from_slice(&(id.len() as u32).to_le_bytes());
+            output.extend_from_slice(id.as_bytes());
+        }
+
+        output
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn size(&self) -> u32 {
+        self.hashes.len() as u32
+    }
+
+    /// Adds an image, replacing its previous hash if the id is already indexed.
+    #[cfg_attr(feature = "wasm", wasm_bindgen)]
+    pub fn insert(&mut self, id: String, hash: &str) -> Result<()> {
+        let hash = parse_hash(hash)?;
+        self.insert_hash(id, hash);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen)]
+    pub fn remove(&mut self, id: &str) -> bool {
+        let hash = match self.hashes.remove(id) {
+            Some(hash) => hash,
+            None => return false,
+        };
+
+        if let Some(node) = self.find_node(hash) {
+            let ids = &mut self.nodes[node].ids;
+            ids.retain(|node_id| node_id != id);
+            if ids.is_empty() {
+                self.empty_nodes += 1;
+            }
+        }
+
+        if self.empty_nodes * 2 > self.nodes.len() {
+            self.rebuild();
+        }
+        true
+    }
+
+    /// Returns every image whose hash is within `distance` bits of `hash`, closest first.
+    #[cfg_attr(feature = "wasm", wasm_bindgen)]
+    pub fn within(&self, hash: &str, distance: u32) -> Result<Vec<SimilarityMatch>> {
+        let hash = parse_hash(hash)?;
+        let mut matches = Vec::new();
+        self.search(hash, distance, |id, d| {
+            matches.push(SimilarityMatch {
+                id: id.to_string(),
+                distance: d,
+            });
+            distance
+        });
+        matches.sort();
+        Ok(matches)
+    }
+
+    /// Returns the `k` images closest to `hash`, closest first.
+    #[cfg_attr(feature = "wasm", wasm_bindgen)]
+    pub fn nearest(&self, hash: &str, k: u32) -> Result<Vec<SimilarityMatch>> {
+        let hash = parse_hash(hash)?;
+        let k = k as usize;
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+
+        // `k` comes from the caller, so the heap is sized by what the index holds.
+        let mut best: BinaryHeap<SimilarityMatch> =
+            BinaryHeap::with_capacity(k.min(self.hashes.len()) + 1);
+        self.search(hash, MAX_DISTANCE, |id, d| {
+            best.push(SimilarityMatch {
+                id: id.to_string(),
+                distance: d,
+            });
+            if best.len() > k {
+                best.pop();
+            }
+            match best.peek() {
+                Some(worst) if best.len() == k => worst.distance,
+                _ => MAX_DISTANCE,
+            }
+        });
+        Ok(best.into_sorted_vec())
+    }
+}
+
+impl SimilarityIndex {
+    pub fn insert_hash(&mut self, id: String, hash: u64) {
+        if self.hashes.contains_key(&id) {
+            self.remove(&id);
+        }
+        self.hashes.insert(id.clone(), hash);
+
+        if self.nodes.is_empty() {
+            self.nodes.push(Node {
+                hash,
+                ids: vec![id],
+                children: Vec::new(),
+            });
+            return;
+        }
+
+        let mut current = 0;
+        loop {
+            let distance = hamming_distance(self.nodes[current].hash, hash);
+            if distance == 0 {
+                if self.nodes[current].ids.is_empty() {
+                    self.empty_nodes -= 1;
+                }
+                self.nodes[current].ids.push(id);
+                return;
+            }
+
+            let child = self.nodes[current]
+                .children
+                .iter()
+                .find(|(d, _)| *d == distance)
+                .map(|(_, child)| *child);
+
+            match child {
+                Some(child) => current = child,
+                None => {
+                    let new_node = self.nodes.len();
+                    self.nodes.push(Node {
+                        hash,
+                        ids: vec![id],
+                        children: Vec::new(),
+                    });
+                    self.nodes[current].children.push((distance, new_node));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn find_node(&self, hash: u64) -> Option<usize> {
+        let mut current = 0;
+        while current < self.nodes.len() {
+            let distance = hamming_distance(self.nodes[current].hash, hash);
+            if distance == 0 {
+                return Some(current);
+            }
+            current = self.nodes[current]
+                .children
+                .iter()
+                .find(|(d, _)| *d == distance)
+                .map(|(_, child)| *child)?;
+        }
+        None
+    }
+
+    /// Walks the tree, reporting every id within `radius` of `hash`. The `visit` callback
+    /// returns the new search radius, which lets k-nearest queries tighten it as they go.
+    fn search(&self, hash: u64, mut radius: u32, mut visit: impl FnMut(&str, u32) -> u32) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = vec![0];
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            let distance = hamming_distance(node.hash, hash);
+
+            if distance <= radius {
+                for id in &node.ids {
+                    radius = visit(id, distance);
+                }
+            }
+
+            for &(child_distance, child) in &node.children {
+                if child_distance.abs_diff(distance) <= radius {
+                    stack.push(child);
+                }
+            }
+        }
+    }
+
+    fn rebuild(&mut self) {
+        let mut entries: Vec<(String, u64)> = self.hashes.drain().collect();
+        entries.sort();
+        self.nodes.clear();
+        self.empty_nodes = 0;
+        for (id, hash) in entries {
+            self.insert_hash(id, hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perceptual_hash::format_hash;
+
+    fn random_hashes(count: usize) -> Vec<u64> {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        (0..count)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                // Clusters of near duplicates, as a library of re-uploads has.
+                match i % 3 {
+                    0 => state,
+                    _ => state & !(0xff << (i % 50)),
+                }
+            })
+            .collect()
+    }
+
+    fn index_of(hashes: &[u64]) -> SimilarityIndex {
+        let ids = (0..hashes.len()).map(|i| format!("image-{}", i)).collect();
+        let hashes = hashes.iter().map(|h| format_hash(*h)).collect();
+        SimilarityIndex::load(ids, hashes).unwrap()
+    }
+
+    /// Every indexed image within `distance`, found by comparing against all of them.
+    fn brute_force(index: &SimilarityIndex, hash: u64, distance: u32) -> Vec<SimilarityMatch> {
+        let mut matches: Vec<_> = index
+            .hashes
+            .iter()
+            .map(|(id, h)| SimilarityMatch {
+                id: id.clone(),
+                distance: hamming_distance(*h, hash),
+            })
+            .filter(|m| m.distance <= distance)
+            .collect();
+        matches.sort();
+        matches
+    }
+
+    fn ids(matches: &[SimilarityMatch]) -> Vec<(String, u32)> {
+        matches.iter().map(|m| (m.id(), m.distance())).collect()
+    }
+
+    #[test]
+    fn queries_match_a_linear_scan() {
+        let hashes = random_hashes(300);
+        let mut index = index_of(&hashes);
+        for round in 0..2 {
+            for (i, query) in random_hashes(40).iter().enumerate() {
+                let query = query ^ (1 << i);
+                for distance in [0, 3, 10, 30] {
+                    let found = index.within(&format_hash(query), distance).unwrap();
+                    assert_eq!(ids(&found), ids(&brute_force(&index, query, distance)));
+                }
+                let nearest = index.nearest(&format_hash(query), 7).unwrap();
+                let all = brute_force(&index, query, MAX_DISTANCE);
+                assert_eq!(ids(&nearest), ids(&all[..7]));
+            }
+            // Removing most images makes the tree rebuild, which must not lose any.
+            if round == 0 {
+                for i in (0..300).filter(|i| i % 5 != 0) {
+                    assert!(index.remove(&format!("image-{}", i)));
+                    let empty = index.nodes.iter().filter(|n| n.ids.is_empty()).count();
+                    assert_eq!(index.empty_nodes, empty);
+                    assert!(empty * 2 <= index.nodes.len());
+                }
+                assert!(!index.remove("image-1"));
+                assert_eq!(index.size(), 60);
+            }
+        }
+    }
+
+    #[test]
+    fn inserting_an_id_again_replaces_its_hash() {
+        let mut index = SimilarityIndex::new();
+        index.insert("a".to_string(), "00000000000000ff").unwrap();
+        index.insert("a".to_string(), "ff00000000000000").unwrap();
+        assert_eq!(index.size(), 1);
+        assert!(index.within("00000000000000ff", 0).unwrap().is_empty());
+        assert_eq!(index.within("ff00000000000000", 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reinserting_into_an_emptied_node_reuses_it() {
+        let mut index = index_of(&[0, 1, 3]);
+        assert!(index.remove("image-1"));
+        assert_eq!(index.empty_nodes, 1);
+        index.insert("again".to_string(), &format_hash(1)).unwrap();
+        assert_eq!(index.empty_nodes, 0);
+        assert_eq!(index.nodes.len(), 3);
+        assert_eq!(
+            ids(&index.within(&format_hash(1), 0).unwrap()),
+            [("again".to_string(), 0)]
+        );
+    }
+
+    #[test]
+    fn serialized_indexes_load_back() {
+        let index = index_of(&random_hashes(50));
+        let bytes = index.to_bytes();
+        let loaded = SimilarityIndex::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.hashes, index.hashes);
+        assert_eq!(loaded.to_bytes(), bytes);
+
+        // One entry: magic, version, count, then the hash, id length and id.
+        let mut single = SimilarityIndex::new();
+        single.insert("id".to_string(), "0102030405060708").unwrap();
+        let expected = [
+            &b"IMGSIDX\x01"[..],
+            &[1, 0, 0, 0],
+            &[8, 7, 6, 5, 4, 3, 2, 1],
+            &[2, 0, 0, 0],
+            b"id",
+        ]
+        .concat();
+        assert_eq!(single.to_bytes(), expected);
+    }
+
+    #[test]
+    fn malformed_serialized_indexes_are_errors() {
+        let code = |data: &[u8]| SimilarityIndex::from_bytes(data).err().map(|e| e.code);
+        let bytes = index_of(&random_hashes(5)).to_bytes();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                code(&bytes[..len]),
+                Some(ErrorCode::CorruptData),
+                "{} bytes",
+                len
+            );
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(code(&trailing), Some(ErrorCode::CorruptData));
+
+        let mut version = bytes.clone();
+        version[7] = 2;
+        assert_eq!(code(&version), Some(ErrorCode::UnsupportedFormat));
+
+        let mut bad_id = bytes.clone();
+        let last = bad_id.len() - 1;
+        bad_id[last] = 0xff;
+        let error = SimilarityIndex::from_bytes(&bad_id).err().unwrap();
+        assert_eq!(error.code, ErrorCode::CorruptData);
+        assert_eq!(error.byte_offset, Some(last as u64));
+
+        // A huge count with no entries fails at the first missing one.
+        let mut huge = b"IMGSIDX\x01".to_vec();
+        huge.extend(u32::MAX.to_le_bytes());
+        assert_eq!(code(&huge), Some(ErrorCode::CorruptData));
+    }
+
+    #[test]
+    fn invalid_arguments_are_errors() {
+        let code = |result: Result<SimilarityIndex>| result.err().map(|e| e.code);
+        assert_eq!(
+            code(SimilarityIndex::load(vec!["a".to_string()], Vec::new())),
+            Some(ErrorCode::InvalidArgument)
+        );
+        assert_eq!(
+            code(SimilarityIndex::load(
+                vec!["a".to_string()],
+                vec!["not a hash".to_string()]
+            )),
+            Some(ErrorCode::InvalidArgument)
+        );
+        let index = index_of(&random_hashes(3));
+        assert!(index.within("zz", 1).is_err());
+        assert!(index.nearest(&format_hash(0), 0).unwrap().is_empty());
+        // A k larger than the index returns everything without sizing anything by k.
+        assert_eq!(index.nearest(&format_hash(0), u32::MAX).unwrap().len(), 3);
+    }
+}
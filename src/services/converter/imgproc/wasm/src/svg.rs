@@ -0,0 +1,290 @@
+//! SVG rasterisation with resvg. Text is set in the bundled DejaVu fonts, and only images
+//! embedded as data URLs are drawn: files and URLs the document refers to are never loaded.
+
+use image::RgbaImage;
+use resvg::{
+    tiny_skia::{Pixmap, Transform},
+    usvg::{self, fontdb, ImageHrefResolver},
+};
+use roxmltree::{Document, ParsingOptions};
+use std::{borrow::Cow, sync::Arc};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    error::{ErrorCode, ImgprocError, Result},
+    limits::current_limits,
+    xml_nesting::check_nesting,
+};
+
+const FONTS: [&[u8]; 4] = [
+    include_bytes!("../fonts/DejaVuSans.ttf"),
+    include_bytes!("../fonts/DejaVuSans-Bold.ttf"),
+    include_bytes!("../fonts/DejaVuSerif.ttf"),
+    include_bytes!("../fonts/DejaVuSansMono.ttf"),
+];
+
+thread_local! {
+    static FONT_DB: Arc<fontdb::Database> = Arc::new(bundled_fonts());
+}
+
+/// The output size. With neither dimension set the document's own size is used, scaled by
+/// `dpi`; with one, the other follows the aspect ratio; with both, the image fits inside.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug)]
+pub struct SvgOptions {
+    width: u32,
+    height: u32,
+    dpi: f32,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        SvgOptions {
+            width: 0,
+            height: 0,
+            dpi: 96.0,
+        }
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl SvgOptions {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new() -> SvgOptions {
+        SvgOptions::default()
+    }
+
+    /// Output width in pixels, or 0 to follow the height or the document.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_width(&mut self, value: u32) {
+        self.width = value;
+    }
+
+    /// Output height in pixels, or 0 to follow the width or the document.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_height(&mut self, value: u32) {
+        self.height = value;
+    }
+
+    /// Resolution used for the document's own size. SVG pixels are 1/96 inch, so 96 keeps
+    /// them one to one and 192 doubles them.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn dpi(&self) -> f32 {
+        self.dpi
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_dpi(&mut self, value: f32) {
+        self.dpi = value;
+    }
+}
+
+/// An `<svg>` root element, possibly after an XML declaration, comments or a doctype. The
+/// document is parsed, so HTML or other XML that merely contains `<svg` is not taken for one.
+pub fn is_svg(data: &[u8]) -> bool {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return false;
+    };
+    let text = text.trim_start_matches('\u{feff}');
+    if !text.trim_start().starts_with('<') {
+        return false;
+    }
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..ParsingOptions::default()
+    };
+    check_nesting(text, "the SVG", "SVG").is_ok()
+        && Document::parse_with_options(text, options)
+            .is_ok_and(|document| document.root_element().tag_name().name() == "svg")
+}
+
+/// The document's size in pixels at 96 DPI, rounded up.
+pub fn svg_dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    let size = parse(data)?.size();
+    Ok((size.width().ceil() as u32, size.height().ceil() as u32))
+}
+
+pub fn svg_rgba(data: &[u8]) -> Result<RgbaImage> {
+    rasterize_svg(data, &SvgOptions::default())
+}
+
+pub fn rasterize_svg(data: &[u8], options: &SvgOptions) -> Result<RgbaImage> {
+    let tree = parse(data)?;
+    let size = tree.size();
+    let (width, height) = output_size(size.width(), size.height(), options)?;
+    current_limits().check_dimensions(width, height, "SVG")?;
+
+    let mut pixmap = Pixmap::new(width, height).ok_or_else(|| {
+        ImgprocError::new(ErrorCode::Internal, "Could not allocate the SVG canvas.").format("SVG")
+    })?;
+    let transform =
+        Transform::from_scale(width as f32 / size.width(), height as f32 / size.height());
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia works in premultiplied alpha.
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(width, height, pixels).ok_or_else(|| {
+        ImgprocError::new(ErrorCode::Internal, "The SVG canvas has the wrong size.").format("SVG")
+    })
+}
+
+fn parse(data: &[u8]) -> Result<usvg::Tree> {
+    let options = usvg::Options {
+        resources_dir: None,
+        font_family: "DejaVu Serif".to_string(),
+        image_href_resolver: ImageHrefResolver {
+            resolve_data: ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(|_, _| None),
+        },
+        fontdb: FONT_DB.with(Arc::clone),
+        ..usvg::Options::default()
+    };
+    // As `usvg::Tree::from_data` does, with the nesting checked before the XML is parsed.
+    let data = match data.starts_with(&[0x1f, 0x8b]) {
+        true => Cow::Owned(usvg::decompress_svgz(data).map_err(parse_error)?),
+        false => Cow::Borrowed(data),
+    };
+    let text = std::str::from_utf8(&data).map_err(|_| parse_error(usvg::Error::NotAnUtf8Str))?;
+    check_nesting(text, "the SVG", "SVG")?;
+    usvg::Tree::from_str(text, &options).map_err(parse_error)
+}
+
+fn parse_error(error: usvg::Error) -> ImgprocError {
+    let message = match error {
+        usvg::Error::ElementsLimitReached => {
+            return ImgprocError::new(
+                ErrorCode::LimitExceeded,
+                "The SVG has more elements than can be rendered.",
+            )
+            .format("SVG");
+        }
+        usvg::Error::InvalidSize => "The SVG has no valid width, height or viewBox.",
+        usvg::Error::MalformedGZip => "The compressed SVG is malformed.",
+        _ => "The SVG could not be parsed.",
+    };
+    ImgprocError::corrupt(message)
+        .format("SVG")
+        .cause(error.to_string())
+}
+
+/// Scales the document size by the requested width, height or DPI.
+fn output_size(width: f32, height: f32, options: &SvgOptions) -> Result<(u32, u32)> {
+    let scale = match (options.width, options.height) {
+        (0, 0) if options.dpi.is_finite() && options.dpi > 0.0 => options.dpi / 96.0,
+        (0, 0) => {
+            return Err(ImgprocError::invalid_argument(format!(
+                "The DPI must be positive, not {}.",
+                options.dpi
+            )))
+        }
+        (w, 0) => w as f32 / width,
+        (0, h) => h as f32 / height,
+        (w, h) => (w as f32 / width).min(h as f32 / height),
+    };
+    let size = |length: f32| (length * scale).round().max(1.0) as u32;
+    Ok((size(width), size(height)))
+}
+
+fn bundled_fonts() -> fontdb::Database {
+    let mut db = fontdb::Database::new();
+    for font in FONTS {
+        db.load_font_source(fontdb::Source::Binary(Arc::new(font)));
+    }
+    db.set_serif_family("DejaVu Serif");
+    db.set_sans_serif_family("DejaVu Sans");
+    db.set_monospace_family("DejaVu Sans Mono");
+    db.set_cursive_family("DejaVu Sans");
+    db.set_fantasy_family("DejaVu Sans");
+    db
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_nesting::MAX_NESTING;
+
+    #[test]
+    fn svg_roots_are_found_past_the_prologue() {
+        let documents = [
+            r#"<svg xmlns="http://www.w3.org/2000/svg"/>"#,
+            "\u{feff}\n  <svg xmlns=\"http://www.w3.org/2000/svg\" width=\"4\" height=\"4\"></svg>",
+            r##"<?xml version="1.0" encoding="UTF-8" standalone="no"?>
+<!-- Created with an editor; <html> is not the root -->
+<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN"
+  "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd" [
+  <!ENTITY accent "#c00">
+]>
+<svg xmlns="http://www.w3.org/2000/svg"><rect fill="&accent;"/></svg>"##,
+            r#"<svg:svg xmlns:svg="http://www.w3.org/2000/svg"/>"#,
+        ];
+        for document in documents {
+            assert!(is_svg(document.as_bytes()), "{}", document);
+        }
+    }
+
+    #[test]
+    fn other_documents_are_not_svg() {
+        let documents: [&[u8]; 7] = [
+            b"",
+            b"hello <svg xmlns=\"http://www.w3.org/2000/svg\"/>",
+            b"<!DOCTYPE html><html><body><svg xmlns=\"http://www.w3.org/2000/svg\"/></body></html>",
+            b"<?xml version=\"1.0\"?><!-- <svg> --><feed><svg/></feed>",
+            b"<svg xmlns=\"http://www.w3.org/2000/svg\"><rect",
+            b"<svg xmlns=\"http://www.w3.org/2000/svg\">\xff\xfe</svg>",
+            b"\x89PNG\r\n\x1a\n",
+        ];
+        for document in documents {
+            assert!(!is_svg(document), "{}", String::from_utf8_lossy(document));
+        }
+    }
+
+    #[test]
+    fn svg_renders_at_its_own_size() {
+        let data = br##"<svg xmlns="http://www.w3.org/2000/svg" width="6" height="3">
+            <rect width="6" height="3" fill="#ff0000"/></svg>"##;
+        assert_eq!(svg_dimensions(data).unwrap(), (6, 3));
+        let image = svg_rgba(data).unwrap();
+        assert_eq!(image.dimensions(), (6, 3));
+        assert!(image.pixels().all(|pixel| pixel.0 == [255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn deep_nesting_is_refused_before_parsing() {
+        let nested = |depth| {
+            format!(
+                r##"<svg xmlns="http://www.w3.org/2000/svg" width="1" height="1">{}<rect width="1" height="1" fill="#00f"/>{}</svg>"##,
+                "<g>".repeat(depth),
+                "</g>".repeat(depth)
+            )
+        };
+        let data = nested(MAX_NESTING - 1);
+        assert!(is_svg(data.as_bytes()));
+        assert_eq!(
+            svg_rgba(data.as_bytes()).unwrap().get_pixel(0, 0).0,
+            [0, 0, 255, 255]
+        );
+
+        let data = nested(100_000);
+        assert!(!is_svg(data.as_bytes()));
+        let error = svg_rgba(data.as_bytes()).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+    }
+}
@@ -0,0 +1,410 @@
+use image::RgbaImage;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    animation_decoder::DecodedFrame,
+    callback_logs::{CallbackLogs, ProgressEvent, Stage},
+    cancellation::never_cancelled,
+    error::{ImgprocError, Result},
+    frame_encode::{encode_indexed_gif, encode_indexed_png, encode_jpeg, encode_rgba_png},
+    image_compare::compare_images,
+    image_frames::decode_frames,
+    quantize::{quantize_frames, QuantizeOptions},
+};
+
+const PALETTE_SIZES: [u32; 8] = [256, 128, 64, 32, 16, 8, 4, 2];
+const GIF_LOSSINESS_LEVELS: [u32; 5] = [5, 10, 20, 40, 80];
+
+#[derive(Clone, Copy, Default)]
+struct EncodeParameters {
+    quality: Option<u8>,
+    colors: Option<u32>,
+    dithering: Option<bool>,
+    lossiness: Option<u32>,
+}
+
+struct Candidate {
+    output: Vec<u8>,
+    score: f64,
+    parameters: EncodeParameters,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct QualityTargetedImage {
+    output: Vec<u8>,
+    score: f64,
+    target_met: bool,
+    parameters: EncodeParameters,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl QualityTargetedImage {
+    #[cfg_attr(feature = "wasm", wasm_bindgen)]
+    pub fn output(self) -> Vec<u8> {
+        self.output
+    }
+
+    /// SSIM of the chosen output against the source.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn score(&self) -> f64 {
+        self.score
+    }
+
+    /// False when no setting reached the target; the output is then the closest one found.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn target_met(&self) -> bool {
+        self.target_met
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn quality(&self) -> Option<u8> {
+        self.parameters.quality
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn colors(&self) -> Option<u32> {
+        self.parameters.colors
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn dithering(&self) -> Option<bool> {
+        self.parameters.dithering
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn lossiness(&self) -> Option<u32> {
+        self.parameters.lossiness
+    }
+}
+
+/// Searches the encoder settings of `target_type` for the smallest output whose SSIM against
+/// `frames` is at least `min_ssim`. Every candidate is decoded again and measured.
+pub fn encode_for_quality(
+    frames: &[DecodedFrame],
+    target_type: &str,
+    min_ssim: f64,
+    logs: &CallbackLogs,
+) -> Result<QualityTargetedImage> {
+    if !(min_ssim > 0.0 && min_ssim <= 1.0) {
+        return Err(ImgprocError::invalid_argument(format!(
+            "The SSIM target must be above 0 and at most 1, not {}.",
+            min_ssim
+        )));
+    }
+    if frames.is_empty() {
+        return Err(ImgprocError::invalid_argument(
+            "Cannot encode an image without frames.",
+        ));
+    }
+
+    let mut search = QualitySearch {
+        frames,
+        min_ssim,
+        logs,
+        candidates: Vec::new(),
+    };
+
+    match target_type.to_lowercase().as_str() {
+        "jpeg" | "jpg" => search.jpeg()?,
+        "gif" => search.gif()?,
+        "png" | "apng" => search.png()?,
+        _ => return Err(ImgprocError::unsupported_target(target_type)),
+    }
+
+    search.best()
+}
+
+struct QualitySearch<'a, 'b> {
+    frames: &'a [DecodedFrame],
+    min_ssim: f64,
+    logs: &'a CallbackLogs<'b>,
+    candidates: Vec<Candidate>,
+}
+
+impl QualitySearch<'_, '_> {
+    /// Measures an encoded candidate, keeps it, and reports whether it met the target.
+    fn try_candidate(
+        &mut self,
+        output: Vec<u8>,
+        output_type: &str,
+        parameters: EncodeParameters,
+    ) -> Result<bool> {
+        let decoded = decode_frames(
+            &output,
+            output_type,
+            &CallbackLogs::silent(),
+            &never_cancelled(),
+        )?;
+        let reference = if output_type == "jpeg" {
+            &self.frames[..1]
+        } else {
+            self.frames
+        };
+        let score = compare_images(reference, &decoded, false)?.ssim();
+
+        self.logs.send(
+            ProgressEvent::new(Stage::Encode, "quality_candidate")
+                .arg(describe(&parameters))
+                .arg(output.len())
+                .arg(format!("{:.4}", score)),
+        )?;
+
+        let passed = score >= self.min_ssim;
+        self.candidates.push(Candidate {
+            output,
+            score,
+            parameters,
+        });
+        Ok(passed)
+    }
+
+    /// JPEG size grows with quality, so a binary search finds the lowest passing quality.
+    fn jpeg(&mut self) -> Result<()> {
+        let image = &self.frames[0].0;
+        let (mut low, mut high) = (1u8, 100u8);
+        while low <= high {
+            let quality = low + (high - low) / 2;
+            let parameters = EncodeParameters {
+                quality: Some(quality),
+                ..EncodeParameters::default()
+            };
+            if self.try_candidate(encode_jpeg(image, quality)?, "jpeg", parameters)? {
+                high = quality - 1;
+            } else {
+                low = quality + 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Shrinks the palette while either dithering mode still passes, then raises lossiness
+    /// on the smallest passing palette.
+    fn gif(&mut self) -> Result<()> {
+        let mut best: Option<QuantizeOptions> = None;
+        for colors in PALETTE_SIZES {
+            let mut passed = false;
+            for dithering in [false, true] {
+                let options = QuantizeOptions {
+                    colors,
+                    dithering,
+                    lossiness: 0,
+                    binary_alpha: true,
+                };
+                if self.try_indexed(options, "gif")? {
+                    best = Some(options);
+                    passed = true;
+                    break;
+                }
+            }
+            if !passed {
+                break;
+            }
+        }
+
+        if let Some(options) = best {
+            for lossiness in GIF_LOSSINESS_LEVELS {
+                if !self.try_indexed(
+                    QuantizeOptions {
+                        lossiness,
+                        ..options
+                    },
+                    "gif",
+                )? {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts from the lossless encoding, then tries ever smaller palettes.
+    fn png(&mut self) -> Result<()> {
+        self.try_candidate(
+            encode_rgba_png(self.frames)?,
+            "png",
+            EncodeParameters::default(),
+        )?;
+
+        for colors in PALETTE_SIZES {
+            let mut passed = false;
+            for dithering in [false, true] {
+                let options = QuantizeOptions {
+                    colors,
+                    dithering,
+                    lossiness: 0,
+                    binary_alpha: false,
+                };
+                if self.try_indexed(options, "png")? {
+                    passed = true;
+                    break;
+                }
+            }
+            if !passed {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn try_indexed(&mut self, options: QuantizeOptions, output_type: &str) -> Result<bool> {
+        let images: Vec<&RgbaImage> = self.frames.iter().map(|(image, _)| image).collect();
+        let delays: Vec<u32> = self.frames.iter().map(|(_, delay)| *delay).collect();
+        let (width, height) = images[0].dimensions();
+
+        self.logs.send(
+            ProgressEvent::new(Stage::Quantize, "quantize_palette")
+                .arg(options.colors)
+                .arg(images.len()),
+        )?;
+        let (palette, indices) = quantize_frames(&images, options);
+        let output = match output_type {
+            "gif" => encode_indexed_gif(width, height, &palette, &indices, &delays)?,
+            _ => encode_indexed_png(width, height, &palette, &indices, &delays)?,
+        };
+
+        let parameters = EncodeParameters {
+            colors: Some(options.colors),
+            dithering: Some(options.dithering),
+            lossiness: Some(options.lossiness),
+            ..EncodeParameters::default()
+        };
+        self.try_candidate(output, output_type, parameters)
+    }
+
+    /// The smallest passing candidate, or the highest scoring one if none passed.
+    fn best(self) -> Result<QualityTargetedImage> {
+        let min_ssim = self.min_ssim;
+        let passing = self.candidates.iter().any(|c| c.score >= min_ssim);
+
+        let chosen = self
+            .candidates
+            .into_iter()
+            .filter(|c| !passing || c.score >= min_ssim)
+            .min_by(|a, b| {
+                if passing {
+                    a.output.len().cmp(&b.output.len())
+                } else {
+                    b.score
+                        .total_cmp(&a.score)
+                        .then(a.output.len().cmp(&b.output.len()))
+                }
+            })
+            .ok_or_else(|| ImgprocError::internal("No encoder settings were tried."))?;
+
+        Ok(QualityTargetedImage {
+            output: chosen.output,
+            score: chosen.score,
+            target_met: passing,
+            parameters: chosen.parameters,
+        })
+    }
+}
+
+fn describe(parameters: &EncodeParameters) -> String {
+    let mut parts = Vec::new();
+    if let Some(quality) = parameters.quality {
+        parts.push(format!("quality {}", quality));
+    }
+    if let Some(colors) = parameters.colors {
+        parts.push(format!("{} colors", colors));
+    }
+    if let Some(true) = parameters.dithering {
+        parts.push("dithering".to_string());
+    }
+    if let Some(lossiness) = parameters.lossiness.filter(|l| *l > 0) {
+        parts.push(format!("lossiness {}", lossiness));
+    }
+    if parts.is_empty() {
+        parts.push("lossless".to_string());
+    }
+    parts.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use image::Rgba;
+
+    fn gradient() -> RgbaImage {
+        RgbaImage::from_fn(32, 32, |x, y| {
+            Rgba([(x * 8) as u8, (y * 8) as u8, ((x + y) * 4) as u8, 255])
+        })
+    }
+
+    fn two_colors() -> RgbaImage {
+        RgbaImage::from_fn(32, 32, |x, _| {
+            if x < 16 {
+                Rgba([200, 30, 30, 255])
+            } else {
+                Rgba([30, 30, 200, 255])
+            }
+        })
+    }
+
+    fn search(frames: &[DecodedFrame], target_type: &str, min_ssim: f64) -> QualityTargetedImage {
+        encode_for_quality(frames, target_type, min_ssim, &CallbackLogs::silent()).unwrap()
+    }
+
+    #[test]
+    fn rejects_targets_outside_the_ssim_range() {
+        let frames = vec![(gradient(), 0)];
+        for min_ssim in [0.0, -0.5, 1.5, f64::NAN, f64::INFINITY] {
+            let error = encode_for_quality(&frames, "jpeg", min_ssim, &CallbackLogs::silent())
+                .err()
+                .unwrap();
+            assert_eq!(error.code, ErrorCode::InvalidArgument, "{}", min_ssim);
+        }
+        let error = encode_for_quality(&[], "png", 0.9, &CallbackLogs::silent())
+            .err()
+            .unwrap();
+        assert_eq!(error.code, ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn jpeg_search_finds_the_lowest_passing_quality() {
+        let frames = vec![(gradient(), 0)];
+        let loose = search(&frames, "jpeg", 0.8);
+        let strict = search(&frames, "jpeg", 0.99);
+        assert!(loose.target_met() && strict.target_met());
+        assert!(loose.score() >= 0.8 && strict.score() >= 0.99);
+        assert!(loose.quality().unwrap() < strict.quality().unwrap());
+        assert!(loose.output().len() <= strict.output().len());
+    }
+
+    #[test]
+    fn gif_search_keeps_every_frame_of_an_animation() {
+        let frames = vec![(two_colors(), 100), (two_colors(), 200)];
+        let result = search(&frames, "gif", 0.99);
+        assert!(result.target_met());
+        assert!(result.colors().is_some());
+        let decoded = decode_frames(
+            &result.output(),
+            "gif",
+            &CallbackLogs::silent(),
+            &never_cancelled(),
+        )
+        .unwrap();
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn png_search_prefers_a_small_palette_when_it_passes() {
+        let frames = vec![(two_colors(), 0)];
+        let lossless = encode_rgba_png(&frames).unwrap();
+        let result = search(&frames, "png", 0.99);
+        assert!(result.target_met());
+        assert!(result.colors().is_some());
+        assert!(result.output().len() < lossless.len());
+
+        // An unreachable target keeps the best scoring candidate, the lossless one.
+        let noise = RgbaImage::from_fn(32, 32, |x, y| {
+            let v = (x * 7919 + y * 104729) as u8;
+            Rgba([v, v.wrapping_mul(3), v.wrapping_mul(7), 255])
+        });
+        let result = search(&[(noise, 0)], "png", 1.0);
+        assert_eq!(result.score(), 1.0);
+        assert_eq!(result.colors(), None);
+    }
+}
@@ -0,0 +1,66 @@
+use image::{imageops::FilterType, DynamicImage, RgbaImage};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::error::{ImgprocError, Result};
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub struct ThumbnailOptions {
+    size: u32,
+    format: String,
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> Self {
+        ThumbnailOptions {
+            size: 256,
+            format: "png".to_string(),
+        }
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl ThumbnailOptions {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new() -> ThumbnailOptions {
+        ThumbnailOptions::default()
+    }
+
+    /// Longest side of the thumbnail in pixels.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_size(&mut self, value: u32) {
+        self.size = value;
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn format(&self) -> String {
+        self.format.clone()
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(setter))]
+    pub fn set_format(&mut self, value: String) {
+        self.format = value;
+    }
+}
+
+/// Scales an image down to fit within `size`x`size`, keeping the aspect ratio.
+/// Images already smaller than `size` are not enlarged.
+pub fn scale_to_fit(image: RgbaImage, size: u32) -> Result<RgbaImage> {
+    if size == 0 {
+        return Err(ImgprocError::invalid_argument(
+            "Thumbnail size must be at least 1.",
+        ));
+    }
+    if image.width() <= size && image.height() <= size {
+        return Ok(image);
+    }
+    Ok(DynamicImage::ImageRgba8(image)
+        .resize(size, size, FilterType::Triangle)
+        .to_rgba8())
+}
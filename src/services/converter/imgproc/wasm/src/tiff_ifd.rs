@@ -0,0 +1,520 @@
+//! Walks the IFDs of TIFF-based files, such as camera raw formats, without decoding any
+//! image data.
+
+use crate::error::{ImgprocError, Result};
+
+pub(crate) const NEW_SUBFILE_TYPE: u16 = 0x00fe;
+pub(crate) const IMAGE_WIDTH: u16 = 0x0100;
+pub(crate) const IMAGE_LENGTH: u16 = 0x0101;
+pub(crate) const BITS_PER_SAMPLE: u16 = 0x0102;
+pub(crate) const COMPRESSION: u16 = 0x0103;
+pub(crate) const PHOTOMETRIC: u16 = 0x0106;
+pub(crate) const MAKE: u16 = 0x010f;
+pub(crate) const MODEL: u16 = 0x0110;
+pub(crate) const STRIP_OFFSETS: u16 = 0x0111;
+pub(crate) const ORIENTATION: u16 = 0x0112;
+pub(crate) const SAMPLES_PER_PIXEL: u16 = 0x0115;
+pub(crate) const ROWS_PER_STRIP: u16 = 0x0116;
+pub(crate) const STRIP_BYTE_COUNTS: u16 = 0x0117;
+pub(crate) const TILE_WIDTH: u16 = 0x0142;
+pub(crate) const TILE_LENGTH: u16 = 0x0143;
+pub(crate) const TILE_OFFSETS: u16 = 0x0144;
+pub(crate) const TILE_BYTE_COUNTS: u16 = 0x0145;
+pub(crate) const SUB_IFDS: u16 = 0x014a;
+pub(crate) const JPEG_OFFSET: u16 = 0x0201;
+pub(crate) const JPEG_LENGTH: u16 = 0x0202;
+pub(crate) const EXIF_IFD: u16 = 0x8769;
+pub(crate) const MAKER_NOTE: u16 = 0x927c;
+
+/// Nested and chained IFDs followed, against loops and deliberately huge files.
+const MAX_IFDS: usize = 64;
+
+/// One directory entry; the value is read on demand.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Entry {
+    pub(crate) tag: u16,
+    kind: u16,
+    count: u32,
+    /// Where the value starts: inside the entry when it fits in four bytes.
+    at: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Ifd {
+    pub(crate) entries: Vec<Entry>,
+}
+
+pub(crate) struct Tiff<'a> {
+    pub(crate) data: &'a [u8],
+    little_endian: bool,
+    format: &'a str,
+}
+
+impl<'a> Tiff<'a> {
+    /// Checks the byte-order mark and magic number; `format` labels errors.
+    pub(crate) fn new(data: &'a [u8], format: &'a str) -> Result<Self> {
+        let little_endian = match data.get(0..4) {
+            Some(b"II*\0") => true,
+            Some(b"MM\0*") => false,
+            _ => return Err(corrupt("The TIFF header is missing.", format, 0)),
+        };
+        Ok(Tiff {
+            data,
+            little_endian,
+            format,
+        })
+    }
+
+    /// Byte order of multi-byte values, including uncompressed 16-bit samples.
+    pub(crate) fn little_endian(&self) -> bool {
+        self.little_endian
+    }
+
+    pub(crate) fn is_tiff(data: &[u8]) -> bool {
+        matches!(data.get(0..4), Some(b"II*\0" | b"MM\0*"))
+    }
+
+    /// The first IFD, where the camera make and model are.
+    pub(crate) fn first_ifd(&self) -> Result<Ifd> {
+        let offset = self.u32_at(4)? as usize;
+        Ok(self.ifd(offset)?.0)
+    }
+
+    /// Every IFD in the main chain and, depth first, the SubIFDs under them.
+    pub(crate) fn all_ifds(&self) -> Result<Vec<Ifd>> {
+        let mut ifds = Vec::new();
+        let mut seen = Vec::new();
+        let mut pending = vec![self.u32_at(4)? as usize];
+        while let Some(offset) = pending.pop() {
+            if offset == 0 || seen.contains(&offset) {
+                continue;
+            }
+            if seen.len() == MAX_IFDS {
+                return Err(corrupt("The TIFF has too many IFDs.", self.format, offset));
+            }
+            seen.push(offset);
+            let (ifd, next) = self.ifd(offset)?;
+            pending.push(next);
+            if let Some(sub_ifds) = ifd.get(SUB_IFDS) {
+                pending.extend(self.values(&sub_ifds)?.iter().rev().map(|v| *v as usize));
+            }
+            ifds.push(ifd);
+        }
+        Ok(ifds)
+    }
+
+    /// The IFD at `offset`, such as the Exif IFD or a maker note that uses file offsets.
+    pub(crate) fn ifd_at(&self, offset: usize) -> Result<Ifd> {
+        Ok(self.ifd(offset)?.0)
+    }
+
+    /// Reads the IFD at `offset`, returning it with the offset of the next one.
+    fn ifd(&self, offset: usize) -> Result<(Ifd, usize)> {
+        let count = self.u16_at(offset)? as usize;
+        // The entries and next offset must be in the file before any are read.
+        self.read(offset, 2 + count * 12 + 4)?;
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let at = offset + 2 + i * 12;
+            let kind = self.u16_at(at + 2)?;
+            let count = self.u32_at(at + 4)?;
+            let size = type_size(kind) as u64 * count as u64;
+            let value_at = match size <= 4 {
+                true => at + 8,
+                false => self.u32_at(at + 8)? as usize,
+            };
+            entries.push(Entry {
+                tag: self.u16_at(at)?,
+                kind,
+                count,
+                at: value_at,
+            });
+        }
+        let next = self.u32_at(offset + 2 + count * 12)? as usize;
+        Ok((Ifd { entries }, next))
+    }
+
+    /// Numeric values of any type, rationals divided out.
+    pub(crate) fn values(&self, entry: &Entry) -> Result<Vec<f64>> {
+        let size = type_size(entry.kind);
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let end = entry.at as u64 + size as u64 * entry.count as u64;
+        if end > self.data.len() as u64 {
+            return Err(corrupt(
+                format!(
+                    "TIFF tag {:#06x} points past the end of the file.",
+                    entry.tag
+                ),
+                self.format,
+                entry.at,
+            ));
+        }
+        (0..entry.count as usize)
+            .map(|i| {
+                let at = entry.at + i * size;
+                Ok(match entry.kind {
+                    1 | 2 | 7 => self.data[at] as f64,
+                    6 => self.data[at] as i8 as f64,
+                    3 => self.u16_at(at)? as f64,
+                    8 => self.u16_at(at)? as i16 as f64,
+                    4 | 13 => self.u32_at(at)? as f64,
+                    9 => self.u32_at(at)? as i32 as f64,
+                    5 => ratio(self.u32_at(at)? as f64, self.u32_at(at + 4)? as f64),
+                    10 => ratio(
+                        self.u32_at(at)? as i32 as f64,
+                        self.u32_at(at + 4)? as i32 as f64,
+                    ),
+                    11 => f32::from_bits(self.u32_at(at)?) as f64,
+                    _ => f64::from_bits(self.u64_at(at)?),
+                })
+            })
+            .collect()
+    }
+
+    /// The raw bytes of a value, such as an ASCII string or an embedded blob.
+    pub(crate) fn bytes(&self, entry: &Entry) -> Option<&'a [u8]> {
+        let len = type_size(entry.kind) * entry.count as usize;
+        self.data.get(entry.at..entry.at.checked_add(len)?)
+    }
+
+    pub(crate) fn string(&self, ifd: &Ifd, tag: u16) -> Option<String> {
+        let bytes = self.bytes(&ifd.get(tag)?)?;
+        let bytes = bytes.split(|b| *b == 0).next().unwrap_or_default();
+        Some(String::from_utf8_lossy(bytes).trim().to_string())
+    }
+
+    /// The first value of a tag, if present.
+    pub(crate) fn number(&self, ifd: &Ifd, tag: u16) -> Result<Option<f64>> {
+        match ifd.get(tag) {
+            Some(entry) => Ok(self.values(&entry)?.first().copied()),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn numbers(&self, ifd: &Ifd, tag: u16) -> Result<Vec<f64>> {
+        match ifd.get(tag) {
+            Some(entry) => self.values(&entry),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn u16_at(&self, at: usize) -> Result<u16> {
+        let b = self.read(at, 2)?;
+        Ok(match self.little_endian {
+            true => u16::from_le_bytes([b[0], b[1]]),
+            false => u16::from_be_bytes([b[0], b[1]]),
+        })
+    }
+
+    fn u32_at(&self, at: usize) -> Result<u32> {
+        let b = self.read(at, 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Ok(match self.little_endian {
+            true => u32::from_le_bytes(b),
+            false => u32::from_be_bytes(b),
+        })
+    }
+
+    fn u64_at(&self, at: usize) -> Result<u64> {
+        let b: [u8; 8] = self.read(at, 8)?.try_into().unwrap_or_default();
+        Ok(match self.little_endian {
+            true => u64::from_le_bytes(b),
+            false => u64::from_be_bytes(b),
+        })
+    }
+
+    fn read(&self, at: usize, len: usize) -> Result<&'a [u8]> {
+        at.checked_add(len)
+            .and_then(|end| self.data.get(at..end))
+            .ok_or_else(|| corrupt("The TIFF structure is truncated.", self.format, at))
+    }
+}
+
+impl Entry {
+    /// Where the value starts in the file.
+    pub(crate) fn offset(&self) -> usize {
+        self.at
+    }
+}
+
+impl Ifd {
+    pub(crate) fn get(&self, tag: u16) -> Option<Entry> {
+        self.entries.iter().find(|entry| entry.tag == tag).copied()
+    }
+}
+
+fn type_size(kind: u16) -> usize {
+    match kind {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 | 13 => 4,
+        5 | 10 | 12 => 8,
+        _ => 0,
+    }
+}
+
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    match denominator {
+        0.0 => 0.0,
+        _ => numerator / denominator,
+    }
+}
+
+fn corrupt(message: impl Into<String>, format: &str, offset: usize) -> ImgprocError {
+    ImgprocError::corrupt(message)
+        .format(format)
+        .offset(offset as u64)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    /// One entry for [`TiffBuilder::ifd`]: its type, count and little-endian value bytes,
+    /// or, for `offset`, a file offset the entry points at.
+    pub(crate) struct Field {
+        tag: u16,
+        kind: u16,
+        count: u32,
+        bytes: Vec<u8>,
+        offset: Option<u32>,
+    }
+
+    fn field(tag: u16, kind: u16, count: usize, bytes: Vec<u8>) -> Field {
+        Field {
+            tag,
+            kind,
+            count: count as u32,
+            bytes,
+            offset: None,
+        }
+    }
+
+    pub(crate) fn byte(tag: u16, values: &[u8]) -> Field {
+        field(tag, 1, values.len(), values.to_vec())
+    }
+
+    pub(crate) fn ascii(tag: u16, value: &str) -> Field {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        field(tag, 2, bytes.len(), bytes)
+    }
+
+    pub(crate) fn short(tag: u16, values: &[u16]) -> Field {
+        let bytes = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        field(tag, 3, values.len(), bytes)
+    }
+
+    pub(crate) fn long(tag: u16, values: &[u32]) -> Field {
+        let bytes = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        field(tag, 4, values.len(), bytes)
+    }
+
+    pub(crate) fn rational(tag: u16, values: &[(u32, u32)]) -> Field {
+        let bytes = values
+            .iter()
+            .flat_map(|(n, d)| [n.to_le_bytes(), d.to_le_bytes()].concat())
+            .collect();
+        field(tag, 5, values.len(), bytes)
+    }
+
+    pub(crate) fn srational(tag: u16, values: &[(i32, i32)]) -> Field {
+        let bytes = values
+            .iter()
+            .flat_map(|(n, d)| [n.to_le_bytes(), d.to_le_bytes()].concat())
+            .collect();
+        field(tag, 10, values.len(), bytes)
+    }
+
+    /// An undefined value of `count` bytes already in the file at `offset`, such as a maker
+    /// note.
+    pub(crate) fn undefined_at(tag: u16, count: u32, offset: u32) -> Field {
+        Field {
+            tag,
+            kind: 7,
+            count,
+            bytes: Vec::new(),
+            offset: Some(offset),
+        }
+    }
+
+    /// Lays out a little-endian TIFF for tests. Whatever an IFD points at has to be added
+    /// before it, so chains are built from the end.
+    pub(crate) struct TiffBuilder {
+        data: Vec<u8>,
+    }
+
+    impl TiffBuilder {
+        /// `extra` follows the eight-byte header, as the CR2 signature does.
+        pub(crate) fn new(extra: &[u8]) -> Self {
+            let mut data = b"II*\0\0\0\0\0".to_vec();
+            data.extend(extra);
+            TiffBuilder { data }
+        }
+
+        pub(crate) fn blob(&mut self, bytes: &[u8]) -> u32 {
+            self.data.resize(self.data.len().next_multiple_of(2), 0);
+            let offset = self.data.len() as u32;
+            self.data.extend(bytes);
+            offset
+        }
+
+        pub(crate) fn ifd(&mut self, mut fields: Vec<Field>, next: u32) -> u32 {
+            fields.sort_by_key(|field| field.tag);
+            let offset = self.blob(&[]);
+            let mut values = offset as usize + 2 + fields.len() * 12 + 4;
+            let mut tail = Vec::new();
+            self.data.extend((fields.len() as u16).to_le_bytes());
+            for field in &fields {
+                self.data.extend(field.tag.to_le_bytes());
+                self.data.extend(field.kind.to_le_bytes());
+                self.data.extend(field.count.to_le_bytes());
+                match (field.offset, field.bytes.len()) {
+                    (Some(at), _) => self.data.extend(at.to_le_bytes()),
+                    (None, len) if len <= 4 => {
+                        let mut inline = field.bytes.clone();
+                        inline.resize(4, 0);
+                        self.data.extend(inline);
+                    }
+                    (None, len) => {
+                        self.data.extend((values as u32).to_le_bytes());
+                        tail.extend(&field.bytes);
+                        if len % 2 == 1 {
+                            tail.push(0);
+                        }
+                        values += len.next_multiple_of(2);
+                    }
+                }
+            }
+            self.data.extend(next.to_le_bytes());
+            self.data.extend(tail);
+            offset
+        }
+
+        pub(crate) fn finish(mut self, first: u32) -> Vec<u8> {
+            self.data[4..8].copy_from_slice(&first.to_le_bytes());
+            self.data
+        }
+    }
+
+    #[test]
+    fn values_of_every_type_are_read() {
+        let mut builder = TiffBuilder::new(&[]);
+        let ifd = builder.ifd(
+            vec![
+                short(IMAGE_WIDTH, &[640]),
+                long(IMAGE_LENGTH, &[70_000]),
+                short(BITS_PER_SAMPLE, &[8, 8, 8]),
+                ascii(MAKE, "Canon "),
+                rational(0x011a, &[(300, 2)]),
+                srational(0x9204, &[(-1, 3)]),
+                byte(0xc612, &[1, 4, 0, 0]),
+            ],
+            0,
+        );
+        let data = builder.finish(ifd);
+        let tiff = Tiff::new(&data, "TIFF").unwrap();
+        assert!(tiff.little_endian());
+        let ifd = tiff.first_ifd().unwrap();
+        assert_eq!(tiff.number(&ifd, IMAGE_WIDTH).unwrap(), Some(640.0));
+        assert_eq!(tiff.number(&ifd, IMAGE_LENGTH).unwrap(), Some(70_000.0));
+        assert_eq!(
+            tiff.numbers(&ifd, BITS_PER_SAMPLE).unwrap(),
+            [8.0, 8.0, 8.0]
+        );
+        assert_eq!(tiff.string(&ifd, MAKE).as_deref(), Some("Canon"));
+        assert_eq!(tiff.number(&ifd, 0x011a).unwrap(), Some(150.0));
+        assert_eq!(tiff.number(&ifd, 0x9204).unwrap(), Some(-1.0 / 3.0));
+        assert_eq!(tiff.numbers(&ifd, 0xc612).unwrap(), [1.0, 4.0, 0.0, 0.0]);
+        assert_eq!(tiff.number(&ifd, MODEL).unwrap(), None);
+        assert!(tiff.numbers(&ifd, MODEL).unwrap().is_empty());
+    }
+
+    #[test]
+    fn big_endian_files_are_read() {
+        // One IFD at offset 8 with ImageWidth 640 as a SHORT.
+        let data = [
+            b'M', b'M', 0, b'*', 0, 0, 0, 8, 0, 1, 0x01, 0x00, 0, 3, 0, 0, 0, 1, 0x02, 0x80, 0, 0,
+            0, 0, 0, 0,
+        ];
+        let tiff = Tiff::new(&data, "TIFF").unwrap();
+        assert!(!tiff.little_endian());
+        let ifd = tiff.first_ifd().unwrap();
+        assert_eq!(tiff.number(&ifd, IMAGE_WIDTH).unwrap(), Some(640.0));
+    }
+
+    #[test]
+    fn chains_and_sub_ifds_are_walked_depth_first() {
+        let mut builder = TiffBuilder::new(&[]);
+        let last = builder.ifd(vec![short(IMAGE_WIDTH, &[3])], 0);
+        let sub_b = builder.ifd(vec![short(IMAGE_WIDTH, &[2])], 0);
+        let sub_a = builder.ifd(vec![short(IMAGE_WIDTH, &[1])], 0);
+        let first = builder.ifd(
+            vec![short(IMAGE_WIDTH, &[0]), long(SUB_IFDS, &[sub_a, sub_b])],
+            last,
+        );
+        let data = builder.finish(first);
+        let tiff = Tiff::new(&data, "TIFF").unwrap();
+        let widths: Vec<_> = tiff
+            .all_ifds()
+            .unwrap()
+            .iter()
+            .map(|ifd| tiff.number(ifd, IMAGE_WIDTH).unwrap().unwrap())
+            .collect();
+        assert_eq!(widths, [0.0, 1.0, 2.0, 3.0]);
+        let sub = tiff.ifd_at(sub_b as usize).unwrap();
+        assert_eq!(tiff.number(&sub, IMAGE_WIDTH).unwrap(), Some(2.0));
+    }
+
+    #[test]
+    fn loops_are_followed_once() {
+        let mut builder = TiffBuilder::new(&[]);
+        // The IFD is the next one after itself and its own SubIFD.
+        let first = builder.ifd(vec![long(SUB_IFDS, &[8])], 8);
+        let data = builder.finish(first);
+        let tiff = Tiff::new(&data, "TIFF").unwrap();
+        assert_eq!(tiff.all_ifds().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn truncated_and_malformed_files_are_errors() {
+        assert_eq!(
+            Tiff::new(b"PK\x03\x04", "TIFF").err().map(|e| e.code),
+            Some(ErrorCode::CorruptData)
+        );
+
+        let mut builder = TiffBuilder::new(&[]);
+        let ifd = builder.ifd(
+            vec![
+                short(IMAGE_WIDTH, &[640]),
+                ascii(MAKE, "A long camera make"),
+            ],
+            0,
+        );
+        let data = builder.finish(ifd);
+        // The last byte pads the odd-length string.
+        for len in 4..data.len() - 1 {
+            let tiff = Tiff::new(&data[..len], "TIFF").unwrap();
+            let make = tiff.first_ifd().and_then(|ifd| tiff.numbers(&ifd, MAKE));
+            assert_eq!(
+                make.err().map(|e| e.code),
+                Some(ErrorCode::CorruptData),
+                "{} bytes",
+                len
+            );
+        }
+
+        // An entry count far larger than the file.
+        let mut data = data.clone();
+        data[8..10].copy_from_slice(&0xffffu16.to_le_bytes());
+        let tiff = Tiff::new(&data, "TIFF").unwrap();
+        assert_eq!(
+            tiff.first_ifd().err().map(|e| e.code),
+            Some(ErrorCode::CorruptData)
+        );
+        assert!(tiff.ifd_at(usize::MAX - 1).is_err());
+    }
+}
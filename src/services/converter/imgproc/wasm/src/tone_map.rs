@@ -0,0 +1,200 @@
+//! Reduces decoded images with more than 8 bits per channel to what a target can hold.
+//! Floating-point images, from EXR and Radiance HDR, hold linear light that can go far
+//! above 1, so they are tone mapped and gamma encoded; 16-bit images are only rescaled.
+
+use image::{DynamicImage, ImageBuffer, Rgba, Rgba32FImage, RgbaImage};
+use serde::Deserialize;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+/// How linear HDR values are brought into the 0 to 1 range of an 8- or 16-bit image.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMap {
+    /// Clips everything above 1, which suits EXRs that are already in display range.
+    #[default]
+    Clip,
+    /// `x / (1 + x)`: keeps every highlight, at the cost of some contrast.
+    Reinhard,
+    /// The ACES filmic curve, fitted by Krzysztof Narkowicz.
+    Aces,
+}
+
+impl ToneMap {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToneMap::Clip => "clip",
+            ToneMap::Reinhard => "reinhard",
+            ToneMap::Aces => "aces",
+        }
+    }
+
+    fn apply(&self, x: f32) -> f32 {
+        match self {
+            ToneMap::Clip => x.min(1.0),
+            ToneMap::Reinhard => x / (1.0 + x),
+            ToneMap::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        }
+    }
+}
+
+/// Floating-point samples, which hold linear light.
+pub fn is_hdr(image: &DynamicImage) -> bool {
+    matches!(
+        image,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    )
+}
+
+/// More than 8 bits per channel, integer or floating point.
+pub fn is_high_bit_depth(image: &DynamicImage) -> bool {
+    let color = image.color();
+    color.bytes_per_pixel() > color.channel_count()
+}
+
+/// 8-bit RGBA, tone mapped with `tone_map` after scaling by `exposure` stops if the image
+/// is HDR.
+pub fn to_rgba8(image: &DynamicImage, tone_map: ToneMap, exposure: f32) -> RgbaImage {
+    match display_range(image, tone_map, exposure) {
+        Some(image) => DynamicImage::ImageRgba32F(image).to_rgba8(),
+        None => image.to_rgba8(),
+    }
+}
+
+/// Like `to_rgba8`, but keeping 16 bits per channel.
+pub fn to_rgba16(
+    image: &DynamicImage,
+    tone_map: ToneMap,
+    exposure: f32,
+) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+    match display_range(image, tone_map, exposure) {
+        Some(image) => DynamicImage::ImageRgba32F(image).to_rgba16(),
+        None => image.to_rgba16(),
+    }
+}
+
+/// sRGB-encoded values from 0 to 1, or `None` if the image is not HDR.
+fn display_range(image: &DynamicImage, tone_map: ToneMap, exposure: f32) -> Option<Rgba32FImage> {
+    if !is_hdr(image) {
+        return None;
+    }
+    let scale = exposure.exp2();
+    let mut image = image.to_rgba32f();
+    for pixel in image.pixels_mut() {
+        for value in &mut pixel.0[..3] {
+            // `max` also turns NaN into 0.
+            *value = srgb_encode(tone_map.apply(value.max(0.0) * scale).clamp(0.0, 1.0));
+        }
+        pixel.0[3] = pixel.0[3].clamp(0.0, 1.0);
+    }
+    Some(image)
+}
+
+fn srgb_encode(linear: f32) -> f32 {
+    match linear <= 0.003_130_8 {
+        true => linear * 12.92,
+        false => 1.055 * linear.powf(1.0 / 2.4) - 0.055,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        callback_logs::CallbackLogs, cancellation::never_cancelled, convert::convert,
+        convert::ConvertOptions, first_frame::encode_png16,
+    };
+    use image::{ImageReader, Rgba32FImage};
+    use std::io::Cursor;
+
+    fn hdr(values: [f32; 3]) -> DynamicImage {
+        let [r, g, b] = values;
+        DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(1, 1, Rgba([r, g, b, 1.0])))
+    }
+
+    #[test]
+    fn clip_saturates_highlights() {
+        let pixel = to_rgba8(&hdr([2.0, 1.0, 0.0]), ToneMap::Clip, 0.0);
+        assert_eq!(pixel.get_pixel(0, 0).0, [255, 255, 0, 255]);
+    }
+
+    #[test]
+    fn reinhard_maps_one_to_one_half_before_encoding() {
+        assert_eq!(ToneMap::Reinhard.apply(1.0), 0.5);
+        assert_eq!(ToneMap::Reinhard.apply(0.0), 0.0);
+
+        // One half is then sRGB encoded, and one stop of exposure doubles the input.
+        let expected = (srgb_encode(0.5) * 255.0).round() as u8;
+        let pixel = to_rgba8(&hdr([1.0, 0.5, 0.0]), ToneMap::Reinhard, 0.0);
+        assert_eq!(pixel.get_pixel(0, 0)[0], expected);
+        let pixel = to_rgba8(&hdr([0.5, 0.0, 0.0]), ToneMap::Reinhard, 1.0);
+        assert_eq!(pixel.get_pixel(0, 0)[0], expected);
+    }
+
+    #[test]
+    fn aces_is_monotonic() {
+        let samples: Vec<f32> = (0..=400)
+            .map(|i| ToneMap::Aces.apply(i as f32 / 20.0))
+            .collect();
+        assert!(samples.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(samples[0].abs() < 1e-6);
+        assert!(*samples.last().unwrap() <= 1.05);
+    }
+
+    #[test]
+    fn nan_and_negative_values_become_black() {
+        for tone_map in [ToneMap::Clip, ToneMap::Reinhard, ToneMap::Aces] {
+            let image = hdr([f32::NAN, -3.0, f32::NEG_INFINITY]);
+            assert_eq!(
+                to_rgba8(&image, tone_map, 0.0).get_pixel(0, 0).0,
+                [0, 0, 0, 255]
+            );
+            assert_eq!(
+                to_rgba16(&image, tone_map, 0.0).get_pixel(0, 0).0,
+                [0, 0, 0, 65535]
+            );
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_png_round_trips() {
+        let image = ImageBuffer::<Rgba<u16>, _>::from_fn(5, 3, |x, y| {
+            Rgba([
+                x as u16 * 12_345,
+                y as u16 * 31_000 + 1,
+                257,
+                65535 - x as u16,
+            ])
+        });
+        let deep = DynamicImage::ImageRgba16(image.clone());
+        assert!(is_high_bit_depth(&deep) && !is_hdr(&deep));
+        assert_eq!(to_rgba16(&deep, ToneMap::Aces, 3.0), image);
+
+        let mut options = ConvertOptions::default();
+        options.set_bit_depth(16);
+        let logs = CallbackLogs::silent();
+        let png = convert(
+            &encode_png16(&image).unwrap(),
+            "png",
+            "png",
+            &options,
+            &logs,
+            never_cancelled(),
+        )
+        .unwrap();
+        let decoded = ImageReader::new(Cursor::new(png))
+            .with_guessed_format()
+            .unwrap()
+            .decode()
+            .unwrap();
+        assert_eq!(decoded.to_rgba16(), image);
+
+        // At 8 bits the samples are rescaled, not tone mapped.
+        let eight = to_rgba8(&deep, ToneMap::Reinhard, 0.0);
+        assert_eq!(
+            eight.get_pixel(1, 0)[0],
+            (12_345.0f32 / 257.0).round() as u8
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! The wasm-bindgen adapter: JS callbacks, errors and the `Wasm*` exports over the core modules.
+
+use js_sys::{Array, Boolean, Date, Error, Function, Object, Reflect};
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    animation_encode::{convert_animated, ConvertedAnimatedImage},
+    callback_logs::{CallbackLogs, ProgressEvent, ProgressSink, Stage},
+    cancellation::{cancelled, never_cancelled, Cancellation},
+    color_palette::{extract_palette, ColorPalette},
+    convert::{convert, ConvertOptions},
+    detect_animation::is_animated,
+    error::{ImgprocError, JsField, Result},
+    first_frame::{encode_png, first_frame},
+    formats::{capabilities, FormatCapabilities},
+    image_compare::{compare_images, ImageComparison},
+    image_decode::decode_static_image,
+    image_frames::{decode_first_frame, decode_frames},
+    jxl::{recompress_jpeg, reconstruct_jpeg},
+    limits::{current_limits, set_limits, DecodeLimits},
+    ora::list_ora_layers,
+    perceptual_hash::{hamming_distance, parse_hash, FrameHashes, PerceptualHashes},
+    pipeline::{run_pipeline, PipelineResult, Recipe},
+    pixel_digest::pixel_digest,
+    placeholder::{create_placeholder, decode_blurhash, decode_thumbhash, ImagePlaceholder},
+    probe::ImageInfo,
+    psd_layers::{list_psd_layers, render_psd, PsdImage, PsdLayerInfo, PsdRenderOptions},
+    raw::{develop_raw, RawOptions},
+    session::ImageSession,
+    svg::SvgOptions,
+    targeted_encode::{encode_for_quality, QualityTargetedImage},
+    thumbnail::ThumbnailOptions,
+};
+
+impl From<ImgprocError> for JsValue {
+    fn from(error: ImgprocError) -> Self {
+        let js_error = Error::new(&error.to_string());
+        js_error.set_name("ImgprocError");
+
+        for (key, value) in error.js_fields() {
+            let value = match value {
+                JsField::Text(text) => JsValue::from(text),
+                JsField::Number(number) => JsValue::from(number),
+            };
+            let _ = Reflect::set(&js_error, &key.into(), &value);
+        }
+        js_error.into()
+    }
+}
+
+fn progress_to_js(event: &ProgressEvent) -> Result<JsValue, JsValue> {
+    let object = Object::new();
+    Reflect::set(&object, &"stage".into(), &event.stage().as_str().into())?;
+    Reflect::set(&object, &"code".into(), &event.code().into())?;
+    let args: Array = event.args().iter().map(|a| JsValue::from_str(a)).collect();
+    Reflect::set(&object, &"args".into(), &args)?;
+    if let Some(current) = event.current() {
+        Reflect::set(&object, &"current".into(), &current.into())?;
+    }
+    if let Some(total) = event.total() {
+        Reflect::set(&object, &"total".into(), &total.into())?;
+    }
+    if let Some(bytes) = event.written() {
+        Reflect::set(&object, &"bytesWritten".into(), &(bytes as f64).into())?;
+    }
+    Ok(object.into())
+}
+
+impl ProgressSink for Function {
+    fn now_ms(&self) -> f64 {
+        Date::now()
+    }
+
+    fn report(&self, event: &ProgressEvent) -> Result<()> {
+        progress_to_js(event)
+            .and_then(|object| self.call1(&JsValue::null(), &object))
+            .map(|_| ())
+            .map_err(|_| {
+                ImgprocError::internal(format!("Failed to report progress: {}", event.code()))
+            })
+    }
+}
+
+fn callback_log(f: &Function) -> CallbackLogs<'_> {
+    CallbackLogs::new(f)
+}
+
+fn optional_callback_log(f: &Option<Function>) -> CallbackLogs<'_> {
+    match f {
+        Some(f) => CallbackLogs::new(f),
+        None => CallbackLogs::silent(),
+    }
+}
+
+/// Wraps a callback that returns `true` once the caller wants the work to stop.
+fn cancellation_check(f: &Option<Function>) -> Cancellation<'_> {
+    match f {
+        Some(f) => Box::new(move || match f.call0(&JsValue::null()) {
+            Ok(value) if value.is_truthy() => Err(cancelled()),
+            Ok(_) => Ok(()),
+            Err(_) => Err(ImgprocError::internal("Failed to check for cancellation.")),
+        }),
+        None => never_cancelled(),
+    }
+}
+
+#[wasm_bindgen(start)]
+pub fn init() {
+    console_error_panic_hook::set_once();
+}
+
+#[wasm_bindgen(js_name = "WasmSetDecodeLimits")]
+pub fn set_decode_limits(limits: &DecodeLimits) {
+    set_limits(*limits);
+}
+
+#[wasm_bindgen(js_name = "WasmGetDecodeLimits")]
+pub fn get_decode_limits() -> DecodeLimits {
+    current_limits()
+}
+
+/// What each format supports, so the JS side doesn't keep its own lists.
+#[wasm_bindgen(js_name = "WasmCapabilities")]
+pub fn get_capabilities() -> Vec<FormatCapabilities> {
+    capabilities()
+}
+
+#[wasm_bindgen(js_name = "WasmDetectAnimation")]
+pub fn detect_animation(image_data: &[u8], source_type: &str) -> Result<Boolean> {
+    Ok(Boolean::from(is_animated(image_data, source_type)?))
+}
+
+/// Decodes to PNG; `svg_options` sets the size SVGs are rendered at.
+#[wasm_bindgen(js_name = "WasmDecodeStaticImage")]
+pub fn decode_image(
+    image_data: &[u8],
+    source_type: &str,
+    callback: Function,
+    svg_options: Option<SvgOptions>,
+) -> Result<Vec<u8>> {
+    decode_static_image(
+        image_data,
+        source_type,
+        &svg_options.unwrap_or_default(),
+        &callback_log(&callback),
+    )
+}
+
+#[wasm_bindgen(js_name = "WasmConvertAnimatedImage")]
+pub fn convert_animated_image(
+    image_data: &[u8],
+    source_type: &str,
+    target_type: &str,
+    callback: Function,
+    is_cancelled: Option<Function>,
+) -> Result<ConvertedAnimatedImage> {
+    let logs = callback_log(&callback);
+    let cancel = cancellation_check(&is_cancelled);
+    convert_animated(image_data, source_type, target_type, &logs, cancel)
+}
+
+/// Converts between any two supported formats. Animations written to still formats keep
+/// one frame, or all of them on a sprite sheet; still output carries the ICC profile and
+/// EXIF data where the target keeps them.
+#[wasm_bindgen(js_name = "WasmConvert")]
+pub fn convert_image(
+    image_data: &[u8],
+    source_type: &str,
+    target_type: &str,
+    options: Option<ConvertOptions>,
+    callback: Function,
+    is_cancelled: Option<Function>,
+) -> Result<Vec<u8>> {
+    let logs = callback_log(&callback);
+    let cancel = cancellation_check(&is_cancelled);
+    let options = options.unwrap_or_default();
+    convert(
+        image_data,
+        source_type,
+        target_type,
+        &options,
+        &logs,
+        cancel,
+    )
+}
+
+#[wasm_bindgen(js_name = "WasmGetFirstFrame")]
+pub fn get_first_frame(image_data: &[u8], source_type: &str) -> Result<Vec<u8>> {
+    first_frame(image_data, source_type)
+}
+
+#[wasm_bindgen(js_name = "WasmPerceptualHash")]
+pub fn perceptual_hash(
+    image_data: &[u8],
+    source_type: &str,
+    callback: Function,
+) -> Result<PerceptualHashes> {
+    let logs = callback_log(&callback);
+
+    let frames = decode_frames(image_data, source_type, &logs, &never_cancelled())?;
+    logs.send(ProgressEvent::new(Stage::Encode, "hash_frames").arg(frames.len()))?;
+
+    let hashes = frames
+        .iter()
+        .map(|(image, _)| FrameHashes::new(image))
+        .collect();
+
+    PerceptualHashes::new(hashes)
+}
+
+#[wasm_bindgen(js_name = "WasmHashDistance")]
+pub fn hash_distance(a: &str, b: &str) -> Result<u32> {
+    Ok(hamming_distance(parse_hash(a)?, parse_hash(b)?))
+}
+
+#[wasm_bindgen(js_name = "WasmPixelDigest")]
+pub fn get_pixel_digest(
+    image_data: &[u8],
+    source_type: &str,
+    callback: Function,
+) -> Result<String> {
+    let logs = callback_log(&callback);
+
+    let frames = decode_frames(image_data, source_type, &logs, &never_cancelled())?;
+    logs.send(ProgressEvent::new(Stage::Encode, "hash_pixels").arg(frames.len()))?;
+
+    Ok(pixel_digest(&frames))
+}
+
+#[wasm_bindgen(js_name = "WasmExtractPalette")]
+pub fn extract_color_palette(
+    image_data: &[u8],
+    source_type: &str,
+    num_colors: u32,
+    callback: Function,
+) -> Result<ColorPalette> {
+    let logs = callback_log(&callback);
+
+    let frames = decode_frames(image_data, source_type, &logs, &never_cancelled())?;
+    logs.send(ProgressEvent::new(Stage::Quantize, "extract_colors").arg(num_colors))?;
+
+    Ok(extract_palette(&frames, num_colors))
+}
+
+#[wasm_bindgen(js_name = "WasmCreatePlaceholder")]
+pub fn create_image_placeholder(
+    image_data: &[u8],
+    source_type: &str,
+    callback: Function,
+) -> Result<ImagePlaceholder> {
+    let logs = callback_log(&callback);
+
+    let image = decode_first_frame(image_data, source_type)?;
+    logs.send(ProgressEvent::new(Stage::Encode, "encode_placeholders"))?;
+
+    create_placeholder(&image)
+}
+
+#[wasm_bindgen(js_name = "WasmRenderBlurHash")]
+pub fn render_blurhash(hash: &str, width: u32, height: u32) -> Result<Vec<u8>> {
+    encode_png(decode_blurhash(hash, width, height)?)
+}
+
+#[wasm_bindgen(js_name = "WasmRenderThumbHash")]
+pub fn render_thumbhash(hash: &str) -> Result<Vec<u8>> {
+    encode_png(decode_thumbhash(hash)?)
+}
+
+#[wasm_bindgen(js_name = "WasmCompareImages")]
+pub fn compare(
+    a_data: &[u8],
+    a_type: &str,
+    b_data: &[u8],
+    b_type: &str,
+    with_heatmap: bool,
+    callback: Function,
+) -> Result<ImageComparison> {
+    let logs = callback_log(&callback);
+
+    let a = decode_frames(a_data, a_type, &logs, &never_cancelled())?;
+    let b = decode_frames(b_data, b_type, &logs, &never_cancelled())?;
+    logs.send(
+        ProgressEvent::new(Stage::Encode, "compare_frames")
+            .arg(a.len())
+            .arg(b.len()),
+    )?;
+
+    compare_images(&a, &b, with_heatmap)
+}
+
+#[wasm_bindgen(js_name = "WasmEncodeForQuality")]
+pub fn encode_quality_targeted(
+    image_data: &[u8],
+    source_type: &str,
+    target_type: &str,
+    min_ssim: f64,
+    callback: Function,
+) -> Result<QualityTargetedImage> {
+    let logs = callback_log(&callback);
+
+    let frames = decode_frames(image_data, source_type, &logs, &never_cancelled())?;
+    logs.send(
+        ProgressEvent::new(Stage::Encode, "quality_search")
+            .arg(target_type)
+            .arg(min_ssim),
+    )?;
+
+    encode_for_quality(&frames, target_type, min_ssim, &logs)
+}
+
+/// Losslessly recompresses a JPEG file into a JPEG XL file that can rebuild it exactly.
+#[wasm_bindgen(js_name = "WasmRecompressJpeg")]
+pub fn recompress_jpeg_file(image_data: &[u8]) -> Result<Vec<u8>> {
+    recompress_jpeg(image_data)
+}
+
+/// Rebuilds the original JPEG file from a recompressed JPEG XL file.
+#[wasm_bindgen(js_name = "WasmReconstructJpeg")]
+pub fn reconstruct_jpeg_file(image_data: &[u8]) -> Result<Vec<u8>> {
+    reconstruct_jpeg(image_data)
+}
+
+#[wasm_bindgen(js_name = "WasmListPsdLayers")]
+pub fn list_layers(image_data: &[u8]) -> Result<Vec<PsdLayerInfo>> {
+    list_psd_layers(image_data)
+}
+
+/// Lists the layers and groups of an OpenRaster or Krita document.
+#[wasm_bindgen(js_name = "WasmListOraLayers")]
+pub fn list_ora(image_data: &[u8]) -> Result<Vec<PsdLayerInfo>> {
+    list_ora_layers(image_data)
+}
+
+/// Develops a DNG or CR2, or extracts the embedded preview of a DNG, CR2, NEF or ARW file.
+#[wasm_bindgen(js_name = "WasmDevelopRaw")]
+pub fn develop_raw_image(
+    image_data: &[u8],
+    options: Option<RawOptions>,
+    callback: Function,
+) -> Result<Vec<u8>> {
+    develop_raw(
+        image_data,
+        &options.unwrap_or_default(),
+        &callback_log(&callback),
+    )
+}
+
+/// Composites the chosen layers and groups of a PSD, or exports each layer as a trimmed PNG.
+#[wasm_bindgen(js_name = "WasmRenderPsd")]
+pub fn render_layers(
+    image_data: &[u8],
+    options: &PsdRenderOptions,
+    callback: Function,
+) -> Result<Vec<PsdImage>> {
+    render_psd(image_data, options, &callback_log(&callback))
+}
+
+/// Runs a JSON recipe such as `[{"op":"decode"},{"op":"resize","width":800},{"op":"encode","format":"webp"}]`
+/// in one call. The recipe is checked before anything is decoded.
+#[wasm_bindgen(js_name = "WasmRunPipeline")]
+pub fn run_recipe(
+    image_data: &[u8],
+    recipe: &str,
+    callback: Function,
+    is_cancelled: Option<Function>,
+) -> Result<PipelineResult> {
+    let recipe = Recipe::from_json(recipe)?;
+    let logs = callback_log(&callback);
+    let cancel = cancellation_check(&is_cancelled);
+    run_pipeline(image_data, &recipe, &logs, cancel, Date::now)
+}
+
+/// Holds one image between calls so that probing, converting and taking frames decode it
+/// only once. Call `free()` when done to release the bytes and decoded frames.
+#[wasm_bindgen(js_name = "ImageSession")]
+pub struct WasmImageSession {
+    session: ImageSession,
+}
+
+#[wasm_bindgen(js_class = "ImageSession")]
+impl WasmImageSession {
+    #[wasm_bindgen(constructor)]
+    pub fn new(image_data: Vec<u8>, source_type: &str) -> Result<WasmImageSession> {
+        Ok(WasmImageSession {
+            session: ImageSession::new(image_data, source_type)?,
+        })
+    }
+
+    pub fn probe(&mut self) -> Result<ImageInfo> {
+        self.session.probe()
+    }
+
+    #[wasm_bindgen(js_name = "isAnimated")]
+    pub fn is_animated(&mut self) -> Result<bool> {
+        self.session.is_animated()
+    }
+
+    #[wasm_bindgen(js_name = "firstFrame")]
+    pub fn first_frame(&mut self) -> Result<Vec<u8>> {
+        self.session.first_frame()
+    }
+
+    pub fn frame(&mut self, index: u32, callback: Option<Function>) -> Result<Vec<u8>> {
+        let logs = optional_callback_log(&callback);
+        self.session.frame(index, &logs, &never_cancelled())
+    }
+
+    pub fn convert(
+        &mut self,
+        target_type: &str,
+        options: Option<ConvertOptions>,
+        callback: Option<Function>,
+        is_cancelled: Option<Function>,
+    ) -> Result<Vec<u8>> {
+        let logs = optional_callback_log(&callback);
+        let cancel = cancellation_check(&is_cancelled);
+        let options = options.unwrap_or_default();
+        self.session.convert(target_type, &options, &logs, cancel)
+    }
+
+    pub fn thumbnail(&mut self, options: Option<ThumbnailOptions>) -> Result<Vec<u8>> {
+        self.session.thumbnail(&options.unwrap_or_default())
+    }
+}
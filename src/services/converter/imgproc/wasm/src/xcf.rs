@@ -0,0 +1,1112 @@
+//! GIMP's native XCF format, for RGB, grayscale and indexed images at 8-bit precision.
+//! Visible layers are composited with their opacity, layer mask and blend mode. Groups are
+//! composited on their own and then drawn like a layer, so pass-through groups render as
+//! Normal ones.
+
+use std::collections::HashMap;
+
+use image::{Rgba, RgbaImage};
+use miniz_oxide::inflate::decompress_to_vec_zlib_with_limit;
+
+use crate::{
+    error::{ErrorCode, ImgprocError, Result},
+    limits::current_limits,
+};
+
+const PROP_END: u32 = 0;
+const PROP_COLORMAP: u32 = 1;
+const PROP_OPACITY: u32 = 6;
+const PROP_MODE: u32 = 7;
+const PROP_VISIBLE: u32 = 8;
+const PROP_APPLY_MASK: u32 = 11;
+const PROP_OFFSETS: u32 = 15;
+const PROP_COMPRESSION: u32 = 17;
+const PROP_GROUP_ITEM: u32 = 29;
+const PROP_ITEM_PATH: u32 = 30;
+const PROP_FLOAT_OPACITY: u32 = 33;
+
+const TILE_SIZE: u32 = 64;
+/// Deeper nesting than GIMP's UI would ever produce; stops runaway recursion.
+const MAX_GROUP_DEPTH: usize = 64;
+
+pub fn is_xcf(data: &[u8]) -> bool {
+    data.starts_with(b"gimp xcf ")
+}
+
+pub fn xcf_dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    let mut reader = Reader::new(data);
+    read_version(&mut reader)?;
+    Ok((reader.u32()?, reader.u32()?))
+}
+
+/// Composites the visible layers onto a transparent canvas.
+pub fn decode_xcf(data: &[u8]) -> Result<RgbaImage> {
+    let xcf = Xcf::parse(data)?;
+    let layers = xcf
+        .layer_pointers
+        .iter()
+        .map(|pointer| xcf.layer(*pointer))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Layers are listed top to bottom, each group followed by its contents; the item path
+    // gives a layer's position in the tree, under the latest group with its parent's path.
+    let mut roots = Vec::new();
+    let mut children = vec![Vec::new(); layers.len()];
+    let mut groups: HashMap<&[u32], usize> = HashMap::new();
+    for (index, layer) in layers.iter().enumerate() {
+        if layer.path.len() > MAX_GROUP_DEPTH {
+            return Err(corrupt("The XCF layer groups are nested too deeply.", 0));
+        }
+        let parent = match layer.path.split_last() {
+            Some((_, parent_path)) if !parent_path.is_empty() => groups.get(parent_path),
+            _ => None,
+        };
+        match parent {
+            Some(parent) => children[*parent].push(index),
+            None => roots.push(index),
+        }
+        if layer.group {
+            groups.insert(layer.path.as_slice(), index);
+        }
+    }
+
+    xcf.render(&layers, &roots, &children, 0)
+}
+
+/// How a layer's colour combines with what is below it. GIMP's legacy modes map onto their
+/// newer equivalents; modes not listed here are drawn as Normal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Difference,
+    Addition,
+    Subtract,
+    Darken,
+    Lighten,
+    Divide,
+    Dodge,
+    Burn,
+    HardLight,
+    SoftLight,
+    GrainExtract,
+    GrainMerge,
+}
+
+impl BlendMode {
+    fn from_u32(mode: u32) -> Self {
+        match mode {
+            3 | 30 => BlendMode::Multiply,
+            4 | 31 => BlendMode::Screen,
+            23 => BlendMode::Overlay,
+            6 | 32 => BlendMode::Difference,
+            7 | 33 => BlendMode::Addition,
+            8 | 34 => BlendMode::Subtract,
+            9 | 35 => BlendMode::Darken,
+            10 | 36 => BlendMode::Lighten,
+            15 | 41 => BlendMode::Divide,
+            16 | 42 => BlendMode::Dodge,
+            17 | 43 => BlendMode::Burn,
+            18 | 44 => BlendMode::HardLight,
+            // The legacy Overlay mode was always computed as Soft Light.
+            5 | 19 | 45 => BlendMode::SoftLight,
+            20 | 46 => BlendMode::GrainExtract,
+            21 | 47 => BlendMode::GrainMerge,
+            _ => BlendMode::Normal,
+        }
+    }
+
+    /// `below` and `above` are colour channels from 0 to 1.
+    fn apply(&self, below: f32, above: f32) -> f32 {
+        let hard_light = |b: f32, a: f32| match a <= 0.5 {
+            true => 2.0 * b * a,
+            false => 1.0 - 2.0 * (1.0 - b) * (1.0 - a),
+        };
+        let value = match self {
+            BlendMode::Normal => above,
+            BlendMode::Multiply => below * above,
+            BlendMode::Screen => below + above - below * above,
+            BlendMode::Overlay => hard_light(above, below),
+            BlendMode::Difference => (below - above).abs(),
+            BlendMode::Addition => below + above,
+            BlendMode::Subtract => below - above,
+            BlendMode::Darken => below.min(above),
+            BlendMode::Lighten => below.max(above),
+            BlendMode::Divide if above <= 0.0 => 1.0,
+            BlendMode::Divide => below / above,
+            BlendMode::Dodge if above >= 1.0 => 1.0,
+            BlendMode::Dodge => below / (1.0 - above),
+            BlendMode::Burn if above <= 0.0 => 0.0,
+            BlendMode::Burn => 1.0 - (1.0 - below) / above,
+            BlendMode::HardLight => hard_light(below, above),
+            BlendMode::SoftLight => {
+                let screen = 1.0 - (1.0 - below) * (1.0 - above);
+                (1.0 - below) * below * above + below * screen
+            }
+            BlendMode::GrainExtract => below - above + 0.5,
+            BlendMode::GrainMerge => below + above - 0.5,
+        };
+        value.clamp(0.0, 1.0)
+    }
+}
+
+/// The image header and properties, and where the layers are.
+struct Xcf<'a> {
+    data: &'a [u8],
+    version: u32,
+    width: u32,
+    height: u32,
+    /// Linear-light samples, from the 8-bit linear precision of GIMP 2.10.
+    linear: bool,
+    compression: u8,
+    colormap: Vec<[u8; 3]>,
+    layer_pointers: Vec<u64>,
+}
+
+struct Layer {
+    width: u32,
+    height: u32,
+    kind: u32,
+    visible: bool,
+    opacity: f32,
+    mode: BlendMode,
+    left: i32,
+    top: i32,
+    group: bool,
+    /// Indices from the top level down to this layer; empty in files without groups.
+    path: Vec<u32>,
+    apply_mask: bool,
+    hierarchy: u64,
+    mask: u64,
+}
+
+impl<'a> Xcf<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        let mut reader = Reader::new(data);
+        let version = read_version(&mut reader)?;
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let base_type = reader.u32()?;
+        if base_type > 2 {
+            return Err(corrupt(format!("Unknown XCF base type {}.", base_type), 22));
+        }
+        current_limits().check_dimensions(width, height, "XCF")?;
+
+        let linear = match version {
+            0..=3 => false,
+            _ => {
+                let precision = reader.u32()?;
+                match (version, precision) {
+                    (4, 0) | (5.., 150) => false,
+                    (5.., 100) => true,
+                    _ => {
+                        return Err(ImgprocError::new(
+                            ErrorCode::UnsupportedFormat,
+                            format!(
+                                "XCF images at {} precision are not supported yet; only 8-bit images are.",
+                                precision_name(version, precision)
+                            ),
+                        )
+                        .format("XCF"));
+                    }
+                }
+            }
+        };
+
+        let mut xcf = Xcf {
+            data,
+            version,
+            width,
+            height,
+            linear,
+            compression: 0,
+            colormap: Vec::new(),
+            layer_pointers: Vec::new(),
+        };
+        for (kind, mut payload) in read_properties(&mut reader)? {
+            match kind {
+                PROP_COMPRESSION => xcf.compression = payload.u8()?,
+                PROP_COLORMAP => {
+                    let count = payload.u32()?;
+                    for _ in 0..count.min(256) {
+                        let rgb = payload.bytes(3)?;
+                        xcf.colormap.push([rgb[0], rgb[1], rgb[2]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if xcf.compression > 2 {
+            return Err(ImgprocError::new(
+                ErrorCode::UnsupportedFormat,
+                format!("XCF compression {} is not supported.", xcf.compression),
+            )
+            .format("XCF"));
+        }
+
+        loop {
+            let pointer = xcf.pointer(&mut reader)?;
+            if pointer == 0 {
+                break;
+            }
+            xcf.layer_pointers.push(pointer);
+        }
+        Ok(xcf)
+    }
+
+    /// Offsets are 64-bit from version 11.
+    fn pointer(&self, reader: &mut Reader) -> Result<u64> {
+        match self.version {
+            11.. => reader.u64(),
+            _ => reader.u32().map(u64::from),
+        }
+    }
+
+    fn reader_at(&self, pointer: u64) -> Result<Reader<'a>> {
+        let mut reader = Reader::new(self.data);
+        reader.at = usize::try_from(pointer)
+            .ok()
+            .filter(|at| *at < self.data.len())
+            .ok_or_else(|| corrupt("An XCF offset points past the end of the file.", pointer))?;
+        Ok(reader)
+    }
+
+    fn layer(&self, pointer: u64) -> Result<Layer> {
+        let mut reader = self.reader_at(pointer)?;
+        let mut layer = Layer {
+            width: reader.u32()?,
+            height: reader.u32()?,
+            kind: reader.u32()?,
+            visible: true,
+            opacity: 1.0,
+            mode: BlendMode::Normal,
+            left: 0,
+            top: 0,
+            group: false,
+            path: Vec::new(),
+            apply_mask: true,
+            hierarchy: 0,
+            mask: 0,
+        };
+        if layer.kind > 5 {
+            return Err(corrupt(
+                format!("Unknown XCF layer type {}.", layer.kind),
+                pointer + 8,
+            ));
+        }
+        read_string(&mut reader)?;
+
+        for (kind, mut payload) in read_properties(&mut reader)? {
+            match kind {
+                PROP_OPACITY => layer.opacity = payload.u32()?.min(255) as f32 / 255.0,
+                PROP_FLOAT_OPACITY => {
+                    layer.opacity = f32::from_bits(payload.u32()?).clamp(0.0, 1.0)
+                }
+                PROP_MODE => layer.mode = BlendMode::from_u32(payload.u32()?),
+                PROP_VISIBLE => layer.visible = payload.u32()? != 0,
+                PROP_APPLY_MASK => layer.apply_mask = payload.u32()? != 0,
+                PROP_OFFSETS => {
+                    layer.left = payload.u32()? as i32;
+                    layer.top = payload.u32()? as i32;
+                }
+                PROP_GROUP_ITEM => layer.group = true,
+                PROP_ITEM_PATH => {
+                    while payload.at < payload.data.len() {
+                        layer.path.push(payload.u32()?);
+                    }
+                }
+                _ => {}
+            }
+        }
+        layer.hierarchy = self.pointer(&mut reader)?;
+        layer.mask = self.pointer(&mut reader)?;
+        Ok(layer)
+    }
+
+    /// Draws `items`, given top to bottom, onto a canvas-sized transparent image. `held` is
+    /// the size of the canvases of the groups being drawn into.
+    fn render(
+        &self,
+        layers: &[Layer],
+        items: &[usize],
+        children: &[Vec<usize>],
+        held: u64,
+    ) -> Result<RgbaImage> {
+        let held = hold(held, self.width, self.height)?;
+        let mut canvas = RgbaImage::new(self.width, self.height);
+        for index in items.iter().rev() {
+            let layer = &layers[*index];
+            if !layer.visible || layer.opacity <= 0.0 {
+                continue;
+            }
+            let (mut image, left, top) = match layer.group {
+                true => (
+                    self.render(layers, &children[*index], children, held)?,
+                    0,
+                    0,
+                ),
+                false => (self.layer_pixels(layer, held)?, layer.left, layer.top),
+            };
+            if layer.apply_mask && layer.mask != 0 {
+                self.apply_mask(&mut image, layer, left, top)?;
+            }
+            draw(&mut canvas, &image, left, top, layer.opacity, layer.mode);
+        }
+        Ok(canvas)
+    }
+
+    fn layer_pixels(&self, layer: &Layer, held: u64) -> Result<RgbaImage> {
+        hold(held, layer.width, layer.height)?;
+        // RGB, RGBA, gray, gray with alpha, indexed, indexed with alpha.
+        let bpp = [3, 4, 1, 2, 1, 2][layer.kind as usize];
+        let samples = self.read_hierarchy(layer.hierarchy, layer.width, layer.height, bpp)?;
+        let color = |value: u8| match self.linear {
+            true => srgb_encode(value),
+            false => value,
+        };
+
+        let mut image = RgbaImage::new(layer.width, layer.height);
+        for (pixel, sample) in image.pixels_mut().zip(samples.chunks_exact(bpp)) {
+            let alpha = if bpp % 2 == 0 { sample[bpp - 1] } else { 255 };
+            let rgb = match layer.kind {
+                0 | 1 => [sample[0], sample[1], sample[2]],
+                2 | 3 => [sample[0]; 3],
+                _ => match self.colormap.get(sample[0] as usize) {
+                    Some(rgb) => *rgb,
+                    None => {
+                        return Err(corrupt(
+                            format!("XCF colour index {} is outside the colormap.", sample[0]),
+                            layer.hierarchy,
+                        ));
+                    }
+                },
+            };
+            // Colormap entries are always stored gamma-encoded.
+            pixel.0 = match layer.kind {
+                4 | 5 => [rgb[0], rgb[1], rgb[2], alpha],
+                _ => [color(rgb[0]), color(rgb[1]), color(rgb[2]), alpha],
+            };
+        }
+        Ok(image)
+    }
+
+    /// Multiplies the alpha of `image`, drawn at `left`, `top`, by the layer's mask, which
+    /// covers the layer's own bounds.
+    fn apply_mask(&self, image: &mut RgbaImage, layer: &Layer, left: i32, top: i32) -> Result<()> {
+        let mut reader = self.reader_at(layer.mask)?;
+        let (width, height) = (reader.u32()?, reader.u32()?);
+        read_string(&mut reader)?;
+        read_properties(&mut reader)?;
+        let hierarchy = self.pointer(&mut reader)?;
+        let mask = self.read_hierarchy(hierarchy, width, height, 1)?;
+
+        let (dx, dy) = (
+            layer.left as i64 - left as i64,
+            layer.top as i64 - top as i64,
+        );
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let (mx, my) = (x as i64 - dx, y as i64 - dy);
+            if mx < 0 || my < 0 || mx >= width as i64 || my >= height as i64 {
+                continue;
+            }
+            let value = mask[my as usize * width as usize + mx as usize];
+            pixel[3] = ((pixel[3] as u32 * value as u32 + 127) / 255) as u8;
+        }
+        Ok(())
+    }
+
+    /// Reads the full-resolution level of a hierarchy: 64x64 tiles in rows, each stored on its
+    /// own with the image's compression. Returns interleaved samples, `bpp` per pixel.
+    fn read_hierarchy(&self, pointer: u64, width: u32, height: u32, bpp: usize) -> Result<Vec<u8>> {
+        current_limits().check_dimensions(width, height, "XCF")?;
+        let mut reader = self.reader_at(pointer)?;
+        let size = (reader.u32()?, reader.u32()?, reader.u32()? as usize);
+        if size != (width, height, bpp) {
+            return Err(corrupt(
+                "An XCF hierarchy does not match its layer.",
+                pointer,
+            ));
+        }
+        let level = self.pointer(&mut reader)?;
+        let mut reader = self.reader_at(level)?;
+        if (reader.u32()?, reader.u32()?) != (width, height) {
+            return Err(corrupt("An XCF level does not match its layer.", level));
+        }
+
+        let tiles_x = width.div_ceil(TILE_SIZE);
+        let tiles_y = height.div_ceil(TILE_SIZE);
+        // Every tile must start inside the file before the samples are allocated.
+        let tile_pointers = (0..tiles_x * tiles_y)
+            .map(|_| {
+                let pointer = self.pointer(&mut reader)?;
+                self.reader_at(pointer)?;
+                Ok(pointer)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let row_len = width as usize * bpp;
+        let mut samples = vec![0; row_len * height as usize];
+        for (index, pointer) in tile_pointers.iter().enumerate() {
+            let (tile_x, tile_y) = (index as u32 % tiles_x, index as u32 / tiles_x);
+            let (x, y) = (tile_x * TILE_SIZE, tile_y * TILE_SIZE);
+            let (tile_width, tile_height) = (
+                TILE_SIZE.min(width - x) as usize,
+                TILE_SIZE.min(height - y) as usize,
+            );
+            // Compressed tiles run until the next one starts.
+            let end = tile_pointers
+                .get(index + 1)
+                .filter(|next| **next > *pointer)
+                .map_or(self.data.len(), |next| {
+                    (*next as usize).min(self.data.len())
+                });
+            let tile = self.read_tile(*pointer, end, tile_width * tile_height, bpp)?;
+
+            let tile_row = tile_width * bpp;
+            for (row, tile_row_samples) in tile.chunks_exact(tile_row).enumerate() {
+                let at = (y as usize + row) * row_len + x as usize * bpp;
+                samples[at..at + tile_row].copy_from_slice(tile_row_samples);
+            }
+        }
+        Ok(samples)
+    }
+
+    fn read_tile(&self, pointer: u64, end: usize, pixels: usize, bpp: usize) -> Result<Vec<u8>> {
+        let mut reader = self.reader_at(pointer)?;
+        let len = pixels * bpp;
+        match self.compression {
+            0 => Ok(reader.bytes(len)?.to_vec()),
+            1 => read_rle_tile(&mut reader, pixels, bpp),
+            _ => {
+                let compressed = self.data.get(reader.at..end).unwrap_or_default();
+                match decompress_to_vec_zlib_with_limit(compressed, len) {
+                    Ok(tile) if tile.len() == len => Ok(tile),
+                    _ => Err(corrupt("An XCF tile failed to decompress.", pointer)),
+                }
+            }
+        }
+    }
+}
+
+/// Reads the 14-byte signature, `gimp xcf file` for version 0 or `gimp xcf vNNN`.
+fn read_version(reader: &mut Reader) -> Result<u32> {
+    let magic = reader.bytes(14)?;
+    if !is_xcf(magic) || magic[13] != 0 {
+        return Err(corrupt("The XCF signature is missing.", 0));
+    }
+    match &magic[9..13] {
+        b"file" => Ok(0),
+        [b'v', digits @ ..] => std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| corrupt("The XCF version is malformed.", 9)),
+        _ => Err(corrupt("The XCF version is malformed.", 9)),
+    }
+}
+
+fn precision_name(version: u32, precision: u32) -> &'static str {
+    let code = match version {
+        4 => precision,
+        _ => precision / 100,
+    };
+    match (version, code) {
+        (4, 1) | (5.., 2) => "16-bit",
+        (4, 2) | (5.., 3) => "32-bit",
+        (4, 3) | (5.., 5) => "half float",
+        (4, 4) | (5.., 6) => "float",
+        (5.., 7) => "double",
+        _ => "unknown",
+    }
+}
+
+/// Properties up to `PROP_END`, each as its type and a reader over its payload.
+fn read_properties<'a>(reader: &mut Reader<'a>) -> Result<Vec<(u32, Reader<'a>)>> {
+    let mut properties = Vec::new();
+    loop {
+        let kind = reader.u32()?;
+        let len = reader.u32()? as usize;
+        if kind == PROP_END {
+            return Ok(properties);
+        }
+        let at = reader.at;
+        let mut payload = Reader::new(reader.bytes(len)?);
+        payload.base = at;
+        properties.push((kind, payload));
+    }
+}
+
+/// A length, counting the trailing NUL, then the bytes.
+fn read_string(reader: &mut Reader) -> Result<String> {
+    let len = reader.u32()? as usize;
+    let bytes = reader.bytes(len)?;
+    let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Each channel of the tile in turn, as runs: 0-126 repeat the next byte n+1 times, 127
+/// repeats it a 16-bit count of times, 128 copies a 16-bit count of bytes, and 129-255 copy
+/// 256-n bytes.
+fn read_rle_tile(reader: &mut Reader, pixels: usize, bpp: usize) -> Result<Vec<u8>> {
+    let mut tile = vec![0; pixels * bpp];
+    for channel in 0..bpp {
+        let mut i = 0;
+        while i < pixels {
+            let at = reader.at;
+            let op = reader.u8()?;
+            let (count, literal) = match op {
+                0..=126 => (op as usize + 1, false),
+                127 => (reader.u16()? as usize, false),
+                128 => (reader.u16()? as usize, true),
+                _ => (256 - op as usize, true),
+            };
+            if i + count > pixels {
+                return Err(corrupt("An XCF tile run overflows the tile.", at as u64));
+            }
+            match literal {
+                true => {
+                    for (k, value) in reader.bytes(count)?.iter().enumerate() {
+                        tile[(i + k) * bpp + channel] = *value;
+                    }
+                }
+                false => {
+                    let value = reader.u8()?;
+                    for k in 0..count {
+                        tile[(i + k) * bpp + channel] = value;
+                    }
+                }
+            }
+            i += count;
+        }
+    }
+    Ok(tile)
+}
+
+/// Adds an RGBA buffer to the `held` bytes already allocated, failing past the byte limit.
+/// Each level of nested groups holds a canvas of its own while its contents are drawn.
+fn hold(held: u64, width: u32, height: u32) -> Result<u64> {
+    let limit = current_limits().max_total_bytes() as u64;
+    let held = held.saturating_add((width as u64 * height as u64).saturating_mul(4));
+    if held > limit {
+        return Err(ImgprocError::new(
+            ErrorCode::LimitExceeded,
+            format!(
+                "The XCF layers and groups need more than the {} byte limit.",
+                limit
+            ),
+        )
+        .format("XCF"));
+    }
+    Ok(held)
+}
+
+/// Draws `layer` over `canvas` at an offset, clipped to the canvas.
+fn draw(
+    canvas: &mut RgbaImage,
+    layer: &RgbaImage,
+    left: i32,
+    top: i32,
+    opacity: f32,
+    mode: BlendMode,
+) {
+    for (x, y, above) in layer.enumerate_pixels() {
+        let (cx, cy) = (left as i64 + x as i64, top as i64 + y as i64);
+        if cx < 0 || cy < 0 || cx >= canvas.width() as i64 || cy >= canvas.height() as i64 {
+            continue;
+        }
+        let below = canvas.get_pixel_mut(cx as u32, cy as u32);
+        *below = blend(*below, *above, opacity, mode);
+    }
+}
+
+/// Source-over compositing with the blend mode applied where both pixels are opaque.
+fn blend(below: Rgba<u8>, above: Rgba<u8>, opacity: f32, mode: BlendMode) -> Rgba<u8> {
+    let above_alpha = above[3] as f32 / 255.0 * opacity;
+    if above_alpha <= 0.0 {
+        return below;
+    }
+    let below_alpha = below[3] as f32 / 255.0;
+    let alpha = above_alpha + below_alpha * (1.0 - above_alpha);
+
+    let mut out = Rgba([0, 0, 0, (alpha * 255.0).round() as u8]);
+    for c in 0..3 {
+        let (b, a) = (below[c] as f32 / 255.0, above[c] as f32 / 255.0);
+        let color = a * above_alpha * (1.0 - below_alpha)
+            + b * below_alpha * (1.0 - above_alpha)
+            + mode.apply(b, a) * above_alpha * below_alpha;
+        out[c] = ((color / alpha).clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
+    out
+}
+
+fn srgb_encode(linear: u8) -> u8 {
+    let linear = linear as f32 / 255.0;
+    let encoded = match linear <= 0.003_130_8 {
+        true => linear * 12.92,
+        false => 1.055 * linear.powf(1.0 / 2.4) - 0.055,
+    };
+    (encoded.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn corrupt(message: impl Into<String>, offset: u64) -> ImgprocError {
+    ImgprocError::corrupt(message).format("XCF").offset(offset)
+}
+
+/// Big-endian reads that fail with the offset of the truncation.
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+    /// Offset of `data` in the file, for errors.
+    base: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader {
+            data,
+            at: 0,
+            base: 0,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .at
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.at..end))
+            .ok_or_else(|| corrupt("The XCF is truncated.", (self.base + self.at) as u64))?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let b = self.bytes(8)?;
+        Ok(u64::from_be_bytes(b.try_into().unwrap_or_default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+
+    use super::*;
+    use crate::limits::{set_limits, DecodeLimits};
+
+    /// A layer, channel or group of a test file. The bytes per pixel of its hierarchy follow
+    /// from the number of samples.
+    struct Layer {
+        kind: u32,
+        width: u32,
+        height: u32,
+        properties: Vec<(u32, Vec<u8>)>,
+        samples: Vec<u8>,
+        mask: Option<Vec<u8>>,
+    }
+
+    impl Layer {
+        fn new(kind: u32, width: u32, height: u32, samples: Vec<u8>) -> Self {
+            Layer {
+                kind,
+                width,
+                height,
+                properties: Vec::new(),
+                samples,
+                mask: None,
+            }
+        }
+
+        /// A group item at `path`; its own pixels are never drawn.
+        fn group(path: &[u32]) -> Self {
+            Layer::new(1, 1, 1, vec![0; 4])
+                .with(PROP_GROUP_ITEM, &[])
+                .path(path)
+        }
+
+        fn with(mut self, kind: u32, payload: &[u8]) -> Self {
+            self.properties.push((kind, payload.to_vec()));
+            self
+        }
+
+        fn with_u32s(self, kind: u32, values: &[u32]) -> Self {
+            let payload: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+            self.with(kind, &payload)
+        }
+
+        fn path(self, path: &[u32]) -> Self {
+            self.with_u32s(PROP_ITEM_PATH, path)
+        }
+    }
+
+    struct Document {
+        version: u32,
+        width: u32,
+        height: u32,
+        base_type: u32,
+        precision: u32,
+        compression: u8,
+        colormap: Vec<[u8; 3]>,
+        layers: Vec<Layer>,
+    }
+
+    impl Document {
+        fn rgb(width: u32, height: u32, layers: Vec<Layer>) -> Self {
+            Document {
+                version: 11,
+                width,
+                height,
+                base_type: 0,
+                precision: 150,
+                compression: 0,
+                colormap: Vec::new(),
+                layers,
+            }
+        }
+
+        fn to_bytes(&self) -> Vec<u8> {
+            let mut out = match self.version {
+                0 => b"gimp xcf file\0".to_vec(),
+                version => format!("gimp xcf v{:03}\0", version).into_bytes(),
+            };
+            for value in [self.width, self.height, self.base_type] {
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            if self.version >= 4 {
+                out.extend_from_slice(&self.precision.to_be_bytes());
+            }
+            let mut colormap = (self.colormap.len() as u32).to_be_bytes().to_vec();
+            colormap.extend(self.colormap.concat());
+            let mut properties = vec![(PROP_COMPRESSION, vec![self.compression])];
+            if !self.colormap.is_empty() {
+                properties.push((PROP_COLORMAP, colormap));
+            }
+            put_properties(&mut out, &properties);
+
+            let table = out.len();
+            for _ in 0..=self.layers.len() {
+                self.put_pointer(&mut out, 0);
+            }
+            for (index, layer) in self.layers.iter().enumerate() {
+                self.patch(&mut out, table, index);
+                put_item(&mut out, layer.width, layer.height, Some(layer.kind));
+                put_properties(&mut out, &layer.properties);
+                let slots = out.len();
+                self.put_pointer(&mut out, 0);
+                self.put_pointer(&mut out, 0);
+
+                self.patch(&mut out, slots, 0);
+                self.put_hierarchy(&mut out, layer, &layer.samples);
+                if let Some(mask) = &layer.mask {
+                    self.patch(&mut out, slots, 1);
+                    put_item(&mut out, layer.width, layer.height, None);
+                    put_properties(&mut out, &[]);
+                    let slot = out.len();
+                    self.put_pointer(&mut out, 0);
+                    self.patch(&mut out, slot, 0);
+                    self.put_hierarchy(&mut out, layer, mask);
+                }
+            }
+            out
+        }
+
+        /// A hierarchy with one level, its tiles stored with the document's compression.
+        fn put_hierarchy(&self, out: &mut Vec<u8>, layer: &Layer, samples: &[u8]) {
+            let (width, height) = (layer.width, layer.height);
+            let bpp = samples.len() / (width * height).max(1) as usize;
+            for value in [width, height, bpp as u32] {
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            let slot = out.len();
+            self.put_pointer(out, 0);
+            self.put_pointer(out, 0);
+            self.patch(out, slot, 0);
+
+            out.extend_from_slice(&width.to_be_bytes());
+            out.extend_from_slice(&height.to_be_bytes());
+            let (tiles_x, tiles_y) = (width.div_ceil(TILE_SIZE), height.div_ceil(TILE_SIZE));
+            let tiles = out.len();
+            for _ in 0..=tiles_x * tiles_y {
+                self.put_pointer(out, 0);
+            }
+            for index in 0..(tiles_x * tiles_y) as usize {
+                self.patch(out, tiles, index);
+                let (x, y) = (
+                    index as u32 % tiles_x * TILE_SIZE,
+                    index as u32 / tiles_x * TILE_SIZE,
+                );
+                let tile_width = TILE_SIZE.min(width - x) as usize;
+                let mut tile = Vec::new();
+                for row in y..TILE_SIZE.min(height - y) + y {
+                    let at = (row * width + x) as usize * bpp;
+                    tile.extend_from_slice(&samples[at..at + tile_width * bpp]);
+                }
+                match self.compression {
+                    0 => out.extend(tile),
+                    1 => out.extend(rle_tile(&tile, bpp)),
+                    _ => out.extend(compress_to_vec_zlib(&tile, 6)),
+                }
+            }
+        }
+
+        fn put_pointer(&self, out: &mut Vec<u8>, pointer: usize) {
+            match self.version {
+                11.. => out.extend_from_slice(&(pointer as u64).to_be_bytes()),
+                _ => out.extend_from_slice(&(pointer as u32).to_be_bytes()),
+            }
+        }
+
+        /// Points the entry at `index` of the table starting at `table` to the end of `out`.
+        fn patch(&self, out: &mut [u8], table: usize, index: usize) {
+            let pointer = out.len();
+            match self.version {
+                11.. => {
+                    out[table + index * 8..][..8].copy_from_slice(&(pointer as u64).to_be_bytes())
+                }
+                _ => out[table + index * 4..][..4].copy_from_slice(&(pointer as u32).to_be_bytes()),
+            }
+        }
+    }
+
+    /// The size, the type for layers but not channels, and the name.
+    fn put_item(out: &mut Vec<u8>, width: u32, height: u32, kind: Option<u32>) {
+        out.extend_from_slice(&width.to_be_bytes());
+        out.extend_from_slice(&height.to_be_bytes());
+        if let Some(kind) = kind {
+            out.extend_from_slice(&kind.to_be_bytes());
+        }
+        out.extend_from_slice(&6u32.to_be_bytes());
+        out.extend_from_slice(b"layer\0");
+    }
+
+    fn put_properties(out: &mut Vec<u8>, properties: &[(u32, Vec<u8>)]) {
+        for (kind, payload) in properties {
+            out.extend_from_slice(&kind.to_be_bytes());
+            out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            out.extend_from_slice(payload);
+        }
+        out.extend_from_slice(&[0; 8]);
+    }
+
+    /// Each channel as runs of up to 127 equal bytes.
+    fn rle_tile(tile: &[u8], bpp: usize) -> Vec<u8> {
+        let mut packed = Vec::new();
+        for channel in 0..bpp {
+            let values: Vec<u8> = tile.iter().skip(channel).step_by(bpp).copied().collect();
+            let mut rest = &values[..];
+            while let Some(&first) = rest.first() {
+                let run = rest.iter().take(127).take_while(|v| **v == first).count();
+                packed.extend_from_slice(&[run as u8 - 1, first]);
+                rest = &rest[run..];
+            }
+        }
+        packed
+    }
+
+    /// A 70x3 gradient, which spans two tile columns, under a half-opaque blue layer that
+    /// hangs off the right edge and a hidden layer.
+    fn composite(version: u32, compression: u8) -> Document {
+        let gradient = (0..3)
+            .flat_map(|y| (0..70).flat_map(move |x| [x as u8 * 3, y as u8 * 50, 7]))
+            .collect();
+        let blue = [0, 0, 255, 255].repeat(6);
+        let hidden = Layer::new(0, 70, 3, vec![255; 70 * 3 * 3]).with_u32s(PROP_VISIBLE, &[0]);
+        let top = Layer::new(1, 3, 2, blue)
+            .with_u32s(PROP_OPACITY, &[128])
+            .with_u32s(PROP_OFFSETS, &[68, 1]);
+        Document {
+            version,
+            compression,
+            ..Document::rgb(70, 3, vec![hidden, top, Layer::new(0, 70, 3, gradient)])
+        }
+    }
+
+    #[test]
+    fn composites_layers_across_versions_and_compressions() {
+        for (version, compression) in [(0, 0), (3, 1), (10, 2), (11, 0), (11, 1), (11, 2)] {
+            let data = composite(version, compression).to_bytes();
+            assert!(is_xcf(&data));
+            assert_eq!(xcf_dimensions(&data).unwrap(), (70, 3));
+
+            let image = decode_xcf(&data).unwrap();
+            let case = (version, compression);
+            assert_eq!(image.get_pixel(0, 0).0, [0, 0, 7, 255], "{:?}", case);
+            assert_eq!(image.get_pixel(65, 2).0, [195, 100, 7, 255], "{:?}", case);
+            assert_eq!(image.get_pixel(67, 1).0, [201, 50, 7, 255], "{:?}", case);
+            assert_eq!(image.get_pixel(69, 2).0, [103, 50, 131, 255], "{:?}", case);
+        }
+    }
+
+    #[test]
+    fn reads_every_run_kind() {
+        // A two-byte repeat, a 16-bit repeat, a 16-bit literal and a short literal.
+        let runs = [1, 9, 127, 0, 3, 8, 128, 0, 2, 5, 6, 255, 7];
+        let tile = read_rle_tile(&mut Reader::new(&runs), 8, 1).unwrap();
+        assert_eq!(tile, [9, 9, 8, 8, 8, 5, 6, 7]);
+
+        let error = read_rle_tile(&mut Reader::new(&[8, 1]), 8, 1).unwrap_err();
+        assert_eq!(
+            (error.code, error.byte_offset),
+            (ErrorCode::CorruptData, Some(0))
+        );
+        let error = read_rle_tile(&mut Reader::new(&[127, 0]), 8, 1).unwrap_err();
+        assert_eq!(error.code, ErrorCode::CorruptData);
+    }
+
+    #[test]
+    fn applies_blend_modes_masks_and_colormaps() {
+        let mut multiply = Layer::new(0, 2, 1, vec![128; 6]).with_u32s(PROP_MODE, &[3]);
+        multiply.mask = Some(vec![255, 0]);
+        let gray = Layer::new(2, 2, 1, vec![200, 200]);
+        let image = decode_xcf(&Document::rgb(2, 1, vec![multiply, gray]).to_bytes()).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [100, 100, 100, 255]);
+        assert_eq!(image.get_pixel(1, 0).0, [200, 200, 200, 255]);
+
+        let indexed = |samples: Vec<u8>| Document {
+            base_type: 2,
+            colormap: vec![[1, 2, 3], [4, 5, 6]],
+            ..Document::rgb(2, 1, vec![Layer::new(5, 2, 1, samples)])
+        };
+        let image = decode_xcf(&indexed(vec![1, 255, 0, 0]).to_bytes()).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [4, 5, 6, 255]);
+        assert_eq!(image.get_pixel(1, 0).0, [0, 0, 0, 0]);
+        let error = decode_xcf(&indexed(vec![2, 255, 0, 0]).to_bytes()).unwrap_err();
+        assert_eq!(error.code, ErrorCode::CorruptData);
+    }
+
+    #[test]
+    fn composites_visible_groups() {
+        let layers = vec![
+            Layer::group(&[0]),
+            Layer::new(1, 1, 1, vec![255, 0, 0, 255]).path(&[0, 0]),
+            Layer::group(&[1]).with_u32s(PROP_VISIBLE, &[0]),
+            Layer::new(1, 1, 1, vec![0, 0, 255, 255])
+                .with_u32s(PROP_OFFSETS, &[1, 0])
+                .path(&[1, 0]),
+            // Its group does not exist, so it is drawn at the top level.
+            Layer::new(1, 1, 1, vec![9, 9, 9, 255])
+                .with_u32s(PROP_OFFSETS, &[2, 0])
+                .path(&[7, 0]),
+            Layer::new(0, 3, 1, [0, 255, 0].repeat(3)).path(&[2]),
+        ];
+        let image = decode_xcf(&Document::rgb(3, 1, layers).to_bytes()).unwrap();
+        let pixels: Vec<_> = image.pixels().map(|pixel| pixel.0).collect();
+        assert_eq!(pixels, [[255, 0, 0, 255], [0, 255, 0, 255], [9, 9, 9, 255]]);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let base = composite(11, 2).to_bytes();
+        let mut bad_signature = base.clone();
+        bad_signature[13] = b' ';
+        let mut bad_version = base.clone();
+        bad_version[10] = b'x';
+        let mut bad_tile = base.clone();
+        let last = bad_tile.len() - 10;
+        bad_tile[last] ^= 0xff;
+        let mut past_end = base.clone();
+        // The layer table follows the header and the compression property.
+        past_end[47..55].copy_from_slice(&u64::MAX.to_be_bytes());
+
+        let corrupt = [
+            b"gimp xcf".to_vec(),
+            bad_signature,
+            bad_version,
+            bad_tile,
+            past_end,
+            Document {
+                base_type: 3,
+                ..Document::rgb(1, 1, Vec::new())
+            }
+            .to_bytes(),
+            Document::rgb(1, 1, vec![Layer::new(6, 1, 1, vec![0; 3])]).to_bytes(),
+            // The hierarchy stores four bytes per pixel for an RGB layer.
+            Document::rgb(1, 1, vec![Layer::new(0, 1, 1, vec![0; 4])]).to_bytes(),
+            Document::rgb(1, 1, vec![Layer::new(0, 0, 1, Vec::new())]).to_bytes(),
+        ];
+        for (index, data) in corrupt.iter().enumerate() {
+            let error = decode_xcf(data).unwrap_err();
+            assert_eq!(error.code, ErrorCode::CorruptData, "case {}", index);
+        }
+
+        let unsupported = [
+            Document {
+                version: 7,
+                precision: 250,
+                ..Document::rgb(1, 1, Vec::new())
+            },
+            Document {
+                compression: 3,
+                ..Document::rgb(1, 1, Vec::new())
+            },
+        ];
+        for document in unsupported {
+            let error = decode_xcf(&document.to_bytes()).unwrap_err();
+            assert_eq!(error.code, ErrorCode::UnsupportedFormat);
+        }
+
+        let deep: Vec<_> = (1..=MAX_GROUP_DEPTH + 1)
+            .map(|depth| Layer::group(&vec![0; depth]))
+            .collect();
+        let error = decode_xcf(&Document::rgb(1, 1, deep).to_bytes()).unwrap_err();
+        assert_eq!(error.code, ErrorCode::CorruptData);
+    }
+
+    #[test]
+    fn truncated_files_fail_without_panicking() {
+        for (version, compression) in [(3, 1), (11, 0), (11, 2)] {
+            let data = composite(version, compression).to_bytes();
+            for len in 0..data.len() {
+                assert!(decode_xcf(&data[..len]).is_err(), "length {}", len);
+            }
+            crate::formats::tests::decode_truncations(&data, "xcf");
+        }
+    }
+
+    #[test]
+    fn limits_are_checked_before_allocating() {
+        // The header alone is enough to refuse the canvas.
+        let data = Document::rgb(40_000, 40_000, Vec::new()).to_bytes();
+        let error = decode_xcf(&data[..26]).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+
+        let mut limits = DecodeLimits::default();
+        limits.set_max_width(2);
+        set_limits(limits);
+        let layer = Layer::new(0, 3, 1, vec![0; 9]);
+        let error = decode_xcf(&Document::rgb(1, 1, vec![layer]).to_bytes()).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+        // The only layer starts after the two entries of the layer table.
+        let mut data = Document::rgb(1, 1, vec![Layer::new(0, 1, 1, vec![0; 3])]).to_bytes();
+        data[63..71].fill(0xff);
+        let error = decode_xcf(&data).unwrap_err();
+        assert_eq!(error.code, ErrorCode::LimitExceeded);
+
+        // Each group holds an 8x8 canvas while its contents are drawn.
+        let nested = |depth: usize| {
+            let mut layers: Vec<_> = (1..=depth)
+                .map(|level| Layer::group(&vec![0; level]))
+                .collect();
+            layers.push(Layer::new(0, 8, 8, vec![0; 8 * 8 * 3]).path(&vec![0; depth + 1]));
+            decode_xcf(&Document::rgb(8, 8, layers).to_bytes())
+        };
+        let mut limits = DecodeLimits::default();
+        limits.set_max_total_bytes(4 * 8 * 8 * 4);
+        set_limits(limits);
+        assert!(nested(2).is_ok());
+        assert_eq!(nested(3).unwrap_err().code, ErrorCode::LimitExceeded);
+    }
+}
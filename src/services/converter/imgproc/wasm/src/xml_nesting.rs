@@ -0,0 +1,109 @@
+//! A pre-scan of XML documents before they reach roxmltree, which parses nested elements
+//! recursively with no depth limit of its own. A document nested deeply enough would
+//! overflow the stack, which aborts rather than failing the decode.
+
+use crate::error::{ErrorCode, ImgprocError, Result};
+
+/// How deep elements may nest. The parser's stack frames are large in debug builds, and
+/// the wasm stack is 1 MiB.
+pub(crate) const MAX_NESTING: usize = 64;
+
+/// Fails if elements in `xml` nest more than `MAX_NESTING` deep, or if an entity holds
+/// markup, which would nest its contents wherever it is used. `name` is the document in
+/// messages, such as `stack.xml`, and `format` the format in errors.
+pub(crate) fn check_nesting(xml: &str, name: &str, format: &str) -> Result<()> {
+    let (mut depth, mut at) = (0usize, 0);
+    while let Some(start) = xml[at..].find('<').map(|i| at + i) {
+        let tag = &xml[start..];
+        let skip_to = |end: &str| tag.find(end).map_or(xml.len(), |i| start + i + end.len());
+        at = if tag.starts_with("<!--") {
+            skip_to("-->")
+        } else if tag.starts_with("<![CDATA[") {
+            skip_to("]]>")
+        } else if tag.starts_with("<?") {
+            skip_to("?>")
+        } else if tag.starts_with("<!ENTITY") {
+            let Some((value, end)) = entity_value(xml, start) else {
+                return Ok(());
+            };
+            if value.contains('<') {
+                return Err(ImgprocError::new(
+                    ErrorCode::UnsupportedFormat,
+                    format!("Entities holding markup in {} are not supported.", name),
+                )
+                .format(format));
+            }
+            end
+        } else if tag.starts_with("</") {
+            depth = depth.saturating_sub(1);
+            start + 2
+        } else if tag.starts_with("<!") {
+            start + 2
+        } else {
+            // A `>` in an attribute value can only make this count too deep.
+            let end = skip_to(">");
+            if !xml[..end].ends_with("/>") {
+                depth += 1;
+            }
+            if depth > MAX_NESTING {
+                return Err(ImgprocError::new(
+                    ErrorCode::LimitExceeded,
+                    format!("Elements in {} nest more than {} deep.", name, MAX_NESTING),
+                )
+                .format(format));
+            }
+            end
+        };
+    }
+    Ok(())
+}
+
+/// The quoted value of the entity declared at `start`, and the offset just past it; `None`
+/// if the quotes are never closed, which leaves the document for the parser to reject.
+fn entity_value(xml: &str, start: usize) -> Option<(&str, usize)> {
+    let open = start + xml[start..].find(['"', '\''])?;
+    let quote = &xml[open..open + 1];
+    let close = open + 1 + xml[open + 1..].find(quote)?;
+    Some((&xml[open + 1..close], close + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(xml: &str) -> Option<ErrorCode> {
+        check_nesting(xml, "test.xml", "XML")
+            .err()
+            .map(|error| error.code)
+    }
+
+    #[test]
+    fn counts_open_elements_only() {
+        let nested = |depth| format!("{}{}", "<a>".repeat(depth), "</a>".repeat(depth));
+        assert_eq!(check(&nested(MAX_NESTING)), None);
+        assert_eq!(
+            check(&nested(MAX_NESTING + 1)),
+            Some(ErrorCode::LimitExceeded)
+        );
+
+        // Closed, empty, commented and quoted elements do not add to the depth.
+        let flat = "<a/><!-- <a><a> --><![CDATA[<a><a>]]><?pi <a>?><b></b>".repeat(MAX_NESTING);
+        assert_eq!(check(&format!("<r>{}</r>", flat)), None);
+    }
+
+    #[test]
+    fn entities_may_hold_text_but_not_markup() {
+        let doctype = |entities: &str| format!("<!DOCTYPE r [{}]><r>&e;</r>", entities);
+        assert_eq!(check(&doctype("<!ENTITY e \"#c00\">")), None);
+        assert_eq!(check(&doctype("<!ENTITY e '> \"quoted\"'>")), None);
+        assert_eq!(
+            check(&doctype("<!ENTITY e '<a>&e;</a>'>")),
+            Some(ErrorCode::UnsupportedFormat)
+        );
+        assert_eq!(
+            check(&doctype("<!ENTITY t 'text'><!ENTITY e \"<a/>\">")),
+            Some(ErrorCode::UnsupportedFormat)
+        );
+        assert_eq!(check("<!DOCTYPE r [<!ENTITY e 'unclosed"), None);
+    }
+}
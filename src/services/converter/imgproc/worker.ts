@@ -1,15 +1,30 @@
 import type { FileFormat } from "services/converter/file-formats";
 import Wasm, {
+  SvgOptions,
+  WasmCapabilities,
   WasmConvertAnimatedImage,
   WasmDecodeStaticImage,
   WasmDetectAnimation,
   WasmGetFirstFrame,
 } from "services/converter/imgproc/wasm/pkg";
+import {
+  type ImgprocErrorInfo,
+  ToImgprocErrorInfo,
+} from "services/converter/imgproc/errors";
+import type { ProgressEvent } from "services/converter/imgproc/progress";
+
+/** Output size for SVG sources; see `SvgOptions` in the crate. */
+export interface SvgRasterOptions {
+  width?: number;
+  height?: number;
+  dpi?: number;
+}
 
 export interface DecodeStaticImageRequest {
   functionName: "DecodeStaticImage";
   fileUrl: string;
   sourceFormat: FileFormat;
+  svgOptions?: SvgRasterOptions;
 }
 
 export interface DecodeStaticImageResponse {
@@ -28,11 +43,24 @@ export interface DetectAnimationResponse {
   isAnimation: boolean;
 }
 
+/**
+ * How the main thread asks a running conversion to stop; see `Imgproc.CreateCancelToken`.
+ * Exactly one of the fields is set.
+ */
+export interface CancelToken {
+  /** Set to a non-zero value on cancel; only when the page is cross-origin isolated. */
+  flag?: Int32Array;
+  /** An object URL, revoked on cancel. */
+  url?: string;
+}
+
 export interface ConvertAnimatedImageRequest {
   functionName: "ConvertAnimatedImage";
   fileUrl: string;
   sourceFormat: FileFormat;
   targetFormat: FileFormat;
+  /** The conversion stops between frames once the main thread cancels it. */
+  cancelToken?: CancelToken;
 }
 
 export interface ConvertAnimatedImageResponse {
@@ -52,13 +80,41 @@ export interface GetFirstFrameResponse {
   firstFrame: Uint8Array;
 }
 
+/** Mirrors `FormatCapabilities` in wasm/src/formats.rs. */
+export interface FormatCapabilityInfo {
+  name: string;
+  extensions: string[];
+  mimeType: string;
+  decode: boolean;
+  encode: boolean;
+  animate: boolean;
+  encodeAnimation: boolean;
+  detectAnimation: boolean;
+  /** Camera raw sensor data is developed; NEF and ARW decode to their embedded preview. */
+  develop: boolean;
+  metadata: boolean;
+  options: string[];
+  /** Why support is partial, for example a format that can be written but not read. */
+  note?: string;
+}
+
+export interface CapabilitiesRequest {
+  functionName: "Capabilities";
+}
+
+export interface CapabilitiesResponse {
+  functionName: "Capabilities";
+  formats: FormatCapabilityInfo[];
+}
+
 export interface LogResponse {
   functionName: "Log";
-  message: string;
+  event: ProgressEvent;
 }
 
 export interface ErrorResponse {
-  error: string;
+  functionName: "Error";
+  error: ImgprocErrorInfo;
 }
 
 export type WorkerRequest =
@@ -72,14 +128,15 @@ type WorkerResponseMap = {
   DetectAnimation: DetectAnimationResponse;
   ConvertAnimatedImage: ConvertAnimatedImageResponse;
   GetFirstFrame: GetFirstFrameResponse;
+  Capabilities: CapabilitiesResponse;
 };
 
 const wasm = Wasm();
 
-function Log(message: string) {
+function Log(event: ProgressEvent) {
   const res: LogResponse = {
     functionName: "Log",
-    message,
+    event,
   };
   self.postMessage(res);
 }
@@ -88,8 +145,97 @@ function resolve(res: WorkerResponseMap[keyof WorkerResponseMap]) {
   self.postMessage(res);
 }
 
-self.onmessage = async (e: MessageEvent<WorkerRequest>) => {
+/** How often a revoked object URL is checked for, since each check is a blocking request. */
+const REVOKE_CHECK_INTERVAL_MS = 100;
+
+/**
+ * Whether the main thread has cancelled `token`. The wasm call blocks this worker's event
+ * loop, so a posted message would only arrive once it returns. A shared flag is read
+ * directly; without cross-origin isolation there is no SharedArrayBuffer, and a synchronous
+ * request for the object URL is the one way left to hear from the main thread. That
+ * request is throttled, and the answer cached in between.
+ */
+function CancelCheck(
+  token: CancelToken | undefined,
+): (() => boolean) | undefined {
+  const { flag, url } = token ?? {};
+  if (flag) {
+    return () => Atomics.load(flag, 0) !== 0;
+  }
+  if (!url) {
+    return undefined;
+  }
+
+  let checkedAt = -Infinity;
+  let revoked = false;
+  return () => {
+    const now = performance.now();
+    if (!revoked && now - checkedAt >= REVOKE_CHECK_INTERVAL_MS) {
+      checkedAt = now;
+      revoked = IsRevoked(url);
+    }
+    return revoked;
+  };
+}
+
+function IsRevoked(url: string): boolean {
+  const xhr = new XMLHttpRequest();
   try {
+    xhr.open("GET", url, false);
+    xhr.send();
+    return xhr.status !== 200;
+  } catch {
+    return true;
+  }
+}
+
+function ToSvgOptions(
+  options: SvgRasterOptions | undefined,
+): SvgOptions | undefined {
+  if (!options) {
+    return undefined;
+  }
+  const svgOptions = new SvgOptions();
+  svgOptions.width = options.width ?? 0;
+  svgOptions.height = options.height ?? 0;
+  svgOptions.dpi = options.dpi ?? 96;
+  return svgOptions;
+}
+
+function Capabilities(): FormatCapabilityInfo[] {
+  return WasmCapabilities().map((format) => {
+    const info: FormatCapabilityInfo = {
+      name: format.name,
+      extensions: format.extensions,
+      mimeType: format.mime_type,
+      decode: format.decode,
+      encode: format.encode,
+      animate: format.animate,
+      encodeAnimation: format.encode_animation,
+      detectAnimation: format.detect_animation,
+      develop: format.develop,
+      metadata: format.metadata,
+      options: format.options,
+      note: format.note,
+    };
+    format.free();
+    return info;
+  });
+}
+
+self.onmessage = async (
+  e: MessageEvent<WorkerRequest | CapabilitiesRequest>,
+) => {
+  try {
+    if (e.data.functionName === "Capabilities") {
+      await wasm;
+      resolve({
+        functionName: "Capabilities",
+        formats: Capabilities(),
+      });
+      return;
+    }
+
     const { functionName, fileUrl, sourceFormat } = e.data;
     const sourceFormatName = sourceFormat.name;
 
@@ -102,10 +248,12 @@ self.onmessage = async (e: MessageEvent<WorkerRequest>) => {
 
     switch (functionName) {
       case "DecodeStaticImage": {
+        const { svgOptions } = e.data;
         const decodedFile = WasmDecodeStaticImage(
           sourceData,
           sourceFormatName,
           Log,
+          ToSvgOptions(svgOptions),
         );
         resolve({
           functionName,
@@ -122,12 +270,13 @@ self.onmessage = async (e: MessageEvent<WorkerRequest>) => {
         break;
       }
       case "ConvertAnimatedImage": {
-        const targetFormatName = e.data.targetFormat.name;
+        const { targetFormat, cancelToken } = e.data;
         const converted = WasmConvertAnimatedImage(
           sourceData,
           sourceFormatName,
-          targetFormatName,
+          targetFormat.name,
           Log,
+          CancelCheck(cancelToken),
         );
         const firstFrame = converted.first_frame;
         const convertedFile = converted.converted_file();
@@ -148,16 +297,11 @@ self.onmessage = async (e: MessageEvent<WorkerRequest>) => {
         break;
       }
     }
-  } catch (err: any) {
-    if (!(err instanceof Error)) {
-      if (err instanceof Object) {
-        throw new Error(JSON.stringify(err));
-      } else {
-        throw new Error(err.toString());
-      }
-    }
-    throw err;
-  } finally {
-    self.close();
+  } catch (err) {
+    const res: ErrorResponse = {
+      functionName: "Error",
+      error: ToImgprocErrorInfo(err),
+    };
+    self.postMessage(res);
   }
 };
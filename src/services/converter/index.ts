@@ -22,6 +22,16 @@ const STATIC_IMAGE_FORMATS: FormatNames[] = [
   "ICO",
   "AVIF",
   "PSD",
+  "ORA",
+  "KRA",
+  "XCF",
+  "DNG",
+  "CR2",
+  "NEF",
+  "ARW",
+  "SVG",
+  "EXR",
+  "HDR",
 ];
 
 const ANIMATED_IMAGE_FORMATS: FormatNames[] = [
@@ -124,7 +134,7 @@ export default class FileConverter {
       return true;
     }
 
-    if (Imgproc.IsSupportedDetectAnimation(sourceFormat)) {
+    if (await Imgproc.IsSupportedDetectAnimation(sourceFormat)) {
       try {
         const imgproc = new Imgproc(abortController, logMessage);
         const { isAnimation } = await imgproc.DetectAnimation(
@@ -183,7 +193,7 @@ export default class FileConverter {
       }
     }
 
-    if (Imgproc.IsSupportedDecodeStaticImage(sourceFormat)) {
+    if (await Imgproc.IsSupportedDecodeStaticImage(sourceFormat)) {
       try {
         const imgproc = new Imgproc(abortController, logMessage);
 
@@ -252,7 +262,11 @@ export default class FileConverter {
     targetFormat: FileFormat,
     logMessage: LogPrinter,
   ): Promise<ConvertedFile | undefined> {
-    if (Imgproc.IsSupportedConvertAnimatedImage(sourceFormat, targetFormat)) {
+    const handledByImgproc = await Imgproc.IsSupportedConvertAnimatedImage(
+      sourceFormat,
+      targetFormat,
+    );
+    if (handledByImgproc) {
       return undefined;
     }
 
@@ -301,7 +315,11 @@ export default class FileConverter {
       fileFormat: FileFormat;
     };
   }> {
-    if (Imgproc.IsSupportedConvertAnimatedImage(sourceFormat, targetFormat)) {
+    const handledByImgproc = await Imgproc.IsSupportedConvertAnimatedImage(
+      sourceFormat,
+      targetFormat,
+    );
+    if (handledByImgproc) {
       try {
         const imgproc = new Imgproc(abortController, logMessage);
         return await imgproc.ConvertAnimatedImage(
@@ -399,7 +417,7 @@ export default class FileConverter {
     firstFrameFile: File;
     firstFrameFileFormat: FileFormat;
   }> {
-    if (Imgproc.IsSupportedGetFirstFrame(sourceFormat)) {
+    if (await Imgproc.IsSupportedGetFirstFrame(sourceFormat)) {
       const imgproc = new Imgproc(abortController, logMessage);
       return await imgproc.GetFirstFrame(file, sourceFormat);
     }